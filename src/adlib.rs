@@ -0,0 +1,731 @@
+/*
+    adlib.rs
+    Implement the AdLib Music Synthesizer Card (Yamaha YM3812 / OPL2)
+
+    The card decodes two ports: 0x388 selects a register and reads the status, 0x389
+    writes the selected register. There is no interrupt line.
+
+    The OPL2 has nine channels of two operators each. An operator is a sine oscillator
+    with a choice of four waveforms, an envelope generator and optional tremolo and
+    vibrato. The first operator of a channel either frequency-modulates the second or is
+    mixed with it. In rhythm mode channels 6, 7 and 8 instead play five percussion
+    instruments, with the hi-hat, snare and cymbal derived from noise and the phases of
+    two operators.
+
+    The chip produces a sample every 72 cycles of its 3.58MHz clock, about 49.7kHz. We
+    generate samples at that rate as emulated time passes, so register writes take
+    effect at the next sample as they would on hardware. Attenuation is calculated in
+    the chip's 0.1875dB steps, following the envelope, key scaling and LFO behavior of
+    the real chip, and the result is converted to a linear amplitude.
+
+    The status register reports the two timers. Timer 1 counts in 80us steps and timer 2
+    in 320us steps, and each sets its flag when it overflows, which is what the usual
+    AdLib detection routine looks for.
+*/
+
+use std::f64::consts::PI;
+
+use crate::cpu::CPU_MHZ;
+use crate::io::IoDevice;
+
+pub const ADLIB_ADDRESS_PORT: u16 = 0x388;
+pub const ADLIB_DATA_PORT: u16 = 0x389;
+
+const OPL_CLOCK_HZ: f64 = 3_579_545.0;
+pub const OPL_SAMPLE_RATE: f64 = OPL_CLOCK_HZ / 72.0;
+const OPL_SAMPLE_CYCLES: f64 = CPU_MHZ * 1_000_000.0 / OPL_SAMPLE_RATE;
+const TIMER1_TICK_CYCLES: f64 = CPU_MHZ * 80.0;
+
+const OPL_CHANNELS: usize = 9;
+const OPL_OPERATORS: usize = 18;
+
+// The low status bits read as 110 on an OPL2
+const STATUS_OPL2: u8    = 0b0000_0110;
+const STATUS_TIMER2: u8  = 0b0010_0000;
+const STATUS_TIMER1: u8  = 0b0100_0000;
+const STATUS_IRQ: u8     = 0b1000_0000;
+
+const TIMER_CTRL_START1: u8 = 0b0000_0001;
+const TIMER_CTRL_START2: u8 = 0b0000_0010;
+const TIMER_CTRL_MASK2: u8  = 0b0010_0000;
+const TIMER_CTRL_MASK1: u8  = 0b0100_0000;
+const TIMER_CTRL_RESET: u8  = 0b1000_0000;
+
+const RHYTHM_ENABLE: u8 = 0b0010_0000;
+const RHYTHM_BD: u8 = 0b0001_0000;
+const RHYTHM_SD: u8 = 0b0000_1000;
+const RHYTHM_TOM: u8 = 0b0000_0100;
+const RHYTHM_TC: u8 = 0b0000_0010;
+const RHYTHM_HH: u8 = 0b0000_0001;
+
+// Operators of the rhythm instruments, numbered in register order
+const SLOT_HH: usize = 13;
+const SLOT_TOM: usize = 14;
+const SLOT_SD: usize = 16;
+const SLOT_TC: usize = 17;
+
+// Maximum attenuation, in 0.1875dB steps
+const ATTENUATION_MAX: i32 = 511;
+const OPERATOR_MAX: f64 = 4095.0;
+// Scale of the summed channel output to -1.0 .. 1.0
+const OUTPUT_SCALE: f32 = 1.0 / 8192.0;
+
+// Frequency multiplier for each MULT setting, times two
+const MULTIPLIER_X2: [u32; 16] = [1, 2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 20, 24, 24, 30, 30];
+// Key scale level attenuation by the top four bits of the F-number, in 0.75dB steps
+const KSL_ROM: [i32; 16] = [0, 32, 40, 45, 48, 51, 53, 55, 56, 58, 59, 60, 61, 62, 63, 64];
+// Shift applied to the key scale level for KSL settings of 0, 1.5, 3 and 6dB/octave
+const KSL_SHIFT: [u32; 4] = [8, 1, 2, 0];
+
+// Key bits of an operator. An operator in a rhythm channel can be keyed both ways.
+const KEY_NORMAL: u8 = 0x01;
+const KEY_RHYTHM: u8 = 0x02;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum EnvelopeState {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+#[derive(Copy, Clone)]
+struct Operator {
+    tremolo: bool,
+    vibrato: bool,
+    sustain: bool,
+    ksr: bool,
+    mult: u8,
+    ksl: u8,
+    total_level: u8,
+    attack_rate: u8,
+    decay_rate: u8,
+    sustain_level: u8,
+    release_rate: u8,
+    waveform: u8,
+
+    key: u8,
+    phase: u32,
+    envelope_state: EnvelopeState,
+    envelope: i32,
+    envelope_accumulator: u32,
+    out: i32,
+    prev_out: i32,
+}
+
+impl Operator {
+    fn new() -> Self {
+        Self {
+            tremolo: false,
+            vibrato: false,
+            sustain: false,
+            ksr: false,
+            mult: 0,
+            ksl: 0,
+            total_level: 0,
+            attack_rate: 0,
+            decay_rate: 0,
+            sustain_level: 0,
+            release_rate: 0,
+            waveform: 0,
+            key: 0,
+            phase: 0,
+            envelope_state: EnvelopeState::Release,
+            envelope: ATTENUATION_MAX,
+            envelope_accumulator: 0,
+            out: 0,
+            prev_out: 0,
+        }
+    }
+
+    fn set_key(&mut self, source: u8, on: bool) {
+        let old = self.key;
+        if on {
+            self.key |= source;
+        }
+        else {
+            self.key &= !source;
+        }
+        if old == 0 && self.key != 0 {
+            self.phase = 0;
+            self.envelope_state = EnvelopeState::Attack;
+        }
+        else if old != 0 && self.key == 0 {
+            self.envelope_state = EnvelopeState::Release;
+        }
+    }
+
+    /// Advance the envelope generator by one sample. `key_scale` is the channel's key
+    /// scale number, used to speed up the envelope for higher notes.
+    fn run_envelope(&mut self, key_scale: u8) {
+        let rate = match self.envelope_state {
+            EnvelopeState::Attack => self.attack_rate,
+            EnvelopeState::Decay => self.decay_rate,
+            EnvelopeState::Sustain if self.sustain => 0,
+            EnvelopeState::Sustain | EnvelopeState::Release => self.release_rate,
+        };
+        if rate == 0 {
+            return
+        }
+        let key_scale = if self.ksr { key_scale } else { key_scale >> 2 };
+        let rate = (rate * 4 + key_scale).min(63) as u32;
+
+        if self.envelope_state == EnvelopeState::Attack && rate >= 60 {
+            self.envelope = 0;
+            self.envelope_state = EnvelopeState::Decay;
+            return
+        }
+
+        // Each step of rate 4 * n takes 2^(13 - n) samples. The low two bits of the rate
+        // make steps 1.25, 1.5 and 1.75 times as frequent.
+        self.envelope_accumulator += (4 + (rate & 3)) << (rate >> 2);
+        let steps = (self.envelope_accumulator >> 15) as i32;
+        self.envelope_accumulator &= 0x7FFF;
+        if steps == 0 {
+            return
+        }
+
+        match self.envelope_state {
+            EnvelopeState::Attack => {
+                // The attack curve is exponential: each step covers an eighth of the
+                // remaining distance, and at least one step.
+                self.envelope += (!self.envelope * steps) >> 3;
+                if self.envelope <= 0 {
+                    self.envelope = 0;
+                    self.envelope_state = EnvelopeState::Decay;
+                }
+            }
+            EnvelopeState::Decay => {
+                self.envelope = (self.envelope + steps).min(ATTENUATION_MAX);
+                // Sustain levels are 3dB steps, with the top setting extended to 93dB
+                let sustain_level = match self.sustain_level {
+                    15 => 31 << 4,
+                    level => (level as i32) << 4
+                };
+                if self.envelope >= sustain_level {
+                    self.envelope_state = EnvelopeState::Sustain;
+                }
+            }
+            EnvelopeState::Sustain | EnvelopeState::Release => {
+                self.envelope = (self.envelope + steps).min(ATTENUATION_MAX);
+            }
+        }
+    }
+
+    /// Advance the phase generator by one sample.
+    fn run_phase(&mut self, fnum: u16, block: u8, vibrato_pos: u32, vibrato_deep: bool) {
+        let mut fnum = fnum as i32;
+        if self.vibrato {
+            // Vibrato bends the F-number by up to 1/128 (deep) or 1/256 of its value in an
+            // eight step triangle wave
+            let mut range = (fnum >> 7) & 7;
+            if vibrato_pos & 3 == 0 {
+                range = 0;
+            }
+            else if vibrato_pos & 1 != 0 {
+                range >>= 1;
+            }
+            if !vibrato_deep {
+                range >>= 1;
+            }
+            if vibrato_pos & 4 != 0 {
+                range = -range;
+            }
+            fnum += range;
+        }
+        let base = ((fnum as u32) << block) >> 1;
+        let increment = (base * MULTIPLIER_X2[self.mult as usize]) >> 1;
+        self.phase = (self.phase + increment) & 0x7FFFF;
+    }
+
+    /// The 10-bit index of the current phase.
+    fn phase_index(&self) -> u32 {
+        self.phase >> 9
+    }
+
+    /// Total attenuation from the envelope, total level, key scaling and tremolo.
+    fn attenuation(&self, fnum: u16, block: u8, tremolo: i32) -> i32 {
+        let ksl = ((KSL_ROM[(fnum >> 6) as usize] << 2) - ((8 - block as i32) << 5)).max(0);
+        let mut attenuation = self.envelope
+            + ((self.total_level as i32) << 2)
+            + (ksl >> KSL_SHIFT[self.ksl as usize]);
+        if self.tremolo {
+            attenuation += tremolo;
+        }
+        attenuation.min(ATTENUATION_MAX)
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Channel {
+    fnum: u16,
+    block: u8,
+    key_on: bool,
+    feedback: u8,
+    additive: bool,
+}
+
+impl Channel {
+    fn new() -> Self {
+        Self {
+            fnum: 0,
+            block: 0,
+            key_on: false,
+            feedback: 0,
+            additive: false,
+        }
+    }
+
+    /// The key scale number: the block and the top bit of the F-number, or the next bit
+    /// if note select is set.
+    fn key_scale(&self, note_select: bool) -> u8 {
+        let bit = if note_select { self.fnum >> 8 } else { self.fnum >> 9 };
+        (self.block << 1) | (bit & 1) as u8
+    }
+}
+
+struct Timer {
+    value: u8,
+    counter: u8,
+    running: bool,
+    masked: bool,
+}
+
+impl Timer {
+    fn new() -> Self {
+        Self {
+            value: 0,
+            counter: 0,
+            running: false,
+            masked: false,
+        }
+    }
+
+    /// Count one step, returning true if the timer overflowed.
+    fn tick(&mut self) -> bool {
+        if !self.running {
+            return false
+        }
+        let (counter, overflow) = self.counter.overflowing_add(1);
+        self.counter = if overflow { self.value } else { counter };
+        overflow
+    }
+}
+
+pub struct AdLib {
+    address: u8,
+    operators: [Operator; OPL_OPERATORS],
+    channels: [Channel; OPL_CHANNELS],
+    waveform_enable: bool,
+    note_select: bool,
+    tremolo_deep: bool,
+    vibrato_deep: bool,
+    rhythm: u8,
+    noise: u32,
+    sample_count: u32,
+    tremolo_pos: u32,
+    timers: [Timer; 2],
+    status: u8,
+    timer_cycles: f64,
+    timer1_ticks: u32,
+    sample_cycles: f64,
+    output: f32,
+    sine_table: Vec<f64>,
+    exp_table: Vec<f64>,
+}
+
+impl AdLib {
+    pub fn new() -> Self {
+        Self {
+            address: 0,
+            operators: [Operator::new(); OPL_OPERATORS],
+            channels: [Channel::new(); OPL_CHANNELS],
+            waveform_enable: false,
+            note_select: false,
+            tremolo_deep: false,
+            vibrato_deep: false,
+            rhythm: 0,
+            noise: 1,
+            sample_count: 0,
+            tremolo_pos: 0,
+            timers: [Timer::new(), Timer::new()],
+            status: 0,
+            timer_cycles: 0.0,
+            timer1_ticks: 0,
+            sample_cycles: 0.0,
+            output: 0.0,
+            sine_table: (0..1024).map(|i| (2.0 * PI * i as f64 / 1024.0).sin()).collect(),
+            exp_table: (0..=ATTENUATION_MAX).map(|i| OPERATOR_MAX * 2f64.powf(-i as f64 / 32.0)).collect(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = AdLib {
+            sine_table: std::mem::take(&mut self.sine_table),
+            exp_table: std::mem::take(&mut self.exp_table),
+            ..AdLib::new()
+        };
+    }
+
+    /// The most recent sample, from -1.0 to 1.0.
+    pub fn output(&self) -> f32 {
+        self.output
+    }
+
+    pub fn read_status(&self) -> u8 {
+        self.status | STATUS_OPL2
+    }
+
+    /// Map an operator register offset to an operator number. Offsets 6, 7, 0xE and 0xF
+    /// of each group of eight are unused.
+    fn operator_index(offset: u8) -> Option<usize> {
+        let group = (offset / 8) as usize;
+        let index = (offset % 8) as usize;
+        (group < 3 && index < 6).then_some(group * 6 + index)
+    }
+
+    /// The first (modulator) operator of a channel. The second is three operators later.
+    fn channel_operator(channel: usize) -> usize {
+        (channel / 3) * 6 + channel % 3
+    }
+
+    pub fn write_register(&mut self, register: u8, data: u8) {
+        match register {
+            0x01 => self.waveform_enable = data & 0x20 != 0,
+            0x02 => self.timers[0].value = data,
+            0x03 => self.timers[1].value = data,
+            0x04 => self.write_timer_control(data),
+            0x08 => self.note_select = data & 0x40 != 0,
+            0x20..=0x35 => {
+                if let Some(op) = AdLib::operator_index(register - 0x20) {
+                    let op = &mut self.operators[op];
+                    op.tremolo = data & 0x80 != 0;
+                    op.vibrato = data & 0x40 != 0;
+                    op.sustain = data & 0x20 != 0;
+                    op.ksr = data & 0x10 != 0;
+                    op.mult = data & 0x0F;
+                }
+            }
+            0x40..=0x55 => {
+                if let Some(op) = AdLib::operator_index(register - 0x40) {
+                    self.operators[op].ksl = data >> 6;
+                    self.operators[op].total_level = data & 0x3F;
+                }
+            }
+            0x60..=0x75 => {
+                if let Some(op) = AdLib::operator_index(register - 0x60) {
+                    self.operators[op].attack_rate = data >> 4;
+                    self.operators[op].decay_rate = data & 0x0F;
+                }
+            }
+            0x80..=0x95 => {
+                if let Some(op) = AdLib::operator_index(register - 0x80) {
+                    self.operators[op].sustain_level = data >> 4;
+                    self.operators[op].release_rate = data & 0x0F;
+                }
+            }
+            0xA0..=0xA8 => {
+                let channel = &mut self.channels[(register - 0xA0) as usize];
+                channel.fnum = (channel.fnum & 0x300) | data as u16;
+            }
+            0xB0..=0xB8 => {
+                let index = (register - 0xB0) as usize;
+                let channel = &mut self.channels[index];
+                channel.fnum = (channel.fnum & 0xFF) | ((data as u16 & 0x03) << 8);
+                channel.block = (data >> 2) & 0x07;
+                let key_on = data & 0x20 != 0;
+                if key_on != channel.key_on {
+                    channel.key_on = key_on;
+                    let op = AdLib::channel_operator(index);
+                    self.operators[op].set_key(KEY_NORMAL, key_on);
+                    self.operators[op + 3].set_key(KEY_NORMAL, key_on);
+                }
+            }
+            0xBD => {
+                self.tremolo_deep = data & 0x80 != 0;
+                self.vibrato_deep = data & 0x40 != 0;
+                self.write_rhythm(data & 0x3F);
+            }
+            0xC0..=0xC8 => {
+                let channel = &mut self.channels[(register - 0xC0) as usize];
+                channel.feedback = (data >> 1) & 0x07;
+                channel.additive = data & 0x01 != 0;
+            }
+            0xE0..=0xF5 => {
+                if let Some(op) = AdLib::operator_index(register - 0xE0) {
+                    self.operators[op].waveform = data & 0x03;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn write_timer_control(&mut self, data: u8) {
+        if data & TIMER_CTRL_RESET != 0 {
+            // Resetting the flags ignores the other bits
+            self.status = 0;
+            return
+        }
+        self.timers[0].masked = data & TIMER_CTRL_MASK1 != 0;
+        self.timers[1].masked = data & TIMER_CTRL_MASK2 != 0;
+        for (timer, start) in self.timers.iter_mut().zip([TIMER_CTRL_START1, TIMER_CTRL_START2]) {
+            let running = data & start != 0;
+            if running && !timer.running {
+                timer.counter = timer.value;
+            }
+            timer.running = running;
+        }
+    }
+
+    fn write_rhythm(&mut self, rhythm: u8) {
+        let enabled = rhythm & RHYTHM_ENABLE != 0;
+        let keys = if enabled { rhythm } else { 0 };
+        let bd = AdLib::channel_operator(6);
+        self.operators[bd].set_key(KEY_RHYTHM, keys & RHYTHM_BD != 0);
+        self.operators[bd + 3].set_key(KEY_RHYTHM, keys & RHYTHM_BD != 0);
+        self.operators[SLOT_SD].set_key(KEY_RHYTHM, keys & RHYTHM_SD != 0);
+        self.operators[SLOT_TOM].set_key(KEY_RHYTHM, keys & RHYTHM_TOM != 0);
+        self.operators[SLOT_TC].set_key(KEY_RHYTHM, keys & RHYTHM_TC != 0);
+        self.operators[SLOT_HH].set_key(KEY_RHYTHM, keys & RHYTHM_HH != 0);
+        self.rhythm = rhythm;
+    }
+
+    fn run_timers(&mut self, cycles: u32) {
+        self.timer_cycles += cycles as f64;
+        while self.timer_cycles >= TIMER1_TICK_CYCLES {
+            self.timer_cycles -= TIMER1_TICK_CYCLES;
+            self.timer1_ticks = self.timer1_ticks.wrapping_add(1);
+
+            if self.timers[0].tick() && !self.timers[0].masked {
+                self.status |= STATUS_TIMER1 | STATUS_IRQ;
+            }
+            // Timer 2 counts at a quarter of the rate of timer 1
+            if self.timer1_ticks & 3 == 0 && self.timers[1].tick() && !self.timers[1].masked {
+                self.status |= STATUS_TIMER2 | STATUS_IRQ;
+            }
+        }
+    }
+
+    /// Calculate an operator's output at a phase index, with waveform selection.
+    fn operator_output(&self, op: usize, phase_index: i32, attenuation: i32) -> i32 {
+        let index = (phase_index & 0x3FF) as usize;
+        let waveform = if self.waveform_enable { self.operators[op].waveform } else { 0 };
+        let sine = self.sine_table[index];
+        let wave = match waveform {
+            // Half sine: the negative half is silent
+            1 if index & 0x200 != 0 => 0.0,
+            // Absolute sine
+            2 => sine.abs(),
+            // Pulsed sine: the rising quarter of each half
+            3 if index & 0x100 != 0 => 0.0,
+            3 => sine.abs(),
+            _ => sine
+        };
+        (wave * self.exp_table[attenuation as usize]) as i32
+    }
+
+    /// Run an operator for one sample, phase modulated by `modulation`.
+    fn run_operator(&mut self, op: usize, channel: usize, modulation: i32, tremolo: i32) -> i32 {
+        let Channel { fnum, block, .. } = self.channels[channel];
+        let attenuation = self.operators[op].attenuation(fnum, block, tremolo);
+        let phase_index = self.operators[op].phase_index() as i32 + modulation;
+        let out = self.operator_output(op, phase_index, attenuation);
+        let operator = &mut self.operators[op];
+        operator.prev_out = operator.out;
+        operator.out = out;
+        out
+    }
+
+    /// The self-modulation of a channel's first operator.
+    fn feedback(&self, channel: usize) -> i32 {
+        let feedback = self.channels[channel].feedback;
+        if feedback == 0 {
+            return 0
+        }
+        let op = &self.operators[AdLib::channel_operator(channel)];
+        (op.out + op.prev_out) >> (9 - feedback)
+    }
+
+    /// Run a two operator channel for one sample.
+    fn run_channel(&mut self, channel: usize, tremolo: i32) -> i32 {
+        let modulator = AdLib::channel_operator(channel);
+        let feedback = self.feedback(channel);
+        let modulator_out = self.run_operator(modulator, channel, feedback, tremolo);
+        if self.channels[channel].additive {
+            modulator_out + self.run_operator(modulator + 3, channel, 0, tremolo)
+        }
+        else {
+            self.run_operator(modulator + 3, channel, modulator_out, tremolo)
+        }
+    }
+
+    /// Run the rhythm instruments for one sample. The cymbal, hi-hat and snare replace the
+    /// phase of their operators with bits mixed from the hi-hat and cymbal phases and
+    /// noise.
+    fn run_rhythm(&mut self, tremolo: i32) -> i32 {
+        let bd = AdLib::channel_operator(6);
+        let feedback = self.feedback(6);
+        let bd_modulator = self.run_operator(bd, 6, feedback, tremolo);
+        let bd_out = if self.channels[6].additive {
+            self.run_operator(bd + 3, 6, 0, tremolo)
+        }
+        else {
+            self.run_operator(bd + 3, 6, bd_modulator, tremolo)
+        };
+
+        let hh_phase = self.operators[SLOT_HH].phase_index();
+        let tc_phase = self.operators[SLOT_TC].phase_index();
+        let bit = |phase: u32, n: u32| (phase >> n) & 1;
+        let mix = (bit(hh_phase, 2) ^ bit(hh_phase, 7))
+            | (bit(hh_phase, 3) ^ bit(tc_phase, 5))
+            | (bit(tc_phase, 3) ^ bit(tc_phase, 5));
+        let noise = self.noise & 1;
+
+        let hh_index = (mix << 9) | if mix ^ noise != 0 { 0xD0 } else { 0x34 };
+        let sd_index = (bit(hh_phase, 8) << 9) | ((bit(hh_phase, 8) ^ noise) << 8);
+        let tc_index = (mix << 9) | 0x80;
+
+        let mut out = bd_out;
+        for (op, channel, phase_index) in [
+            (SLOT_HH, 7, Some(hh_index)),
+            (SLOT_SD, 7, Some(sd_index)),
+            (SLOT_TOM, 8, None),
+            (SLOT_TC, 8, Some(tc_index)),
+        ] {
+            let Channel { fnum, block, .. } = self.channels[channel];
+            let attenuation = self.operators[op].attenuation(fnum, block, tremolo);
+            let phase_index = phase_index.unwrap_or_else(|| self.operators[op].phase_index());
+            let op_out = self.operator_output(op, phase_index as i32, attenuation);
+            self.operators[op].out = op_out;
+            out += op_out;
+        }
+        // The rhythm instruments play at twice the level of a melodic channel
+        out * 2
+    }
+
+    /// Generate one sample.
+    fn generate_sample(&mut self) {
+        self.sample_count = self.sample_count.wrapping_add(1);
+        let vibrato_pos = (self.sample_count >> 10) & 7;
+        if self.sample_count & 63 == 0 {
+            self.tremolo_pos = (self.tremolo_pos + 1) % 210;
+        }
+        // Tremolo is a triangle wave of up to 4.8dB (deep) or 1.2dB
+        let tremolo = if self.tremolo_pos < 105 { self.tremolo_pos } else { 210 - self.tremolo_pos };
+        let tremolo = (tremolo >> if self.tremolo_deep { 2 } else { 4 }) as i32;
+
+        for op in 0..OPL_OPERATORS {
+            let channel = &self.channels[(op / 6) * 3 + op % 3];
+            let (fnum, block) = (channel.fnum, channel.block);
+            let key_scale = channel.key_scale(self.note_select);
+            self.operators[op].run_envelope(key_scale);
+            self.operators[op].run_phase(fnum, block, vibrato_pos, self.vibrato_deep);
+        }
+
+        let rhythm = self.rhythm & RHYTHM_ENABLE != 0;
+        let melodic_channels = if rhythm { 6 } else { OPL_CHANNELS };
+        let mut sum = 0;
+        for channel in 0..melodic_channels {
+            sum += self.run_channel(channel, tremolo);
+        }
+        if rhythm {
+            sum += self.run_rhythm(tremolo);
+        }
+
+        // 23-bit noise generator
+        let bit = ((self.noise >> 14) ^ self.noise) & 1;
+        self.noise = (self.noise >> 1) | (bit << 22);
+
+        self.output = (sum as f32 * OUTPUT_SCALE).clamp(-1.0, 1.0);
+    }
+
+    /// Run the chip for the specified number of CPU cycles.
+    pub fn run(&mut self, cycles: u32) {
+        self.run_timers(cycles);
+
+        self.sample_cycles += cycles as f64;
+        while self.sample_cycles >= OPL_SAMPLE_CYCLES {
+            self.sample_cycles -= OPL_SAMPLE_CYCLES;
+            self.generate_sample();
+        }
+    }
+}
+
+impl IoDevice for AdLib {
+    fn read_u8(&mut self, port: u16) -> u8 {
+        match port {
+            ADLIB_ADDRESS_PORT => self.read_status(),
+            _ => 0xFF
+        }
+    }
+
+    fn write_u8(&mut self, port: u16, data: u8) {
+        match port {
+            ADLIB_ADDRESS_PORT => self.address = data,
+            _ => self.write_register(self.address, data)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(adlib: &mut AdLib, register: u8, data: u8) {
+        adlib.write_u8(ADLIB_ADDRESS_PORT, register);
+        adlib.write_u8(ADLIB_DATA_PORT, data);
+    }
+
+    #[test]
+    pub fn test_detection() {
+        let mut adlib = AdLib::new();
+
+        // The detection routine from the AdLib programming guide
+        write(&mut adlib, 0x04, 0x60);
+        write(&mut adlib, 0x04, 0x80);
+        assert_eq!(adlib.read_u8(ADLIB_ADDRESS_PORT) & 0xE0, 0x00);
+        write(&mut adlib, 0x02, 0xFF);
+        write(&mut adlib, 0x04, 0x21);
+        adlib.run((CPU_MHZ * 100.0) as u32);
+        assert_eq!(adlib.read_u8(ADLIB_ADDRESS_PORT) & 0xE0, 0xC0);
+        write(&mut adlib, 0x04, 0x60);
+        write(&mut adlib, 0x04, 0x80);
+        assert_eq!(adlib.read_u8(ADLIB_ADDRESS_PORT) & 0xE0, 0x00);
+    }
+
+    #[test]
+    pub fn test_tone() {
+        let mut adlib = AdLib::new();
+
+        // A 440Hz sine on channel 0: additive, carrier only, instant attack
+        write(&mut adlib, 0x20, 0x01);
+        write(&mut adlib, 0x40, 0x3F);
+        write(&mut adlib, 0x23, 0x21);
+        write(&mut adlib, 0x43, 0x00);
+        write(&mut adlib, 0x63, 0xF0);
+        write(&mut adlib, 0x83, 0x00);
+        write(&mut adlib, 0xC0, 0x01);
+        // F-number 0x244 in block 4 is 440Hz
+        write(&mut adlib, 0xA0, 0x44);
+        write(&mut adlib, 0xB0, 0x20 | (4 << 2) | 0x02);
+
+        let mut samples = Vec::new();
+        for _ in 0..OPL_SAMPLE_RATE as usize {
+            adlib.generate_sample();
+            samples.push(adlib.output());
+        }
+        let peak = samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        assert!(peak > 0.4);
+
+        let crossings = samples.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count();
+        assert!((438..=442).contains(&crossings));
+
+        // Key off releases the note
+        write(&mut adlib, 0x83, 0x0F);
+        write(&mut adlib, 0xB0, (4 << 2) | 0x02);
+        for _ in 0..OPL_SAMPLE_RATE as usize / 10 {
+            adlib.generate_sample();
+        }
+        assert!(adlib.output().abs() < 0.01);
+    }
+}
@@ -0,0 +1,543 @@
+/*
+    cassette.rs
+    Implement the IBM 5150 cassette interface
+
+    The 5150 has a cassette port driven through the PPI and PIT. Port B bit 3 switches the
+    cassette motor relay (low = motor on). The data output is the output of PIT channel #2,
+    which the BIOS programs for a 1ms cycle for a 1 bit and a 0.5ms cycle for a 0 bit. The
+    data input is a squared-up copy of the signal from the tape, read on port C bit 4. The
+    BIOS measures the time between transitions to recover bits.
+
+    A tape is stored as a list of pulses: the durations, in microseconds, of alternating
+    low and high levels. Tapes can be loaded from and saved to WAV files, or to a compact
+    bit-level format (.mct) that stores only the decoded bits and the gaps between blocks.
+
+    MCT format:
+        "MCAS" magic, u16 version
+        Blocks of: u32 gap before block (us), u32 bit count, bits packed MSB first
+    All values are little endian.
+*/
+
+use std::{
+    ffi::OsString,
+    fs,
+    io,
+    path::Path,
+};
+
+use crate::cpu::CPU_MHZ;
+use crate::sound::{SoundSink, WavFileSink};
+
+pub const CASSETTE_DIR: &str = "./cassette";
+
+const MCT_MAGIC: &[u8; 4] = b"MCAS";
+const MCT_VERSION: u16 = 1;
+
+// Half-cycle lengths written by the BIOS for 0 and 1 bits
+const HALF_BIT_0_MICROS: u32 = 250;
+const HALF_BIT_1_MICROS: u32 = 500;
+// A full cycle longer than this is a 1 bit
+const BIT_THRESHOLD_MICROS: u32 = 750;
+// A level held longer than this is a gap between blocks, not part of a bit
+const GAP_THRESHOLD_MICROS: u32 = 2500;
+// Blank tape left by the motor starting and stopping around a recording
+const MOTOR_GAP_MICROS: u32 = 100_000;
+
+const WAV_SAVE_RATE: u32 = 44100;
+const WAV_SAVE_LEVEL: f32 = 0.5;
+// Fraction of peak amplitude a WAV signal must cross to change level
+const WAV_HYSTERESIS: f32 = 0.1;
+
+#[derive(Debug)]
+pub enum TapeError {
+    Io(io::Error),
+    BadFormat(&'static str),
+}
+
+impl std::fmt::Display for TapeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TapeError::Io(e) => write!(f, "{}", e),
+            TapeError::BadFormat(s) => write!(f, "Invalid tape image: {}", s),
+        }
+    }
+}
+
+impl From<io::Error> for TapeError {
+    fn from(e: io::Error) -> Self {
+        TapeError::Io(e)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Tape {
+    /// Durations of alternating levels in microseconds. Even indices are low.
+    pulses: Vec<u32>,
+}
+
+fn pulse_level(index: usize) -> bool {
+    index & 1 != 0
+}
+
+impl Tape {
+    pub fn new() -> Self {
+        Self { pulses: Vec::new() }
+    }
+
+    pub fn len_micros(&self) -> u64 {
+        self.pulses.iter().map(|p| *p as u64).sum()
+    }
+
+    /// Load a tape from a file. The format is chosen by extension.
+    pub fn load(path: &Path) -> Result<Self, TapeError> {
+        let data = fs::read(path)?;
+        match extension(path).as_deref() {
+            Some("wav") => Tape::from_wav(&data),
+            Some("mct") => Tape::from_mct(&data),
+            _ => Err(TapeError::BadFormat("unknown file extension"))
+        }
+    }
+
+    /// Save a tape to a file. The format is chosen by extension.
+    pub fn save(&self, path: &Path) -> Result<(), TapeError> {
+        match extension(path).as_deref() {
+            Some("wav") => self.save_wav(path),
+            Some("mct") => Ok(fs::write(path, self.to_mct())?),
+            _ => Err(TapeError::BadFormat("unknown file extension"))
+        }
+    }
+
+    /// Decode a PCM WAV file. The first channel is squared up with some hysteresis so that
+    /// noise around the zero crossings doesn't produce extra transitions.
+    pub fn from_wav(data: &[u8]) -> Result<Self, TapeError> {
+
+        if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+            return Err(TapeError::BadFormat("not a WAV file"))
+        }
+
+        let mut fmt = None;
+        let mut samples = None;
+        let mut pos = 12;
+        while pos + 8 <= data.len() {
+            let id = &data[pos..pos + 4];
+            let size = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap()) as usize;
+            let body = &data[pos + 8..(pos + 8 + size).min(data.len())];
+            match id {
+                b"fmt " if body.len() >= 16 => {
+                    let format = u16::from_le_bytes([body[0], body[1]]);
+                    let channels = u16::from_le_bytes([body[2], body[3]]) as usize;
+                    let rate = u32::from_le_bytes(body[4..8].try_into().unwrap());
+                    let bits = u16::from_le_bytes([body[14], body[15]]);
+                    fmt = Some((format, channels, rate, bits));
+                }
+                b"data" => samples = Some(body),
+                _ => {}
+            }
+            // Chunks are padded to an even size
+            pos += 8 + size + (size & 1);
+        }
+
+        let (format, channels, rate, bits) = fmt.ok_or(TapeError::BadFormat("missing fmt chunk"))?;
+        let body = samples.ok_or(TapeError::BadFormat("missing data chunk"))?;
+        if format != 1 || channels == 0 || rate == 0 {
+            return Err(TapeError::BadFormat("unsupported WAV encoding"))
+        }
+
+        let levels: Vec<f32> = match bits {
+            8 => body.chunks_exact(channels)
+                .map(|frame| (frame[0] as f32 - 128.0) / 128.0)
+                .collect(),
+            16 => body.chunks_exact(channels * 2)
+                .map(|frame| i16::from_le_bytes([frame[0], frame[1]]) as f32 / 32768.0)
+                .collect(),
+            _ => return Err(TapeError::BadFormat("unsupported WAV sample size"))
+        };
+
+        let mean = levels.iter().sum::<f32>() / levels.len().max(1) as f32;
+        let peak = levels.iter().fold(0.0f32, |peak, s| peak.max((s - mean).abs()));
+        let threshold = peak * WAV_HYSTERESIS;
+
+        let mut tape = Tape::new();
+        let mut level = false;
+        let mut run = 0u64;
+        for sample in levels {
+            let sample = sample - mean;
+            let new_level = if sample > threshold {
+                true
+            }
+            else if sample < -threshold {
+                false
+            }
+            else {
+                level
+            };
+            if new_level != level {
+                tape.pulses.push((run * 1_000_000 / rate as u64) as u32);
+                run = 0;
+                level = new_level;
+            }
+            run += 1;
+        }
+        tape.pulses.push((run * 1_000_000 / rate as u64) as u32);
+        Ok(tape)
+    }
+
+    fn save_wav(&self, path: &Path) -> Result<(), TapeError> {
+        let mut sink = WavFileSink::new(path, WAV_SAVE_RATE)?;
+        let mut samples = Vec::new();
+        let mut elapsed = 0u64;
+        let mut written = 0u64;
+        for (i, pulse) in self.pulses.iter().enumerate() {
+            elapsed += *pulse as u64;
+            let end = elapsed * WAV_SAVE_RATE as u64 / 1_000_000;
+            let level = if pulse_level(i) { WAV_SAVE_LEVEL } else { -WAV_SAVE_LEVEL };
+            samples.clear();
+            samples.resize((end - written) as usize, level);
+            sink.queue_samples(&samples);
+            written = end;
+        }
+        Ok(())
+    }
+
+    /// Read the compact bit-level format.
+    pub fn from_mct(data: &[u8]) -> Result<Self, TapeError> {
+        if data.len() < 6 || &data[0..4] != MCT_MAGIC {
+            return Err(TapeError::BadFormat("not an MCT file"))
+        }
+        if u16::from_le_bytes([data[4], data[5]]) != MCT_VERSION {
+            return Err(TapeError::BadFormat("unsupported MCT version"))
+        }
+
+        let mut tape = Tape::new();
+        let mut pos = 6;
+        while pos < data.len() {
+            if pos + 8 > data.len() {
+                return Err(TapeError::BadFormat("truncated block header"))
+            }
+            let gap = u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap());
+            let bit_count = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap()) as usize;
+            pos += 8;
+            let byte_count = bit_count.div_ceil(8);
+            if pos + byte_count > data.len() {
+                return Err(TapeError::BadFormat("truncated block"))
+            }
+            let bits = &data[pos..pos + byte_count];
+            pos += byte_count;
+
+            // The gap is held low, so the bits start on a high half-cycle
+            if pulse_level(tape.pulses.len()) {
+                tape.pulses.push(0);
+            }
+            tape.pulses.push(gap);
+            for i in 0..bit_count {
+                let half = match bits[i / 8] & (0x80 >> (i % 8)) {
+                    0 => HALF_BIT_0_MICROS,
+                    _ => HALF_BIT_1_MICROS
+                };
+                tape.pulses.push(half);
+                tape.pulses.push(half);
+            }
+        }
+        Ok(tape)
+    }
+
+    /// Write the compact bit-level format. Pairs of half-cycles between gaps are decoded
+    /// into bits.
+    pub fn to_mct(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(MCT_MAGIC);
+        data.extend_from_slice(&MCT_VERSION.to_le_bytes());
+
+        // Merge empty pulses, which join the levels either side of them
+        let mut merged: Vec<u32> = Vec::new();
+        let mut iter = self.pulses.iter().copied();
+        while let Some(pulse) = iter.next() {
+            if pulse == 0 && !merged.is_empty() {
+                let next = iter.next().unwrap_or(0);
+                *merged.last_mut().unwrap() += next;
+            }
+            else {
+                merged.push(pulse);
+            }
+        }
+
+        let write_block = |data: &mut Vec<u8>, gap: u32, halves: &[u32]| {
+            let bits: Vec<bool> = halves.chunks_exact(2)
+                .map(|cycle| cycle[0] + cycle[1] > BIT_THRESHOLD_MICROS)
+                .collect();
+            if bits.is_empty() {
+                return
+            }
+            data.extend_from_slice(&gap.to_le_bytes());
+            data.extend_from_slice(&(bits.len() as u32).to_le_bytes());
+            for byte_bits in bits.chunks(8) {
+                let byte = byte_bits.iter().enumerate()
+                    .fold(0u8, |byte, (i, bit)| byte | ((*bit as u8) << (7 - i)));
+                data.push(byte);
+            }
+        };
+
+        let mut gap = 0u32;
+        let mut block_start = 0;
+        for (i, pulse) in merged.iter().enumerate() {
+            if *pulse > GAP_THRESHOLD_MICROS {
+                write_block(&mut data, gap, &merged[block_start..i]);
+                gap = *pulse;
+                block_start = i + 1;
+            }
+        }
+        write_block(&mut data, gap, &merged[block_start..]);
+        data
+    }
+}
+
+fn extension(path: &Path) -> Option<String> {
+    path.extension().map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+}
+
+/// Return the names of tape images in the cassette directory.
+pub fn scan_dir(path: &str) -> Vec<OsString> {
+    let mut names: Vec<OsString> = match fs::read_dir(path) {
+        Ok(dir) => dir
+            .flatten()
+            .filter(|entry| matches!(extension(&entry.path()).as_deref(), Some("wav") | Some("mct")))
+            .map(|entry| entry.file_name())
+            .collect(),
+        Err(_) => Vec::new()
+    };
+    names.sort();
+    names
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CassetteMode {
+    Play,
+    Record,
+}
+
+#[derive(Default)]
+pub struct CassetteStringState {
+    pub tape_name: String,
+    pub mode: String,
+    pub motor: String,
+    pub position: String,
+    pub length: String,
+}
+
+/// The cassette deck. The motor is driven by the PPI relay and the tape moves only while
+/// it is on. The play and record buttons are pressed by the user.
+pub struct Cassette {
+    tape: Option<Tape>,
+    tape_name: String,
+    mode: CassetteMode,
+    motor_on: bool,
+    pulse_idx: usize,
+    pulse_offset: f64,
+    input_level: bool,
+    recording: Option<f64>,
+}
+
+impl Cassette {
+    pub fn new() -> Self {
+        Self {
+            tape: None,
+            tape_name: String::new(),
+            mode: CassetteMode::Play,
+            motor_on: false,
+            pulse_idx: 0,
+            pulse_offset: 0.0,
+            input_level: false,
+            recording: None,
+        }
+    }
+
+    pub fn insert(&mut self, tape: Tape, name: &str) {
+        self.stop_recording();
+        self.tape = Some(tape);
+        self.tape_name = name.to_string();
+        self.rewind();
+    }
+
+    pub fn eject(&mut self) -> Option<Tape> {
+        self.stop_recording();
+        self.tape_name.clear();
+        self.tape.take()
+    }
+
+    pub fn tape(&self) -> Option<&Tape> {
+        self.tape.as_ref()
+    }
+
+    pub fn rewind(&mut self) {
+        self.stop_recording();
+        self.pulse_idx = 0;
+        self.pulse_offset = 0.0;
+        self.input_level = false;
+    }
+
+    /// Press play or record. Recording with no tape inserted inserts a blank tape.
+    pub fn set_mode(&mut self, mode: CassetteMode) {
+        self.stop_recording();
+        if mode == CassetteMode::Record && self.tape.is_none() {
+            self.insert(Tape::new(), "blank");
+        }
+        self.mode = mode;
+    }
+
+    /// Return the level read from the tape.
+    pub fn get_input(&self) -> bool {
+        self.input_level
+    }
+
+    fn position_micros(&self) -> u64 {
+        match &self.tape {
+            Some(tape) => {
+                let idx = self.pulse_idx.min(tape.pulses.len());
+                tape.pulses[..idx].iter().map(|p| *p as u64).sum::<u64>() + self.pulse_offset as u64
+            }
+            None => 0
+        }
+    }
+
+    /// Start recording at the current position. The rest of the tape is overwritten.
+    fn start_recording(&mut self) {
+        if let Some(tape) = &mut self.tape {
+            tape.pulses.truncate(self.pulse_idx);
+            if self.pulse_offset > 0.0 {
+                tape.pulses.push(self.pulse_offset as u32);
+            }
+            tape.pulses.push(MOTOR_GAP_MICROS);
+            self.recording = Some(0.0);
+        }
+    }
+
+    fn stop_recording(&mut self) {
+        if let (Some(tape), Some(elapsed)) = (&mut self.tape, self.recording.take()) {
+            tape.pulses.push(elapsed as u32);
+            tape.pulses.push(MOTOR_GAP_MICROS);
+            self.pulse_idx = tape.pulses.len();
+            self.pulse_offset = 0.0;
+        }
+    }
+
+    /// Run the deck for the specified number of cycles. `motor_on` is the state of the motor
+    /// relay and `output` the level of the data output.
+    pub fn run(&mut self, motor_on: bool, output: bool, cycles: u32) {
+
+        if motor_on != self.motor_on {
+            self.motor_on = motor_on;
+            log::trace!("Cassette: motor {}", if motor_on { "on" } else { "off" });
+            if motor_on && self.mode == CassetteMode::Record {
+                self.start_recording();
+            }
+            else if !motor_on {
+                self.stop_recording();
+            }
+        }
+        if !self.motor_on {
+            return
+        }
+
+        let micros = cycles as f64 / CPU_MHZ;
+        let tape = match &mut self.tape {
+            Some(tape) => tape,
+            None => return
+        };
+
+        match self.mode {
+            CassetteMode::Record => {
+                if let Some(elapsed) = &mut self.recording {
+                    *elapsed += micros;
+                    if output != pulse_level(tape.pulses.len()) {
+                        tape.pulses.push(*elapsed as u32);
+                        *elapsed = 0.0;
+                    }
+                }
+            }
+            CassetteMode::Play => {
+                self.pulse_offset += micros;
+                while self.pulse_idx < tape.pulses.len()
+                    && self.pulse_offset >= tape.pulses[self.pulse_idx] as f64 {
+                    self.pulse_offset -= tape.pulses[self.pulse_idx] as f64;
+                    self.pulse_idx += 1;
+                }
+                if self.pulse_idx >= tape.pulses.len() {
+                    self.pulse_offset = 0.0;
+                }
+                self.input_level = pulse_level(self.pulse_idx);
+            }
+        }
+    }
+
+    pub fn get_string_state(&self) -> CassetteStringState {
+        let recorded = self.recording.unwrap_or(0.0) as u64;
+        CassetteStringState {
+            tape_name: match self.tape {
+                Some(_) => self.tape_name.clone(),
+                None => "No tape".to_string()
+            },
+            mode: format!("{:?}", self.mode),
+            motor: if self.motor_on { "On".to_string() } else { "Off".to_string() },
+            position: format!("{:.1}s", (self.position_micros() + recorded) as f64 / 1_000_000.0),
+            length: format!("{:.1}s", self.tape.as_ref().map_or(0, |t| t.len_micros() + recorded) as f64 / 1_000_000.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Record a byte as the BIOS would: toggle the output every half-cycle
+    fn record_bits(cassette: &mut Cassette, bits: &[bool]) {
+        let mut level = false;
+        for bit in bits {
+            let half = if *bit { HALF_BIT_1_MICROS } else { HALF_BIT_0_MICROS };
+            for _ in 0..2 {
+                level = !level;
+                let cycles = (half as f64 * CPU_MHZ) as u32;
+                let mut elapsed = 0;
+                while elapsed < cycles {
+                    cassette.run(true, level, 7);
+                    elapsed += 7;
+                }
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_record_and_play() {
+        let bits = [true, false, true, true, false, false, true, false, true];
+
+        let mut cassette = Cassette::new();
+        cassette.set_mode(CassetteMode::Record);
+        record_bits(&mut cassette, &bits);
+        cassette.run(false, false, 7);
+
+        // The recording is decoded to the same bits
+        let mct = cassette.eject().unwrap().to_mct();
+        assert_eq!(u32::from_le_bytes(mct[10..14].try_into().unwrap()), bits.len() as u32);
+        assert_eq!(mct[14], 0b1011_0010);
+        assert_eq!(mct[15], 0b1000_0000);
+
+        // Play it back and time the transitions
+        cassette.insert(Tape::from_mct(&mct).unwrap(), "test");
+        cassette.set_mode(CassetteMode::Play);
+        let mut halves = Vec::new();
+        let mut level = cassette.get_input();
+        let mut elapsed = 0;
+        for _ in 0..((MOTOR_GAP_MICROS as f64 + 20_000.0) * CPU_MHZ / 7.0) as u32 {
+            cassette.run(true, false, 7);
+            elapsed += 7;
+            if cassette.get_input() != level {
+                level = cassette.get_input();
+                halves.push((elapsed as f64 / CPU_MHZ) as u32);
+                elapsed = 0;
+            }
+        }
+        let decoded: Vec<bool> = halves[1..].chunks_exact(2)
+            .map(|cycle| cycle[0] + cycle[1] > BIT_THRESHOLD_MICROS)
+            .collect();
+        assert_eq!(decoded, bits);
+    }
+}
@@ -0,0 +1,172 @@
+/*
+    d86f.rs
+    Import 86Box (.86F) bitstream floppy images
+
+    86F images start with an 8 byte header:
+
+        4 bytes     Signature "86BF"
+        u16         Version, 0x020C for 2.12
+        u16         Disk flags:
+                        Bit 0       Surface data follows each track's cells
+                        Bit 3       Double sided
+                        Bit 7       Tracks give a count of extra bitcells
+                        Bit 11      Cell words are stored big endian
+
+    followed by a table of 512 u32 track offsets, indexed by track * sides + side, where 0
+    marks a missing track. Each track has:
+
+        u16         Track flags:
+                        Bits 0-1    Data rate: 500, 300, 250, 1000Kbps
+                        Bits 3-4    Encoding: 1 for MFM
+                        Bits 5-6    RPM: 0 for 300, 1 for 360
+        i32         Extra bitcells, if the disk flags say so
+        u32         Index hole position, in bitcells
+        Cells, as u16 words read from the most significant bit
+        Surface data, if present, in the same layout. A set bit marks a weak or unrecorded
+        cell.
+
+    The number of cells on a track is set by its data rate and RPM, plus any extra bitcells.
+*/
+
+use crate::floppy_image::{DiskImage, ImageError, ImageFormat};
+use crate::mfm::{self, TrackCells};
+
+pub const D86F_SIGNATURE: &[u8] = b"86BF";
+
+const HEADER_LEN: usize = 8;
+const TRACK_TABLE_LEN: usize = 512;
+
+const DISK_SURFACE: u16 = 0b0000_0000_0000_0001;
+const DISK_DOUBLE_SIDED: u16 = 0b0000_0000_0000_1000;
+const DISK_EXTRA_BITCELLS: u16 = 0b0000_0000_1000_0000;
+const DISK_BIG_ENDIAN: u16 = 0b0000_1000_0000_0000;
+
+const TRACK_RATE_MASK: u16 = 0b0000_0011;
+const TRACK_ENCODING_MASK: u16 = 0b0001_1000;
+const TRACK_ENCODING_MFM: u16 = 0b0000_1000;
+const TRACK_RPM_MASK: u16 = 0b0110_0000;
+const TRACK_RPM_360: u16 = 0b0010_0000;
+
+const RATES: [u16; 4] = [500, 300, 250, 1000];
+
+fn u16_at(data: &[u8], pos: usize) -> Result<u16, ImageError> {
+    data.get(pos..pos + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or(ImageError::BadFormat("Truncated 86F image"))
+}
+
+fn u32_at(data: &[u8], pos: usize) -> Result<u32, ImageError> {
+    data.get(pos..pos + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(ImageError::BadFormat("Truncated 86F image"))
+}
+
+/// Unpack cells stored as u16 words, most significant bit first.
+fn unpack_words(data: &[u8], count: usize, big_endian: bool) -> Vec<bool> {
+    let bytes: Vec<u8> = data.chunks(2)
+        .flat_map(|word| match (big_endian, word) {
+            (false, &[lo, hi]) => [hi, lo],
+            (_, &[first, second]) => [first, second],
+            (_, _) => [word[0], 0],
+        })
+        .collect();
+    mfm::unpack_cells(&bytes, count, true)
+}
+
+pub fn load(data: &[u8]) -> Result<DiskImage, ImageError> {
+    let disk_flags = u16_at(data, 6)?;
+    let sides = if disk_flags & DISK_DOUBLE_SIDED != 0 { 2 } else { 1 };
+    let big_endian = disk_flags & DISK_BIG_ENDIAN != 0;
+
+    let mut tracks = Vec::new();
+    for index in 0..TRACK_TABLE_LEN {
+        let offset = u32_at(data, HEADER_LEN + index * 4)? as usize;
+        if offset == 0 {
+            continue
+        }
+        let (cylinder, head) = (index / sides, index % sides);
+        let track_flags = u16_at(data, offset)?;
+        let mut pos = offset + 2;
+        let extra_cells = if disk_flags & DISK_EXTRA_BITCELLS != 0 {
+            pos += 4;
+            u32_at(data, pos - 4)? as i32
+        }
+        else {
+            0
+        };
+        // Skip the index hole position; the track starts at the index
+        pos += 4;
+
+        let rate_kbps = RATES[(track_flags & TRACK_RATE_MASK) as usize];
+        let rpm = if track_flags & TRACK_RPM_MASK == TRACK_RPM_360 { 360 } else { 300 };
+        let count = (rate_kbps as i32 * 2000 * 60 / rpm + extra_cells).max(0) as usize;
+        let len = count.div_ceil(16) * 2;
+
+        if track_flags & TRACK_ENCODING_MASK != TRACK_ENCODING_MFM {
+            log::warn!("86F track {} head {} isn't MFM encoded, skipping", cylinder, head);
+            continue
+        }
+        let cell_data = data.get(pos..pos + len).ok_or(ImageError::BadFormat("Truncated 86F image"))?;
+        let weak = if disk_flags & DISK_SURFACE != 0 {
+            let surface = data.get(pos + len..pos + len * 2).ok_or(ImageError::BadFormat("Truncated 86F image"))?;
+            unpack_words(surface, count, big_endian)
+        }
+        else {
+            Vec::new()
+        };
+
+        let cells = TrackCells {
+            cells: unpack_words(cell_data, count, big_endian),
+            weak,
+        };
+        tracks.push(mfm::decode_track(&cells, cylinder as u8, head as u8, rate_kbps));
+    }
+    Ok(DiskImage::new(ImageFormat::D86f, tracks, String::new()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::floppy_image::SectorId;
+
+    #[test]
+    pub fn test_86f_image() {
+        let id = SectorId { c: 0, h: 0, r: 1, n: 2 };
+        let mut cells = mfm::encode_track(&[(id, 0xFB, vec![0xC3; 512])]);
+        // 250Kbps at 300 RPM, less 96 extra cells
+        let count = 100_000 - 96;
+        cells.resize(count, false);
+        let mut weak = vec![false; count];
+        // Make a data cell of the sector's first data byte weak
+        weak[(80 + 12 + 4 + 6 + 22 + 12 + 4) * 16 + 1] = true;
+
+        let pack = |cells: &[bool]| -> Vec<u8> {
+            cells.chunks(16)
+                .flat_map(|word| {
+                    let w = word.iter().enumerate().fold(0u16, |acc, (i, &c)| acc | ((c as u16) << (15 - i)));
+                    w.to_le_bytes()
+                })
+                .collect()
+        };
+
+        let mut image = D86F_SIGNATURE.to_vec();
+        image.extend_from_slice(&0x020Cu16.to_le_bytes());
+        image.extend_from_slice(&(DISK_SURFACE | DISK_EXTRA_BITCELLS).to_le_bytes());
+        let track_offset = (HEADER_LEN + TRACK_TABLE_LEN * 4) as u32;
+        image.extend_from_slice(&track_offset.to_le_bytes());
+        image.resize(track_offset as usize, 0);
+        image.extend_from_slice(&(TRACK_ENCODING_MFM | 2).to_le_bytes());
+        image.extend_from_slice(&(-96i32).to_le_bytes());
+        image.extend_from_slice(&0u32.to_le_bytes());
+        image.extend(pack(&cells));
+        image.extend(pack(&weak));
+
+        let image = DiskImage::load(&image).unwrap();
+        assert_eq!(image.format, ImageFormat::D86f);
+        let sector = image.sector(image.find_sector(0, 0, id).unwrap());
+        assert_eq!(sector.data.as_ref().unwrap()[1], 0xC3);
+        assert!(sector.data_error);
+        assert_eq!(sector.weak.as_ref().unwrap()[0], 0x80);
+        assert_eq!(image.tracks[0].mode.rate_kbps, 250);
+    }
+}
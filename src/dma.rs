@@ -5,10 +5,7 @@
 
 */
 
-
-use std::ops::Add;
-
-use crate::io::{IoBusInterface, IoDevice};
+use crate::io::IoDevice;
 use crate::bus::BusInterface;
 
 use log;
@@ -52,6 +49,24 @@ pub const DMA_COMMAND_PRIORITY: u8          = 0x10;
 
 pub const DMA_CHANNEL_COUNT: usize = 4;
 
+// Length of a transfer cycle in clocks. The DMA clock on the PC/XT is the CPU clock.
+pub const DMA_TRANSFER_CYCLES_NORMAL: u32 = 4;
+pub const DMA_TRANSFER_CYCLES_COMPRESSED: u32 = 2;
+
+/// A device that can be serviced by the DMA controller. 
+/// 
+/// Devices request service by raising DREQ via DMAController::request_dma_service(). When the
+/// controller acknowledges the request it calls back into the device to perform the transfer.
+pub trait DmaDevice {
+    /// Provide a byte to the controller on an I/O read (a DMA Write transfer, I/O to memory).
+    /// This is also called during Verify transfers, where the byte is discarded.
+    fn dma_read_u8(&mut self, channel: usize) -> u8;
+    /// Accept a byte from the controller on an I/O write (a DMA Read transfer, memory to I/O).
+    fn dma_write_u8(&mut self, channel: usize, data: u8);
+    /// Signal EOP to the device when its channel reaches Terminal Count.
+    fn dma_end_of_process(&mut self, channel: usize);
+}
+
 
 
 pub enum TimingMode {
@@ -144,13 +159,19 @@ pub struct DMAController {
     timing_mode: TimingMode,
     priority_mode: PriorityMode,
 
+    priority_base: usize,
+
     flipflop: bool,
     channels: [DMAChannel; 4],
     
     command_register: u8,
     request_reg: u8,
+    latched_request_reg: u8,
     status_reg: u8,
-    temp_reg: u8
+    temp_reg: u8,
+    // A channel in Block mode keeps the bus from its first transfer until TC
+    block_channel: Option<usize>,
+    cycle_accumulator: u32
 }
 
 impl IoDevice for DMAController {
@@ -284,10 +305,11 @@ impl DMAController {
 
         Self {
             enabled: true,
-            mem_to_mem_enabled: true,
+            mem_to_mem_enabled: false,
             channel_0_hold_enabled: false,
             timing_mode: TimingMode::NormalTiming,
             priority_mode: PriorityMode::Fixed,
+            priority_base: 0,
         
             flipflop: false,
             channels: [
@@ -298,8 +320,11 @@ impl DMAController {
             ],
             command_register: 0,
             request_reg: 0,
+            latched_request_reg: 0,
             status_reg: 0,
-            temp_reg: 0
+            temp_reg: 0,
+            block_channel: None,
+            cycle_accumulator: 0
         }
     }
 
@@ -400,6 +425,7 @@ impl DMAController {
 
     pub fn handle_status_register_read(&mut self) -> u8 {
         let mut status_byte = 0;
+        let dreq = self.request_reg | self.latched_request_reg;
         for (i, chan) in self.channels.iter_mut().enumerate() {
 
             // Intel: Bits 0-3 are set every time a TC is reached by that channel or an external EOP is applied. 
@@ -411,7 +437,7 @@ impl DMAController {
             }
             
             // Intel: Bits 4-7 are set whenever their corresponding channel is requesting service.
-            if chan.request {
+            if chan.request || (dreq & (0x01 << i) != 0) {
                 status_byte |= 0x01 << (i + 4);
             }
         }
@@ -451,7 +477,12 @@ impl DMAController {
     }
 
     pub fn handle_write_req_register(&mut self, data: u8 ) {
-        log::debug!("DMA: Unimplemented write to Write Request Register: {:02X}", data);
+        // Bits 0-1: Channel Number
+        // Bit 2: Request bit state
+        let chan_n = data & 0x03;
+        self.channels[chan_n as usize].request = (data & 0x04) != 0;
+
+        log::trace!("DMA: Software request for channel {}: {}", chan_n, self.channels[chan_n as usize].request);
     }
 
     pub fn handle_channel_mask_register_write(&mut self, data: u8) {
@@ -512,14 +543,18 @@ impl DMAController {
         // This software instruction has the same effect as the hardware Reset. The Command, Status, Request, Temporary, and Internal
         // First/Last Flip-Flop registers are cleared and the Mask register is set.
         
-        // Set mask for each channel
+        // Set mask and clear request for each channel
         for chan in &mut self.channels {
             chan.masked = true;
+            chan.request = false;
+            chan.terminal_count_reached = false;
         }
-        self.command_register = 0;
+        self.handle_command_register_write(0);
+        self.priority_base = 0;
         self.status_reg = 0;
         self.temp_reg = 0;
         self.flipflop = false;
+        self.block_channel = None;
 
     }
 
@@ -568,13 +603,14 @@ impl DMAController {
         }
     }
 
+
     pub fn get_dma_transfer_size(&self, channel: usize) -> usize {
         if channel >= DMA_CHANNEL_COUNT {
             panic!("Invalid DMA Channel");
         }  
 
         let size: usize = self.channels[channel].base_word_count_reg as usize + 1;
-        return size
+        size
     }
 
     pub fn get_dma_transfer_address(&self, channel: usize) -> usize {
@@ -585,17 +621,6 @@ impl DMAController {
         address
     }
 
-    pub fn check_dma_ready(&self, channel: usize) -> bool {
-        if channel >= DMA_CHANNEL_COUNT {
-            panic!("Invalid DMA Channel");
-        }
-        let mut is_ready = false;
-        if !self.channels[channel].masked {
-            is_ready = true;
-        }
-        is_ready
-    }
-
     /// Request DMA Serivce 
     /// Equivalent to setting the DREQ line high for the given DMA channel
     pub fn request_dma_service(&mut self, channel: usize) {
@@ -610,129 +635,410 @@ impl DMAController {
         self.request_reg &= !(0x01 << channel);
     }
 
-    /// Get the state of the DACK line for the specified DMA channel. 
-    /// DACK signals whether the requestin device can be serviced.
-    pub fn read_dma_acknowledge(&self, channel: usize) -> bool {
+    /// Pulse the DREQ line for the given DMA channel. The request is latched until the channel
+    /// is acknowledged. On the PC this is how PIT channel #1 requests a DRAM refresh cycle on
+    /// DMA channel #0 - the refresh flip-flop is cleared by DACK0.
+    pub fn pulse_dma_request(&mut self, channel: usize) {
 
-        true
+        self.latched_request_reg |= 0x01 << channel;
     }
 
-    pub fn check_terminal_count(&self, channel: usize) -> bool {
-        if channel >= DMA_CHANNEL_COUNT {
-            panic!("Invalid DMA Channel");
+    /// Return whether the given channel is requesting service, either via its DREQ line or a
+    /// software request.
+    fn is_requesting(&self, channel: usize) -> bool {
+        let chan_bit = 0x01 << channel;
+
+        // Intel: Software requests are non-maskable and subject to priority resolution.
+        if self.channels[channel].request {
+            return true
         }
-        
-        self.channels[channel].terminal_count
+        (self.request_reg | self.latched_request_reg) & chan_bit != 0 && !self.channels[channel].masked
     }
 
-    pub fn do_dma_read_u8(&mut self, bus: &mut BusInterface, channel: usize ) -> u8 {
-        if channel >= DMA_CHANNEL_COUNT {
-            panic!("Invalid DMA Channel");
-        }  
+    /// Resolve priority between requesting channels and return the channel to be serviced.
+    fn get_highest_priority_request(&self) -> Option<usize> {
+        // In Fixed priority mode the priority base is always channel 0. In Rotating mode, the 
+        // channel after the last channel serviced has the highest priority.
+        let base = match self.priority_mode {
+            PriorityMode::Fixed => 0,
+            PriorityMode::Rotating => self.priority_base
+        };
 
-        if !self.enabled {
-            return 0;
+        (0..DMA_CHANNEL_COUNT)
+            .map(|i| (base + i) % DMA_CHANNEL_COUNT)
+            .find(|&channel| self.is_requesting(channel))
+    }
+
+    fn get_transfer_cycles(&self) -> u32 {
+        match self.timing_mode {
+            TimingMode::NormalTiming => DMA_TRANSFER_CYCLES_NORMAL,
+            TimingMode::CompressedTiming => DMA_TRANSFER_CYCLES_COMPRESSED
         }
+    }
 
-        let mut data: u8 = 0;
-        let mut _cost = 0;
-        let bus_address = self.get_dma_transfer_address(channel);
+    /// Update the current address register of a channel after a transfer.
+    fn step_address(&mut self, channel: usize) {
+        let chan = &mut self.channels[channel];
 
-        match self.channels[channel].address_mode {
-            AddressMode::Increment => {
-                if self.channels[channel].current_word_count_reg > 0 {
+        // Internal address register wraps around; the page register is not updated.
+        chan.current_address_reg = match chan.address_mode {
+            AddressMode::Increment => chan.current_address_reg.wrapping_add(1),
+            AddressMode::Decrement => chan.current_address_reg.wrapping_sub(1)
+        };
+    }
 
-                    (data, _cost) = bus.read_u8(bus_address).unwrap();
-                    
-                    if self.channels[channel].current_word_count_reg == 1 {
-                        //log::trace!("car: {} cwc: {} ", self.channels[channel].current_address_reg, self.channels[channel].current_word_count_reg);
-                    }
+    /// Decrement the current word count register of a channel after a transfer, and handle 
+    /// Terminal Count when it rolls over from 0 to FFFF. Returns true if TC was reached.
+    fn step_word_count(&mut self, channel: usize) -> bool {
+        let chan = &mut self.channels[channel];
 
-                    // Internal address register wraps around
-                    self.channels[channel].current_address_reg = self.channels[channel].current_address_reg.wrapping_add(1);
-                    self.channels[channel].current_word_count_reg -= 1;
+        chan.current_word_count_reg = chan.current_word_count_reg.wrapping_sub(1);
+        if chan.current_word_count_reg != 0xFFFF {
+            return false
+        }
+
+        // Set the tc status bit regardless of auto-init
+        chan.terminal_count_reached = true;
+        chan.terminal_count = true;
+
+        // Intel: The request bit is cleared upon TC or external EOP.
+        chan.request = false;
 
-                    //log::trace!("DMA read {:02X} from address: {:06X} CWC: {}", data, bus_address, self.channels[channel].current_word_count_reg);
+        if chan.auto_init {
+            // Reload channel if auto-init on
+            chan.current_address_reg = chan.base_address_reg;
+            chan.current_word_count_reg = chan.base_word_count_reg;
+        }
+        else {
+            // Intel: If the channel is not programmed for Autoinitialize, the mask bit is set at TC.
+            chan.masked = true;
+        }
+        log::trace!("Terminal count reached on DMA channel {:01X}", channel);
+        true
+    }
+
+    /// Perform a single transfer cycle for the given channel. Returns true if TC was reached.
+    fn do_transfer(&mut self, bus: &mut BusInterface, devices: &mut [Option<&mut dyn DmaDevice>], channel: usize) -> bool {
+
+        // Acknowledging the channel resets any latched request.
+        self.latched_request_reg &= !(0x01 << channel);
+
+        let bus_address = self.get_dma_transfer_address(channel);
+        let device = devices.get_mut(channel).and_then(|d| d.as_mut());
+
+        match self.channels[channel].transfer_type {
+            TransferType::Write => {
+                // I/O to Memory. With no device present the data bus floats high.
+                let data = match device {
+                    Some(device) => device.dma_read_u8(channel),
+                    None => 0xFF
+                };
+                if bus.write_u8(bus_address, data).is_err() {
+                    log::warn!("DMA write to invalid address: {:06X}", bus_address);
                 }
-                else if self.channels[channel].current_word_count_reg == 0 && !self.channels[channel].terminal_count {
-                    
-                    // Transfer one more on a 0 count, then set TC
-                    (data, _cost) = bus.read_u8(bus_address).unwrap();
-
-                    //self.channels[channel].current_address_reg += 1;
-
-                    //log::trace!("DMA read {:02X} from address: {:06X} CWC: {}", data, bus_address, self.channels[channel].current_word_count_reg);
-                    if self.channels[channel].auto_init {
-                        // Reload channel if auto-init on
-                        self.channels[channel].current_address_reg = self.channels[channel].base_address_reg;
-                        self.channels[channel].current_word_count_reg  = self.channels[channel].base_word_count_reg;
-                    }
-                    else {
-                        self.channels[channel].terminal_count = true;
-                        log::trace!("Terminal count reached on DMA channel {:01X}", channel);
-                    }
-                    // Set the tc status bit regardless of auto-init
-                    self.channels[channel].terminal_count_reached = true;
+            }
+            TransferType::Read => {
+                // Memory to I/O
+                let data = match bus.read_u8(bus_address) {
+                    Ok((data, _cost)) => data,
+                    Err(_) => 0xFF
+                };
+                if let Some(device) = device {
+                    device.dma_write_u8(channel, data);
                 }
-                else {
-                    // Trying to transfer on a terminal count
-                }                
             }
-            _=> panic!("DMA Decrement address mode unimplemented")
-        }        
-        
-        data
+            TransferType::Verify | TransferType::Illegal => {
+                // Addresses are generated and DACK is asserted, but neither memory nor I/O read/write
+                // lines are driven. The device still sees its byte as transferred.
+                if let Some(device) = device {
+                    let _ = device.dma_read_u8(channel);
+                }
+            }
+        }
+
+        self.step_address(channel);
+        let tc = self.step_word_count(channel);
+        if tc {
+            if let Some(Some(device)) = devices.get_mut(channel) {
+                device.dma_end_of_process(channel);
+            }
+        }
+        tc
     }
 
-    pub fn do_dma_write_u8(&mut self, bus: &mut BusInterface, channel: usize, data: u8) {
-        if channel >= DMA_CHANNEL_COUNT {
-            panic!("Invalid DMA Channel");
-        }  
+    /// Perform a memory-to-memory transfer cycle from channel 0 to channel 1 via the 
+    /// temporary register. Returns true if TC was reached on channel 1.
+    fn do_mem_to_mem_transfer(&mut self, bus: &mut BusInterface) -> bool {
 
-        let bus_address = self.get_dma_transfer_address(channel);
+        let src_address = self.get_dma_transfer_address(0);
+        let dst_address = self.get_dma_transfer_address(1);
 
-        match self.channels[channel].address_mode {
-            AddressMode::Increment => {
+        self.temp_reg = match bus.read_u8(src_address) {
+            Ok((data, _cost)) => data,
+            Err(_) => 0xFF
+        };
+        if bus.write_u8(dst_address, self.temp_reg).is_err() {
+            log::warn!("DMA write to invalid address: {:06X}", dst_address);
+        }
 
-                if self.channels[channel].current_word_count_reg > 0 {
-                    
-                    // Don't transfer anything if in Verify mode
-                    if let TransferType::Write = self.channels[channel].transfer_type {
-                        bus.write_u8(bus_address, data);
-                    }
-                    
-                    self.channels[channel].current_address_reg = self.channels[channel].current_address_reg.wrapping_add(1);
-                    self.channels[channel].current_word_count_reg -= 1;
+        if !self.channel_0_hold_enabled {
+            self.step_address(0);
+        }
+        self.step_address(1);
 
-                    //log::trace!("DMA write {:02X} to address: {:06X} CWC: {}", data, bus_address, self.channels[channel].current_word_count_reg);
-                }
-                else if self.channels[channel].current_word_count_reg == 0 && !self.channels[channel].terminal_count {
-                    
-                    // Transfer one more on a 0 count, then set TC
-                    if let TransferType::Write = self.channels[channel].transfer_type {
-                        bus.write_u8(bus_address, data);
-                    }
-                    //self.channels[channel].current_address_reg += 1;
+        // Intel: The transfer is terminated when Channel 1 reaches TC.
+        let tc = self.step_word_count(1);
+        if tc {
+            // Channel 0 is not auto-initialized by TC on channel 1, so end its request here.
+            self.channels[0].request = false;
+        }
+        tc
+    }
 
-                    //log::trace!("DMA write {:02X} to address: {:06X} CWC: {}", data, bus_address, self.channels[channel].current_word_count_reg);
-                    self.channels[channel].terminal_count = true;
-                    log::trace!("Terminal count reached on DMA channel {:01X}", channel);
+    /// Run the DMA controller for the given number of CPU cycles, performing transfers for any
+    /// channels requesting service. Devices attached to DMA channels are passed in by channel 
+    /// number so that the controller can read from or write to them when a channel is acknowledged.
+    pub fn run(&mut self, bus: &mut BusInterface, devices: &mut [Option<&mut dyn DmaDevice>], cpu_cycles: u32) {
+
+        if !self.enabled {
+            self.cycle_accumulator = 0;
+            return
+        }
+
+        let channel = match self.block_channel.or_else(|| self.get_highest_priority_request()) {
+            Some(channel) => channel,
+            None => {
+                // Don't bank cycles while the bus is idle
+                self.cycle_accumulator = 0;
+                return
+            }
+        };
 
-                    // TODO: Support auto-init here
+        self.cycle_accumulator += cpu_cycles;
 
-                    // Set the tc status bit regardless of auto-init
-                    self.channels[channel].terminal_count_reached = true;
+        // Memory-to-memory transfers are initiated by a software request on channel 0.
+        if channel == 0 && self.mem_to_mem_enabled && self.channels[0].request {
+            let xfer_cycles = self.get_transfer_cycles() * 2;
+            while self.cycle_accumulator >= xfer_cycles {
+                self.cycle_accumulator -= xfer_cycles;
+                if self.do_mem_to_mem_transfer(bus) {
+                    break
                 }
-                else {
-                    // Trying to transfer on a terminal count
+            }
+            self.priority_base = 1;
+            return
+        }
+
+        let xfer_cycles = self.get_transfer_cycles();
+        match self.channels[channel].service_mode {
+            ServiceMode::Single => {
+                // One transfer, then the bus is released back to the CPU.
+                if self.cycle_accumulator >= xfer_cycles {
+                    self.cycle_accumulator -= xfer_cycles;
+                    self.do_transfer(bus, devices, channel);
                 }
+                self.cycle_accumulator = self.cycle_accumulator.min(xfer_cycles);
             }
-            _=> panic!("DMA Decrement address mode unimplemented")
-        }        
+            ServiceMode::Block => {
+                // Transfers continue until TC, regardless of the state of DREQ.
+                self.block_channel = Some(channel);
+                while self.cycle_accumulator >= xfer_cycles {
+                    self.cycle_accumulator -= xfer_cycles;
+                    if self.do_transfer(bus, devices, channel) {
+                        self.block_channel = None;
+                        break
+                    }
+                }
+            }
+            ServiceMode::Demand => {
+                // Transfers continue until TC or until DREQ goes inactive.
+                while self.cycle_accumulator >= xfer_cycles && self.is_requesting(channel) {
+                    self.cycle_accumulator -= xfer_cycles;
+                    if self.do_transfer(bus, devices, channel) {
+                        break
+                    }
+                }
+            }
+            ServiceMode::Cascade => {
+                // The channel's DREQ/DACK are passed through to a cascaded controller, which owns 
+                // the bus and generates its own addresses. Nothing is cascaded on the PC/XT.
+                self.cycle_accumulator = 0;
+            }
+        }
+
+        // In rotating priority mode, the channel just serviced becomes the lowest priority.
+        self.priority_base = (channel + 1) % DMA_CHANNEL_COUNT;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestDevice {
+        data: Vec<u8>,
+        received: Vec<u8>,
+        eop: bool
+    }
+
+    impl DmaDevice for TestDevice {
+        fn dma_read_u8(&mut self, _channel: usize) -> u8 {
+            self.data.remove(0)
+        }
+        fn dma_write_u8(&mut self, _channel: usize, data: u8) {
+            self.received.push(data);
+        }
+        fn dma_end_of_process(&mut self, _channel: usize) {
+            self.eop = true;
+        }
+    }
+
+    fn program_channel(dma: &mut DMAController, channel: u8, mode: u8, address: u16, count: u16) {
+        dma.handle_clear_flopflop();
+        dma.handle_channel_mode_register_write(mode | channel);
+        dma.handle_addr_port_write(channel as usize, (address & 0xFF) as u8);
+        dma.handle_addr_port_write(channel as usize, (address >> 8) as u8);
+        dma.handle_wc_port_write(channel as usize, (count & 0xFF) as u8);
+        dma.handle_wc_port_write(channel as usize, (count >> 8) as u8);
+        dma.handle_channel_mask_register_write(channel);
     }
 
-    pub fn run(&mut self, io_bus: &mut IoBusInterface) {
+    #[test]
+    pub fn test_single_write_decrement() {
+        let mut dma = DMAController::new();
+        let mut bus = BusInterface::new();
+        let mut device = TestDevice { data: vec![1, 2, 3, 4], received: Vec::new(), eop: false };
 
+        // Single mode, decrement, write transfer of 3 bytes
+        program_channel(&mut dma, 2, 0b0110_0100, 0x1002, 2);
+        dma.request_dma_service(2);
 
+        for _ in 0..8 {
+            let mut devices: [Option<&mut dyn DmaDevice>; DMA_CHANNEL_COUNT] = [None, None, Some(&mut device), None];
+            dma.run(&mut bus, &mut devices, 7);
+        }
+
+        assert_eq!(bus.read_u8(0x1002).unwrap().0, 1);
+        assert_eq!(bus.read_u8(0x1001).unwrap().0, 2);
+        assert_eq!(bus.read_u8(0x1000).unwrap().0, 3);
+        assert_eq!(device.data, vec![4]);
+        assert!(device.eop);
+
+        // TC bit is set and cleared on read, channel is masked at TC without autoinit
+        assert_eq!(dma.handle_status_register_read() & 0x0F, 0x04);
+        assert_eq!(dma.handle_status_register_read() & 0x0F, 0x00);
+        assert!(dma.channels[2].masked);
     }
-}
\ No newline at end of file
+
+    #[test]
+    pub fn test_block_read_autoinit() {
+        let mut dma = DMAController::new();
+        let mut bus = BusInterface::new();
+        let mut device = TestDevice { data: Vec::new(), received: Vec::new(), eop: false };
+
+        for i in 0..4 {
+            bus.write_u8(0x2000 + i, i as u8 + 0x10).unwrap();
+        }
+
+        // Block mode, autoinit, read transfer of 4 bytes
+        program_channel(&mut dma, 3, 0b1001_1000, 0x2000, 3);
+        dma.request_dma_service(3);
+        {
+            let mut devices: [Option<&mut dyn DmaDevice>; DMA_CHANNEL_COUNT] = [None, None, None, Some(&mut device)];
+            dma.run(&mut bus, &mut devices, 16);
+        }
+
+        assert_eq!(device.received, vec![0x10, 0x11, 0x12, 0x13]);
+        assert!(device.eop);
+        assert!(!dma.channels[3].masked);
+        assert_eq!(dma.channels[3].current_address_reg, 0x2000);
+        assert_eq!(dma.channels[3].current_word_count_reg, 3);
+    }
+
+    #[test]
+    pub fn test_block_continues_without_dreq() {
+        let mut dma = DMAController::new();
+        let mut bus = BusInterface::new();
+        let mut device = TestDevice { data: Vec::new(), received: Vec::new(), eop: false };
+
+        // Block mode read transfer of 4 bytes, with DREQ dropped after the first transfer
+        program_channel(&mut dma, 3, 0b1000_1000, 0x2000, 3);
+        dma.request_dma_service(3);
+        {
+            let mut devices: [Option<&mut dyn DmaDevice>; DMA_CHANNEL_COUNT] = [None, None, None, Some(&mut device)];
+            dma.run(&mut bus, &mut devices, 4);
+        }
+        assert_eq!(device.received.len(), 1);
+        dma.clear_dma_service(3);
+        {
+            let mut devices: [Option<&mut dyn DmaDevice>; DMA_CHANNEL_COUNT] = [None, None, None, Some(&mut device)];
+            dma.run(&mut bus, &mut devices, 16);
+        }
+
+        assert_eq!(device.received.len(), 4);
+        assert!(device.eop);
+        assert_eq!(dma.block_channel, None);
+    }
+
+    #[test]
+    pub fn test_verify_does_not_write() {
+        let mut dma = DMAController::new();
+        let mut bus = BusInterface::new();
+        let mut device = TestDevice { data: vec![0xAA, 0xBB], received: Vec::new(), eop: false };
+
+        program_channel(&mut dma, 2, 0b1000_0000, 0x3000, 1);
+        dma.request_dma_service(2);
+        {
+            let mut devices: [Option<&mut dyn DmaDevice>; DMA_CHANNEL_COUNT] = [None, None, Some(&mut device), None];
+            dma.run(&mut bus, &mut devices, 8);
+        }
+
+        assert!(device.data.is_empty());
+        assert!(device.eop);
+        assert_eq!(bus.read_u8(0x3000).unwrap().0, 0);
+        assert_eq!(bus.read_u8(0x3001).unwrap().0, 0);
+    }
+
+    #[test]
+    pub fn test_mem_to_mem() {
+        let mut dma = DMAController::new();
+        let mut bus = BusInterface::new();
+
+        for i in 0..4 {
+            bus.write_u8(0x4000 + i, 0xC0 + i as u8).unwrap();
+        }
+
+        dma.handle_command_register_write(DMA_COMMAND_MEM_TO_MEM);
+        program_channel(&mut dma, 0, 0b1000_1000, 0x4000, 3);
+        program_channel(&mut dma, 1, 0b1000_0100, 0x5000, 3);
+
+        // Software request on channel 0 starts the transfer
+        dma.handle_write_req_register(0x04);
+        let mut devices: [Option<&mut dyn DmaDevice>; DMA_CHANNEL_COUNT] = [None, None, None, None];
+        dma.run(&mut bus, &mut devices, 32);
+
+        for i in 0..4 {
+            assert_eq!(bus.read_u8(0x5000 + i).unwrap().0, 0xC0 + i as u8);
+        }
+        assert_eq!(dma.handle_temp_register_read(), 0xC3);
+        assert_eq!(dma.handle_status_register_read() & 0x0F, 0x02);
+    }
+
+    #[test]
+    pub fn test_rotating_priority() {
+        let mut dma = DMAController::new();
+
+        dma.handle_command_register_write(DMA_COMMAND_PRIORITY);
+        dma.handle_clear_mask_register();
+        dma.request_dma_service(1);
+        dma.request_dma_service(3);
+
+        assert_eq!(dma.get_highest_priority_request(), Some(1));
+        dma.priority_base = 2;
+        assert_eq!(dma.get_highest_priority_request(), Some(3));
+
+        // Masked channels do not request service
+        dma.handle_channel_mask_register_write(0x04 | 3);
+        assert_eq!(dma.get_highest_priority_request(), Some(1));
+    }
+}
@@ -4,23 +4,28 @@
 
 */
 #![allow(dead_code)]
-use std::collections::{VecDeque, HashMap};
-use lazy_static::lazy_static;
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
 
+use crate::cpu::CPU_MHZ;
 use crate::io::{IoDevice};
 use crate::dma;
-use crate::bus::{BusInterface};
+use crate::floppy_image::{DiskFormat, DiskImage, SectorAddress, SectorId};
 use crate::pic;
 
 pub const FDC_IRQ: u8 = 0x06;
 pub const FDC_DMA: usize = 2;
 pub const FDC_MAX_DRIVES: usize = 4;
 pub const FORMAT_BUFFER_SIZE: usize = 4;
-pub const SECTOR_SIZE: usize = 512;
+
+// Reading past the end of a sector's data field reads the gap that follows it
+const GAP_BYTE: u8 = 0x4E;
 
 pub const FDC_DIGITAL_OUTPUT_REGISTER: u16 = 0x3F2;
 pub const FDC_STATUS_REGISTER: u16 = 0x3F4;
 pub const FDC_DATA_REGISTER: u16 = 0x3F5;
+pub const FDC_CONFIG_CONTROL_REGISTER: u16 = 0x3F7;
 
 // Main Status Register Bit Definitions
 // --------------------------------------------------------------------------------
@@ -59,12 +64,19 @@ pub const DOR_MOTOR_FDD_C: u8           = 0b0100_0000;
 pub const DOR_MOTOR_FDD_D: u8           = 0b1000_0000;
 
 pub const COMMAND_MASK: u8                  = 0b0001_1111;
+pub const COMMAND_MULTI_TRACK: u8           = 0b1000_0000;
+pub const COMMAND_MFM: u8                   = 0b0100_0000;
+pub const COMMAND_SKIP: u8                  = 0b0010_0000;
+
 pub const COMMAND_READ_TRACK: u8            = 0x02;
 pub const COMMAND_WRITE_SECTOR: u8          = 0x05;
 pub const COMMAND_READ_SECTOR: u8           = 0x06;
 pub const COMMAND_WRITE_DELETED_SECTOR: u8  = 0x09;
 pub const COMMAND_READ_DELETED_SECTOR: u8   = 0x0C;
 pub const COMMAND_FORMAT_TRACK: u8          = 0x0D;
+pub const COMMAND_SCAN_EQUAL: u8            = 0x11;
+pub const COMMAND_SCAN_LOW_OR_EQUAL: u8     = 0x19;
+pub const COMMAND_SCAN_HIGH_OR_EQUAL: u8    = 0x1D;
 
 pub const COMMAND_FIX_DRIVE_DATA: u8        = 0x03;
 pub const COMMAND_CHECK_DRIVE_STATUS: u8    = 0x04;
@@ -73,11 +85,14 @@ pub const COMMAND_SENSE_INT_STATUS: u8      = 0x08;
 pub const COMMAND_READ_SECTOR_ID: u8        = 0x0A;
 pub const COMMAND_SEEK_HEAD: u8             = 0x0F;
 
+// Second parameter byte of the Fix Drive Data (Specify) command
+pub const SPECIFY_NON_DMA: u8               = 0b0000_0001;
+
 pub const ST0_HEAD_ACTIVE: u8   = 0b0000_0100;
 pub const ST0_NOT_READY: u8     = 0b0000_1000;
 pub const ST0_UNIT_CHECK: u8    = 0b0001_0000;
 pub const ST0_SEEK_END: u8      = 0b0010_0000;
-pub const ST0_ABNORMAL_TERMINATION: u8 = 0b01000_0000;
+pub const ST0_ABNORMAL_TERMINATION: u8 = 0b0100_0000;
 pub const ST0_INVALID_OPCODE: u8    = 0b1000_0000;
 pub const ST0_ABNORMAL_POLLING: u8  = 0b1100_0000;
 pub const ST0_RESET: u8             = 0b1100_0000;
@@ -85,7 +100,17 @@ pub const ST0_RESET: u8             = 0b1100_0000;
 pub const ST1_NO_ID: u8         = 0b0000_0001;
 pub const ST1_WRITE_PROTECT: u8 = 0b0000_0010;
 pub const ST1_NODATA: u8        = 0b0000_0100;
-
+pub const ST1_OVERRUN: u8       = 0b0001_0000;
+pub const ST1_DATA_ERROR: u8    = 0b0010_0000;
+pub const ST1_END_OF_CYLINDER: u8 = 0b1000_0000;
+
+pub const ST2_NO_DATA_MARK: u8  = 0b0000_0001;
+pub const ST2_BAD_CYLINDER: u8  = 0b0000_0010;
+pub const ST2_SCAN_NOT_SATISFIED: u8 = 0b0000_0100;
+pub const ST2_SCAN_EQUAL_HIT: u8 = 0b0000_1000;
+pub const ST2_WRONG_CYLINDER: u8 = 0b0001_0000;
+pub const ST2_DATA_ERROR: u8    = 0b0010_0000;
+pub const ST2_CONTROL_MARK: u8  = 0b0100_0000;
 
 pub const ST3_ESIG: u8          = 0b1000_0000;
 pub const ST3_WRITE_PROTECT: u8 = 0b0100_0000;
@@ -94,61 +119,81 @@ pub const ST3_TRACK0: u8        = 0b0001_0000;
 pub const ST3_DOUBLESIDED: u8   = 0b0000_1000;
 pub const ST3_HEAD: u8          = 0b0000_0100;
 
-pub struct DiskFormat {
-    cylinders: u8,
-    heads: u8,
-    sectors: u8
+/// The data rates the controller can be set to through the Configuration Control Register.
+/// XT controllers only run at 250Kbps, which is the rate after power on.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DataRate {
+    Rate500K,
+    Rate300K,
+    Rate250K,
+    Rate1M,
 }
 
-lazy_static! {
-    static ref DISK_FORMATS: HashMap<usize, DiskFormat> = {
-        let map = HashMap::from([
-            (
-                163_840, 
-                DiskFormat{
-                    cylinders: 40,
-                    heads: 1,
-                    sectors: 8
-                }
-            ),(
-                184_320,
-                DiskFormat{
-                    cylinders: 40,
-                    heads: 1,
-                    sectors: 9
-                }
-            ),(
-                327_680,
-                DiskFormat{
-                    cylinders: 40,
-                    heads: 2,
-                    sectors: 8
-                }
-            ),( 
-                368_640,
-                DiskFormat{
-                    cylinders: 40,
-                    heads: 2,
-                    sectors: 9
-                }
-            ),(
-                737_280,
-                DiskFormat{
-                    cylinders: 80,
-                    heads: 2,
-                    sectors: 9
-                }                
-            ),(
-                1_228_800,
-                DiskFormat{
-                    cylinders: 80,
-                    heads: 2,
-                    sectors: 15
-                }                                
-            )
-        ]);
-        map
-    };
+impl DataRate {
+    fn from_ccr(data: u8) -> Self {
+        match data & 0x03 {
+            0 => DataRate::Rate500K,
+            1 => DataRate::Rate300K,
+            2 => DataRate::Rate250K,
+            _ => DataRate::Rate1M,
+        }
+    }
+
+    /// The number of CPU cycles it takes a byte to pass under the head at this data rate.
+    fn byte_cycles(&self) -> u32 {
+        let kbps = match self {
+            DataRate::Rate500K => 500.0,
+            DataRate::Rate300K => 300.0,
+            DataRate::Rate250K => 250.0,
+            DataRate::Rate1M => 1000.0,
+        };
+        (8.0 / kbps * CPU_MHZ * 1000.0) as u32
+    }
+}
+
+/// The type of a floppy drive. A drive's track count and spindle speed decide which media
+/// it can read and the data rate the controller must use to read it. 40 track media in an
+/// 80 track drive is read by double stepping: the drive steps twice per media track.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DriveType {
+    Drive360K,
+    Drive1200K,
+    Drive720K,
+    Drive1440K,
+}
+
+impl DriveType {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "360k" => Some(DriveType::Drive360K),
+            "1.2m" => Some(DriveType::Drive1200K),
+            "720k" => Some(DriveType::Drive720K),
+            "1.44m" => Some(DriveType::Drive1440K),
+            _ => None
+        }
+    }
+
+    pub fn cylinders(&self) -> u8 {
+        match self {
+            DriveType::Drive360K => 40,
+            _ => 80
+        }
+    }
+
+    /// The data rate needed to read media of the given format in this drive, or None if
+    /// the drive can't read it.
+    pub fn data_rate(&self, format: &DiskFormat) -> Option<DataRate> {
+        match (self, format.cylinders, format.high_density) {
+            (DriveType::Drive360K, 40, false) => Some(DataRate::Rate250K),
+            // A 360 RPM drive reads double density media at 300Kbps
+            (DriveType::Drive1200K, _, false) => Some(DataRate::Rate300K),
+            (DriveType::Drive1200K, 80, true) if format.sectors <= 15 => Some(DataRate::Rate500K),
+            (DriveType::Drive720K, 80, false) => Some(DataRate::Rate250K),
+            (DriveType::Drive1440K, 80, false) => Some(DataRate::Rate250K),
+            (DriveType::Drive1440K, 80, true) => Some(DataRate::Rate500K),
+            _ => None
+        }
+    }
 }
 
 /// Represent the state of the DIO bit of the Main Status Register in a readable way.
@@ -173,9 +218,20 @@ pub enum Command {
     SenseIntStatus,
     ReadSectorID,
     SeekParkHead,
+    ScanEqual,
+    ScanLowOrEqual,
+    ScanHighOrEqual,
     Invalid
 }
 
+/// The comparison a Scan command makes between the data on disk and the data from the CPU.
+#[derive (Clone, Copy, Debug, PartialEq)]
+pub enum ScanCondition {
+    Equal,
+    LowOrEqual,
+    HighOrEqual,
+}
+
 /// Represents the possible values of the Interrupt Code field in Status Register 0.
 /// Returning 'AbnormalTermination' may result in a General Failure reading drive 
 /// message in DOS.
@@ -199,6 +255,15 @@ pub enum DriveError {
     BadRead,
     BadWrite,
     WriteProtect,
+    NoId,
+    IdError,
+    NoDataMark,
+    WrongCylinder,
+    BadCylinder,
+    DataError,
+    ControlMark,
+    EndOfCylinder,
+    Overrun,
     DMAError,
 }
 
@@ -210,42 +275,58 @@ pub enum DriveError {
 pub enum Operation {
     NoOperation,
     ReadSector(u8, u8, u8, u8, u8, u8, u8),     // cylinder, head, sector, sector_size, track_len, gap3_len, data_len
+    ReadDeletedSector(u8, u8, u8, u8, u8, u8, u8),
+    ReadTrack(u8, u8, u8, u8, u8, u8, u8),
     WriteSector(u8, u8, u8, u8, u8, u8, u8),    // cylinder, head, sector, sector_size, track_len, gap3_len, data_len
+    WriteDeletedSector(u8, u8, u8, u8, u8, u8, u8),
+    ScanSector(ScanCondition, u8, u8, u8, u8, u8, u8, u8), // condition, cylinder, head, sector, sector_size, track_len, gap3_len, step
     FormatTrack(u8, u8, u8, u8)
 }
 
 pub struct DiskDrive {
+    drive_type: DriveType,
+    // Drive tracks per media track: 2 for 40 track media in an 80 track drive
+    step_rate: u8,
+    data_rate: Option<DataRate>,
     error_signal: bool,
     cylinder: u8,
     head: u8,
     sector: u8,
-    max_cylinders: u8,
-    max_heads: u8,
-    max_sectors: u8,
+    // Index of the next sector on the track to pass under the head, for Read Sector ID
+    rotation: usize,
     ready: bool,
     motor_on: bool,
     positioning: bool,
     have_disk: bool,
     write_protected: bool,
-    disk_image: Vec<u8>
+    protect_switch: bool,
+    image_read_only: bool,
+    image_path: Option<PathBuf>,
+    dirty: bool,
+    image: Option<DiskImage>,
 }
 
 impl DiskDrive {
     pub fn new() -> Self {
         Self {
+            drive_type: DriveType::Drive360K,
+            step_rate: 1,
+            data_rate: None,
             error_signal: false,
             cylinder: 0,
             head: 0,
             sector: 0,
-            max_cylinders: 0,
-            max_heads: 0,
-            max_sectors: 0,
+            rotation: 0,
             ready: false,
             motor_on: false,
             positioning: false,
             have_disk: false,
             write_protected: false,
-            disk_image: Vec::new(),
+            protect_switch: false,
+            image_read_only: false,
+            image_path: None,
+            dirty: false,
+            image: None,
         }
     }
 }
@@ -256,6 +337,16 @@ pub enum Continuation {
     ContinueAsOperation
 }
 
+/// A description of the disk in a drive, for display
+#[derive(Default)]
+pub struct FloppyStringState {
+    pub drive: String,
+    pub format: String,
+    pub geometry: String,
+    pub created: String,
+    pub comment: String,
+}
+
 pub struct FloppyController {
 
     status_byte: u8,
@@ -288,10 +379,42 @@ pub struct FloppyController {
 
     drives: [DiskDrive; 4],    
     drive_select: usize,
+    data_rate: DataRate,
 
     in_dma: bool,
+    // Cycles since the last byte of a non-DMA transfer came under the head
+    pio_cycles: u32,
     dma_byte_count: usize,
-    dma_bytes_left: usize
+    dma_bytes_left: usize,
+    dma_tc: bool,
+
+    // Flag bits (MT, MF, SK) of the command being executed
+    command_flags: u8,
+    // Status bits set by an operation that don't end it
+    st1_flags: u8,
+    st2_flags: u8,
+
+    // Sector being transferred by a sector operation, and the offset into it
+    transfer: Option<SectorAddress>,
+    transfer_offset: usize,
+    // ID of the sector the transfer is at. The FDC moves it on after each sector and reports
+    // it in the results phase.
+    transfer_id: SectorId,
+    // Whether the sector at transfer_id is still to be found
+    transfer_pending: bool,
+    // Bytes transferred per sector, the last sector number of the track and the step between sectors
+    transfer_len: usize,
+    transfer_eot: u8,
+    transfer_step: u8,
+    // Set when a transfer runs past the last sector of the cylinder
+    end_of_cylinder: bool,
+    // The last sector read by a Read Track operation, and the number of sectors left to read
+    track_last: Option<SectorAddress>,
+    track_sectors_left: u8,
+    // Whether the sector being scanned meets the scan condition, and whether it matches exactly
+    scan_match: bool,
+    scan_equal: bool,
+    format_ids: Vec<SectorId>,
 }
 
 /// IO Port handlers for the FDC
@@ -309,6 +432,10 @@ impl IoDevice for FloppyController {
             FDC_DATA_REGISTER => {
                 self.handle_data_register_read()
             },
+            FDC_CONFIG_CONTROL_REGISTER => {
+                log::warn!("Read from Write-only CCR register");
+                0
+            },
             _ => unreachable!("FLOPPY: Bad port #")
         }        
     }
@@ -323,6 +450,10 @@ impl IoDevice for FloppyController {
             FDC_DATA_REGISTER => {
                 self.handle_data_register_write(data);
             },
+            FDC_CONFIG_CONTROL_REGISTER => {
+                self.data_rate = DataRate::from_ccr(data);
+                log::trace!("FDC data rate set to {:?}", self.data_rate);
+            },
             _ => unreachable!("FLOPPY: Bad port #")
         }    
     }    
@@ -366,10 +497,31 @@ impl FloppyController {
                 DiskDrive::new()
             ],
             drive_select: 0,
+            data_rate: DataRate::Rate250K,
             
             in_dma: false,
+            pio_cycles: 0,
             dma_byte_count: 0,
             dma_bytes_left: 0,
+            dma_tc: false,
+
+            command_flags: 0,
+            st1_flags: 0,
+            st2_flags: 0,
+
+            transfer: None,
+            transfer_offset: 0,
+            transfer_id: SectorId { c: 0, h: 0, r: 0, n: 0 },
+            transfer_pending: false,
+            transfer_len: 0,
+            transfer_eot: 0,
+            transfer_step: 1,
+            end_of_cylinder: false,
+            track_last: None,
+            track_sectors_left: 0,
+            scan_match: false,
+            scan_equal: false,
+            format_ids: Vec::new(),
         }
     }
 
@@ -408,69 +560,198 @@ impl FloppyController {
         self.in_dma = false;
         self.dma_byte_count = 0;
         self.dma_bytes_left = 0;
-
-
+        self.dma_tc = false;
     }
 
-    /// Load a disk into the specified drive
-    pub fn load_image_from(&mut self, drive_select: usize, src_vec: Vec<u8>) -> Result<(), &'static str>  {
+    /// Load a disk into the specified drive. The image format is detected from its contents.
+    /// Changes are written back to the image file at `path` when flushed, unless the file is
+    /// read-only or its format can't be written. An image with no path can be written, but
+    /// changes are only kept in memory.
+    pub fn load_image_from(&mut self, drive_select: usize, src_vec: Vec<u8>, path: Option<PathBuf>) -> Result<(), String>  {
         
         if drive_select >= FDC_MAX_DRIVES {
-            return Err("Invalid drive selection");
+            return Err("Invalid drive selection".to_string());
         }
 
-        let image_len: usize = src_vec.len();
+        // Don't lose changes to the disk being replaced
+        self.flush_image(drive_select);
 
-        // Disk images must contain whole sectors
-        if image_len % SECTOR_SIZE > 0 {
-            return Err("Invalid image length")
-        }
+        let image = DiskImage::load(&src_vec).map_err(|e| e.to_string())?;
+        let fmt = image.disk_format();
 
-        // Look up disk parameters based on image size
-        if let Some(fmt) = DISK_FORMATS.get(&image_len) {
-            self.drives[drive_select].max_cylinders = fmt.cylinders;
-            self.drives[drive_select].max_heads = fmt.heads;
-            self.drives[drive_select].max_sectors = fmt.sectors;
-        }
-        else {
-            // No image format found. 
-            if image_len < 163_840 {
-                // If image is smaller than single sided disk, assume single sided disk, 8 sectors per track
-                // This is useful for loading things like boot sector images without having to copy them to
-                // a full disk image
-                self.drives[drive_select].max_cylinders = 40;
-                self.drives[drive_select].max_heads = 1;
-                self.drives[drive_select].max_sectors = 8;
-            }
-            else {
-                return Err("Invalid image length")
-            }
-        }
+        // Media sense: refuse media the drive can't read
+        let drive_type = self.drives[drive_select].drive_type;
+        let data_rate = match drive_type.data_rate(&fmt) {
+            Some(data_rate) => data_rate,
+            None => return Err("Drive type can't read this media".to_string())
+        };
 
-        self.drives[drive_select].have_disk = true;
-        self.drives[drive_select].disk_image = src_vec;
-        log::debug!("Loaded floppy image, size: {} c: {} h: {} s: {}", 
-            self.drives[drive_select].disk_image.len(),
-            self.drives[drive_select].max_cylinders,
-            self.drives[drive_select].max_heads,
-            self.drives[drive_select].max_sectors
+        // Images in a format we can't write back are write protected like read-only files
+        let image_read_only = !image.format.writable() || match &path {
+            Some(path) => fs::metadata(path).map(|m| m.permissions().readonly()).unwrap_or(true),
+            None => false
+        };
+        log::debug!("Loaded {:?} floppy image, c: {} h: {} s: {}", 
+            image.format,
+            fmt.cylinders,
+            fmt.heads,
+            fmt.sectors
         );
 
+        let drive = &mut self.drives[drive_select];
+        drive.step_rate = drive_type.cylinders() / fmt.cylinders;
+        drive.data_rate = Some(data_rate);
+        drive.have_disk = true;
+        drive.image = Some(image);
+        drive.image_path = path;
+        drive.image_read_only = image_read_only;
+        drive.write_protected = drive.protect_switch || image_read_only;
+        drive.dirty = false;
+
         Ok(())
     }
 
-    /// Unload (eject) the disk in the specified drive
+    /// Unload (eject) the disk in the specified drive, writing back any changes
     pub fn unload_image(&mut self, drive_select: usize) {
+        self.flush_image(drive_select);
         let drive = &mut self.drives[drive_select];
 
         drive.cylinder = 0;
         drive.head = 0;
         drive.sector = 1;
-        drive.max_cylinders = 40;
-        drive.max_heads = 1;
-        drive.max_sectors = 8;
         drive.have_disk = false;
-        drive.disk_image.clear();
+        drive.step_rate = 1;
+        drive.data_rate = None;
+        drive.image_path = None;
+        drive.image_read_only = false;
+        drive.write_protected = drive.protect_switch;
+        drive.dirty = false;
+        drive.image = None;
+    }
+
+    /// Install a drive of the given type. Any disk in the drive is ejected.
+    pub fn set_drive_type(&mut self, drive_select: usize, drive_type: DriveType) {
+        self.unload_image(drive_select);
+        self.drives[drive_select].drive_type = drive_type;
+    }
+
+    /// Find the track under the selected head. No IDs can be read if the controller isn't set
+    /// to the media's data rate or the command's recording mode (FM or MFM), or from an
+    /// unformatted track.
+    fn find_track(&self, drive_select: usize, head_select: u8) -> Result<usize, DriveError> {
+        let drive = &self.drives[drive_select];
+        if drive.data_rate != Some(self.data_rate) {
+            log::debug!("Drive {} media needs data rate {:?}, FDC set to {:?}", 
+                drive_select, drive.data_rate, self.data_rate);
+            return Err(DriveError::NoId)
+        }
+        let track_cylinder = drive.cylinder / drive.step_rate;
+        let image = drive.image.as_ref().ok_or(DriveError::NoMedia)?;
+        let mfm = self.command_flags & COMMAND_MFM != 0;
+        match image.track(track_cylinder, head_select) {
+            Some(track) if image.tracks[track].mode.mfm != mfm => {
+                log::debug!("Drive {} cylinder {} head {} can't be read with MF={}", drive_select, drive.cylinder, head_select, mfm);
+                Err(DriveError::NoId)
+            }
+            Some(track) if !image.tracks[track].sectors.is_empty() => Ok(track),
+            _ => {
+                log::debug!("Drive {} has no IDs on cylinder {} head {}", drive_select, drive.cylinder, head_select);
+                Err(DriveError::NoId)
+            }
+        }
+    }
+
+    /// Find the sector with the given ID on the track under the selected head. If it isn't
+    /// found, IDs on the track with another cylinder number are reported as a wrong cylinder.
+    fn find_sector(&self, drive_select: usize, head_select: u8, id: SectorId) -> Result<SectorAddress, DriveError> {
+        let track = self.find_track(drive_select, head_select)?;
+        let sectors = &self.drives[drive_select].image.as_ref().ok_or(DriveError::NoMedia)?.tracks[track].sectors;
+        match sectors.iter().position(|s| s.id == id) {
+            Some(sector) if sectors[sector].id_error => Err(DriveError::IdError),
+            Some(sector) => Ok(SectorAddress { track, sector }),
+            None => {
+                log::debug!("Drive {} sector {:?} not found on cylinder {} head {}", 
+                    drive_select, id, self.drives[drive_select].cylinder, head_select);
+                match sectors.iter().find(|s| s.id.c != id.c) {
+                    Some(s) if s.id.c == 0xFF => Err(DriveError::BadCylinder),
+                    Some(_) => Err(DriveError::WrongCylinder),
+                    None => Err(DriveError::BadRead)
+                }
+            }
+        }
+    }
+
+    /// Set the write protect tab of the disk in the specified drive. A disk loaded from a
+    /// read-only file is always write protected.
+    pub fn set_write_protect(&mut self, drive_select: usize, protect: bool) {
+        let drive = &mut self.drives[drive_select];
+        drive.protect_switch = protect;
+        drive.write_protected = protect || drive.image_read_only;
+    }
+
+    pub fn is_dirty(&self, drive_select: usize) -> bool {
+        self.drives[drive_select].dirty
+    }
+
+    pub fn get_string_state(&self) -> Vec<FloppyStringState> {
+        self.drives.iter().enumerate().take(2).map(|(i, drive)| {
+            let mut state = FloppyStringState {
+                drive: format!("{}:", (b'A' + i as u8) as char),
+                format: "No disk".to_string(),
+                ..Default::default()
+            };
+            if let Some(image) = &drive.image {
+                let fmt = image.disk_format();
+                state.format = format!("{:?}", image.format);
+                state.geometry = format!("c: {} h: {} s: {}", image.cylinders(), fmt.heads, fmt.sectors);
+                state.created = image.created.map_or(String::new(), |created| created.to_string());
+                state.comment = image.comment.clone();
+            }
+            state
+        }).collect()
+    }
+
+    /// Write a modified disk image back to the file it was loaded from. The image is written
+    /// to a temporary file which then replaces the original, so an interrupted write can't
+    /// leave a truncated image behind. Returns whether the image was written.
+    pub fn flush_image(&mut self, drive_select: usize) -> bool {
+        let drive = &mut self.drives[drive_select];
+        let path = match &drive.image_path {
+            Some(path) if drive.dirty => path,
+            _ => return false
+        };
+
+        let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(".tmp");
+        let temp_path = path.with_file_name(temp_name);
+        let data = match drive.image.as_ref().map(|image| image.save()) {
+            Some(Ok(data)) => data,
+            Some(Err(e)) => {
+                log::error!("Couldn't save floppy image {:?}: {}", path, e);
+                return false
+            }
+            None => return false
+        };
+        let result = fs::write(&temp_path, data).and_then(|_| fs::rename(&temp_path, path));
+        match result {
+            Ok(()) => {
+                log::info!("Wrote floppy image: {:?}", path);
+                drive.dirty = false;
+                true
+            }
+            Err(e) => {
+                log::error!("Couldn't write floppy image {:?}: {}", path, e);
+                let _ = fs::remove_file(&temp_path);
+                false
+            }
+        }
+    }
+
+    /// Write back all modified disk images.
+    pub fn flush_all(&mut self) {
+        for drive_select in 0..FDC_MAX_DRIVES {
+            self.flush_image(drive_select);
+        }
     }
 
     pub fn handle_status_register_read(&mut self) -> u8 {
@@ -486,7 +767,8 @@ impl FloppyController {
             msr_byte |= FDC_STATUS_FDC_BUSY;
         }
 
-        if !self.dma {
+        // The Non-DMA bit is set during the execution phase of a non-DMA transfer
+        if !self.in_dma && !matches!(self.operation, Operation::NoOperation) {
             msr_byte |= FDC_STATUS_NON_DMA_MODE;
         }
         
@@ -617,11 +899,28 @@ impl FloppyController {
 
         // Set the "No Data" bit if we received an invalid request
         match self.last_error {
-            DriveError::BadRead | DriveError::BadWrite | DriveError::BadSeek => {
+            DriveError::BadRead | DriveError::BadWrite | DriveError::BadSeek 
+                | DriveError::WrongCylinder | DriveError::BadCylinder => {
                 st1_byte |= ST1_NODATA
             }
+            DriveError::WriteProtect => {
+                st1_byte |= ST1_WRITE_PROTECT
+            }
+            DriveError::NoId | DriveError::NoDataMark => {
+                st1_byte |= ST1_NO_ID
+            }
+            DriveError::DataError | DriveError::IdError => {
+                st1_byte |= ST1_DATA_ERROR
+            }
+            DriveError::EndOfCylinder => {
+                st1_byte |= ST1_END_OF_CYLINDER
+            }
+            DriveError::Overrun => {
+                st1_byte |= ST1_OVERRUN
+            }
             _=> {}
         }
+        st1_byte |= self.st1_flags;
 
         // Based on DOS's behavior regarding the "Not ready error" it appears that 
         // operations without a disk timeout instead of returning a particular error
@@ -634,9 +933,16 @@ impl FloppyController {
 
     /// Generate the value of the ST2 Status Register in response to a command
     pub fn make_st2_byte(&self, drive_select: usize) -> u8 {
-        // The ST2 status register contains mostly error codes, so for now we can just always return success
-        // by returning 0 until we handle possible errors.
-        0
+        // The ST2 status register contains mostly error codes
+        let st2_byte = match self.last_error {
+            DriveError::NoDataMark => ST2_NO_DATA_MARK,
+            DriveError::WrongCylinder => ST2_WRONG_CYLINDER,
+            DriveError::BadCylinder => ST2_WRONG_CYLINDER | ST2_BAD_CYLINDER,
+            DriveError::DataError => ST2_DATA_ERROR,
+            DriveError::ControlMark => ST2_CONTROL_MARK,
+            _ => 0
+        };
+        st2_byte | self.st2_flags
     }
 
     /// Generate the value of the ST3 Status Register in response to a command
@@ -677,6 +983,17 @@ impl FloppyController {
 
     pub fn handle_data_register_read(&mut self) -> u8 {
 
+        // In the execution phase of a non-DMA read the data register holds the byte under the head
+        if !self.in_dma && self.transfers_to_cpu() {
+            if !self.mrq {
+                log::warn!("Data Register read before the FDC presented a byte");
+                return 0xFF
+            }
+            self.mrq = false;
+            self.end_interrupt = true;
+            return self.transfer_read_u8()
+        }
+
         let mut out_byte = 0;
         
         if self.data_register_out.len() > 0 {
@@ -696,6 +1013,8 @@ impl FloppyController {
     pub fn set_command(&mut self, command: Command, n_bytes: u32, command_fn: CommandDispatchFn ) {
         // Since we are entering a new command, clear the previous error status
         self.last_error = DriveError::NoError;
+        self.st1_flags = 0;
+        self.st2_flags = 0;
         self.receiving_command = true;
         self.command = command;
         self.command_fn = Some(command_fn);
@@ -708,24 +1027,6 @@ impl FloppyController {
         self.mrq = true;
     }
     
-    /// Returns whether the CHS address is valid for the specified drive
-    pub fn is_id_valid(&mut self, drive_select: usize, c: u8, h: u8, s:u8 ) -> bool {
-
-        if !self.drives[drive_select].have_disk {
-            return false;
-        }
-
-        // Sectors are 1-indexed 
-        if c < self.drives[drive_select].max_cylinders
-            && h < self.drives[drive_select].max_heads
-            && s <= self.drives[drive_select].max_sectors {
-
-            return true;
-        }
-
-        return false;
-    }
-
     /// Handle a write to the Data Register, 0x3F5. 
     /// 
     /// This register receives various commands which may be up to 8 bytes long.
@@ -737,12 +1038,28 @@ impl FloppyController {
     /// time like DMA transfers.
     pub fn handle_data_register_write(&mut self, data: u8) {
         //log::trace!("Data Register Write");
+
+        // In the execution phase of a non-DMA write, the data register takes the next byte to write
+        if !self.in_dma && self.transfers_from_cpu() {
+            if self.mrq {
+                self.mrq = false;
+                self.end_interrupt = true;
+                self.transfer_write_u8(data);
+            }
+            else {
+                log::warn!("Data Register write before the FDC requested a byte");
+            }
+            return
+        }
+
         if !self.receiving_command { 
 
             let command = data & COMMAND_MASK;
+            self.command_flags = data & !COMMAND_MASK;
             match command {
                 COMMAND_READ_TRACK => {
                     log::trace!("Received Read Track command: {:02}", command);
+                    self.set_command(Command::ReadTrack, 8, FloppyController::command_read_track);
                 }
                 COMMAND_WRITE_SECTOR => {
                     log::trace!("Received Write Sector command: {:02}", command);
@@ -754,9 +1071,11 @@ impl FloppyController {
                 }
                 COMMAND_WRITE_DELETED_SECTOR => {
                     log::trace!("Received Write Deleted Sector command: {:02}", command);
+                    self.set_command(Command::WriteDeletedSector, 8, FloppyController::command_write_deleted_sector);
                 }
                 COMMAND_READ_DELETED_SECTOR => {
                     log::trace!("Received Read Deleted Sector command: {:02}", command);
+                    self.set_command(Command::ReadDeletedSector, 8, FloppyController::command_read_deleted_sector);
                 }
                 COMMAND_FORMAT_TRACK => {
                     log::trace!("Received Format Track command: {:02}", command);
@@ -781,13 +1100,27 @@ impl FloppyController {
                 }
                 COMMAND_READ_SECTOR_ID => {
                     log::trace!("Received Read Sector ID command: {:02}", command);
+                    self.set_command(Command::ReadSectorID, 1, FloppyController::command_read_sector_id);
                 }
                 COMMAND_SEEK_HEAD => {
                     log::trace!("Received Seek/Park Head command: {:02}", command);
                     self.set_command(Command::SeekParkHead, 2, FloppyController::command_seek_head);
                 }    
+                COMMAND_SCAN_EQUAL => {
+                    log::trace!("Received Scan Equal command: {:02}", command);
+                    self.set_command(Command::ScanEqual, 8, FloppyController::command_scan_equal);
+                }
+                COMMAND_SCAN_LOW_OR_EQUAL => {
+                    log::trace!("Received Scan Low or Equal command: {:02}", command);
+                    self.set_command(Command::ScanLowOrEqual, 8, FloppyController::command_scan_low_or_equal);
+                }
+                COMMAND_SCAN_HIGH_OR_EQUAL => {
+                    log::trace!("Received Scan High or Equal command: {:02}", command);
+                    self.set_command(Command::ScanHighOrEqual, 8, FloppyController::command_scan_high_or_equal);
+                }
                 _ => {
                     log::warn!("Received invalid command byte: {:02}", command);
+                    self.command_invalid();
                 }   
             }
         }
@@ -881,7 +1214,11 @@ impl FloppyController {
                 };
 
                 let code = match self.last_error {
-                    DriveError::BadRead | DriveError::BadWrite | DriveError::BadSeek => InterruptCode::AbnormalTermination,
+                    DriveError::BadRead | DriveError::BadWrite | DriveError::BadSeek
+                        | DriveError::NoId | DriveError::IdError | DriveError::NoDataMark | DriveError::DataError 
+                        | DriveError::WrongCylinder | DriveError::BadCylinder | DriveError::EndOfCylinder
+                        | DriveError::Overrun
+                        | DriveError::WriteProtect => InterruptCode::AbnormalTermination,
                     _=> InterruptCode::NormalTermination
                 };
 
@@ -912,13 +1249,28 @@ impl FloppyController {
         
     }
 
+    /// Respond to a command byte the FDC doesn't recognize. The result phase is a single ST0
+    /// byte with the Invalid Command interrupt code, and no interrupt is raised.
+    pub fn command_invalid(&mut self) {
+
+        self.data_register_out.clear();
+        self.data_register_out.push_back(ST0_INVALID_OPCODE);
+        self.send_data_register();
+
+        self.last_command = Command::Invalid;
+        self.command = Command::NoCommand;
+    }
+
     /// Perform the Fix Drive Data command.
-    /// We don't do anything currently with the provided values which are only useful for real drive timings.
+    /// The ND bit selects non-DMA mode, where data is transferred a byte at a time through the data
+    /// register. The drive timings aren't used.
     pub fn command_fix_drive_data(&mut self) -> Continuation {
         
         let steprate_unload = self.data_register_in.pop_front().unwrap();
         let headload_ndm = self.data_register_in.pop_front().unwrap();
 
+        self.dma = headload_ndm & SPECIFY_NON_DMA == 0;
+
         log::trace!("command_fix_drive_data completed: {:08b},{:08b}", steprate_unload, headload_ndm);
 
         Continuation::CommandComplete
@@ -928,8 +1280,11 @@ impl FloppyController {
     /// This command returns the ST3 status register.
     pub fn command_check_drive_status(&mut self) -> Continuation {
 
-        let drive_select: usize = (self.data_register_in.pop_front().unwrap() & 0x03) as usize;
+        let drive_head_select = self.data_register_in.pop_front().unwrap();
+        let drive_select = (drive_head_select & 0x03) as usize;
 
+        // ST3 reports the head given in the command
+        self.drives[drive_select].head = (drive_head_select >> 2) & 0x01;
         let st3 = self.make_st3_byte(drive_select);
         self.data_register_out.push_back(st3);
 
@@ -979,8 +1334,8 @@ impl FloppyController {
         let drive_select = (drive_head_select & 0x03) as usize;
         let head_select = (drive_head_select >> 2) & 0x01;
 
-        // Is this seek out of bounds?
-        if !self.is_id_valid(drive_select, cylinder, head_select, 1) {
+        // Is this seek out of bounds? The head moves over the drive's tracks, not the media's.
+        if cylinder >= self.drives[drive_select].drive_type.cylinders() {
             self.last_error = DriveError::BadSeek;
             self.send_interrupt = true;
             log::warn!("command_seek_head: invalid seek: drive:{} c: {} h: {}", drive_head_select, cylinder, head_select);
@@ -999,8 +1354,14 @@ impl FloppyController {
         Continuation::CommandComplete
     }
 
-    /// Perform the Read Sector Command
-    pub fn command_read_sector(&mut self) -> Continuation {
+    /// Start a sector operation: Read or Write Sector, Read or Write Deleted Sector, Read Track
+    /// or a Scan. All of these commands take the same parameters:
+    ///
+    ///     drive and head select, C, H, R and N of the first sector, EOT, GPL, DTL
+    ///
+    /// EOT is the last sector number on the track, and DTL the number of bytes transferred
+    /// per sector when N is 0. Scan commands take the step between sectors in place of DTL.
+    fn command_sector(&mut self, command: Command) -> Continuation {
 
         let drive_head_select = self.data_register_in.pop_front().unwrap();
         let cylinder = self.data_register_in.pop_front().unwrap();
@@ -1016,12 +1377,21 @@ impl FloppyController {
         
         if head != head_select {
             // Head and head_select should always match. Seems redundant
-            log::warn!("command_read_sector: non-matching head specifiers");
+            log::warn!("{:?}: non-matching head specifiers", command);
         }
 
         // Set drive_select for status register reads
         self.drive_select = drive_select;
 
+        let writes = matches!(command, Command::WriteSector | Command::WriteDeletedSector);
+        if writes && self.drives[drive_select].write_protected {
+            log::debug!("{:?}: disk in drive {} is write protected", command, drive_select);
+            self.last_error = DriveError::WriteProtect;
+            self.send_results_phase(InterruptCode::AbnormalTermination, drive_select, cylinder, head, sector, sector_size);
+            self.send_interrupt = true;
+            return Continuation::CommandComplete
+        }
+
         // Is there no disk in the drive?
         // 
         // Initially I had this command send an interrupt and try to return some error code in the 
@@ -1033,49 +1403,60 @@ impl FloppyController {
             return Continuation::CommandComplete
         }
 
-        // Is this read out of bounds?
-        if !self.is_id_valid(drive_select, cylinder, head, sector) {
-            self.last_error = DriveError::BadRead;
-            self.send_interrupt = true;
-            log::warn!("command_read_sector: invalid chs: drive:{}, c:{} h:{} s:{}", 
-                drive_select, cylinder, head, sector);
-            return Continuation::CommandComplete;
-        }
+        self.operation = match command {
+            Command::ReadDeletedSector => Operation::ReadDeletedSector(cylinder, head, sector, sector_size, track_len, gap3_len, data_len),
+            Command::ReadTrack => Operation::ReadTrack(cylinder, head, sector, sector_size, track_len, gap3_len, data_len),
+            Command::WriteSector => Operation::WriteSector(cylinder, head, sector, sector_size, track_len, gap3_len, data_len),
+            Command::WriteDeletedSector => Operation::WriteDeletedSector(cylinder, head, sector, sector_size, track_len, gap3_len, data_len),
+            Command::ScanEqual => Operation::ScanSector(ScanCondition::Equal, cylinder, head, sector, sector_size, track_len, gap3_len, data_len),
+            Command::ScanLowOrEqual => Operation::ScanSector(ScanCondition::LowOrEqual, cylinder, head, sector, sector_size, track_len, gap3_len, data_len),
+            Command::ScanHighOrEqual => Operation::ScanSector(ScanCondition::HighOrEqual, cylinder, head, sector, sector_size, track_len, gap3_len, data_len),
+            _ => Operation::ReadSector(cylinder, head, sector, sector_size, track_len, gap3_len, data_len),
+        };
 
-        // "Seek" to values given in command
-        self.drives[drive_select].cylinder = cylinder;
-        self.drives[drive_select].head = head;
+        // Select the head and sector given in the command
+        self.drives[drive_select].head = head_select;
         self.drives[drive_select].sector = sector;
-        
-        // Start read operation
-        self.operation = Operation::ReadSector(cylinder, head, sector, sector_size, track_len, gap3_len, data_len);
-
-        // Clear MRQ until operation completion so there is no attempt to read result values
-        self.mrq = false;
-
-        // DMA now in progress (TODO: Support PIO mode?)
-        self.in_dma = true;
 
         // The IBM PC BIOS only seems to ever set a track_len of 8. How do we support 9 sector (365k) floppies?
-        // Answer: DOS seems to know to request sector #9 and the BIOS doesn't complain
-
-        // Maximum size of DMA transfer
+        // Answer: DOS seems to know to request sector #9 and the BIOS doesn't complain. The transfer only
+        // checks for the end of the track once a sector is done, and DMA terminal count has ended it by then.
+        self.transfer_id = SectorId { c: cylinder, h: head, r: sector, n: sector_size };
+        self.transfer_len = if sector_size == 0 { (data_len as usize).min(128) } else { self.transfer_id.size() };
+        self.transfer_eot = track_len;
+        self.transfer_step = match self.operation {
+            Operation::ScanSector(..) => data_len.max(1),
+            _ => 1
+        };
+        self.transfer = None;
+        self.transfer_pending = true;
+        self.end_of_cylinder = false;
+        self.track_last = None;
+        self.track_sectors_left = track_len;
+
+        // Find the first sector under the head
+        self.locate_sector();
+        if !matches!(self.last_error, DriveError::NoError) {
+            let id = self.transfer_id;
+            self.operation = Operation::NoOperation;
+            self.send_results_phase(InterruptCode::AbnormalTermination, drive_select, id.c, id.h, id.r, id.n);
+            self.send_interrupt = true;
+            return Continuation::CommandComplete
+        }
 
-        //let max_sectors;
-        //if track_len > 0 {
-        //    max_sectors = track_len - sector + 1;
-        //}
-        //else {
-        //    max_sectors = 1;
-        //}
-        //self.dma_bytes_left = max_sectors as usize * SECTOR_SIZE;
+        // A scan isn't satisfied until a sector meets its condition
+        if let Operation::ScanSector(..) = self.operation {
+            self.st2_flags |= ST2_SCAN_NOT_SATISFIED;
+        }
 
-        log::trace!("command_read_sector: drive: {} cyl:{} head:{} sector:{} sector_size:{} track_len:{} gap3_len:{} data_len:{}",
-            drive_select, cylinder, head, sector, sector_size, track_len, gap3_len, data_len);
-        //log::trace!("command_read_sector: may operate on maximum of {} sectors", max_sectors);
+        // Clear MRQ until the first byte is ready, or for DMA until operation completion, so there 
+        // is no attempt to read result values
+        self.mrq = false;
+        self.busy = true;
+        self.in_dma = self.dma;
 
-        let base_address = self.get_image_address(self.drive_select, cylinder, head, sector);
-        log::trace!("command_read_sector: base address of image read: {:06X}", base_address);
+        log::trace!("{:?}: drive: {} cyl:{} head:{} sector:{} sector_size:{} track_len:{} gap3_len:{} data_len:{}",
+            command, drive_select, cylinder, head, sector, sector_size, track_len, gap3_len, data_len);
 
         // Flag to set up transfer size later
         self.operation_init = false;
@@ -1084,54 +1465,94 @@ impl FloppyController {
         Continuation::ContinueAsOperation
     }
 
+    /// Perform the Read Sector Command
+    pub fn command_read_sector(&mut self) -> Continuation {
+        self.command_sector(Command::ReadSector)
+    }
+
+    /// Perform the Read Deleted Sector Command
+    pub fn command_read_deleted_sector(&mut self) -> Continuation {
+        self.command_sector(Command::ReadDeletedSector)
+    }
+
+    /// Perform the Read Track Command. Sectors are read in the order they pass the head from
+    /// the index, whatever their IDs.
+    pub fn command_read_track(&mut self) -> Continuation {
+        self.command_sector(Command::ReadTrack)
+    }
+
     /// Perform the Write Sector Command
     pub fn command_write_sector(&mut self) -> Continuation {
+        self.command_sector(Command::WriteSector)
+    }
 
-        let drive_head_select = self.data_register_in.pop_front().unwrap();
-        let cylinder = self.data_register_in.pop_front().unwrap();
-        let head = self.data_register_in.pop_front().unwrap();
-        let sector = self.data_register_in.pop_front().unwrap();
-        let sector_size = self.data_register_in.pop_front().unwrap();
-        let track_len = self.data_register_in.pop_front().unwrap();
-        let gap3_len = self.data_register_in.pop_front().unwrap();
-        let data_len = self.data_register_in.pop_front().unwrap();
+    /// Perform the Write Deleted Sector Command
+    pub fn command_write_deleted_sector(&mut self) -> Continuation {
+        self.command_sector(Command::WriteDeletedSector)
+    }
 
-        let drive_select = (drive_head_select & 0x03) as usize;
-        let head_select = (drive_head_select >> 2) & 0x01;
-        
-        if head != head_select {
-            log::warn!("command_write_sector: non-matching head specifiers");
-        }
+    /// Perform the Scan Equal Command
+    pub fn command_scan_equal(&mut self) -> Continuation {
+        self.command_sector(Command::ScanEqual)
+    }
 
-        // Set CHS
-        self.drives[drive_select].cylinder = cylinder;
-        self.drives[drive_select].head = head;
-        self.drives[drive_select].sector = sector;
+    /// Perform the Scan Low or Equal Command
+    pub fn command_scan_low_or_equal(&mut self) -> Continuation {
+        self.command_sector(Command::ScanLowOrEqual)
+    }
 
-        // Start write operation
-        self.operation = Operation::WriteSector(cylinder, head, sector, sector_size, track_len, gap3_len, data_len);
+    /// Perform the Scan High or Equal Command
+    pub fn command_scan_high_or_equal(&mut self) -> Continuation {
+        self.command_sector(Command::ScanHighOrEqual)
+    }
 
-        // Clear MRQ until operation completion so there is no attempt to read result values
-        self.mrq = false;
+    /// Perform the Read Sector ID command (0x0A)
+    /// 
+    /// Returns the first good ID to pass under the head. Each Read Sector ID returns the next
+    /// ID around the track.
+    pub fn command_read_sector_id(&mut self) -> Continuation {
 
-        // DMA now in progress (TODO: Support PIO mode?)
-        self.in_dma = true;
+        let drive_head_select = self.data_register_in.pop_front().unwrap();
+        let drive_select = (drive_head_select & 0x03) as usize;
+        let head_select = (drive_head_select >> 2) & 0x01;
 
-        log::trace!("command_write_sector: cyl:{} head:{} sector:{} sector_size:{} track_len:{} gap3_len:{} data_len:{}",
-            cylinder, head, sector, sector_size, track_len, gap3_len, data_len);
-        //log::trace!("command_read_sector: may operate on maximum of {} sectors", max_sectors);
+        self.drive_select = drive_select;
+        self.drives[drive_select].head = head_select;
 
-        let base_address = self.get_image_address(self.drive_select, cylinder, head, sector);
-        log::trace!("command_write_sector: base address of image write: {:06X}", base_address);
+        // Without a disk the command times out, as for Read Sector
+        if !self.drives[drive_select].have_disk {
+            return Continuation::CommandComplete
+        }
 
-        // Flag to set up transfer size later
-        self.operation_init = false;
+        let result = self.find_track(drive_select, head_select).and_then(|track| {
+            let drive = &self.drives[drive_select];
+            let sectors = &drive.image.as_ref().ok_or(DriveError::NoMedia)?.tracks[track].sectors;
+            (0..sectors.len())
+                .map(|i| (drive.rotation + i) % sectors.len())
+                .find(|&i| !sectors[i].id_error)
+                .map(|i| (i, sectors[i].id))
+                .ok_or(DriveError::NoId)
+        });
+
+        match result {
+            Ok((index, id)) => {
+                self.drives[drive_select].rotation = index + 1;
+                log::trace!("command_read_sector_id: drive: {} read ID {:?}", drive_select, id);
+                self.send_results_phase(InterruptCode::NormalTermination, drive_select, id.c, id.h, id.r, id.n);
+            }
+            Err(err) => {
+                self.last_error = err;
+                let drive = &self.drives[drive_select];
+                let (cylinder, sector) = (drive.cylinder, drive.sector);
+                self.send_results_phase(InterruptCode::AbnormalTermination, drive_select, cylinder, head_select, sector, 0);
+            }
+        }
 
-        // Keep running command until DMA transfer completes
-        Continuation::ContinueAsOperation
+        self.send_interrupt = true;
+        Continuation::CommandComplete
     }
 
-    /// Perform the Write Sector Command
+    /// Perform the Format Track Command
     pub fn command_format_track(&mut self) -> Continuation {
 
         let drive_head_select = self.data_register_in.pop_front().unwrap();
@@ -1143,15 +1564,38 @@ impl FloppyController {
         let drive_select = (drive_head_select & 0x03) as usize;
         let head_select = (drive_head_select >> 2) & 0x01;
 
+        // Set drive_select for status register reads
+        self.drive_select = drive_select;
+
+        if self.drives[drive_select].write_protected {
+            log::debug!("command_format_track: disk in drive {} is write protected", drive_select);
+            self.last_error = DriveError::WriteProtect;
+            let cylinder = self.drives[drive_select].cylinder;
+            self.send_results_phase(InterruptCode::AbnormalTermination, drive_select, cylinder, head_select, 0, sector_size);
+            self.send_interrupt = true;
+            return Continuation::CommandComplete
+        }
+
+        // Formatting at the wrong data rate can't write readable sectors
+        if self.drives[drive_select].have_disk && self.drives[drive_select].data_rate != Some(self.data_rate) {
+            log::debug!("command_format_track: wrong data rate {:?} for drive {}", self.data_rate, drive_select);
+            self.last_error = DriveError::NoId;
+            let cylinder = self.drives[drive_select].cylinder;
+            self.send_results_phase(InterruptCode::AbnormalTermination, drive_select, cylinder, head_select, 0, sector_size);
+            self.send_interrupt = true;
+            return Continuation::CommandComplete
+        }
+
         // Start format operation
+        self.drives[drive_select].head = head_select;
         self.operation_init = false;
         self.operation = Operation::FormatTrack(sector_size, track_len, gap3_len, fill_byte);
 
-        // Clear MRQ until operation completion so there is no attempt to read result values
+        // Clear MRQ until the first byte is ready, or for DMA until operation completion, so there 
+        // is no attempt to read result values
         self.mrq = false;
-
-        // DMA now in progress (TODO: Support PIO mode?)
-        self.in_dma = true;
+        self.busy = true;
+        self.in_dma = self.dma;
 
         log::trace!("command_format_track: sector_size:{} track_len:{} gap3_len:{} fill_byte:{:02X}",
             sector_size, track_len, gap3_len, fill_byte);
@@ -1160,39 +1604,6 @@ impl FloppyController {
         Continuation::ContinueAsOperation
     }
 
-    /// Return a byte offset given a CHS (Cylinder, Head, Sector) address
-    pub fn get_image_address(&self, drive_select: usize, cylinder: u8, head: u8, sector: u8) -> usize {
-
-        if sector == 0 {
-            log::warn!("Invalid sector == 0");
-            return 0;
-        }
-        let hpc = self.drives[drive_select].max_heads as usize;
-        let spt = self.drives[drive_select].max_sectors as usize;
-        let lba: usize = (cylinder as usize * hpc + (head as usize)) * spt + (sector as usize - 1);
-        lba * SECTOR_SIZE
-    }
-
-    pub fn get_next_sector(&self, drive_select: usize, cylinder: u8, head: u8, sector: u8) -> (u8, u8, u8) {
-
-        if sector < self.drives[drive_select].max_sectors - 1 {
-            // Not at last sector, just return next sector
-            return (cylinder, head, sector + 1)
-        }
-        else if head < self.drives[drive_select].max_heads - 1 {
-            // At last sector, but not at last head, go to next head, same cylinder, sector 1
-            return (cylinder, head + 1, 1) 
-        }
-        else if cylinder < self.drives[drive_select].max_cylinders - 1 {
-            // At last sector and last head, go to next cylinder, head 0, sector 1
-            return (cylinder + 1, 0, 1)
-        }
-        else {
-            // Return end of drive? What does this do on real hardware
-            return (self.drives[drive_select].max_cylinders, 0, 1)
-        }
-    }
-    
     fn send_results_phase(
         &mut self, 
         result: InterruptCode, 
@@ -1221,194 +1632,388 @@ impl FloppyController {
         self.send_data_register();
         // Clear error state
         self.last_error = DriveError::NoError;
+        self.st1_flags = 0;
+        self.st2_flags = 0;
     }
 
-    fn operation_read_sector(
-        &mut self, 
-        dma: &mut dma::DMAController, 
-        bus: &mut BusInterface,
-        cylinder: u8, 
-        head: u8,
-        sector: u8, 
-        sector_size: u8, 
-        track_len: u8 ) {
-
-        if !self.in_dma {
-            log::error!("FDC in invalid state: ReadSector operation without DMA! Aborting.");
-            self.operation = Operation::NoOperation;
-            return
+    /// Finalize a sector operation once its transfer has ended, and send the results phase
+    /// with the ID the transfer stopped at: the sector in error if it ended on an error, or
+    /// otherwise the sector it would have continued with.
+    fn finish_sector_operation(&mut self) {
+
+        // A terminal count part way through a sector ends the command once the sector is done
+        if self.transfer.is_some() && self.transfer_offset > 0 {
+            self.transfer = None;
+            self.advance_sector();
         }
 
-        // Is read valid?
+        // Running off the end of the cylinder without a terminal count is an error, except
+        // for a scan, which stops there when no sector met its condition
+        let scanning = matches!(self.operation, Operation::ScanSector(..));
+        if self.end_of_cylinder && !self.dma_tc && !scanning && matches!(self.last_error, DriveError::NoError) {
+            self.last_error = DriveError::EndOfCylinder;
+        }
 
+        let result = match self.last_error {
+            DriveError::NoError | DriveError::ControlMark => InterruptCode::NormalTermination,
+            _ => InterruptCode::AbnormalTermination
+        };
+        let id = self.transfer_id;
 
-        if !self.operation_init {
-            let xfer_size = dma.get_dma_transfer_size(FDC_DMA);
+        self.dma_byte_count = 0;
+        self.dma_bytes_left = 0;
+        self.dma_tc = false;
+        self.transfer = None;
+        self.transfer_pending = false;
+        self.end_of_cylinder = false;
 
-            if xfer_size % SECTOR_SIZE != 0 {
-                log::warn!("DMA word count not multiple of sector size");
-            }
+        // Terminate by sending results registers
+        self.send_results_phase(result, self.drive_select, id.c, id.h, id.r, id.n);
 
-            let xfer_sectors = xfer_size / SECTOR_SIZE;
-            log::trace!("DMA programmed for transfer of {} sectors", xfer_sectors);
+        self.drives[self.drive_select].sector = id.r;
+    
+        // Finalize operation
+        self.operation = Operation::NoOperation;
+        self.send_interrupt = true;
+    }
 
-            let dst_address = dma.get_dma_transfer_address(FDC_DMA);
-            log::trace!("DMA destination address: {:05X}", dst_address);
+    fn current_image(&self) -> &DiskImage {
+        self.drives[self.drive_select].image.as_ref().expect("Sector transfer without a disk")
+    }
 
-            self.dma_bytes_left = xfer_sectors * SECTOR_SIZE;
-            self.operation_init = true;
-        }
+    /// Whether the operation reads the data fields of the sectors it transfers
+    fn reads_disk(&self) -> bool {
+        matches!(self.operation, 
+            Operation::ReadSector(..) | Operation::ReadDeletedSector(..) | Operation::ReadTrack(..) | Operation::ScanSector(..))
+    }
 
-        if self.dma_bytes_left > 0 {
-            // Bytes left to transfer
+    /// Whether the operation transfers data to the CPU
+    fn transfers_to_cpu(&self) -> bool {
+        matches!(self.operation, Operation::ReadSector(..) | Operation::ReadDeletedSector(..) | Operation::ReadTrack(..))
+    }
 
-            // Check if DMA is ready
-            if dma.check_dma_ready(FDC_DMA) {
-                let base_address = self.get_image_address(self.drive_select, cylinder, head, sector);
-                let byte_address = base_address + self.dma_byte_count;
+    /// Whether the operation takes data from the CPU
+    fn transfers_from_cpu(&self) -> bool {
+        matches!(self.operation, 
+            Operation::WriteSector(..) | Operation::WriteDeletedSector(..) | Operation::ScanSector(..) | Operation::FormatTrack(..))
+    }
 
-                //log::trace!("Byte address for FDC read: {:04X}", byte_address);
-                if byte_address >= self.drives[self.drive_select].disk_image.len() {
-                    log::error!("Read past end of disk image: {}/{}!", byte_address, self.drives[self.drive_select].disk_image.len() );
-                    self.dma_bytes_left = 0;
-                }
-                else {
-                    let byte = self.drives[self.drive_select].disk_image[byte_address];
-
-                    dma.do_dma_write_u8(bus, FDC_DMA, byte);
-                    self.dma_byte_count += 1;
-                    self.dma_bytes_left -= 1;
-
-                    // See if we are done
-                    let tc = dma.check_terminal_count(FDC_DMA);
-                    if tc {
-                        log::trace!("DMA terminal count triggered end of Sector Read operation, {} bytes read.", self.dma_byte_count);
-                        self.dma_bytes_left = 0;
-                    }
-                    
+    /// Whether the operation is looking for sectors with a deleted data mark
+    fn wants_deleted(&self) -> bool {
+        matches!(self.operation, Operation::ReadDeletedSector(..))
+    }
+
+    /// Find the sector at transfer_id for the transfer to continue with. With the SK flag, a
+    /// read skips sectors whose data mark isn't the one it's looking for. An error ends the
+    /// transfer.
+    fn locate_sector(&mut self) {
+        if let Operation::ReadTrack(..) = self.operation {
+            self.locate_track_sector();
+            return
+        }
+        while self.transfer_pending {
+            self.transfer_pending = false;
+            let head = self.drives[self.drive_select].head;
+            let addr = match self.find_sector(self.drive_select, head, self.transfer_id) {
+                Ok(addr) => addr,
+                Err(err) => {
+                    self.last_error = err;
+                    return
                 }
+            };
+            let sector = self.current_image().sector(addr);
+            let wrong_mark = sector.data.is_some() && sector.deleted != self.wants_deleted();
+            if self.reads_disk() && wrong_mark && self.command_flags & COMMAND_SKIP != 0 {
+                log::trace!("Skipping sector {:?}", self.transfer_id);
+                self.advance_sector();
+                continue
             }
+            self.enter_sector(addr);
         }
-        else {
-            // No more bytes left to transfer. Finalize operation
+    }
 
-            let tc = dma.check_terminal_count(FDC_DMA);
-            if !tc {
-                log::warn!("FDC sector read complete without DMA terminal count.");
+    /// Find the next sector for a Read Track operation: the next good ID to pass the head,
+    /// until the index comes round again. A sector whose ID isn't transfer_id is still read,
+    /// but sets ND.
+    fn locate_track_sector(&mut self) {
+        self.transfer_pending = false;
+        let head = self.drives[self.drive_select].head;
+        let track = match self.find_track(self.drive_select, head) {
+            Ok(track) => track,
+            Err(err) => {
+                self.last_error = err;
+                return
             }
+        };
+        let sectors = &self.current_image().tracks[track].sectors;
+        let start = self.track_last.map_or(0, |addr| addr.sector + 1);
+        let sector = match (start..sectors.len()).find(|&i| !sectors[i].id_error) {
+            Some(sector) => sector,
+            None => {
+                log::debug!("Read Track reached the index with {} sectors left", self.track_sectors_left);
+                self.end_of_cylinder = true;
+                return
+            }
+        };
+        if sectors[sector].id != self.transfer_id {
+            self.st1_flags |= ST1_NODATA;
+        }
+        let addr = SectorAddress { track, sector };
+        self.track_last = Some(addr);
+        self.enter_sector(addr);
+    }
 
-            self.dma_byte_count = 0;
-            self.dma_bytes_left = 0;
-
-            let (new_c, new_h, new_s) = self.get_next_sector(self.drive_select, cylinder, head, sector);
+    /// Start transferring the sector at `addr`. A read ends before a sector with no data field.
+    fn enter_sector(&mut self, addr: SectorAddress) {
+        if self.reads_disk() && self.current_image().sector(addr).data.is_none() {
+            log::debug!("Sector {:?} can't be transferred: no data address mark", self.transfer_id);
+            self.last_error = DriveError::NoDataMark;
+            return
+        }
+        self.transfer = Some(addr);
+        self.transfer_offset = 0;
+        self.scan_match = true;
+        self.scan_equal = true;
+    }
 
-            // Terminate normally by sending results registers
-            self.send_results_phase(InterruptCode::NormalTermination, self.drive_select, new_c, new_h, new_s, sector_size);
+    /// Finish transferring the sector at `addr` and move on to the next sector ID. A read
+    /// ends after a sector recorded with a CRC error, or after a sector with the wrong data
+    /// mark, which sets CM. Read Track notes CRC errors and carries on, and a scan ends on the
+    /// first sector that meets its condition.
+    fn end_sector(&mut self, addr: SectorAddress) {
+        self.transfer = None;
+        let sector = self.current_image().sector(addr);
+        let (data_error, deleted) = (sector.data_error, sector.deleted);
+
+        if let Operation::ReadTrack(..) = self.operation {
+            if data_error {
+                self.st1_flags |= ST1_DATA_ERROR;
+                self.st2_flags |= ST2_DATA_ERROR;
+            }
+            self.advance_sector();
+            return
+        }
+        if self.reads_disk() && data_error {
+            self.last_error = DriveError::DataError;
+            return
+        }
+        if matches!(self.operation, Operation::ScanSector(..)) && self.scan_match {
+            self.st2_flags &= !ST2_SCAN_NOT_SATISFIED;
+            if self.scan_equal {
+                self.st2_flags |= ST2_SCAN_EQUAL_HIT;
+            }
+            return
+        }
 
-            // Set new CHS
-            self.drives[self.drive_select].cylinder = new_c;
-            self.drives[self.drive_select].head = new_h;
-            self.drives[self.drive_select].sector = new_s;
-        
-            // Finalize operation
-            self.operation = Operation::NoOperation;
-            self.send_interrupt = true;
+        self.advance_sector();
+        if self.reads_disk() && deleted != self.wants_deleted() {
+            self.last_error = DriveError::ControlMark;
+            self.transfer_pending = false;
+            self.end_of_cylinder = false;
         }
     }
 
-    fn operation_write_sector(
-        &mut self, 
-        dma: &mut dma::DMAController, 
-        bus: &mut BusInterface,
-        cylinder: u8, 
-        head: u8,
-        sector: u8, 
-        sector_size: u8, 
-        track_len: u8 ) {
-
-        if !self.in_dma {
-            log::error!("Error: WriteSector operation without DMA!");
-            self.operation = Operation::NoOperation;
-            return
+    /// Move transfer_id on to the next sector as the FDC does after each sector: up to EOT on
+    /// this head, then on to sector 1 of head 1 for a multi-track command. Past the end of the
+    /// cylinder the ID moves to the next cylinder and the transfer ends.
+    fn advance_sector(&mut self) {
+        let id = self.transfer_id;
+        let multi_track = self.command_flags & COMMAND_MULTI_TRACK != 0;
+        let drive = &mut self.drives[self.drive_select];
+
+        if let Operation::ReadTrack(..) = self.operation {
+            // Read Track counts sectors rather than looking for sector EOT
+            self.transfer_id.r = id.r.wrapping_add(1);
+            self.track_sectors_left = self.track_sectors_left.saturating_sub(1);
+            self.transfer_pending = self.track_sectors_left > 0;
+            self.end_of_cylinder = !self.transfer_pending;
+        }
+        else if id.r < self.transfer_eot {
+            self.transfer_id.r = id.r.wrapping_add(self.transfer_step);
+            self.transfer_pending = true;
+        }
+        else if multi_track && drive.head == 0 {
+            drive.head = 1;
+            self.transfer_id = SectorId { h: id.h ^ 1, r: 1, ..id };
+            self.transfer_pending = true;
+        }
+        else {
+            let h = if multi_track { id.h ^ 1 } else { id.h };
+            self.transfer_id = SectorId { c: id.c.wrapping_add(1), h, r: 1, ..id };
+            self.end_of_cylinder = true;
         }
+    }
 
-        if !self.operation_init {
-            let xfer_size = dma.get_dma_transfer_size(FDC_DMA);
+    /// Set up the byte counters for a sector transfer. The FDC will keep transferring sectors 
+    /// until it receives a terminal count from the DMA controller, or runs off the end of the image.
+    fn init_sector_operation(&mut self) {
 
-            if xfer_size % SECTOR_SIZE != 0 {
-                log::warn!("DMA word count not multiple of sector size");
-            }
+        self.dma_byte_count = 0;
+        self.dma_tc = false;
+        self.pio_cycles = 0;
+        self.operation_init = true;
+    }
 
-            let xfer_sectors = xfer_size / SECTOR_SIZE;
-            log::trace!("DMA programmed for transfer of {} sectors", xfer_sectors);
+    /// Run a sector operation. Sectors are transferred until a terminal count from the DMA
+    /// controller, an error, or the end of the cylinder. Without DMA there is no terminal count,
+    /// so a non-DMA transfer always runs to EOT.
+    fn operation_sector(&mut self, dma: &mut dma::DMAController, cpu_cycles: u32) {
 
-            self.dma_bytes_left = xfer_sectors * SECTOR_SIZE;
-            self.operation_init = true;
+        if !self.operation_init {
+            self.init_sector_operation();
+            if self.in_dma {
+                log::trace!("DMA transfer address: {:05X}", dma.get_dma_transfer_address(FDC_DMA));
+            }
         }
 
-        if self.dma_bytes_left == SECTOR_SIZE {
-            let dst_address = dma.get_dma_transfer_address(FDC_DMA);
-            log::trace!("DMA source address: {:05X}", dst_address)
+        // Find the next sector once the last is done, unless terminal count has ended the transfer
+        if !self.dma_tc && self.transfer.is_none() && self.transfer_pending {
+            self.locate_sector();
         }
 
-        if self.dma_bytes_left > 0 {
-            // Bytes left to transfer
-
-            // Check if DMA is ready
-            if dma.check_dma_ready(FDC_DMA) {
-                let base_address = self.get_image_address(self.drive_select, cylinder, head, sector);
-                let byte_address = base_address + self.dma_byte_count;
-
-                //log::trace!("Byte address for FDC write: {:04X}", byte_address);
-                if byte_address >= self.drives[self.drive_select].disk_image.len() {
-                    log::error!("Write past end of disk image: {}/{}!", byte_address, self.drives[self.drive_select].disk_image.len() );
-                    self.dma_bytes_left = 0;
-                    // cleanup ?
-                }
-                else {
-
-                    let byte = dma.do_dma_read_u8(bus, FDC_DMA);
-                    self.drives[self.drive_select].disk_image[byte_address] = byte;
-                    self.dma_byte_count += 1;
-                    self.dma_bytes_left -= 1;
-
-                    // See if we are done
-                    let tc = dma.check_terminal_count(FDC_DMA);
-                    if tc {
-                        log::trace!("DMA terminal count triggered end of Sector Write operation, {} byte(s) written.", self.dma_byte_count);
-                        self.dma_bytes_left = 0;
-                    }
-                    
-                }
+        if !self.dma_tc && self.transfer.is_some() {
+            if self.in_dma {
+                // Keep DREQ asserted while there is data to exchange with the DMA controller
+                dma.request_dma_service(FDC_DMA);
             }
+            else {
+                self.run_pio(cpu_cycles);
+            }
+            return
+        }
+
+        // No more bytes left to transfer. Finalize operation
+        dma.clear_dma_service(FDC_DMA);
+        self.mrq = false;
+        if self.dma_tc {
+            log::trace!("DMA terminal count triggered end of {:?} operation, {} bytes transferred.", 
+                self.operation, 
+                self.dma_byte_count);
         }
         else {
-            // No more bytes left to transfer. Finalize operation
+            log::debug!("{:?} operation ended without terminal count after {} bytes, at sector {:?}", 
+                self.operation,
+                self.dma_byte_count, 
+                self.transfer_id);
+        }
+        self.finish_sector_operation();
+    }
 
-            let tc = dma.check_terminal_count(FDC_DMA);
-            if !tc {
-                log::warn!("FDC sector write complete without DMA terminal count.");
-            }
+    /// Run the execution phase of a non-DMA transfer. Each time a byte passes under the head
+    /// the FDC presents it in the data register, or asks for the next byte to write, setting
+    /// RQM and raising an interrupt. If the CPU hasn't serviced the last byte by then, the
+    /// transfer ends with an overrun.
+    fn run_pio(&mut self, cpu_cycles: u32) {
 
-            self.dma_byte_count = 0;
-            self.dma_bytes_left = 0;
+        self.pio_cycles += cpu_cycles;
+        let byte_cycles = self.data_rate.byte_cycles();
+        if self.pio_cycles < byte_cycles {
+            return
+        }
+        self.pio_cycles -= byte_cycles;
 
-            let (new_c, new_h, new_s) = self.get_next_sector(self.drive_select, cylinder, head, sector);
+        if self.mrq {
+            log::debug!("FDC overrun: data register not serviced after {} bytes", self.dma_byte_count);
+            self.last_error = DriveError::Overrun;
+            self.transfer = None;
+            self.transfer_pending = false;
+            self.end_of_cylinder = false;
+            self.dma_bytes_left = 0;
+            self.mrq = false;
+            return
+        }
 
-            // Terminate normally by sending results registers
-            self.send_results_phase(InterruptCode::NormalTermination, self.drive_select, new_c, new_h, new_s, sector_size);
+        self.dio = if self.transfers_to_cpu() { IoMode::ToCpu } else { IoMode::FromCpu };
+        self.mrq = true;
+        self.send_interrupt = true;
+    }
+    
+    /// Transfer the next byte of a read operation to the DMA controller or the CPU.
+    fn transfer_read_u8(&mut self) -> u8 {
+        // In block mode the DMA controller can ask for the next sector before the FDC has run
+        if self.transfer.is_none() && self.transfer_pending {
+            self.locate_sector();
+        }
+        match (&self.operation, self.transfer) {
+            (Operation::ReadSector(..) | Operation::ReadDeletedSector(..) | Operation::ReadTrack(..), Some(addr)) => {
+                let sector = self.current_image().sector(addr);
+                let offset = self.transfer_offset;
+                let mut byte = sector.data.as_ref().and_then(|data| data.get(offset)).copied().unwrap_or(GAP_BYTE);
+                // Weak bits read differently each time
+                if let Some(mask) = sector.weak.as_ref().and_then(|weak| weak.get(offset)) {
+                    byte ^= rand::random::<u8>() & mask;
+                }
+                self.dma_byte_count += 1;
+                self.transfer_offset += 1;
+                if self.transfer_offset == self.transfer_len {
+                    self.end_sector(addr);
+                }
+                byte
+            }
+            _ => {
+                log::warn!("FDC: Unexpected data read during operation: {:?}", self.operation);
+                0xFF
+            }
+        }
+    }
 
-            // Set new CHS
-            self.drives[self.drive_select].cylinder = new_c;
-            self.drives[self.drive_select].head = new_h;
-            self.drives[self.drive_select].sector = new_s;
-        
-            // Finalize operation
-            self.operation = Operation::NoOperation;
-            self.send_interrupt = true;
+    /// Transfer the next byte of a write, scan or format operation from the DMA controller or the CPU.
+    fn transfer_write_u8(&mut self, data: u8) {
+        if self.transfer.is_none() && self.transfer_pending {
+            self.locate_sector();
+        }
+        match (&self.operation, self.transfer) {
+            (Operation::WriteSector(..) | Operation::WriteDeletedSector(..), Some(addr)) => {
+                let deleted = matches!(self.operation, Operation::WriteDeletedSector(..));
+                let offset = self.transfer_offset;
+                let drive = &mut self.drives[self.drive_select];
+                let sector = drive.image.as_mut().expect("Sector transfer without a disk").sector_mut(addr);
+                let len = sector.id.size();
+                // Writing a sector records good data with a normal or deleted data mark
+                let sector_data = sector.data.get_or_insert_with(|| vec![0; len]);
+                if let Some(byte) = sector_data.get_mut(offset) {
+                    *byte = data;
+                }
+                sector.deleted = deleted;
+                sector.data_error = false;
+                sector.weak = None;
+                drive.dirty = true;
+                self.dma_byte_count += 1;
+                self.transfer_offset += 1;
+                if self.transfer_offset == self.transfer_len {
+                    self.end_sector(addr);
+                }
+            }
+            (Operation::ScanSector(condition, ..), Some(addr)) => {
+                let condition = *condition;
+                let sector = self.current_image().sector(addr);
+                let disk_byte = sector.data.as_ref().and_then(|data| data.get(self.transfer_offset)).copied().unwrap_or(GAP_BYTE);
+                // 0xFF from either the disk or the CPU matches anything
+                if disk_byte != 0xFF && data != 0xFF {
+                    self.scan_equal &= disk_byte == data;
+                    self.scan_match &= match condition {
+                        ScanCondition::Equal => disk_byte == data,
+                        ScanCondition::LowOrEqual => disk_byte <= data,
+                        ScanCondition::HighOrEqual => disk_byte >= data,
+                    };
+                }
+                self.dma_byte_count += 1;
+                self.transfer_offset += 1;
+                if self.transfer_offset == self.transfer_len {
+                    self.end_sector(addr);
+                }
+            }
+            (Operation::FormatTrack(..), _) if self.dma_bytes_left > 0 => {
+                self.format_buffer.push_back(data);
+                self.dma_byte_count += 1;
+                self.dma_bytes_left -= 1;
+            }
+            _ => {
+                log::warn!("FDC: Unexpected data write during operation: {:?}", self.operation);
+            }
         }
     }
-    
+
     /// Run the Format Track Operation
     /// 
     /// DOS will program DMA for the entire track length, but we only read track_len * 4 bytes from DMA 
@@ -1416,104 +2021,93 @@ impl FloppyController {
     fn operation_format_track(
         &mut self, 
         dma: &mut dma::DMAController, 
-        bus: &mut BusInterface,
         sector_size: u8,
         track_len: u8, 
-        gap3_len: u8,
-        fill_byte: u8 ) {
-
-        if !self.in_dma {
-            log::error!("Error: Format Track operation without DMA!");
-            self.operation = Operation::NoOperation;
-            return
-        }
+        fill_byte: u8,
+        cpu_cycles: u32 ) {
 
         if !self.operation_init {
-            let xfer_size = dma.get_dma_transfer_size(FDC_DMA);
-
-            if xfer_size < (track_len as usize * FORMAT_BUFFER_SIZE) {
-                log::error!("Format Track: DMA word count too small for track_len({:02}) format buffers.", track_len);
-                self.operation = Operation::NoOperation;
-                return 
-            }
-
-            let xfer_sectors = xfer_size / SECTOR_SIZE;
-            log::trace!("Format Track: DMA programmed for transfer of {} sectors", xfer_sectors);
+            log::trace!("Format Track: DMA programmed for transfer of {} bytes", dma.get_dma_transfer_size(FDC_DMA));
 
+            self.format_buffer.clear();
+            self.format_ids.clear();
             self.dma_bytes_left = track_len as usize * FORMAT_BUFFER_SIZE;
+            self.dma_tc = false;
+            self.pio_cycles = 0;
             self.operation_init = true;
         }
 
-        if self.dma_bytes_left > 0 {
-            // Bytes left to transfer
+        // Have we read in all 4 bytes of a format buffer? Add the sector ID it specifies to the track.
+        while self.format_buffer.len() >= FORMAT_BUFFER_SIZE {
 
-            // Check if DMA is ready
-            if dma.check_dma_ready(FDC_DMA) {
-                let byte = dma.do_dma_read_u8(bus, FDC_DMA);
-                self.format_buffer.push_back(byte);
-                self.dma_bytes_left = self.dma_bytes_left.saturating_sub(1);
-            }
-
-            // Have we read in all 4 bytes of a format buffer? Format the sector specified by the buffer.
-            if self.format_buffer.len() == FORMAT_BUFFER_SIZE {
-
-                let f_cylinder = self.format_buffer.pop_front().unwrap();
-                let f_head = self.format_buffer.pop_front().unwrap();
-                let f_sector = self.format_buffer.pop_front().unwrap();
-                let f_sector_size = self.format_buffer.pop_front().unwrap();
+            let id = SectorId {
+                c: self.format_buffer.pop_front().unwrap(),
+                h: self.format_buffer.pop_front().unwrap(),
+                r: self.format_buffer.pop_front().unwrap(),
+                n: self.format_buffer.pop_front().unwrap(),
+            };
 
-                log::trace!("Formatting cylinder: {} head: {} sector: {} size: {} with byte: {:02X}", 
-                    f_cylinder, f_head, f_sector, f_sector_size, fill_byte);
+            log::trace!("Formatting cylinder: {} head: {} sector: {} size: {} with byte: {:02X}", 
+                id.c, id.h, id.r, id.n, fill_byte);
 
-                self.format_sector(f_cylinder, f_head, f_sector, fill_byte);
-                self.send_interrupt = true;
+            self.format_ids.push(id);
+        }
 
-                // Clear for next 4 bytes
-                self.format_buffer.clear();
+        if !self.dma_tc && self.dma_bytes_left > 0 {
+            if self.in_dma {
+                // Keep DREQ asserted until we have received all the format buffers
+                dma.request_dma_service(FDC_DMA);
+            }
+            else {
+                self.run_pio(cpu_cycles);
             }
+            return
         }
-        else {
-            // No more bytes left to transfer. Finalize operation
-
-            //let tc = dma.check_terminal_count(FDC_DMA);
-            //if !tc {
-            //    log::warn!("FDC Format Track complete without DMA terminal count.");
-            //}
 
-            self.dma_byte_count = 0;
-            self.dma_bytes_left = 0;
+        // No more bytes left to transfer. Finalize operation
+        dma.clear_dma_service(FDC_DMA);
+        self.mrq = false;
+        if self.dma_bytes_left > 0 {
+            log::warn!("Format Track: DMA terminal count before all format buffers were received.");
+        }
 
-            //let (new_c, new_h, new_s) = self.get_next_sector(self.drive_select, cylinder, head, sector);
+        // Write the new track under the head
+        let drive = &mut self.drives[self.drive_select];
+        let track_cylinder = drive.cylinder / drive.step_rate;
+        if let Some(image) = &mut drive.image {
+            image.format_track(track_cylinder, drive.head, &self.format_ids, fill_byte);
+            drive.dirty = true;
+        }
 
-            // Terminate normally by sending results registers
+        self.format_buffer.clear();
+        self.format_ids.clear();
+        self.dma_byte_count = 0;
+        self.dma_bytes_left = 0;
+        self.dma_tc = false;
 
-            // Note the u765a whitepaper says this about the result codes of the Format Track command:
-            // "In this case, the ID information has no meaning"
-            self.send_results_phase(
-                InterruptCode::NormalTermination, 
-                self.drive_select, 
-                0, 
-                0,
-                0, 
-                sector_size);
+        // Terminate by sending results registers. Only an overrun ends a format early.
 
-            // Set new CHS
-            //self.drives[self.drive_select].cylinder = new_c;
-            //self.drives[self.drive_select].head = new_h;
-            //self.drives[self.drive_select].sector = new_s;
-        
-            // Finalize operation
-            self.operation = Operation::NoOperation;
-            self.send_interrupt = true;
-        }
+        // Note the u765a whitepaper says this about the result codes of the Format Track command:
+        // "In this case, the ID information has no meaning"
+        let result = match self.last_error {
+            DriveError::NoError => InterruptCode::NormalTermination,
+            _ => InterruptCode::AbnormalTermination
+        };
+        self.send_results_phase(
+            result, 
+            self.drive_select, 
+            0, 
+            0,
+            0, 
+            sector_size);
+    
+        // Finalize operation
+        self.operation = Operation::NoOperation;
+        self.send_interrupt = true;
     }    
 
-    pub fn format_sector(&mut self, cylinder: u8, head: u8, sector: u8, fill_byte: u8) {
-
-    }
-
     /// Run the Floppy Drive Controller. Process running Operations.
-    pub fn run(&mut self, pic: &mut pic::Pic, dma: &mut dma::DMAController, bus: &mut BusInterface, cpu_cycles: u32 ) {
+    pub fn run(&mut self, pic: &mut pic::Pic, dma: &mut dma::DMAController, cpu_cycles: u32 ) {
 
         // Send an interrupt if one is queued
         if self.send_interrupt {
@@ -1534,18 +2128,280 @@ impl FloppyController {
             Operation::NoOperation => {
                 // Do nothing
             }
-            Operation::ReadSector(cylinder, head, sector, sector_size, track_len, _gap3_len, _data_len) => {
-                self.operation_read_sector(dma, bus, cylinder, head, sector, sector_size, track_len)
+            Operation::ReadSector(..) | Operation::ReadDeletedSector(..) | Operation::ReadTrack(..) 
+                | Operation::WriteSector(..) | Operation::WriteDeletedSector(..) | Operation::ScanSector(..) => {
+                self.operation_sector(dma, cpu_cycles)
+            }
+            Operation::FormatTrack(sector_size, track_len, _gap3_len, fill_byte) => {
+                self.operation_format_track(dma, sector_size, track_len, fill_byte, cpu_cycles)
             }
-            Operation::WriteSector(cylinder, head, sector, sector_size, track_len, _gap3_len, _data_len) => {
-                self.operation_write_sector(dma, bus, cylinder, head, sector, sector_size, track_len)
+        }
+    }
+}
+
+/// DMA transfers for the FDC. The FDC raises DREQ during sector operations and the DMA
+/// controller moves data in or out of the FDC one byte per acknowledged transfer.
+impl dma::DmaDevice for FloppyController {
+
+    fn dma_read_u8(&mut self, _channel: usize) -> u8 {
+        self.transfer_read_u8()
+    }
+
+    fn dma_write_u8(&mut self, _channel: usize, data: u8) {
+        self.transfer_write_u8(data)
+    }
+
+    fn dma_end_of_process(&mut self, _channel: usize) {
+        self.dma_tc = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dma::DmaDevice;
+    use crate::floppy_image::SECTOR_SIZE;
+
+    fn send_command(fdc: &mut FloppyController, bytes: &[u8]) {
+        for &byte in bytes {
+            fdc.handle_data_register_write(byte);
+        }
+    }
+
+    #[test]
+    pub fn test_write_back() {
+        let dir = std::env::temp_dir().join(format!("marty_fdc_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("disk.img");
+        fs::write(&path, vec![0xF6; 163_840]).unwrap();
+
+        let mut fdc = FloppyController::new();
+        fdc.load_image_from(0, fs::read(&path).unwrap(), Some(path.clone())).unwrap();
+        assert!(!fdc.flush_image(0));
+
+        // Write sector 2 of cylinder 0, head 0
+        send_command(&mut fdc, &[0xC5, 0x00, 0, 0, 2, 2, 8, 0x2A, 0xFF]);
+        for _ in 0..SECTOR_SIZE {
+            fdc.dma_write_u8(FDC_DMA, 0xE5);
+        }
+        fdc.dma_end_of_process(FDC_DMA);
+        assert!(fdc.is_dirty(0));
+        assert!(fdc.flush_image(0));
+        let image = fs::read(&path).unwrap();
+        assert_eq!(image.len(), 163_840);
+        assert!(image[SECTOR_SIZE..SECTOR_SIZE * 2].iter().all(|b| *b == 0xE5));
+        assert_eq!(image[0], 0xF6);
+
+        // Writes to a protected disk fail with the write protect flag set
+        fdc.set_write_protect(0, true);
+        assert!(fdc.make_st3_byte(0) & ST3_WRITE_PROTECT != 0);
+        send_command(&mut fdc, &[0xC5, 0x00, 0, 0, 1, 2, 8, 0x2A, 0xFF]);
+        assert!(fdc.data_register_out[0] & ST0_ABNORMAL_TERMINATION != 0);
+        assert_eq!(fdc.data_register_out[1] & ST1_WRITE_PROTECT, ST1_WRITE_PROTECT);
+        assert!(!fdc.is_dirty(0));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    pub fn test_drive_types() {
+        let mut fdc = FloppyController::new();
+        fdc.set_drive_type(0, DriveType::Drive1200K);
+        assert!(fdc.load_image_from(0, vec![0; 1_474_560], None).is_err());
+        fdc.load_image_from(0, vec![0; 368_640], None).unwrap();
+
+        // Reading 360K media in a 1.2M drive needs the 300Kbps data rate
+        send_command(&mut fdc, &[0x46, 0x00, 0, 0, 1, 2, 9, 0x2A, 0xFF]);
+        assert_eq!(fdc.data_register_out[1] & ST1_NO_ID, ST1_NO_ID);
+
+        // and double stepping: the drive is at cylinder 10 for media track 5
+        fdc.write_u8(FDC_CONFIG_CONTROL_REGISTER, 0x01);
+        send_command(&mut fdc, &[0x0F, 0x00, 5]);
+        send_command(&mut fdc, &[0x46, 0x00, 5, 0, 1, 2, 9, 0x2A, 0xFF]);
+        assert_eq!(fdc.data_register_out[1] & ST1_NODATA, ST1_NODATA);
+
+        send_command(&mut fdc, &[0x0F, 0x00, 10]);
+        send_command(&mut fdc, &[0x46, 0x00, 5, 0, 1, 2, 9, 0x2A, 0xFF]);
+        assert!(matches!(fdc.operation, Operation::ReadSector(5, 0, 1, ..)));
+    }
+
+    #[test]
+    pub fn test_imd_sectors() {
+        // A track of two 1K sectors numbered from 0x41; the second has a CRC error
+        let mut data = b"IMD 1.18: 01/02/1985 10:20:30\r\n".to_vec();
+        data.push(0x1A);
+        data.extend_from_slice(&[5, 0, 0, 2, 3, 0x41, 0x42]);
+        data.extend_from_slice(&[2, 0x11, 6, 0x22]);
+
+        let mut fdc = FloppyController::new();
+        fdc.load_image_from(0, data, None).unwrap();
+
+        // A standard sector ID isn't found on this track
+        send_command(&mut fdc, &[0x46, 0x00, 0, 0, 1, 2, 9, 0x2A, 0xFF]);
+        assert_eq!(fdc.data_register_out[1] & ST1_NODATA, ST1_NODATA);
+
+        // Reading on from sector 0x41 stops after the sector with the CRC error
+        fdc.data_register_out.clear();
+        send_command(&mut fdc, &[0x46, 0x00, 0, 0, 0x41, 3, 0x42, 0x2A, 0xFF]);
+        let bytes: Vec<u8> = (0..2048).map(|_| fdc.dma_read_u8(FDC_DMA)).collect();
+        assert!(bytes[..1024].iter().all(|b| *b == 0x11));
+        assert!(bytes[1024..].iter().all(|b| *b == 0x22));
+        assert!(fdc.transfer.is_none());
+        assert!(matches!(fdc.last_error, DriveError::DataError));
+    }
+
+    #[test]
+    pub fn test_id_errors() {
+        let mut fdc = FloppyController::new();
+        fdc.load_image_from(0, vec![0; 163_840], None).unwrap();
+        let image = fdc.drives[0].image.as_mut().unwrap();
+        image.tracks[0].sectors[0].id_error = true;
+        image.tracks[0].sectors[1].data = None;
+
+        // An ID CRC error sets DE without DD
+        send_command(&mut fdc, &[0x46, 0x00, 0, 0, 1, 2, 8, 0x2A, 0xFF]);
+        assert_eq!(fdc.data_register_out[1], ST1_DATA_ERROR);
+        assert_eq!(fdc.data_register_out[2], 0);
+
+        // An ID with no data field is a missing data address mark
+        fdc.data_register_out.clear();
+        send_command(&mut fdc, &[0x46, 0x00, 0, 0, 2, 2, 8, 0x2A, 0xFF]);
+        assert_eq!(fdc.data_register_out[1], ST1_NO_ID);
+        assert_eq!(fdc.data_register_out[2], ST2_NO_DATA_MARK);
+
+        // A track with no IDs is a missing address mark
+        fdc.drives[0].image.as_mut().unwrap().tracks[0].sectors.clear();
+        fdc.data_register_out.clear();
+        send_command(&mut fdc, &[0x46, 0x00, 0, 0, 3, 2, 8, 0x2A, 0xFF]);
+        assert_eq!(fdc.data_register_out[1], ST1_NO_ID);
+    }
+
+    #[test]
+    pub fn test_sector_commands() {
+        let mut fdc = FloppyController::new();
+        let mut dma = dma::DMAController::new();
+        fdc.load_image_from(0, vec![0; 327_680], None).unwrap();
+        let image = fdc.drives[0].image.as_mut().unwrap();
+        image.tracks[0].sectors[1].deleted = true;
+        image.tracks[0].sectors[1].data = Some(vec![0xDD; SECTOR_SIZE]);
+        image.tracks[1].sectors[0].data = Some(vec![0x11; SECTOR_SIZE]);
+
+        let mut read = |fdc: &mut FloppyController, command: &[u8], len: usize, tc: bool| -> Vec<u8> {
+            send_command(fdc, command);
+            fdc.operation_sector(&mut dma, 0);
+            let bytes = (0..len).map(|_| fdc.dma_read_u8(FDC_DMA)).collect();
+            if tc {
+                fdc.dma_end_of_process(FDC_DMA);
             }
-            Operation::FormatTrack(sector_size, track_len, gap3_len, fill_byte) => {
-                self.operation_format_track(dma, bus, sector_size, track_len, gap3_len, fill_byte)
+            fdc.operation_sector(&mut dma, 0);
+            bytes
+        };
+
+        // Read Data with SK skips the deleted sector 2 and reports the sector after the last read
+        let bytes = read(&mut fdc, &[0x66, 0x00, 0, 0, 1, 2, 8, 0x2A, 0xFF], SECTOR_SIZE * 2, true);
+        assert!(bytes.iter().all(|b| *b == 0));
+        assert_eq!(fdc.data_register_out[0] & 0xC0, 0);
+        assert_eq!(fdc.data_register_out[5], 4);
+
+        // Without SK the deleted sector is read and sets CM; Read Deleted reads it normally
+        let bytes = read(&mut fdc, &[0x46, 0x00, 0, 0, 2, 2, 8, 0x2A, 0xFF], SECTOR_SIZE, false);
+        assert!(bytes.iter().all(|b| *b == 0xDD));
+        assert_eq!(fdc.data_register_out[2], ST2_CONTROL_MARK);
+        read(&mut fdc, &[0x4C, 0x00, 0, 0, 2, 2, 8, 0x2A, 0xFF], SECTOR_SIZE, true);
+        assert_eq!(fdc.data_register_out[2], 0);
+
+        // Running past EOT without terminal count ends the cylinder, unless MT moves on to head 1
+        read(&mut fdc, &[0x46, 0x00, 0, 0, 8, 2, 8, 0x2A, 0xFF], SECTOR_SIZE, false);
+        assert_eq!(fdc.data_register_out[0] & 0xC0, ST0_ABNORMAL_TERMINATION);
+        assert_eq!(fdc.data_register_out[1], ST1_END_OF_CYLINDER);
+        assert_eq!((fdc.data_register_out[3], fdc.data_register_out[5]), (1, 1));
+        let bytes = read(&mut fdc, &[0xC6, 0x00, 0, 0, 8, 2, 8, 0x2A, 0xFF], SECTOR_SIZE + 1, true);
+        assert_eq!(bytes[SECTOR_SIZE], 0x11);
+        assert_eq!(fdc.data_register_out[0] & ST0_HEAD_ACTIVE, ST0_HEAD_ACTIVE);
+
+        // Read Track reads sectors from the index whatever their IDs, setting ND on a mismatch
+        let bytes = read(&mut fdc, &[0x42, 0x00, 0, 0, 5, 2, 2, 0x2A, 0xFF], SECTOR_SIZE * 2, true);
+        assert!(bytes[SECTOR_SIZE..].iter().all(|b| *b == 0xDD));
+        assert_eq!(fdc.data_register_out[0] & 0xC0, 0);
+        assert_eq!(fdc.data_register_out[1], ST1_NODATA);
+
+        // Read ID returns successive IDs around the track
+        send_command(&mut fdc, &[0x4A, 0x00]);
+        assert_eq!(fdc.data_register_out.range(3..).copied().collect::<Vec<u8>>(), [0, 0, 1, 2]);
+        send_command(&mut fdc, &[0x4A, 0x00]);
+        assert_eq!(fdc.data_register_out[5], 2);
+
+        // Scan Equal is satisfied by sector 3, where 0xFF matches anything
+        send_command(&mut fdc, &[0x51, 0x00, 0, 0, 3, 2, 4, 0x2A, 0x01]);
+        fdc.operation_sector(&mut dma, 0);
+        for i in 0..SECTOR_SIZE {
+            fdc.dma_write_u8(FDC_DMA, if i == 0 { 0x00 } else { 0xFF });
+        }
+        fdc.operation_sector(&mut dma, 0);
+        assert_eq!(fdc.data_register_out[2], ST2_SCAN_EQUAL_HIT);
+        assert_eq!(fdc.data_register_out[5], 3);
+
+        // and unsatisfied to EOT
+        send_command(&mut fdc, &[0x51, 0x00, 0, 0, 3, 2, 4, 0x2A, 0x01]);
+        fdc.operation_sector(&mut dma, 0);
+        for _ in 0..SECTOR_SIZE * 2 {
+            fdc.dma_write_u8(FDC_DMA, 0x01);
+        }
+        fdc.operation_sector(&mut dma, 0);
+        assert_eq!(fdc.data_register_out[0] & 0xC0, 0);
+        assert_eq!(fdc.data_register_out[2], ST2_SCAN_NOT_SATISFIED);
+
+        // An invalid command returns ST0 alone
+        fdc.data_register_out.clear();
+        send_command(&mut fdc, &[0x1F]);
+        assert_eq!(fdc.data_register_out, [ST0_INVALID_OPCODE]);
+    }
+
+    #[test]
+    pub fn test_non_dma_mode() {
+        let mut fdc = FloppyController::new();
+        let mut dma = dma::DMAController::new();
+        fdc.load_image_from(0, vec![0x5A; 163_840], None).unwrap();
+
+        // Specify with ND set
+        send_command(&mut fdc, &[0x03, 0xCF, 0x03]);
+
+        // Bytes are read through the data register as the FDC presents them. With no terminal
+        // count the transfer runs to EOT.
+        send_command(&mut fdc, &[0x46, 0x00, 0, 0, 1, 2, 1, 0x2A, 0xFF]);
+        let mut bytes = Vec::new();
+        while !matches!(fdc.operation, Operation::NoOperation) {
+            fdc.operation_sector(&mut dma, 50);
+            let msr = fdc.handle_status_register_read();
+            if msr & FDC_STATUS_MRQ != 0 && msr & FDC_STATUS_NON_DMA_MODE != 0 {
+                assert_eq!(msr & FDC_STATUS_DIO, FDC_STATUS_DIO);
+                bytes.push(fdc.handle_data_register_read());
             }
-            _ => {
-                log::error!("Invalid FDC operation: {:?}", self.operation)
+        }
+        assert_eq!(bytes, vec![0x5A; SECTOR_SIZE]);
+        assert_eq!(fdc.handle_status_register_read() & (FDC_STATUS_MRQ | FDC_STATUS_DIO | FDC_STATUS_NON_DMA_MODE), 
+            FDC_STATUS_MRQ | FDC_STATUS_DIO);
+        assert_eq!(fdc.data_register_out[1], ST1_END_OF_CYLINDER);
+
+        // Writes take each byte from the data register
+        send_command(&mut fdc, &[0x45, 0x00, 0, 0, 2, 2, 2, 0x2A, 0xFF]);
+        while !matches!(fdc.operation, Operation::NoOperation) {
+            fdc.operation_sector(&mut dma, 50);
+            if fdc.handle_status_register_read() & (FDC_STATUS_MRQ | FDC_STATUS_DIO) == FDC_STATUS_MRQ {
+                fdc.handle_data_register_write(0xAB);
             }
         }
+        let image = fdc.drives[0].image.as_ref().unwrap();
+        assert_eq!(image.tracks[0].sectors[1].data, Some(vec![0xAB; SECTOR_SIZE]));
+
+        // A byte not read before the next arrives is an overrun
+        send_command(&mut fdc, &[0x46, 0x00, 0, 0, 1, 2, 1, 0x2A, 0xFF]);
+        let byte_cycles = fdc.data_rate.byte_cycles();
+        fdc.operation_sector(&mut dma, byte_cycles);
+        assert!(fdc.handle_status_register_read() & FDC_STATUS_MRQ != 0);
+        fdc.operation_sector(&mut dma, byte_cycles);
+        fdc.operation_sector(&mut dma, byte_cycles);
+        assert_eq!(fdc.data_register_out[0] & 0xC0, ST0_ABNORMAL_TERMINATION);
+        assert_eq!(fdc.data_register_out[1], ST1_OVERRUN);
     }
-}
\ No newline at end of file
+}
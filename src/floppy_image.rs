@@ -0,0 +1,359 @@
+/*
+    floppy_image.rs
+    Represent floppy disk images as tracks of sectors
+
+    The FDC finds sectors by the ID fields recorded on each track, which don't have to
+    follow the standard PC layout. Sectors can be numbered from any value, be from 128
+    bytes to 8K long, carry a cylinder or head number other than the track they are on, be
+    marked deleted or recorded with a bad CRC, or have no readable data at all. Images are
+    loaded into a list of tracks holding each sector's ID and data, so that formats which
+    record these details (IMD, TD0) can be presented to the FDC as they are on the disk.
+    Bitstream images (86F, HFE, HxC MFM) are decoded from their flux cells into the same
+    model, which adds the ID CRC errors and weak bits only they can record.
+
+    Raw sector dumps are loaded into the same model with the standard layout for their
+    size. Images are saved in the format they were loaded from; a raw image can only be
+    saved while the disk keeps its standard layout, and Teledisk and bitstream images can't
+    be saved.
+*/
+
+use std::collections::HashMap;
+
+use lazy_static::lazy_static;
+use time::PrimitiveDateTime;
+
+use crate::{d86f, hxc, imd, td0};
+
+pub const SECTOR_SIZE: usize = 512;
+// Sector size code for 512 byte sectors
+pub const SECTOR_SIZE_CODE: u8 = 2;
+
+/// The geometry of a disk, used to decide which drives can read it.
+pub struct DiskFormat {
+    pub cylinders: u8,
+    pub heads: u8,
+    pub sectors: u8,
+    pub high_density: bool,
+}
+
+lazy_static! {
+    static ref DISK_FORMATS: HashMap<usize, DiskFormat> = {
+        let formats = [
+            (40, 1, 8),
+            (40, 1, 9),
+            (40, 2, 8),
+            (40, 2, 9),
+            (80, 2, 9),
+            (80, 2, 15),
+            (80, 2, 18),
+        ];
+        formats.iter().map(|&(cylinders, heads, sectors)| {
+            let size = cylinders as usize * heads as usize * sectors as usize * SECTOR_SIZE;
+            (size, DiskFormat { cylinders, heads, sectors, high_density: sectors > 10 })
+        }).collect()
+    };
+}
+
+#[derive(Debug)]
+pub enum ImageError {
+    BadFormat(&'static str),
+    NotStandard,
+    NotWritable,
+}
+
+impl std::fmt::Display for ImageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageError::BadFormat(s) => write!(f, "Invalid disk image: {}", s),
+            ImageError::NotStandard => write!(f, "Disk no longer has a standard layout for a raw image"),
+            ImageError::NotWritable => write!(f, "Image format can't be written"),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ImageFormat {
+    Raw,
+    Imd,
+    Td0,
+    D86f,
+    Hfe,
+    HxcMfm,
+}
+
+impl ImageFormat {
+    /// Whether images of this format can be written back.
+    pub fn writable(&self) -> bool {
+        matches!(self, ImageFormat::Raw | ImageFormat::Imd)
+    }
+}
+
+/// The ID field of a sector: cylinder, head, sector number and size code.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SectorId {
+    pub c: u8,
+    pub h: u8,
+    pub r: u8,
+    pub n: u8,
+}
+
+impl SectorId {
+    pub fn size(&self) -> usize {
+        128 << self.n.min(6)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Sector {
+    pub id: SectorId,
+    // None if the sector's data can't be read
+    pub data: Option<Vec<u8>>,
+    pub deleted: bool,
+    pub data_error: bool,
+    // The ID field was read with a CRC error
+    pub id_error: bool,
+    // Bits of the data that read differently each time, from bitstream images
+    pub weak: Option<Vec<u8>>,
+}
+
+/// How a track was recorded: its data rate and whether it uses MFM or FM encoding.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TrackMode {
+    pub rate_kbps: u16,
+    pub mfm: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Track {
+    pub cylinder: u8,
+    pub head: u8,
+    pub mode: TrackMode,
+    pub sectors: Vec<Sector>,
+}
+
+/// The location of a sector in an image, as indexes of its track and of the sector within
+/// the track.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SectorAddress {
+    pub track: usize,
+    pub sector: usize,
+}
+
+pub struct DiskImage {
+    pub format: ImageFormat,
+    pub tracks: Vec<Track>,
+    // Header text of formats that carry one, such as the IMD signature and comment
+    pub comment: String,
+    // When the image was made, for formats that record it
+    pub created: Option<PrimitiveDateTime>,
+    // Length of a raw image, which may be shorter than its format
+    raw_len: usize,
+}
+
+impl DiskImage {
+    pub fn new(format: ImageFormat, tracks: Vec<Track>, comment: String) -> Self {
+        Self {
+            format,
+            tracks,
+            comment,
+            created: None,
+            raw_len: 0,
+        }
+    }
+
+    /// Load an image, detecting its format from its contents.
+    pub fn load(data: &[u8]) -> Result<Self, ImageError> {
+        if data.starts_with(imd::IMD_SIGNATURE) {
+            imd::load(data)
+        }
+        else if data.starts_with(td0::TD0_SIGNATURE) || data.starts_with(td0::TD0_ADVANCED_SIGNATURE) {
+            td0::load(data)
+        }
+        else if data.starts_with(d86f::D86F_SIGNATURE) {
+            d86f::load(data)
+        }
+        else if data.starts_with(hxc::HFE_SIGNATURE) {
+            hxc::load_hfe(data)
+        }
+        else if data.starts_with(hxc::MFM_SIGNATURE) {
+            hxc::load_mfm(data)
+        }
+        else {
+            Self::from_raw(data)
+        }
+    }
+
+    /// Save the image in the format it was loaded from.
+    pub fn save(&self) -> Result<Vec<u8>, ImageError> {
+        match self.format {
+            ImageFormat::Raw => self.to_raw(),
+            ImageFormat::Imd => Ok(imd::save(self)),
+            _ => Err(ImageError::NotWritable),
+        }
+    }
+
+    /// Load a raw sector dump. Its size gives its geometry. Images smaller than a single
+    /// sided disk, such as boot sector images, are treated as the start of a 160K disk.
+    pub fn from_raw(data: &[u8]) -> Result<Self, ImageError> {
+        // Disk images must contain whole sectors
+        if !data.len().is_multiple_of(SECTOR_SIZE) {
+            return Err(ImageError::BadFormat("Invalid image length"))
+        }
+        let fmt = match DISK_FORMATS.get(&data.len()) {
+            Some(fmt) => fmt,
+            None if data.len() < 163_840 => &DISK_FORMATS[&163_840],
+            None => return Err(ImageError::BadFormat("Invalid image length"))
+        };
+
+        let mode = TrackMode {
+            rate_kbps: if fmt.high_density { 500 } else { 250 },
+            mfm: true,
+        };
+        let mut chunks = data.chunks(SECTOR_SIZE);
+        let mut tracks = Vec::new();
+        for c in 0..fmt.cylinders {
+            for h in 0..fmt.heads {
+                let sectors = (1..=fmt.sectors).map(|r| {
+                    let mut sector_data = chunks.next().unwrap_or_default().to_vec();
+                    sector_data.resize(SECTOR_SIZE, 0);
+                    Sector {
+                        id: SectorId { c, h, r, n: SECTOR_SIZE_CODE },
+                        data: Some(sector_data),
+                        deleted: false,
+                        data_error: false,
+                        id_error: false,
+                        weak: None,
+                    }
+                }).collect();
+                tracks.push(Track { cylinder: c, head: h, mode, sectors });
+            }
+        }
+
+        let mut image = Self::new(ImageFormat::Raw, tracks, String::new());
+        image.raw_len = data.len();
+        Ok(image)
+    }
+
+    /// Write the image as a raw sector dump, if it has the standard layout of a raw image.
+    pub fn to_raw(&self) -> Result<Vec<u8>, ImageError> {
+        let spt = self.tracks.first().map(|t| t.sectors.len()).unwrap_or(0) as u8;
+        let mut data = Vec::new();
+        for c in 0..self.cylinders() {
+            for h in 0..self.heads() {
+                // Below the count of u8 track numbers, so these fit
+                let (c, h) = (c as u8, h as u8);
+                for r in 1..=spt {
+                    let id = SectorId { c, h, r, n: SECTOR_SIZE_CODE };
+                    let sector = self.find_sector(c, h, id).map(|addr| self.sector(addr));
+                    match sector.and_then(|s| s.data.as_ref()) {
+                        Some(sector_data) => data.extend_from_slice(sector_data),
+                        None => return Err(ImageError::NotStandard)
+                    }
+                }
+            }
+        }
+        if !DISK_FORMATS.contains_key(&data.len()) {
+            return Err(ImageError::NotStandard)
+        }
+        if self.raw_len > 0 {
+            data.truncate(self.raw_len);
+        }
+        Ok(data)
+    }
+
+    /// The number of cylinders, up to the highest numbered track. Counted in u16 since a
+    /// track may be numbered 255.
+    pub fn cylinders(&self) -> u16 {
+        self.tracks.iter().map(|t| t.cylinder as u16 + 1).max().unwrap_or(0)
+    }
+
+    pub fn heads(&self) -> u16 {
+        self.tracks.iter().map(|t| t.head as u16 + 1).max().unwrap_or(0)
+    }
+
+    /// The geometry of the disk. Sectors per track is given in 512 byte sectors, and any
+    /// track recorded at 500Kbps makes the disk high density.
+    pub fn disk_format(&self) -> DiskFormat {
+        let track_bytes = self.tracks.iter()
+            .map(|t| t.sectors.iter().map(|s| s.id.size()).sum::<usize>())
+            .max()
+            .unwrap_or(0);
+        DiskFormat {
+            cylinders: if self.cylinders() > 45 { 80 } else { 40 },
+            // A floppy disk has at most two sides
+            heads: self.heads().min(2) as u8,
+            sectors: (track_bytes / SECTOR_SIZE) as u8,
+            high_density: self.tracks.iter().any(|t| t.mode.rate_kbps >= 500),
+        }
+    }
+
+    /// Find the track at the given physical cylinder and head.
+    pub fn track(&self, cylinder: u8, head: u8) -> Option<usize> {
+        self.tracks.iter().position(|t| t.cylinder == cylinder && t.head == head)
+    }
+
+    /// Find a sector with the given ID on the track at the given physical cylinder and head.
+    pub fn find_sector(&self, cylinder: u8, head: u8, id: SectorId) -> Option<SectorAddress> {
+        let track = self.track(cylinder, head)?;
+        let sector = self.tracks[track].sectors.iter().position(|s| s.id == id)?;
+        Some(SectorAddress { track, sector })
+    }
+
+    pub fn sector(&self, addr: SectorAddress) -> &Sector {
+        &self.tracks[addr.track].sectors[addr.sector]
+    }
+
+    pub fn sector_mut(&mut self, addr: SectorAddress) -> &mut Sector {
+        &mut self.tracks[addr.track].sectors[addr.sector]
+    }
+
+    /// Replace the track at the given physical cylinder and head with newly formatted
+    /// sectors, filled with `fill_byte`.
+    pub fn format_track(&mut self, cylinder: u8, head: u8, ids: &[SectorId], fill_byte: u8) {
+        let sectors = ids.iter().map(|&id| Sector {
+            id,
+            data: Some(vec![fill_byte; id.size()]),
+            deleted: false,
+            data_error: false,
+            id_error: false,
+            weak: None,
+        }).collect();
+
+        match self.track(cylinder, head) {
+            Some(track) => self.tracks[track].sectors = sectors,
+            None => {
+                let mode = self.tracks.first().map(|t| t.mode).unwrap_or(TrackMode { rate_kbps: 250, mfm: true });
+                self.tracks.push(Track { cylinder, head, mode, sectors });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_raw_image() {
+        let mut data = vec![0; 368_640];
+        data[SECTOR_SIZE * 9] = 0xAA;
+        let mut image = DiskImage::from_raw(&data).unwrap();
+        assert_eq!(image.cylinders(), 40);
+        assert_eq!(image.heads(), 2);
+
+        // Head 1 of each cylinder follows head 0
+        let addr = image.find_sector(0, 1, SectorId { c: 0, h: 1, r: 1, n: 2 }).unwrap();
+        assert_eq!(image.sector(addr).data.as_ref().unwrap()[0], 0xAA);
+        assert_eq!(image.to_raw().unwrap(), data);
+
+        // A track numbered 255 doesn't overflow the cylinder count
+        let mut odd = DiskImage::from_raw(&data).unwrap();
+        odd.format_track(255, 0, &[SectorId { c: 255, h: 0, r: 1, n: 2 }], 0xF6);
+        assert_eq!(odd.cylinders(), 256);
+        assert_eq!(odd.disk_format().cylinders, 80);
+
+        // A non-standard layout can't be saved as a raw image
+        image.format_track(0, 0, &[SectorId { c: 0, h: 0, r: 0x41, n: 3 }], 0xF6);
+        assert!(image.to_raw().is_err());
+    }
+}
@@ -72,6 +72,11 @@ impl FloppyManager {
         vec
     }
 
+    /// The path of the named image, for writing changes back to it.
+    pub fn get_floppy_path(&self, name: &OsString) -> Option<PathBuf> {
+        self.image_map.get(name).map(|floppy| floppy.path.clone())
+    }
+
     pub fn load_floppy_data(&self, name: &OsString ) -> Result<Vec<u8>, FloppyError> {
 
         let mut floppy_vec = Vec::new();
@@ -0,0 +1,167 @@
+/*
+    gameport.rs
+    Implement the IBM Game Control Adapter
+
+    The adapter at port 0x201 reads two joysticks, each with two resistive axes and two
+    buttons. Writing any value to the port fires four one-shot timers, one per axis. Each
+    timer's output, read in bits 0-3, stays high for a time set by the resistance of its
+    potentiometer:
+
+        Time = 24.2us + 0.011us * Resistance (0 - 100k ohms)
+
+    Software counts how long each bit stays high to find the stick position. The timers
+    are not retriggerable while running. An axis with nothing connected never times out.
+    Bits 4-7 read the buttons, low when pressed.
+
+    Host input is bound to the sticks in joystick.rs.
+*/
+
+use crate::cpu::CPU_MHZ;
+use crate::io::IoDevice;
+
+pub const GAMEPORT_PORT: u16 = 0x201;
+
+pub const GAMEPORT_AXES: usize = 4;
+pub const GAMEPORT_BUTTONS: usize = 4;
+
+const ONESHOT_BASE_MICROS: f64 = 24.2;
+const ONESHOT_MICROS_PER_OHM: f64 = 0.011;
+const AXIS_MAX_OHMS: f64 = 100_000.0;
+
+/// A change to a joystick axis or button. Axes are numbered stick A X, A Y, B X, B Y and
+/// range from -1.0 (left or up) to 1.0. Buttons are numbered A1, A2, B1, B2.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum JoystickEvent {
+    Axis(usize, f32),
+    Button(usize, bool),
+}
+
+pub struct GamePort {
+    axes: [f32; GAMEPORT_AXES],
+    buttons: [bool; GAMEPORT_BUTTONS],
+    sticks_connected: [bool; 2],
+    cycles: u64,
+    // Cycle count when each one-shot times out, or None if it never will
+    timeouts: [Option<u64>; GAMEPORT_AXES],
+}
+
+impl GamePort {
+    /// Create a game port with the given sticks connected.
+    pub fn new(sticks_connected: [bool; 2]) -> Self {
+        Self {
+            axes: [0.0; GAMEPORT_AXES],
+            buttons: [false; GAMEPORT_BUTTONS],
+            sticks_connected,
+            cycles: 0,
+            timeouts: [Some(0); GAMEPORT_AXES],
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.timeouts = [Some(0); GAMEPORT_AXES];
+    }
+
+    pub fn handle_event(&mut self, event: JoystickEvent) {
+        match event {
+            JoystickEvent::Axis(axis, value) if axis < GAMEPORT_AXES => {
+                self.axes[axis] = value.clamp(-1.0, 1.0);
+            }
+            JoystickEvent::Button(button, pressed) if button < GAMEPORT_BUTTONS => {
+                self.buttons[button] = pressed;
+            }
+            _ => {}
+        }
+    }
+
+    /// The one-shot time of an axis in CPU cycles, or None if no stick is connected.
+    fn axis_cycles(&self, axis: usize) -> Option<u64> {
+        if !self.sticks_connected[axis / 2] {
+            return None
+        }
+        let ohms = (self.axes[axis] as f64 + 1.0) / 2.0 * AXIS_MAX_OHMS;
+        let micros = ONESHOT_BASE_MICROS + ONESHOT_MICROS_PER_OHM * ohms;
+        Some((micros * CPU_MHZ) as u64)
+    }
+
+    fn timing(&self, axis: usize) -> bool {
+        match self.timeouts[axis] {
+            Some(timeout) => self.cycles < timeout,
+            None => true
+        }
+    }
+
+    fn trigger(&mut self) {
+        for axis in 0..GAMEPORT_AXES {
+            if !self.timing(axis) {
+                self.timeouts[axis] = self.axis_cycles(axis).map(|cycles| self.cycles + cycles);
+            }
+        }
+    }
+
+    pub fn run(&mut self, cycles: u32) {
+        self.cycles += cycles as u64;
+    }
+}
+
+impl IoDevice for GamePort {
+    fn read_u8(&mut self, _port: u16) -> u8 {
+        let mut byte = 0;
+        for axis in 0..GAMEPORT_AXES {
+            if self.timing(axis) {
+                byte |= 0x01 << axis;
+            }
+        }
+        for button in 0..GAMEPORT_BUTTONS {
+            if !self.buttons[button] {
+                byte |= 0x10 << button;
+            }
+        }
+        byte
+    }
+
+    fn write_u8(&mut self, _port: u16, _data: u8) {
+        self.trigger();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Count cycles until an axis bit falls, as a game's calibration loop would.
+    fn measure(port: &mut GamePort, axis: usize) -> Option<u32> {
+        port.write_u8(GAMEPORT_PORT, 0);
+        let mut cycles = 0;
+        while port.read_u8(GAMEPORT_PORT) & (1 << axis) != 0 {
+            port.run(7);
+            cycles += 7;
+            if cycles > 100_000 {
+                return None
+            }
+        }
+        Some(cycles)
+    }
+
+    #[test]
+    pub fn test_axis_timing() {
+        let mut port = GamePort::new([true, false]);
+        assert_eq!(port.read_u8(GAMEPORT_PORT), 0xF0);
+
+        let center = measure(&mut port, 0).unwrap();
+        assert!((center as f64 - 574.2 * CPU_MHZ).abs() < 8.0);
+
+        port.handle_event(JoystickEvent::Axis(0, -1.0));
+        let left = measure(&mut port, 0).unwrap();
+        port.handle_event(JoystickEvent::Axis(0, 1.0));
+        let right = measure(&mut port, 0).unwrap();
+        assert!(left < center && center < right);
+        // The position is linear in time, so the center calibrates halfway
+        assert!(((left + right) as i64 / 2 - center as i64).abs() < 8);
+
+        // Stick B isn't connected, so its axes never time out
+        assert_eq!(measure(&mut port, 2), None);
+
+        port.handle_event(JoystickEvent::Button(1, true));
+        assert_eq!(port.read_u8(GAMEPORT_PORT) & 0xF0, 0xD0);
+    }
+}
@@ -5,29 +5,31 @@
 
 */
 use std::{
-    cell::RefCell,
     collections::{HashMap, VecDeque},
     ffi::OsString,
-    rc::Rc
 };
 
 use egui::{ClippedMesh, Context, ColorImage, ImageData, TexturesDelta};
 use egui_wgpu_backend::{BackendError, RenderPass, ScreenDescriptor};
 use pixels::{wgpu, PixelsContext};
 use regex::Regex;
-use winit::{window::Window};
+use winit::{event::VirtualKeyCode, window::Window};
 
 use crate::{
 
     gui_image::{UiImage, get_ui_image},
+    cassette::{CassetteMode, CassetteStringState},
+    input::{self, HostLayout, KeyMap, KeyMapMode},
 
-    machine::{ExecutionControl, ExecutionState},
+    machine::{ExecutionOperation, ExecutionState},
     cpu::CpuStringState, 
     dma::DMAControllerStringState,
+    fdc::FloppyStringState,
     hdc::HardDiskFormat,
     pit::PitStringState, 
     pic::PicStringState,
     ppi::PpiStringState, 
+    serial::SerialStringState,
     
 };
 
@@ -42,16 +44,27 @@ pub(crate) enum GuiWindow {
     PitViewer,
     PicViewer,
     PpiViewer,
+    SerialViewer,
     DmaViewer,
     CallStack,
     VHDCreator,
+    Cassette,
+    FloppyInfo,
 }
 
 pub(crate) enum GuiEvent {
     LoadVHD(u32,OsString),
     CreateVHD(OsString, HardDiskFormat),
     LoadFloppy(usize, OsString),
-    EjectFloppy(usize)
+    EjectFloppy(usize),
+    SaveFloppy(usize),
+    SetFloppyWriteProtect(usize, bool),
+    PasteText(String),
+    LoadCassette(OsString),
+    EjectCassette,
+    RewindCassette,
+    SetCassetteMode(CassetteMode),
+    SaveCassette(OsString),
 }
 
 /// Manages all state required for rendering egui over `Pixels`.
@@ -77,6 +90,7 @@ pub(crate) struct GuiState {
     /// Only show the associated window when true.
     about_window_open: bool,
     error_dialog_open: bool,
+    paste_window_open: bool,
     cpu_control_dialog_open: bool,
     memory_viewer_open: bool,
     register_viewer_open: bool,
@@ -85,14 +99,28 @@ pub(crate) struct GuiState {
     pit_viewer_open: bool,
     pic_viewer_open: bool,
     ppi_viewer_open: bool,
+    serial_viewer_open: bool,
     dma_viewer_open: bool,
     call_stack_open: bool,
     vhd_creator_open: bool,
+    cassette_window_open: bool,
+    floppy_info_open: bool,
+    paste_text: String,
+    pasting: bool,
+
+    // Key binding editor
+    keymap_window_open: bool,
+    keymap_mode: KeyMapMode,
+    keymap_layout: HostLayout,
+    keymap_bindings: Vec<(VirtualKeyCode, String)>,
+    keymap_learning: bool,
+    keymap_update: Option<(KeyMap, bool)>,
     
     // Floppy Disk Images
     floppy_names: Vec<OsString>,
     new_floppy_name0: Option<OsString>,
     new_floppy_name1: Option<OsString>,
+    floppy_write_protect: [bool; 2],
     
     // VHD Images
     vhd_names: Vec<OsString>,
@@ -101,12 +129,17 @@ pub(crate) struct GuiState {
     new_vhd_name1: Option<OsString>,
     vhd_name1: OsString,
 
+    // Cassette images
+    cassette_names: Vec<OsString>,
+    cassette_save_name: String,
+
     vhd_formats: Vec<HardDiskFormat>,
     selected_format_idx: usize,
     new_vhd_filename: String,
     vhd_regex: Regex,
 
-    exec_control: Rc<RefCell<ExecutionControl>>,
+    exec_state: ExecutionState,
+    exec_op_queue: VecDeque<ExecutionOperation>,
     cpu_single_step: bool,
     cpu_step_flag: bool,
 
@@ -117,7 +150,10 @@ pub(crate) struct GuiState {
     pub pit_state: PitStringState,
     pub pic_state: PicStringState,
     pub ppi_state: PpiStringState,
+    pub serial_state: Vec<SerialStringState>,
     pub dma_state: DMAControllerStringState,
+    pub cassette_state: CassetteStringState,
+    pub floppy_state: Vec<FloppyStringState>,
     dma_channel_select: u32,
     dma_channel_select_str: String,
     memory_viewer_dump: String,
@@ -135,8 +171,7 @@ impl Framework {
         width: u32, 
         height: u32, 
         scale_factor: f32, 
-        pixels: &pixels::Pixels,
-        exec_control: Rc<RefCell<ExecutionControl>>) -> Self {
+        pixels: &pixels::Pixels) -> Self {
         let max_texture_size = pixels.device().limits().max_texture_dimension_2d as usize;
 
         let egui_ctx = Context::default();
@@ -148,7 +183,7 @@ impl Framework {
         };
         let rpass = RenderPass::new(pixels.device(), pixels.render_texture_format(), 1);
         let textures = TexturesDelta::default();
-        let gui = GuiState::new(exec_control);
+        let gui = GuiState::new();
 
         Self {
             egui_ctx,
@@ -235,13 +270,14 @@ impl Framework {
 
 impl GuiState {
     /// Create a struct representing the state of the GUI.
-    fn new(exec_control: Rc<RefCell<ExecutionControl>>) -> Self {
+    fn new() -> Self {
         Self { 
 
             texture: None,
             event_queue: VecDeque::new(),
             about_window_open: false, 
             error_dialog_open: false,
+            paste_window_open: false,
             cpu_control_dialog_open: true,
             memory_viewer_open: false,
             register_viewer_open: true,
@@ -250,13 +286,26 @@ impl GuiState {
             pit_viewer_open: false,
             pic_viewer_open: false,
             ppi_viewer_open: false,
+            serial_viewer_open: false,
             dma_viewer_open: false,
             call_stack_open: false,
             vhd_creator_open: false,
+            cassette_window_open: false,
+            floppy_info_open: false,
+            paste_text: String::new(),
+            pasting: false,
+
+            keymap_window_open: false,
+            keymap_mode: KeyMapMode::Symbolic,
+            keymap_layout: HostLayout::Us,
+            keymap_bindings: Vec::new(),
+            keymap_learning: false,
+            keymap_update: None,
             
             floppy_names: Vec::new(),
             new_floppy_name0: Option::None,
             new_floppy_name1: Option::None,
+            floppy_write_protect: [false; 2],
 
             vhd_names: Vec::new(),
             new_vhd_name0: Option::None,
@@ -264,12 +313,16 @@ impl GuiState {
             new_vhd_name1: Option::None,
             vhd_name1: OsString::new(),
 
+            cassette_names: Vec::new(),
+            cassette_save_name: "tape.mct".to_string(),
+
             vhd_formats: Vec::new(),
             selected_format_idx: 0,
             new_vhd_filename: String::new(),
             vhd_regex: Regex::new(VHD_REGEX).unwrap(),
 
-            exec_control: exec_control,
+            exec_state: ExecutionState::Paused,
+            exec_op_queue: VecDeque::new(),
             cpu_single_step: true,
             cpu_step_flag: false,
 
@@ -281,7 +334,10 @@ impl GuiState {
             pit_state: Default::default(),
             pic_state: Default::default(),
             ppi_state: Default::default(),
+            serial_state: Vec::new(),
             dma_state: Default::default(),
+            cassette_state: Default::default(),
+            floppy_state: Vec::new(),
             dma_channel_select: 0,
             dma_channel_select_str: String::new(),
             disassembly_viewer_string: String::new(),
@@ -302,6 +358,63 @@ impl GuiState {
         self.event_queue.push_back(event);
     }
 
+    /// Retrieve the next execution control operation requested from the CPU Control window.
+    pub fn get_exec_op(&mut self) -> Option<ExecutionOperation> {
+        self.exec_op_queue.pop_front()
+    }
+
+    pub fn update_exec_state(&mut self, state: ExecutionState) {
+        self.exec_state = state;
+    }
+
+    pub fn update_pasting(&mut self, pasting: bool) {
+        self.pasting = pasting;
+    }
+
+    /// Load a keymap into the key binding editor.
+    pub fn set_keymap(&mut self, keymap: &KeyMap) {
+        self.keymap_mode = keymap.mode;
+        self.keymap_layout = keymap.layout;
+        self.keymap_bindings = keymap.bindings()
+            .into_iter()
+            .map(|(key, code)| (key, format!("{:02X}", code)))
+            .collect();
+    }
+
+    /// Build a keymap from the editor. Returns an error naming the first invalid binding.
+    fn build_keymap(&self) -> Result<KeyMap, String> {
+        let mut keymap = KeyMap::from_layout(self.keymap_layout);
+        keymap.mode = self.keymap_mode;
+        for (key, _) in keymap.bindings() {
+            keymap.remove_binding(key);
+        }
+        for (key, code_str) in &self.keymap_bindings {
+            let code = u8::from_str_radix(code_str.trim(), 16)
+                .map_err(|_| format!("Invalid scancode '{}' for key {}", code_str, input::key_name(*key)))?;
+            keymap.set_binding(*key, code);
+        }
+        Ok(keymap)
+    }
+
+    /// Retrieve a keymap applied in the key binding editor, and whether it should be saved.
+    pub fn get_keymap_update(&mut self) -> Option<(KeyMap, bool)> {
+        self.keymap_update.take()
+    }
+
+    pub fn is_learning_key(&self) -> bool {
+        self.keymap_learning
+    }
+
+    /// Receive a host key pressed while learning. The key is added to the bindings if it
+    /// isn't already bound, using the XT key in the same position if known.
+    pub fn learn_key(&mut self, key: VirtualKeyCode, positional_code: Option<u8>) {
+        self.keymap_learning = false;
+        if !self.keymap_bindings.iter().any(|(k, _)| *k == key) {
+            let code = positional_code.map_or(String::new(), |code| format!("{:02X}", code));
+            self.keymap_bindings.push((key, code));
+        }
+    }
+
     pub fn get_cpu_single_step(&self) -> bool {
         self.cpu_single_step
     }
@@ -326,9 +439,12 @@ impl GuiState {
             GuiWindow::PitViewer => self.pic_viewer_open,
             GuiWindow::PicViewer => self.pic_viewer_open,
             GuiWindow::PpiViewer => self.ppi_viewer_open,
+            GuiWindow::SerialViewer => self.serial_viewer_open,
             GuiWindow::DmaViewer => self.dma_viewer_open,
             GuiWindow::CallStack => self.call_stack_open,
             GuiWindow::VHDCreator => self.vhd_creator_open,
+            GuiWindow::Cassette => self.cassette_window_open,
+            GuiWindow::FloppyInfo => self.floppy_info_open,
         }
     }
 
@@ -345,6 +461,10 @@ impl GuiState {
         self.vhd_names = names;
     }
 
+    pub fn set_cassette_names(&mut self, names: Vec<OsString>) {
+        self.cassette_names = names;
+    }
+
     /// Retrieve a newly selected floppy image name.
     /// 
     /// If a floppy image was selected from the UI then we return it as an Option.
@@ -429,6 +549,18 @@ impl GuiState {
         self.ppi_state = state;
     }
 
+    pub fn update_serial_state(&mut self, state: Vec<SerialStringState>) {
+        self.serial_state = state;
+    }
+
+    pub fn update_cassette_state(&mut self, state: CassetteStringState) {
+        self.cassette_state = state;
+    }
+
+    pub fn update_floppy_state(&mut self, state: Vec<FloppyStringState>) {
+        self.floppy_state = state;
+    }
+
     pub fn update_dma_state(&mut self, state: DMAControllerStringState) {
         self.dma_state = state;
     }
@@ -449,6 +581,14 @@ impl GuiState {
                         self.about_window_open = true;
                         ui.close_menu();
                     }
+                    if ui.button("Paste Text...").clicked() {
+                        self.paste_window_open = true;
+                        ui.close_menu();
+                    }
+                    if ui.button("Keyboard Mapping...").clicked() {
+                        self.keymap_window_open = true;
+                        ui.close_menu();
+                    }
                 });
                 ui.menu_button("Media", |ui| {
                     ui.style_mut().spacing.item_spacing = egui::Vec2{ x: 6.0, y:6.0 };
@@ -489,6 +629,21 @@ impl GuiState {
                         ui.close_menu();
                     };                              
 
+                    for (drive, letter) in ['A', 'B'].into_iter().enumerate() {
+                        if ui.button(format!("Save Floppy in Drive {}:", letter)).clicked() {
+                            self.event_queue.push_back(GuiEvent::SaveFloppy(drive));
+                            ui.close_menu();
+                        };
+                        if ui.checkbox(&mut self.floppy_write_protect[drive], format!("Write Protect Drive {}:", letter)).clicked() {
+                            self.event_queue.push_back(GuiEvent::SetFloppyWriteProtect(drive, self.floppy_write_protect[drive]));
+                        };
+                    }
+
+                    if ui.button("Floppy Info...").clicked() {
+                        self.floppy_info_open = true;
+                        ui.close_menu();
+                    };
+
                     ui.menu_button("Load VHD in Drive 0:...", |ui| {
                         for name in &self.vhd_names {
 
@@ -505,6 +660,11 @@ impl GuiState {
                         self.vhd_creator_open = true;
                         ui.close_menu();
                     };
+
+                    if ui.button("Cassette...").clicked() {
+                        self.cassette_window_open = true;
+                        ui.close_menu();
+                    };
                     
                 });
                 ui.menu_button("Debug", |ui| {
@@ -544,6 +704,10 @@ impl GuiState {
                         self.ppi_viewer_open = true;
                         ui.close_menu();
                     }
+                    if ui.button("Serial Ports...").clicked() {
+                        self.serial_viewer_open = true;
+                        ui.close_menu();
+                    }
                     if ui.button("DMA...").clicked() {
                         self.dma_viewer_open = true;
                         ui.close_menu();
@@ -593,27 +757,204 @@ impl GuiState {
                 });
             });
 
+        egui::Window::new("Paste Text")
+            .open(&mut self.paste_window_open)
+            .resizable(true)
+            .show(ctx, |ui| {
+                ui.label("Text entered here is typed into the emulated keyboard.");
+                ui.add(egui::TextEdit::multiline(&mut self.paste_text)
+                    .font(egui::TextStyle::Monospace)
+                    .desired_rows(10));
+                ui.horizontal(|ui| {
+                    if ui.button("Paste").clicked() && !self.paste_text.is_empty() {
+                        self.event_queue.push_back(GuiEvent::PasteText(self.paste_text.clone()));
+                    }
+                    if ui.button("Clear").clicked() {
+                        self.paste_text.clear();
+                    }
+                    if self.pasting {
+                        ui.label("Typing...");
+                    }
+                });
+            });
+
+        egui::Window::new("Floppy Info")
+            .open(&mut self.floppy_info_open)
+            .show(ctx, |ui| {
+                for state in &self.floppy_state {
+                    egui::Grid::new(format!("floppy_state_{}", state.drive))
+                        .striped(true)
+                        .show(ui, |ui| {
+                            ui.label("Drive:");
+                            ui.label(&state.drive);
+                            ui.end_row();
+                            ui.label("Format:");
+                            ui.label(&state.format);
+                            ui.end_row();
+                            ui.label("Geometry:");
+                            ui.label(&state.geometry);
+                            ui.end_row();
+                            ui.label("Created:");
+                            ui.label(&state.created);
+                            ui.end_row();
+                            ui.label("Comment:");
+                            ui.label(&state.comment);
+                            ui.end_row();
+                        });
+                    ui.separator();
+                }
+            });
+
+        egui::Window::new("Cassette")
+            .open(&mut self.cassette_window_open)
+            .show(ctx, |ui| {
+                let state = &self.cassette_state;
+                egui::Grid::new("cassette_state")
+                    .striped(true)
+                    .show(ui, |ui| {
+                        ui.label("Tape:");
+                        ui.label(&state.tape_name);
+                        ui.end_row();
+                        ui.label("Mode:");
+                        ui.label(&state.mode);
+                        ui.end_row();
+                        ui.label("Motor:");
+                        ui.label(&state.motor);
+                        ui.end_row();
+                        ui.label("Position:");
+                        ui.label(format!("{} / {}", state.position, state.length));
+                        ui.end_row();
+                    });
+                ui.separator();
+
+                let event_queue = &mut self.event_queue;
+                ui.menu_button("Load tape...", |ui| {
+                    for name in &self.cassette_names {
+                        if ui.button(name.to_string_lossy().to_string()).clicked() {
+                            event_queue.push_back(GuiEvent::LoadCassette(name.clone()));
+                            ui.close_menu();
+                        }
+                    }
+                });
+                ui.horizontal(|ui| {
+                    if ui.button("Play").clicked() {
+                        event_queue.push_back(GuiEvent::SetCassetteMode(CassetteMode::Play));
+                    }
+                    if ui.button("Record").clicked() {
+                        event_queue.push_back(GuiEvent::SetCassetteMode(CassetteMode::Record));
+                    }
+                    if ui.button("Rewind").clicked() {
+                        event_queue.push_back(GuiEvent::RewindCassette);
+                    }
+                    if ui.button("Eject").clicked() {
+                        event_queue.push_back(GuiEvent::EjectCassette);
+                    }
+                });
+                ui.separator();
+
+                ui.label("Save as .wav or .mct in the cassette directory:");
+                ui.horizontal(|ui| {
+                    ui.text_edit_singleline(&mut self.cassette_save_name);
+                    if ui.button("Save").clicked() && !self.cassette_save_name.is_empty() {
+                        event_queue.push_back(GuiEvent::SaveCassette(OsString::from(&self.cassette_save_name)));
+                    }
+                });
+            });
+
+        let mut keymap_window_open = self.keymap_window_open;
+        egui::Window::new("Keyboard Mapping")
+            .open(&mut keymap_window_open)
+            .resizable(true)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Mode:");
+                    ui.radio_value(&mut self.keymap_mode, KeyMapMode::Symbolic, "Symbolic");
+                    ui.radio_value(&mut self.keymap_mode, KeyMapMode::Positional, "Positional");
+                });
+                ui.label("Symbolic mode maps keys by the character on them. Positional mode maps keys by their position on the keyboard.");
+
+                ui.horizontal(|ui| {
+                    egui::ComboBox::from_label("Host layout")
+                        .selected_text(self.keymap_layout.name())
+                        .show_ui(ui, |ui| {
+                            for layout in HostLayout::ALL {
+                                ui.selectable_value(&mut self.keymap_layout, layout, layout.name());
+                            }
+                        });
+                    if ui.button("Load preset").clicked() {
+                        let mode = self.keymap_mode;
+                        self.set_keymap(&KeyMap::from_layout(self.keymap_layout));
+                        self.keymap_mode = mode;
+                    }
+                });
+                ui.separator();
+
+                let mut remove = None;
+                egui::ScrollArea::vertical().max_height(300.0).show(ui, |ui| {
+                    egui::Grid::new("keymap_bindings")
+                        .striped(true)
+                        .show(ui, |ui| {
+                            ui.label("Host key");
+                            ui.label("XT scancode");
+                            ui.end_row();
+                            for (i, (key, code_str)) in self.keymap_bindings.iter_mut().enumerate() {
+                                ui.label(input::key_name(*key));
+                                ui.add(egui::TextEdit::singleline(code_str).desired_width(30.0));
+                                if ui.button("Remove").clicked() {
+                                    remove = Some(i);
+                                }
+                                ui.end_row();
+                            }
+                        });
+                });
+                if let Some(i) = remove {
+                    self.keymap_bindings.remove(i);
+                }
+                ui.separator();
+
+                ui.horizontal(|ui| {
+                    if self.keymap_learning {
+                        ui.label("Press a key...");
+                    }
+                    else if ui.button("Add key").clicked() {
+                        self.keymap_learning = true;
+                    }
+                    let apply = ui.button("Apply").clicked();
+                    let save = ui.button("Save").clicked();
+                    if apply || save {
+                        match self.build_keymap() {
+                            Ok(keymap) => self.keymap_update = Some((keymap, save)),
+                            Err(e) => self.show_error(&e)
+                        }
+                    }
+                });
+            });
+        self.keymap_window_open = keymap_window_open;
+        if !self.keymap_window_open {
+            self.keymap_learning = false;
+        }
+
         egui::Window::new("CPU Control")
             .open(&mut self.cpu_control_dialog_open)
             .show(ctx, |ui| {
 
-                let mut exec_control = self.exec_control.borrow_mut();
+                let exec_op_queue = &mut self.exec_op_queue;
                 ui.horizontal(|ui|{
                     if ui.button(egui::RichText::new("⏸").font(egui::FontId::proportional(20.0))).clicked() {
-                        exec_control.set_state(ExecutionState::Paused);
+                        exec_op_queue.push_back(ExecutionOperation::Pause);
                     };
                     if ui.button(egui::RichText::new("⏭").font(egui::FontId::proportional(20.0))).clicked() {
-                        exec_control.do_step();
+                        exec_op_queue.push_back(ExecutionOperation::Step);
                     };
                     if ui.button(egui::RichText::new("▶").font(egui::FontId::proportional(20.0))).clicked() {
-                        exec_control.set_state(ExecutionState::Running);
+                        exec_op_queue.push_back(ExecutionOperation::Run);
                     };
                     if ui.button(egui::RichText::new("R").font(egui::FontId::proportional(20.0))).clicked() {
-                        exec_control.do_reset();
+                        exec_op_queue.push_back(ExecutionOperation::Reset);
                     };
                 });
 
-                let state_str = format!("{:?}", exec_control.get_state());
+                let state_str = format!("{:?}", self.exec_state);
                 ui.separator();
                 ui.horizontal(|ui|{
                     ui.label("Run state: ");
@@ -887,6 +1228,14 @@ impl GuiState {
                     ui.label(egui::RichText::new("#0 Reload Val:  ").text_style(egui::TextStyle::Monospace));
                     ui.add(egui::TextEdit::singleline(&mut self.pit_state.c0_reload_value).font(egui::TextStyle::Monospace));
                     ui.end_row();
+
+                    ui.label(egui::RichText::new("#0 Output:      ").text_style(egui::TextStyle::Monospace));
+                    ui.add(egui::TextEdit::singleline(&mut self.pit_state.c0_output).font(egui::TextStyle::Monospace));
+                    ui.end_row();
+
+                    ui.label(egui::RichText::new("#0 Null Count:  ").text_style(egui::TextStyle::Monospace));
+                    ui.add(egui::TextEdit::singleline(&mut self.pit_state.c0_null_count).font(egui::TextStyle::Monospace));
+                    ui.end_row();
                     
                     ui.label(egui::RichText::new("#1 Access Mode: ").text_style(egui::TextStyle::Monospace));
                     ui.add(egui::TextEdit::singleline(&mut self.pit_state.c1_access_mode).font(egui::TextStyle::Monospace));
@@ -902,6 +1251,14 @@ impl GuiState {
 
                     ui.label(egui::RichText::new("#1 Reload Val:  ").text_style(egui::TextStyle::Monospace));
                     ui.add(egui::TextEdit::singleline(&mut self.pit_state.c1_reload_value).font(egui::TextStyle::Monospace));
+                    ui.end_row();
+
+                    ui.label(egui::RichText::new("#1 Output:      ").text_style(egui::TextStyle::Monospace));
+                    ui.add(egui::TextEdit::singleline(&mut self.pit_state.c1_output).font(egui::TextStyle::Monospace));
+                    ui.end_row();
+
+                    ui.label(egui::RichText::new("#1 Null Count:  ").text_style(egui::TextStyle::Monospace));
+                    ui.add(egui::TextEdit::singleline(&mut self.pit_state.c1_null_count).font(egui::TextStyle::Monospace));
                     ui.end_row();  
                     
                     ui.label(egui::RichText::new("#2 Access Mode: ").text_style(egui::TextStyle::Monospace));
@@ -918,6 +1275,14 @@ impl GuiState {
 
                     ui.label(egui::RichText::new("#2 Reload Val:  ").text_style(egui::TextStyle::Monospace));
                     ui.add(egui::TextEdit::singleline(&mut self.pit_state.c2_reload_value).font(egui::TextStyle::Monospace));
+                    ui.end_row();
+
+                    ui.label(egui::RichText::new("#2 Output:      ").text_style(egui::TextStyle::Monospace));
+                    ui.add(egui::TextEdit::singleline(&mut self.pit_state.c2_output).font(egui::TextStyle::Monospace));
+                    ui.end_row();
+
+                    ui.label(egui::RichText::new("#2 Null Count:  ").text_style(egui::TextStyle::Monospace));
+                    ui.add(egui::TextEdit::singleline(&mut self.pit_state.c2_null_count).font(egui::TextStyle::Monospace));
                     ui.end_row();  
 
                     ui.label(egui::RichText::new("#2 Gate Status: ").text_style(egui::TextStyle::Monospace));
@@ -951,6 +1316,26 @@ impl GuiState {
                         ui.add(egui::TextEdit::singleline(&mut self.pic_state.isr).font(egui::TextStyle::Monospace));
                     });
                     ui.end_row();
+                    ui.horizontal(|ui| {
+                        ui.label(egui::RichText::new("IR Lines:     ").text_style(egui::TextStyle::Monospace));
+                        ui.add(egui::TextEdit::singleline(&mut self.pic_state.ir_lines).font(egui::TextStyle::Monospace));
+                    });
+                    ui.end_row();
+                    ui.horizontal(|ui| {
+                        ui.label(egui::RichText::new("Lowest Prio:  ").text_style(egui::TextStyle::Monospace));
+                        ui.add(egui::TextEdit::singleline(&mut self.pic_state.lowest_priority).font(egui::TextStyle::Monospace));
+                    });
+                    ui.end_row();
+                    ui.horizontal(|ui| {
+                        ui.label(egui::RichText::new("Trigger Mode: ").text_style(egui::TextStyle::Monospace));
+                        ui.add(egui::TextEdit::singleline(&mut self.pic_state.trigger_mode).font(egui::TextStyle::Monospace));
+                    });
+                    ui.end_row();
+                    ui.horizontal(|ui| {
+                        ui.label(egui::RichText::new("Spurious IRQ: ").text_style(egui::TextStyle::Monospace));
+                        ui.add(egui::TextEdit::singleline(&mut self.pic_state.spurious_count).font(egui::TextStyle::Monospace));
+                    });
+                    ui.end_row();
 
                     for i in 0..self.pic_state.interrupt_stats.len() {
                         ui.horizontal(|ui| {
@@ -1007,6 +1392,10 @@ impl GuiState {
                     ui.add(egui::TextEdit::singleline(&mut self.ppi_state.kb_resets_counter).font(egui::TextStyle::Monospace));
                     ui.end_row();
 
+                    ui.label(egui::RichText::new("Keyboard buffer:").text_style(egui::TextStyle::Monospace));
+                    ui.add(egui::TextEdit::singleline(&mut self.ppi_state.kb_buffer_len).font(egui::TextStyle::Monospace));
+                    ui.end_row();
+
                     ui.label(egui::RichText::new("Port C Mode:  ").text_style(egui::TextStyle::Monospace));
                     ui.add(egui::TextEdit::singleline(&mut self.ppi_state.port_c_mode).font(egui::TextStyle::Monospace));
                     ui.end_row();
@@ -1017,6 +1406,50 @@ impl GuiState {
                 });
             });
 
+            egui::Window::new("Serial Port View")
+            .open(&mut self.serial_viewer_open)
+            .resizable(true)
+            .show(ctx, |ui| {
+                for port in &mut self.serial_state {
+                    ui.label(egui::RichText::new(&port.port).text_style(egui::TextStyle::Monospace));
+                    egui::Grid::new(format!("serial_view_{}", port.port))
+                        .num_columns(2)
+                        .striped(true)
+                        .spacing([40.0, 4.0])
+                        .show(ui, |ui| {
+
+                        ui.label(egui::RichText::new("Attached to:    ").text_style(egui::TextStyle::Monospace));
+                        ui.add(egui::TextEdit::singleline(&mut port.attachment).font(egui::TextStyle::Monospace));
+                        ui.end_row();
+
+                        ui.label(egui::RichText::new("Baud rate:      ").text_style(egui::TextStyle::Monospace));
+                        ui.add(egui::TextEdit::singleline(&mut port.baud).font(egui::TextStyle::Monospace));
+                        ui.end_row();
+
+                        ui.label(egui::RichText::new("Line control:   ").text_style(egui::TextStyle::Monospace));
+                        ui.add(egui::TextEdit::singleline(&mut port.line_control).font(egui::TextStyle::Monospace));
+                        ui.end_row();
+
+                        ui.label(egui::RichText::new("Line status:    ").text_style(egui::TextStyle::Monospace));
+                        ui.add(egui::TextEdit::singleline(&mut port.line_status).font(egui::TextStyle::Monospace));
+                        ui.end_row();
+
+                        ui.label(egui::RichText::new("Modem control:  ").text_style(egui::TextStyle::Monospace));
+                        ui.add(egui::TextEdit::singleline(&mut port.modem_control).font(egui::TextStyle::Monospace));
+                        ui.end_row();
+
+                        ui.label(egui::RichText::new("Modem status:   ").text_style(egui::TextStyle::Monospace));
+                        ui.add(egui::TextEdit::singleline(&mut port.modem_status).font(egui::TextStyle::Monospace));
+                        ui.end_row();
+
+                        ui.label(egui::RichText::new("Int. enable:    ").text_style(egui::TextStyle::Monospace));
+                        ui.add(egui::TextEdit::singleline(&mut port.interrupt_enable).font(egui::TextStyle::Monospace));
+                        ui.end_row();
+                    });
+                    ui.separator();
+                }
+            });
+
             egui::Window::new("DMA View")
             .open(&mut self.dma_viewer_open)
             .resizable(false)
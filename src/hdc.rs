@@ -7,15 +7,13 @@
 */
 
 use std::{
-    cell::RefCell,
     collections::VecDeque,
     error::Error,
-    rc::Rc,
+    sync::{Arc, Mutex},
 };
 
 use core::fmt::Display;
 
-use crate::bus::BusInterface;
 use crate::dma;
 //use crate::fdc::Operation;
 use crate::io::IoDevice;
@@ -226,6 +224,7 @@ pub struct OperationStatus {
     block_n: u8,
     dma_bytes_left: usize,
     dma_byte_count: usize,
+    terminal_count: bool,
 }
 
 pub enum Continuation {
@@ -247,7 +246,7 @@ pub struct DeviceControlBlock {
 
 pub struct HardDiskController {
 
-    dma: Rc<RefCell<dma::DMAController>>,
+    dma: Arc<Mutex<dma::DMAController>>,
     drives: [HardDisk; 2],
     drive_select: usize,
 
@@ -281,7 +280,7 @@ pub struct HardDiskController {
 }
 
 impl HardDiskController {
-    pub fn new(dma: Rc<RefCell<dma::DMAController>>, drive_type_dip: u8) -> Self {
+    pub fn new(dma: Arc<Mutex<dma::DMAController>>, drive_type_dip: u8) -> Self {
         Self {
 
             dma,
@@ -790,7 +789,7 @@ impl HardDiskController {
         let dcb = self.read_dcb();
         self.data_register_in.clear();
 
-        let xfer_size = self.dma.borrow().get_dma_transfer_size(HDC_DMA);
+        let xfer_size = self.dma.lock().unwrap().get_dma_transfer_size(HDC_DMA);
         log::trace!("Command Read: drive: {} c: {} h: {} s: {}, xfer_size:{}", 
             dcb.drive_select, 
             dcb.c, 
@@ -830,6 +829,7 @@ impl HardDiskController {
             self.operation_status.block_n = 0;
             self.operation_status.dma_bytes_left = xfer_size;
             self.operation_status.dma_byte_count = 0;
+            self.operation_status.terminal_count = false;
 
             self.state = State::ExecutingCommand;
             self.send_dreq = true;
@@ -853,7 +853,7 @@ impl HardDiskController {
         let dcb = self.read_dcb();
         self.data_register_in.clear();
 
-        let xfer_size = self.dma.borrow().get_dma_transfer_size(HDC_DMA);
+        let xfer_size = self.dma.lock().unwrap().get_dma_transfer_size(HDC_DMA);
         log::trace!("Command Write: drive: {} c: {} h: {} s: {} bc: {}, xfer_size:{}", 
             dcb.drive_select, 
             dcb.c, 
@@ -882,6 +882,7 @@ impl HardDiskController {
 
             self.operation_status.dma_bytes_left = xfer_size;
             self.operation_status.dma_byte_count = 0;
+            self.operation_status.terminal_count = false;
 
             self.state = State::ExecutingCommand;
             self.send_dreq = true;
@@ -961,12 +962,14 @@ impl HardDiskController {
     fn command_read_sector_buffer(&mut self) -> Continuation {
         // Don't care about DBC bytes
 
-        let xfer_size = self.dma.borrow().get_dma_transfer_size(HDC_DMA);
+        let xfer_size = self.dma.lock().unwrap().get_dma_transfer_size(HDC_DMA);
         if xfer_size != SECTOR_SIZE {
             log::warn!("Command ReadSectorBuffer: DMA word count != sector size");
         }
         self.operation_status.dma_bytes_left = xfer_size;
+        self.operation_status.buffer_idx = 0;
         self.operation_status.dma_byte_count = 0;
+        self.operation_status.terminal_count = false;
 
         log::trace!("Command ReadSectorBuffer: DMA xfer size: {}", xfer_size);
 
@@ -982,12 +985,14 @@ impl HardDiskController {
     fn command_write_sector_buffer(&mut self) -> Continuation {
         // Don't care about DBC bytes
 
-        let xfer_size = self.dma.borrow().get_dma_transfer_size(HDC_DMA);
+        let xfer_size = self.dma.lock().unwrap().get_dma_transfer_size(HDC_DMA);
         if xfer_size != SECTOR_SIZE {
             log::warn!("Command WriteSectorBuffer: DMA word count != sector size");
         }
         self.operation_status.dma_bytes_left = xfer_size;
+        self.operation_status.buffer_idx = 0;
         self.operation_status.dma_byte_count = 0;
+        self.operation_status.terminal_count = false;
 
         log::trace!("Command WriteSectorBuffer: DMA xfer size: {}", xfer_size);
 
@@ -1118,6 +1123,7 @@ impl HardDiskController {
         self.clear_dreq = true;
         self.operation_status.dma_byte_count = 0;
         self.operation_status.dma_bytes_left = 0;
+        self.operation_status.terminal_count = false;
 
         self.error_flag = error;
         self.send_interrupt = true;
@@ -1125,199 +1131,104 @@ impl HardDiskController {
         self.state = State::HaveCommandStatus;
     }
 
-    /// Process the Write Sector Buffer operation.
-    /// This operation continues until the DMA transfer is complete.
-    fn opearation_write_sector_buffer(&mut self, dma: &mut dma::DMAController, bus: &mut BusInterface) {
-        if self.dreq_active && dma.read_dma_acknowledge(HDC_DMA) {
-
-            if self.operation_status.dma_bytes_left > 0 {
-                // Bytes left to transfer
-                let _byte = dma.do_dma_read_u8(bus, HDC_DMA);
-                self.operation_status.dma_byte_count += 1;
-                self.operation_status.dma_bytes_left -= 1;
-                
-                // See if we are done based on DMA controller
-                let tc = dma.check_terminal_count(HDC_DMA);
-                if tc {
-                    log::trace!("DMA terminal count triggered end of WriteSectorBuffer command.");
-                    if self.operation_status.dma_bytes_left != 0 {
-                        log::warn!("Incomplete DMA transfer on terminal count!")
-                    }
+    /// Check whether a DMA operation has completed, either by the DMA controller reaching
+    /// terminal count, or by transferring all the bytes we expected. 
+    fn check_dma_operation_end(&mut self, name: &str) {
+        if !self.dreq_active {
+            log::error!("Error: {} command without DMA active!", name);
+            return
+        }
 
-                    log::trace!("Completed WriteSectorBuffer command.");
-                    self.end_dma_command(0, false);
-                }
+        if self.operation_status.terminal_count {
+            log::trace!("DMA terminal count triggered end of {} command.", name);
+            if self.operation_status.dma_bytes_left != 0 {
+                log::warn!("Incomplete DMA transfer on terminal count!")
             }
-            else {
-                // No more bytes left to transfer. Finalize operation
-                let tc = dma.check_terminal_count(HDC_DMA);
-                if !tc {
-                    log::warn!("WriteSectorBuffer complete without DMA terminal count.");
-                }
 
-                log::trace!("Completed WriteSectorBuffer command.");
-                self.end_dma_command(0, false);                                
-            }
+            log::trace!("Completed {} command.", name);
+            self.end_dma_command(0, false);
+        }
+        else if self.operation_status.dma_bytes_left == 0 {
+            log::warn!("{} complete without DMA terminal count.", name);
+
+            log::trace!("Completed {} command.", name);
+            self.end_dma_command(0, false);
         }
-        else if !self.dreq_active {
-            log::error!("Error: WriteSectorBuffer command without DMA active!")
-        }   
     }
 
-    /// Process the Read Sector operation. 
-    /// This operation continues until the DMA transfer is complete.
-    fn operation_read_sector(&mut self, dma: &mut dma::DMAController, bus: &mut BusInterface) {
-        if self.dreq_active && dma.read_dma_acknowledge(HDC_DMA) {
+    /// Advance to the next sector and fill the sector buffer from disk.
+    fn read_next_sector(&mut self) {
 
-            if self.operation_status.dma_bytes_left > 0 {
-                // Bytes left to transfer
+        log::trace!("Command Read: Advancing to next sector...");
+        let(new_c, new_h, new_s) = self.drives[self.drive_select].get_next_sector(
+            self.drives[self.drive_select].cylinder,
+            self.drives[self.drive_select].head,
+            self.drives[self.drive_select].sector);
 
-                let byte = self.drives[self.drive_select].sector_buf[self.operation_status.buffer_idx];
-                dma.do_dma_write_u8(bus, HDC_DMA,byte);
-                self.operation_status.buffer_idx += 1;
-                self.operation_status.dma_byte_count += 1;
-                self.operation_status.dma_bytes_left -= 1;
+        self.drives[self.drive_select].cylinder = new_c;
+        self.drives[self.drive_select].head = new_h;
+        self.drives[self.drive_select].sector = new_s;
+        self.operation_status.buffer_idx = 0;
 
-                // Exhausted the sector buffer, read more from disk
-                if self.operation_status.buffer_idx == SECTOR_SIZE {
+        match &mut self.drives[self.drive_select].vhd {
+            Some(vhd) => {
+                match vhd.read_sector(&mut self.drives[self.drive_select].sector_buf,
+                    self.drives[self.drive_select].cylinder,
+                    self.drives[self.drive_select].head,
+                    self.drives[self.drive_select].sector) {
 
-                    // Advance to next sector
-                    log::trace!("Command Read: Advancing to next sector...");
-                    let(new_c, new_h, new_s) = self.drives[self.drive_select].get_next_sector(
-                        self.drives[self.drive_select].cylinder,
-                        self.drives[self.drive_select].head,
-                        self.drives[self.drive_select].sector);
-
-                    self.drives[self.drive_select].cylinder = new_c;
-                    self.drives[self.drive_select].head = new_h;
-                    self.drives[self.drive_select].sector = new_s;
-                    self.operation_status.buffer_idx = 0;
-
-                    match &mut self.drives[self.drive_select].vhd {
-                        Some(vhd) => {
-                            match vhd.read_sector(&mut self.drives[self.drive_select].sector_buf,
-                                self.drives[self.drive_select].cylinder,
-                                self.drives[self.drive_select].head,
-                                self.drives[self.drive_select].sector) {
-
-                                    Ok(_) => {
-                                        // Sector read successful
-                                    }
-                                    Err(err) => {
-                                        log::error!("Sector read failed: {}", err);
-                                    }
-                                };
+                        Ok(_) => {
+                            // Sector read successful
                         }
-                        None => {
-                            log::error!("Read operation without VHD mounted.");
+                        Err(err) => {
+                            log::error!("Sector read failed: {}", err);
                         }
-                    }
-                }
-
-                // See if we are done based on DMA controller
-                let tc = dma.check_terminal_count(HDC_DMA);
-                if tc {
-                    log::trace!("DMA terminal count triggered end of Read command.");
-                    if self.operation_status.dma_bytes_left != 0 {
-                        log::warn!("Incomplete DMA transfer on terminal count!")
-                    }
-
-                    log::trace!("Completed Read Command");
-                    self.end_dma_command(0, false);
-                }
+                    };
             }
-            else {
-                // No more bytes left to transfer. Finalize operation
-                let tc = dma.check_terminal_count(HDC_DMA);
-                if !tc {
-                    log::warn!("Command Read complete without DMA terminal count.");
-                }
-
-                log::trace!("Completed Read Command");
-                self.end_dma_command(0, false);                                
+            None => {
+                log::error!("Read operation without VHD mounted.");
             }
         }
-        else if !self.dreq_active {
-            log::error!("Error: Read command without DMA active!")
-        }   
     }
 
-    fn operation_write_sector(&mut self, dma: &mut dma::DMAController, bus: &mut BusInterface) {
-        if self.dreq_active && dma.read_dma_acknowledge(HDC_DMA) {
+    /// Write the sector buffer to disk and advance to the next sector.
+    fn write_next_sector(&mut self) {
 
-            if self.operation_status.dma_bytes_left > 0 {
-                // Bytes left to transfer
-
-                let byte = dma.do_dma_read_u8(bus, HDC_DMA);
-                self.drives[self.drive_select].sector_buf[self.operation_status.buffer_idx] = byte;
-                self.operation_status.buffer_idx += 1;
-                self.operation_status.dma_byte_count += 1;
-                self.operation_status.dma_bytes_left -= 1;
+        match &mut self.drives[self.drive_select].vhd {
+            Some(vhd) => {
+                match vhd.write_sector(&self.drives[self.drive_select].sector_buf,
+                    self.drives[self.drive_select].cylinder,
+                    self.drives[self.drive_select].head,
+                    self.drives[self.drive_select].sector) {
 
-                // Filled the sector buffer, write it to disk
-                if self.operation_status.buffer_idx == SECTOR_SIZE {
-                    
-                    match &mut self.drives[self.drive_select].vhd {
-                        Some(vhd) => {
-                            match vhd.write_sector(&self.drives[self.drive_select].sector_buf,
-                                self.drives[self.drive_select].cylinder,
-                                self.drives[self.drive_select].head,
-                                self.drives[self.drive_select].sector) {
-
-                                    Ok(_) => {
-                                        // Sector write successful
-                                    }
-                                    Err(err) => {
-                                        log::error!("Sector write failed: {}", err);
-                                    }
-                                };
+                        Ok(_) => {
+                            // Sector write successful
                         }
-                        None => {
-                            log::error!("Write operation without VHD mounted.");
+                        Err(err) => {
+                            log::error!("Sector write failed: {}", err);
                         }
-                    }
-
-                    // Advance to next sector
-                    log::trace!("Command Write: Advancing to next sector...");
-                    let(new_c, new_h, new_s) = self.drives[self.drive_select].get_next_sector(
-                        self.drives[self.drive_select].cylinder,
-                        self.drives[self.drive_select].head,
-                        self.drives[self.drive_select].sector);
-
-                    self.drives[self.drive_select].cylinder = new_c;
-                    self.drives[self.drive_select].head = new_h;
-                    self.drives[self.drive_select].sector = new_s;
-                    self.operation_status.buffer_idx = 0;
-                }
-
-                // See if we are done based on DMA controller
-                let tc = dma.check_terminal_count(HDC_DMA);
-                if tc {
-                    log::trace!("DMA terminal count triggered end of Write command.");
-                    if self.operation_status.dma_bytes_left != 0 {
-                        log::warn!("Incomplete DMA transfer on terminal count!")
-                    }
-
-                    self.end_dma_command(0, false);
-                }
+                    };
             }
-            else {
-                // No more bytes left to transfer. Finalize operation
-                let tc = dma.check_terminal_count(HDC_DMA);
-                if !tc {
-                    log::warn!("Command Write complete without DMA terminal count.");
-                }
-
-                self.end_dma_command(0, false);                                
+            None => {
+                log::error!("Write operation without VHD mounted.");
             }
         }
-        else if !self.dreq_active {
-            log::error!("Error: Read command without DMA active!")
-        }                       
+
+        // Advance to next sector
+        log::trace!("Command Write: Advancing to next sector...");
+        let(new_c, new_h, new_s) = self.drives[self.drive_select].get_next_sector(
+            self.drives[self.drive_select].cylinder,
+            self.drives[self.drive_select].head,
+            self.drives[self.drive_select].sector);
+
+        self.drives[self.drive_select].cylinder = new_c;
+        self.drives[self.drive_select].head = new_h;
+        self.drives[self.drive_select].sector = new_s;
+        self.operation_status.buffer_idx = 0;
     }
 
     /// Run the HDC device.
-    pub fn run(&mut self, pic: &mut pic::Pic, dma: &mut dma::DMAController, bus: &mut BusInterface, cpu_cycles: u32 ) {
+    pub fn run(&mut self, pic: &mut pic::Pic, dma: &mut dma::DMAController, cpu_cycles: u32 ) {
 
         // Handle interrupts
         if self.send_interrupt {
@@ -1359,14 +1270,17 @@ impl HardDiskController {
 
             State::ExecutingCommand => {
                 match self.command {
+                    Command::ReadSectorBuffer => {
+                        self.check_dma_operation_end("ReadSectorBuffer");
+                    }
                     Command::WriteSectorBuffer => {
-                        self.opearation_write_sector_buffer(dma, bus);
+                        self.check_dma_operation_end("WriteSectorBuffer");
                     }
                     Command::Read => {
-                        self.operation_read_sector(dma, bus);
+                        self.check_dma_operation_end("Read");
                     }
                     Command::Write => {
-                        self.operation_write_sector(dma, bus);
+                        self.check_dma_operation_end("Write");
                     }                    
                     _ => panic!("Unexpected command")
                 }
@@ -1377,4 +1291,73 @@ impl HardDiskController {
         }
     }
 
-}
\ No newline at end of file
+}
+
+/// DMA transfers for the HDC. The HDC raises DREQ while executing a data transfer command and
+/// the DMA controller moves data in or out of the sector buffer one byte per acknowledged transfer.
+impl dma::DmaDevice for HardDiskController {
+
+    fn dma_read_u8(&mut self, _channel: usize) -> u8 {
+        if self.operation_status.dma_bytes_left == 0 {
+            log::warn!("HDC: Unexpected DMA read with no bytes left to transfer");
+            return 0xFF
+        }
+
+        match (&self.state, &self.command) {
+            (State::ExecutingCommand, Command::Read) | (State::ExecutingCommand, Command::ReadSectorBuffer) => {
+                let byte = self.drives[self.drive_select].sector_buf[self.operation_status.buffer_idx];
+                self.operation_status.buffer_idx += 1;
+                self.operation_status.dma_byte_count += 1;
+                self.operation_status.dma_bytes_left -= 1;
+
+                // Exhausted the sector buffer, read more from disk
+                if self.operation_status.buffer_idx == SECTOR_SIZE {
+                    if let Command::Read = self.command {
+                        self.read_next_sector();
+                    }
+                    else {
+                        self.operation_status.buffer_idx = 0;
+                    }
+                }
+                byte
+            }
+            _ => {
+                log::warn!("HDC: Unexpected DMA read during command: {:?}", self.command);
+                0xFF
+            }
+        }
+    }
+
+    fn dma_write_u8(&mut self, _channel: usize, data: u8) {
+        if self.operation_status.dma_bytes_left == 0 {
+            log::warn!("HDC: Unexpected DMA write with no bytes left to transfer");
+            return
+        }
+
+        match (&self.state, &self.command) {
+            (State::ExecutingCommand, Command::Write) | (State::ExecutingCommand, Command::WriteSectorBuffer) => {
+                self.drives[self.drive_select].sector_buf[self.operation_status.buffer_idx] = data;
+                self.operation_status.buffer_idx += 1;
+                self.operation_status.dma_byte_count += 1;
+                self.operation_status.dma_bytes_left -= 1;
+
+                // Filled the sector buffer, write it to disk
+                if self.operation_status.buffer_idx == SECTOR_SIZE {
+                    if let Command::Write = self.command {
+                        self.write_next_sector();
+                    }
+                    else {
+                        self.operation_status.buffer_idx = 0;
+                    }
+                }
+            }
+            _ => {
+                log::warn!("HDC: Unexpected DMA write during command: {:?}", self.command);
+            }
+        }
+    }
+
+    fn dma_end_of_process(&mut self, _channel: usize) {
+        self.operation_status.terminal_count = true;
+    }
+}
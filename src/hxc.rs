@@ -0,0 +1,175 @@
+/*
+    hxc.rs
+    Import HxC Floppy Emulator bitstream images (.HFE and .MFM)
+
+    HFE images start with a 512 byte header:
+
+        8 bytes     Signature "HXCPICFE"
+        u8          Format revision
+        u8          Number of tracks
+        u8          Number of sides
+        u8          Track encoding: 0 for IBM MFM
+        u16         Bit rate in Kbps
+        u16         RPM
+        u8          Interface mode
+        u8          Unused
+        u16         Track list offset, in 512 byte blocks
+
+    The track list holds a u16 offset, in blocks, and u16 length in bytes of each track.
+    Track data is interleaved in 512 byte blocks of 256 bytes of side 0 then 256 bytes of
+    side 1, with cells least significant bit first.
+
+    HxC MFM images start with:
+
+        7 bytes     Signature "HXCMFM" and a NUL
+        u16         Number of tracks
+        u8          Number of sides
+        u16         RPM
+        u16         Bit rate in Kbps
+        u8          Interface mode
+        u32         Track list offset
+
+    The track list holds a u16 track number, u8 side, u32 length and u32 offset of each
+    track, whose cells are stored most significant bit first.
+
+    All values are little endian.
+*/
+
+use crate::floppy_image::{DiskImage, ImageError, ImageFormat};
+use crate::mfm::{self, TrackCells};
+
+pub const HFE_SIGNATURE: &[u8] = b"HXCPICFE";
+pub const MFM_SIGNATURE: &[u8] = b"HXCMFM\0";
+
+const HFE_BLOCK_SIZE: usize = 512;
+const HFE_SIDE_BLOCK_SIZE: usize = 256;
+const HFE_ENCODING_MFM: u8 = 0;
+const MFM_TRACK_ENTRY_SIZE: usize = 11;
+
+fn u16_at(data: &[u8], pos: usize) -> Result<u16, ImageError> {
+    data.get(pos..pos + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or(ImageError::BadFormat("Truncated HxC image"))
+}
+
+fn u32_at(data: &[u8], pos: usize) -> Result<u32, ImageError> {
+    data.get(pos..pos + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(ImageError::BadFormat("Truncated HxC image"))
+}
+
+pub fn load_hfe(data: &[u8]) -> Result<DiskImage, ImageError> {
+    if data.len() < HFE_BLOCK_SIZE {
+        return Err(ImageError::BadFormat("Truncated HFE image"))
+    }
+    let track_count = data[9] as usize;
+    let sides = data[10] as usize;
+    let encoding = data[11];
+    let bit_rate = u16_at(data, 12)?;
+    let track_list = u16_at(data, 18)? as usize * HFE_BLOCK_SIZE;
+
+    if encoding != HFE_ENCODING_MFM {
+        return Err(ImageError::BadFormat("Only MFM encoded HFE images are supported"))
+    }
+
+    let mut tracks = Vec::new();
+    for cylinder in 0..track_count {
+        let offset = u16_at(data, track_list + cylinder * 4)? as usize * HFE_BLOCK_SIZE;
+        let len = u16_at(data, track_list + cylinder * 4 + 2)? as usize;
+        let track_data = data.get(offset..offset + len).ok_or(ImageError::BadFormat("Truncated HFE image"))?;
+
+        for head in 0..sides {
+            // Gather this side's half of each block
+            let side_data: Vec<u8> = track_data
+                .chunks(HFE_BLOCK_SIZE)
+                .flat_map(|block| block.iter().skip(head * HFE_SIDE_BLOCK_SIZE).take(HFE_SIDE_BLOCK_SIZE))
+                .copied()
+                .collect();
+            let cells = TrackCells {
+                cells: mfm::unpack_cells(&side_data, side_data.len() * 8, false),
+                weak: Vec::new(),
+            };
+            tracks.push(mfm::decode_track(&cells, cylinder as u8, head as u8, bit_rate));
+        }
+    }
+    Ok(DiskImage::new(ImageFormat::Hfe, tracks, String::new()))
+}
+
+pub fn load_mfm(data: &[u8]) -> Result<DiskImage, ImageError> {
+    let track_count = u16_at(data, 7)? as usize;
+    let sides = *data.get(9).ok_or(ImageError::BadFormat("Truncated HxC image"))? as usize;
+    let bit_rate = u16_at(data, 12)?;
+    let track_list = u32_at(data, 15)? as usize;
+
+    let mut tracks = Vec::new();
+    for i in 0..track_count * sides {
+        let entry = track_list + i * MFM_TRACK_ENTRY_SIZE;
+        let cylinder = u16_at(data, entry)? as u8;
+        let head = *data.get(entry + 2).ok_or(ImageError::BadFormat("Truncated HxC image"))?;
+        let len = u32_at(data, entry + 3)? as usize;
+        let offset = u32_at(data, entry + 7)? as usize;
+        let track_data = data.get(offset..offset + len).ok_or(ImageError::BadFormat("Truncated HxC image"))?;
+
+        let cells = TrackCells {
+            cells: mfm::unpack_cells(track_data, len * 8, true),
+            weak: Vec::new(),
+        };
+        tracks.push(mfm::decode_track(&cells, cylinder, head, bit_rate));
+    }
+    Ok(DiskImage::new(ImageFormat::HxcMfm, tracks, String::new()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::floppy_image::SectorId;
+
+    fn pack(cells: &[bool], msb_first: bool) -> Vec<u8> {
+        cells.chunks(8)
+            .map(|byte| byte.iter().enumerate().fold(0, |acc, (i, &c)| {
+                acc | ((c as u8) << if msb_first { 7 - i } else { i })
+            }))
+            .collect()
+    }
+
+    #[test]
+    pub fn test_hxc_images() {
+        let id = SectorId { c: 0, h: 1, r: 0x41, n: 2 };
+        let cells = mfm::encode_track(&[(id, 0xFB, vec![0x5A; 512])]);
+
+        // A single track, double sided HFE image with the sector on side 1
+        let side = pack(&cells, false);
+        let mut hfe = HFE_SIGNATURE.to_vec();
+        hfe.extend_from_slice(&[0, 1, 2, HFE_ENCODING_MFM, 250, 0, 44, 1, 0, 0, 1, 0]);
+        hfe.resize(HFE_BLOCK_SIZE, 0xFF);
+        let len = side.len().div_ceil(HFE_SIDE_BLOCK_SIZE) * HFE_BLOCK_SIZE;
+        hfe.extend_from_slice(&[2, 0]);
+        hfe.extend_from_slice(&(len as u16).to_le_bytes());
+        hfe.resize(HFE_BLOCK_SIZE * 2, 0xFF);
+        for chunk in side.chunks(HFE_SIDE_BLOCK_SIZE) {
+            let mut block = vec![0; HFE_BLOCK_SIZE];
+            block[HFE_SIDE_BLOCK_SIZE..HFE_SIDE_BLOCK_SIZE + chunk.len()].copy_from_slice(chunk);
+            hfe.extend(block);
+        }
+
+        let image = DiskImage::load(&hfe).unwrap();
+        assert_eq!(image.format, ImageFormat::Hfe);
+        assert!(image.tracks[0].sectors.is_empty());
+        let addr = image.find_sector(0, 1, id).unwrap();
+        assert_eq!(image.sector(addr).data, Some(vec![0x5A; 512]));
+
+        // The same track as an HxC MFM image
+        let track = pack(&cells, true);
+        let mut hxc_mfm = MFM_SIGNATURE.to_vec();
+        hxc_mfm.extend_from_slice(&[1, 0, 1, 44, 1, 250, 0, 0, 19, 0, 0, 0]);
+        hxc_mfm.extend_from_slice(&[0, 0, 1]);
+        hxc_mfm.extend_from_slice(&(track.len() as u32).to_le_bytes());
+        hxc_mfm.extend_from_slice(&30u32.to_le_bytes());
+        hxc_mfm.extend(track);
+
+        let image = DiskImage::load(&hxc_mfm).unwrap();
+        assert_eq!(image.format, ImageFormat::HxcMfm);
+        let addr = image.find_sector(0, 1, id).unwrap();
+        assert!(!image.sector(addr).data_error);
+    }
+}
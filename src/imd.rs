@@ -0,0 +1,245 @@
+/*
+    imd.rs
+    Load and save ImageDisk (.IMD) floppy images
+
+    ImageDisk images record each track as it was read from the disk:
+
+        ASCII header "IMD v.vv: dd/mm/yyyy hh:mm:ss", CR LF, comment, terminated by 0x1A
+        Tracks of:
+            u8 mode             Data rate and encoding: 0-2 FM, 3-5 MFM at 500, 300, 250Kbps
+            u8 cylinder
+            u8 head             Bit 7: cylinder map present, bit 6: head map present
+            u8 sector count
+            u8 sector size      Size code 0-6 (128 << n), or 0xFF for a table of sizes
+            Sector numbering map, one byte per sector
+            Optional sector cylinder map, one byte per sector
+            Optional sector head map, one byte per sector
+            Optional sector size table, one u16 per sector
+            Sector data records, each starting with a type byte:
+                0       Data unavailable
+                1, 2    Normal data, or compressed to a single fill byte
+                3, 4    Deleted data, or compressed
+                5, 6    Data with a CRC error, or compressed
+                7, 8    Deleted data with a CRC error, or compressed
+
+    All values are little endian.
+*/
+
+use time::{Date, Month, OffsetDateTime, PrimitiveDateTime, Time};
+
+use crate::floppy_image::{DiskImage, ImageError, ImageFormat, Sector, SectorId, Track, TrackMode};
+
+pub const IMD_SIGNATURE: &[u8] = b"IMD ";
+const IMD_VERSION: &str = "1.18";
+const COMMENT_END: u8 = 0x1A;
+
+const HEAD_CYLINDER_MAP: u8 = 0b1000_0000;
+const HEAD_HEAD_MAP: u8     = 0b0100_0000;
+const HEAD_MASK: u8         = 0b0000_0001;
+const SIZE_TABLE: u8 = 0xFF;
+
+// Data rates of modes 0-2 and 3-5
+const MODE_RATES: [u16; 3] = [500, 300, 250];
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], ImageError> {
+        let bytes = self.data.get(self.pos..self.pos + len).ok_or(ImageError::BadFormat("Truncated IMD image"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, ImageError> {
+        Ok(self.bytes(1)?[0])
+    }
+}
+
+fn size_code(size: usize) -> Option<u8> {
+    (0..=6).find(|&n| 128 << n == size)
+}
+
+/// Parse the date and time the image was made from its header, "IMD v.vv: dd/mm/yyyy hh:mm:ss".
+fn parse_created(comment: &str) -> Option<PrimitiveDateTime> {
+    let stamp = comment.lines().next()?.split_once(": ")?.1;
+    let fields = stamp.split(['/', ' ', ':']).map(|f| f.trim().parse::<u16>().ok()).collect::<Option<Vec<u16>>>()?;
+    if fields.len() != 6 {
+        return None
+    }
+    let month = Month::try_from(fields[1] as u8).ok()?;
+    let date = Date::from_calendar_date(fields[2] as i32, month, fields[0] as u8).ok()?;
+    let time = Time::from_hms(fields[3] as u8, fields[4] as u8, fields[5] as u8).ok()?;
+    Some(PrimitiveDateTime::new(date, time))
+}
+
+pub fn load(data: &[u8]) -> Result<DiskImage, ImageError> {
+    let comment_len = data.iter().position(|&b| b == COMMENT_END).ok_or(ImageError::BadFormat("No IMD comment terminator"))?;
+    let comment = String::from_utf8_lossy(&data[..comment_len]).into_owned();
+    let mut reader = Reader { data, pos: comment_len + 1 };
+
+    let mut tracks = Vec::new();
+    while reader.pos < data.len() {
+        let mode = reader.u8()?;
+        let cylinder = reader.u8()?;
+        let head_flags = reader.u8()?;
+        let sector_count = reader.u8()? as usize;
+        let size = reader.u8()?;
+
+        let mode = match mode {
+            0..=5 => TrackMode { rate_kbps: MODE_RATES[mode as usize % 3], mfm: mode >= 3 },
+            _ => return Err(ImageError::BadFormat("Invalid IMD track mode"))
+        };
+        let head = head_flags & HEAD_MASK;
+        let numbers = reader.bytes(sector_count)?;
+        let cylinders = match head_flags & HEAD_CYLINDER_MAP {
+            0 => None,
+            _ => Some(reader.bytes(sector_count)?)
+        };
+        let heads = match head_flags & HEAD_HEAD_MAP {
+            0 => None,
+            _ => Some(reader.bytes(sector_count)?)
+        };
+        let sizes: Vec<u8> = match size {
+            SIZE_TABLE => reader.bytes(sector_count * 2)?
+                .chunks(2)
+                .map(|b| size_code(u16::from_le_bytes([b[0], b[1]]) as usize).ok_or(ImageError::BadFormat("Invalid IMD sector size")))
+                .collect::<Result<_, _>>()?,
+            0..=6 => vec![size; sector_count],
+            _ => return Err(ImageError::BadFormat("Invalid IMD sector size"))
+        };
+
+        let mut sectors = Vec::with_capacity(sector_count);
+        for i in 0..sector_count {
+            let id = SectorId {
+                c: cylinders.map_or(cylinder, |map| map[i]),
+                h: heads.map_or(head, |map| map[i]),
+                r: numbers[i],
+                n: sizes[i],
+            };
+            let record = reader.u8()?;
+            let data = match record {
+                0 => None,
+                1..=8 if record % 2 == 0 => Some(vec![reader.u8()?; id.size()]),
+                1..=8 => Some(reader.bytes(id.size())?.to_vec()),
+                _ => return Err(ImageError::BadFormat("Invalid IMD sector record"))
+            };
+            sectors.push(Sector {
+                id,
+                data,
+                deleted: matches!(record, 3 | 4 | 7 | 8),
+                data_error: record >= 5,
+                id_error: false,
+                weak: None,
+            });
+        }
+        tracks.push(Track { cylinder, head, mode, sectors });
+    }
+
+    let created = parse_created(&comment);
+    let mut image = DiskImage::new(ImageFormat::Imd, tracks, comment);
+    image.created = created;
+    Ok(image)
+}
+
+pub fn save(image: &DiskImage) -> Vec<u8> {
+    let mut data = Vec::new();
+    if image.comment.as_bytes().starts_with(IMD_SIGNATURE) {
+        data.extend_from_slice(image.comment.as_bytes());
+    }
+    else {
+        let now = OffsetDateTime::now_utc();
+        data.extend_from_slice(format!("IMD {}: {:02}/{:02}/{:04} {:02}:{:02}:{:02}\r\n{}",
+            IMD_VERSION, now.day(), now.month() as u8, now.year(), now.hour(), now.minute(), now.second(),
+            image.comment).as_bytes());
+    }
+    data.push(COMMENT_END);
+
+    for track in &image.tracks {
+        let rate = MODE_RATES.iter().position(|&r| r == track.mode.rate_kbps).unwrap_or(2) as u8;
+        data.push(if track.mode.mfm { rate + 3 } else { rate });
+        data.push(track.cylinder);
+
+        let cylinder_map = track.sectors.iter().any(|s| s.id.c != track.cylinder);
+        let head_map = track.sectors.iter().any(|s| s.id.h != track.head);
+        let mut head_flags = track.head;
+        if cylinder_map {
+            head_flags |= HEAD_CYLINDER_MAP;
+        }
+        if head_map {
+            head_flags |= HEAD_HEAD_MAP;
+        }
+        data.push(head_flags);
+        data.push(track.sectors.len() as u8);
+
+        let first_n = track.sectors.first().map_or(0, |s| s.id.n);
+        let uniform_size = track.sectors.iter().all(|s| s.id.n == first_n);
+        data.push(if uniform_size { first_n } else { SIZE_TABLE });
+
+        data.extend(track.sectors.iter().map(|s| s.id.r));
+        if cylinder_map {
+            data.extend(track.sectors.iter().map(|s| s.id.c));
+        }
+        if head_map {
+            data.extend(track.sectors.iter().map(|s| s.id.h));
+        }
+        if !uniform_size {
+            for sector in &track.sectors {
+                data.extend_from_slice(&(sector.id.size() as u16).to_le_bytes());
+            }
+        }
+
+        for sector in &track.sectors {
+            let sector_data = match &sector.data {
+                Some(sector_data) => sector_data,
+                None => {
+                    data.push(0);
+                    continue
+                }
+            };
+            let record = 1 + (sector.deleted as u8) * 2 + (sector.data_error as u8) * 4;
+            if sector_data.iter().all(|&b| b == sector_data[0]) {
+                data.push(record + 1);
+                data.push(sector_data[0]);
+            }
+            else {
+                data.push(record);
+                data.extend_from_slice(sector_data);
+            }
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_imd_round_trip() {
+        let mut data = b"IMD 1.18: 01/02/1985 10:20:30\r\nTest disk".to_vec();
+        data.push(COMMENT_END);
+        // MFM 250Kbps track at cylinder 0 head 0, with a cylinder map, two 1K sectors
+        data.extend_from_slice(&[5, 0, HEAD_CYLINDER_MAP, 2, 3]);
+        data.extend_from_slice(&[0x41, 0x42]);
+        data.extend_from_slice(&[0, 7]);
+        data.extend_from_slice(&[2, 0xE5]);
+        data.push(3);
+        data.extend((0..1024).map(|i| i as u8));
+
+        let image = load(&data).unwrap();
+        assert_eq!(image.format, ImageFormat::Imd);
+        assert_eq!(image.created.unwrap().to_string(), "1985-02-01 10:20:30.0");
+        let track = &image.tracks[0];
+        assert_eq!(track.mode, TrackMode { rate_kbps: 250, mfm: true });
+        assert_eq!(track.sectors[0].id, SectorId { c: 0, h: 0, r: 0x41, n: 3 });
+        assert_eq!(track.sectors[0].data, Some(vec![0xE5; 1024]));
+        assert_eq!(track.sectors[1].id, SectorId { c: 7, h: 0, r: 0x42, n: 3 });
+        assert!(track.sectors[1].deleted);
+        assert_eq!(track.sectors[1].data.as_ref().unwrap()[5], 5);
+
+        assert_eq!(save(&image), data);
+    }
+}
@@ -1,5 +1,34 @@
+/*
+    input.rs
+    Translate host keyboard input into IBM XT scancodes
+
+    The default table maps winit virtual keycodes on a US layout. A KeyMap adds
+    user bindings loaded from a keymap file, presets for common host layouts, and a
+    choice of symbolic or positional mapping:
+
+    Symbolic:   Keys are mapped by the symbol the host reports. This is the right choice
+                when the guest uses the default US layout; a host 'Z' types 'Z' in the guest
+                whatever the host layout.
+    Positional: Keys are mapped by physical position using the host scancode. This is the
+                right choice when the guest runs a keyboard driver (e.g. KEYB) matching the
+                host layout.
+
+    Keys with no symbolic binding fall back to their position.
+*/
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+};
+
 use winit::event::VirtualKeyCode;
 
+pub const XT_LSHIFT: u8 = 0x2A;
+pub const XT_SYSRQ: u8 = 0x54;
+
+pub const KEYMAP_DEFAULT_PATH: &str = "./keymap.cfg";
+
 pub fn match_virtual_keycode( vkc: VirtualKeyCode ) -> Option<u8> {
 
     match vkc {
@@ -92,12 +121,539 @@ pub fn match_virtual_keycode( vkc: VirtualKeyCode ) -> Option<u8> {
         VirtualKeyCode::Numpad9 => Some(0x49),
         VirtualKeyCode::NumpadSubtract => Some(0x4A),
         VirtualKeyCode::NumpadAdd => Some(0x4E),
+        VirtualKeyCode::NumpadDecimal => Some(0x53),
+        VirtualKeyCode::NumpadMultiply => Some(0x37),
+        VirtualKeyCode::NumpadDivide => Some(0x35),
+        VirtualKeyCode::NumpadEnter => Some(0x1C),
         
         VirtualKeyCode::Left => Some(0x4B),
         VirtualKeyCode::Right => Some(0x4D),
         VirtualKeyCode::Up => Some(0x48),
         VirtualKeyCode::Down => Some(0x50),
+        VirtualKeyCode::Home => Some(0x47),
+        VirtualKeyCode::End => Some(0x4F),
+        VirtualKeyCode::PageUp => Some(0x49),
+        VirtualKeyCode::PageDown => Some(0x51),
+
+        VirtualKeyCode::RControl => Some(0x1D),
+        VirtualKeyCode::RAlt => Some(0x38),
+        // The 83-key keyboard has no SysRq key. Send the 84-key AT keyboard's scancode.
+        VirtualKeyCode::Sysrq => Some(XT_SYSRQ),
         _=>None
     }
 
-}
\ No newline at end of file
+}
+
+/// Keys that may be bound in a keymap. Key names in keymap files are the winit
+/// VirtualKeyCode names.
+pub const BINDABLE_KEYS: &[VirtualKeyCode] = &[
+    VirtualKeyCode::Escape, VirtualKeyCode::F1, VirtualKeyCode::F2, VirtualKeyCode::F3,
+    VirtualKeyCode::F4, VirtualKeyCode::F5, VirtualKeyCode::F6, VirtualKeyCode::F7,
+    VirtualKeyCode::F8, VirtualKeyCode::F9, VirtualKeyCode::F10, VirtualKeyCode::F11,
+    VirtualKeyCode::F12, VirtualKeyCode::Key1, VirtualKeyCode::Key2, VirtualKeyCode::Key3,
+    VirtualKeyCode::Key4, VirtualKeyCode::Key5, VirtualKeyCode::Key6, VirtualKeyCode::Key7,
+    VirtualKeyCode::Key8, VirtualKeyCode::Key9, VirtualKeyCode::Key0, VirtualKeyCode::A,
+    VirtualKeyCode::B, VirtualKeyCode::C, VirtualKeyCode::D, VirtualKeyCode::E,
+    VirtualKeyCode::F, VirtualKeyCode::G, VirtualKeyCode::H, VirtualKeyCode::I,
+    VirtualKeyCode::J, VirtualKeyCode::K, VirtualKeyCode::L, VirtualKeyCode::M,
+    VirtualKeyCode::N, VirtualKeyCode::O, VirtualKeyCode::P, VirtualKeyCode::Q,
+    VirtualKeyCode::R, VirtualKeyCode::S, VirtualKeyCode::T, VirtualKeyCode::U,
+    VirtualKeyCode::V, VirtualKeyCode::W, VirtualKeyCode::X, VirtualKeyCode::Y,
+    VirtualKeyCode::Z, VirtualKeyCode::Minus, VirtualKeyCode::Equals, VirtualKeyCode::Back,
+    VirtualKeyCode::Tab, VirtualKeyCode::LBracket, VirtualKeyCode::RBracket, VirtualKeyCode::Return,
+    VirtualKeyCode::LControl, VirtualKeyCode::RControl, VirtualKeyCode::Semicolon, VirtualKeyCode::Apostrophe,
+    VirtualKeyCode::Grave, VirtualKeyCode::LShift, VirtualKeyCode::RShift, VirtualKeyCode::Backslash,
+    VirtualKeyCode::Comma, VirtualKeyCode::Period, VirtualKeyCode::Slash, VirtualKeyCode::LAlt,
+    VirtualKeyCode::RAlt, VirtualKeyCode::Space, VirtualKeyCode::Capital, VirtualKeyCode::Numlock,
+    VirtualKeyCode::Scroll, VirtualKeyCode::Snapshot, VirtualKeyCode::Sysrq, VirtualKeyCode::Pause,
+    VirtualKeyCode::Insert, VirtualKeyCode::Delete, VirtualKeyCode::Home, VirtualKeyCode::End,
+    VirtualKeyCode::PageUp, VirtualKeyCode::PageDown, VirtualKeyCode::Left, VirtualKeyCode::Right,
+    VirtualKeyCode::Up, VirtualKeyCode::Down, VirtualKeyCode::Numpad0, VirtualKeyCode::Numpad1,
+    VirtualKeyCode::Numpad2, VirtualKeyCode::Numpad3, VirtualKeyCode::Numpad4, VirtualKeyCode::Numpad5,
+    VirtualKeyCode::Numpad6, VirtualKeyCode::Numpad7, VirtualKeyCode::Numpad8, VirtualKeyCode::Numpad9,
+    VirtualKeyCode::NumpadAdd, VirtualKeyCode::NumpadSubtract, VirtualKeyCode::NumpadMultiply, VirtualKeyCode::NumpadDivide,
+    VirtualKeyCode::NumpadDecimal, VirtualKeyCode::NumpadComma, VirtualKeyCode::NumpadEnter, VirtualKeyCode::NumpadEquals,
+    VirtualKeyCode::OEM102, VirtualKeyCode::Caret, VirtualKeyCode::Plus, VirtualKeyCode::Asterisk,
+    VirtualKeyCode::At, VirtualKeyCode::Colon, VirtualKeyCode::Underline, VirtualKeyCode::Ax,
+    VirtualKeyCode::Yen, VirtualKeyCode::AbntC1, VirtualKeyCode::AbntC2, VirtualKeyCode::LWin,
+    VirtualKeyCode::RWin, VirtualKeyCode::Apps,
+];
+
+pub fn key_name(key: VirtualKeyCode) -> String {
+    format!("{:?}", key)
+}
+
+pub fn key_from_name(name: &str) -> Option<VirtualKeyCode> {
+    BINDABLE_KEYS.iter().copied().find(|key| key_name(*key).eq_ignore_ascii_case(name))
+}
+
+/// The XT keyboard shares its cursor keys with the numeric keypad. Return whether a host key
+/// is a dedicated cursor key, which should act as one regardless of the guest's NumLock state.
+pub fn is_navigation_key(key: VirtualKeyCode) -> bool {
+    matches!(key,
+        VirtualKeyCode::Insert | VirtualKeyCode::Delete | VirtualKeyCode::Home | VirtualKeyCode::End |
+        VirtualKeyCode::PageUp | VirtualKeyCode::PageDown | VirtualKeyCode::Left | VirtualKeyCode::Right |
+        VirtualKeyCode::Up | VirtualKeyCode::Down)
+}
+
+/// Map a host scancode to the XT key in the same position. Windows and Linux both report
+/// set 1 scancodes for the main keyboard block, which match the XT. Keys outside that
+/// block are reported as E0-prefixed codes on Windows and as evdev codes on Linux.
+/// Returns the scancode and whether the key is a dedicated cursor key.
+#[cfg(not(target_os = "macos"))]
+pub fn match_positional_scancode(scancode: u32) -> Option<(u8, bool)> {
+    match scancode {
+        0x01..=0x53 => Some((scancode as u8, false)),
+        // Windows extended keys
+        0xE01C | 0xE01D | 0xE035 | 0xE037 | 0xE038 => Some(((scancode & 0xFF) as u8, false)),
+        0xE047..=0xE053 => Some(((scancode & 0xFF) as u8, true)),
+        // Linux evdev codes
+        96 => Some((0x1C, false)),  // KP Enter
+        97 => Some((0x1D, false)),  // Right Ctrl
+        98 => Some((0x35, false)),  // KP Divide
+        99 => Some((0x37, false)),  // Print Screen
+        100 => Some((0x38, false)), // Right Alt
+        102 => Some((0x47, true)),  // Home
+        103 => Some((0x48, true)),  // Up
+        104 => Some((0x49, true)),  // Page Up
+        105 => Some((0x4B, true)),  // Left
+        106 => Some((0x4D, true)),  // Right
+        107 => Some((0x4F, true)),  // End
+        108 => Some((0x50, true)),  // Down
+        109 => Some((0x51, true)),  // Page Down
+        110 => Some((0x52, true)),  // Insert
+        111 => Some((0x53, true)),  // Delete
+        _ => None
+    }
+}
+
+/// macOS reports its own virtual key codes rather than PC scancodes. Positional mapping
+/// isn't supported there, and falls back to symbolic mapping.
+#[cfg(target_os = "macos")]
+pub fn match_positional_scancode(_scancode: u32) -> Option<(u8, bool)> {
+    None
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum KeyMapMode {
+    Symbolic,
+    Positional
+}
+
+/// Presets for host keyboard layouts. Each adds bindings for keys winit reports on that
+/// layout which have no equivalent on a US keyboard.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum HostLayout {
+    Us,
+    Uk,
+    German,
+    French,
+}
+
+impl HostLayout {
+    pub const ALL: [HostLayout; 4] = [HostLayout::Us, HostLayout::Uk, HostLayout::German, HostLayout::French];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HostLayout::Us => "us",
+            HostLayout::Uk => "uk",
+            HostLayout::German => "de",
+            HostLayout::French => "fr",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<HostLayout> {
+        HostLayout::ALL.iter().copied().find(|layout| layout.name().eq_ignore_ascii_case(name))
+    }
+
+    fn extra_bindings(&self) -> &'static [(VirtualKeyCode, u8)] {
+        match self {
+            HostLayout::Us => &[],
+            // The extra key next to left shift
+            HostLayout::Uk => &[
+                (VirtualKeyCode::OEM102, 0x2B),
+            ],
+            // '+' and '*' have their own keys. Send the keypad keys, which type the same
+            // symbols without shift.
+            HostLayout::German => &[
+                (VirtualKeyCode::Plus, 0x4E),
+                (VirtualKeyCode::Asterisk, 0x37),
+                (VirtualKeyCode::OEM102, 0x2B),
+                (VirtualKeyCode::NumpadComma, 0x53),
+            ],
+            HostLayout::French => &[
+                (VirtualKeyCode::Asterisk, 0x37),
+                (VirtualKeyCode::OEM102, 0x2B),
+                (VirtualKeyCode::NumpadComma, 0x53),
+            ],
+        }
+    }
+
+    /// Keys on this layout for symbols that need shift on a US keyboard. They are typed as
+    /// the US key with shift held.
+    fn shifted_bindings(&self) -> &'static [(VirtualKeyCode, u8)] {
+        match self {
+            // '^' has its own key
+            HostLayout::German | HostLayout::French => &[
+                (VirtualKeyCode::Caret, 0x07),
+            ],
+            _ => &[],
+        }
+    }
+}
+
+/// A host key translated to an XT scancode. `navigation` is set for dedicated cursor keys,
+/// and `shift` for keys that must be typed with shift held.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MappedKey {
+    pub code: u8,
+    pub navigation: bool,
+    pub shift: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct KeyMap {
+    pub mode: KeyMapMode,
+    pub layout: HostLayout,
+    bindings: HashMap<VirtualKeyCode, u8>,
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        KeyMap::from_layout(HostLayout::Us)
+    }
+}
+
+impl KeyMap {
+
+    /// Create a symbolic keymap with the preset bindings for a host layout.
+    pub fn from_layout(layout: HostLayout) -> Self {
+        let mut bindings = HashMap::new();
+        for key in BINDABLE_KEYS {
+            if let Some(code) = match_virtual_keycode(*key) {
+                bindings.insert(*key, code);
+            }
+        }
+        for (key, code) in layout.extra_bindings() {
+            bindings.insert(*key, *code);
+        }
+        Self {
+            mode: KeyMapMode::Symbolic,
+            layout,
+            bindings,
+        }
+    }
+
+    /// Return all bindings, in the order of BINDABLE_KEYS.
+    pub fn bindings(&self) -> Vec<(VirtualKeyCode, u8)> {
+        BINDABLE_KEYS.iter()
+            .filter_map(|key| self.bindings.get(key).map(|code| (*key, *code)))
+            .collect()
+    }
+
+    pub fn get_binding(&self, key: VirtualKeyCode) -> Option<u8> {
+        self.bindings.get(&key).copied()
+    }
+
+    pub fn set_binding(&mut self, key: VirtualKeyCode, code: u8) {
+        self.bindings.insert(key, code);
+    }
+
+    pub fn remove_binding(&mut self, key: VirtualKeyCode) {
+        self.bindings.remove(&key);
+    }
+
+    /// Translate a host key event. In positional mode the host scancode is used if it can be
+    /// mapped. Otherwise the virtual keycode is looked up in the bindings, then in the
+    /// layout's shifted keys, falling back to the key's position.
+    pub fn translate(&self, key: Option<VirtualKeyCode>, scancode: u32) -> Option<MappedKey> {
+
+        if self.mode == KeyMapMode::Positional {
+            if let Some((code, navigation)) = match_positional_scancode(scancode) {
+                return Some(MappedKey { code, navigation, shift: false })
+            }
+        }
+
+        if let Some(key) = key {
+            if let Some(code) = self.bindings.get(&key) {
+                // Keypad keys with host NumLock off may report cursor keycodes. Only treat the
+                // key as a dedicated cursor key if its position agrees.
+                let navigation = is_navigation_key(key)
+                    && !matches!(match_positional_scancode(scancode), Some((_, false)));
+                return Some(MappedKey { code: *code, navigation, shift: false })
+            }
+            if let Some((_, code)) = self.layout.shifted_bindings().iter().find(|(k, _)| *k == key) {
+                return Some(MappedKey { code: *code, navigation: false, shift: true })
+            }
+        }
+
+        match_positional_scancode(scancode).map(|(code, navigation)| MappedKey { code, navigation, shift: false })
+    }
+
+    /// Parse a keymap file. The file consists of lines of the form 'name = value'. 'mode'
+    /// selects 'symbolic' or 'positional' mapping, 'layout' loads a host layout preset,
+    /// and any other name is a key binding to an XT scancode in hex, or 'none' to unbind
+    /// the key. Bindings are applied on top of the layout preset. '#' starts a comment.
+    pub fn parse(text: &str) -> Result<Self, String> {
+
+        let mut keymap = KeyMap::default();
+        let mut mode = KeyMapMode::Symbolic;
+        let mut bindings: Vec<(VirtualKeyCode, Option<u8>)> = Vec::new();
+
+        for (line_no, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue
+            }
+            let (name, value) = line.split_once('=')
+                .map(|(n, v)| (n.trim(), v.trim()))
+                .ok_or_else(|| format!("Line {}: expected 'name = value'", line_no + 1))?;
+
+            match name.to_ascii_lowercase().as_str() {
+                "mode" => {
+                    mode = match value.to_ascii_lowercase().as_str() {
+                        "symbolic" => KeyMapMode::Symbolic,
+                        "positional" => KeyMapMode::Positional,
+                        _ => return Err(format!("Line {}: invalid mode '{}'", line_no + 1, value))
+                    };
+                }
+                "layout" => {
+                    let layout = HostLayout::from_name(value)
+                        .ok_or_else(|| format!("Line {}: unknown layout '{}'", line_no + 1, value))?;
+                    keymap = KeyMap::from_layout(layout);
+                }
+                _ => {
+                    let key = key_from_name(name)
+                        .ok_or_else(|| format!("Line {}: unknown key '{}'", line_no + 1, name))?;
+                    let code = if value.eq_ignore_ascii_case("none") {
+                        None
+                    }
+                    else {
+                        let code = u8::from_str_radix(value.trim_start_matches("0x"), 16)
+                            .map_err(|_| format!("Line {}: invalid scancode '{}'", line_no + 1, value))?;
+                        Some(code)
+                    };
+                    bindings.push((key, code));
+                }
+            }
+        }
+
+        keymap.mode = mode;
+        for (key, code) in bindings {
+            match code {
+                Some(code) => keymap.set_binding(key, code),
+                None => keymap.remove_binding(key)
+            }
+        }
+        Ok(keymap)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        KeyMap::parse(&text)
+    }
+
+    /// Write the keymap in the format read by parse(). Only bindings that differ from the
+    /// layout preset are written.
+    pub fn to_text(&self) -> String {
+        let preset = KeyMap::from_layout(self.layout);
+        let mut text = String::from("# Marty keymap\n");
+        text.push_str(&format!("mode = {}\n", match self.mode {
+            KeyMapMode::Symbolic => "symbolic",
+            KeyMapMode::Positional => "positional"
+        }));
+        text.push_str(&format!("layout = {}\n", self.layout.name()));
+
+        for key in BINDABLE_KEYS {
+            let code = self.get_binding(*key);
+            if code == preset.get_binding(*key) {
+                continue
+            }
+            match code {
+                Some(code) => text.push_str(&format!("{} = {:02X}\n", key_name(*key), code)),
+                None => text.push_str(&format!("{} = none\n", key_name(*key)))
+            }
+        }
+        text
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        fs::write(path, self.to_text()).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+/// Map a character of host text to the key that types it on a US layout, and whether
+/// shift must be held.
+pub fn match_char(c: char) -> Option<(VirtualKeyCode, bool)> {
+
+    let key = match c.to_ascii_lowercase() {
+        'a' => VirtualKeyCode::A,
+        'b' => VirtualKeyCode::B,
+        'c' => VirtualKeyCode::C,
+        'd' => VirtualKeyCode::D,
+        'e' => VirtualKeyCode::E,
+        'f' => VirtualKeyCode::F,
+        'g' => VirtualKeyCode::G,
+        'h' => VirtualKeyCode::H,
+        'i' => VirtualKeyCode::I,
+        'j' => VirtualKeyCode::J,
+        'k' => VirtualKeyCode::K,
+        'l' => VirtualKeyCode::L,
+        'm' => VirtualKeyCode::M,
+        'n' => VirtualKeyCode::N,
+        'o' => VirtualKeyCode::O,
+        'p' => VirtualKeyCode::P,
+        'q' => VirtualKeyCode::Q,
+        'r' => VirtualKeyCode::R,
+        's' => VirtualKeyCode::S,
+        't' => VirtualKeyCode::T,
+        'u' => VirtualKeyCode::U,
+        'v' => VirtualKeyCode::V,
+        'w' => VirtualKeyCode::W,
+        'x' => VirtualKeyCode::X,
+        'y' => VirtualKeyCode::Y,
+        'z' => VirtualKeyCode::Z,
+        _ => {
+            // Not a letter
+            let (key, shift) = match c {
+                '1' => (VirtualKeyCode::Key1, false),
+                '2' => (VirtualKeyCode::Key2, false),
+                '3' => (VirtualKeyCode::Key3, false),
+                '4' => (VirtualKeyCode::Key4, false),
+                '5' => (VirtualKeyCode::Key5, false),
+                '6' => (VirtualKeyCode::Key6, false),
+                '7' => (VirtualKeyCode::Key7, false),
+                '8' => (VirtualKeyCode::Key8, false),
+                '9' => (VirtualKeyCode::Key9, false),
+                '0' => (VirtualKeyCode::Key0, false),
+                '!' => (VirtualKeyCode::Key1, true),
+                '@' => (VirtualKeyCode::Key2, true),
+                '#' => (VirtualKeyCode::Key3, true),
+                '$' => (VirtualKeyCode::Key4, true),
+                '%' => (VirtualKeyCode::Key5, true),
+                '^' => (VirtualKeyCode::Key6, true),
+                '&' => (VirtualKeyCode::Key7, true),
+                '*' => (VirtualKeyCode::Key8, true),
+                '(' => (VirtualKeyCode::Key9, true),
+                ')' => (VirtualKeyCode::Key0, true),
+                '-' => (VirtualKeyCode::Minus, false),
+                '_' => (VirtualKeyCode::Minus, true),
+                '=' => (VirtualKeyCode::Equals, false),
+                '+' => (VirtualKeyCode::Equals, true),
+                '[' => (VirtualKeyCode::LBracket, false),
+                '{' => (VirtualKeyCode::LBracket, true),
+                ']' => (VirtualKeyCode::RBracket, false),
+                '}' => (VirtualKeyCode::RBracket, true),
+                '\\' => (VirtualKeyCode::Backslash, false),
+                '|' => (VirtualKeyCode::Backslash, true),
+                ';' => (VirtualKeyCode::Semicolon, false),
+                ':' => (VirtualKeyCode::Semicolon, true),
+                '\'' => (VirtualKeyCode::Apostrophe, false),
+                '"' => (VirtualKeyCode::Apostrophe, true),
+                '`' => (VirtualKeyCode::Grave, false),
+                '~' => (VirtualKeyCode::Grave, true),
+                ',' => (VirtualKeyCode::Comma, false),
+                '<' => (VirtualKeyCode::Comma, true),
+                '.' => (VirtualKeyCode::Period, false),
+                '>' => (VirtualKeyCode::Period, true),
+                '/' => (VirtualKeyCode::Slash, false),
+                '?' => (VirtualKeyCode::Slash, true),
+                ' ' => (VirtualKeyCode::Space, false),
+                '\t' => (VirtualKeyCode::Tab, false),
+                '\n' => (VirtualKeyCode::Return, false),
+                _ => return None
+            };
+            return Some((key, shift))
+        }
+    };
+    Some((key, c.is_ascii_uppercase()))
+}
+
+/// Convert host text into a sequence of XT scancodes to type it. Each entry is a scancode
+/// and whether it is a press (true) or release (false). Shift is pressed around characters
+/// that need it. Carriage returns are dropped so CRLF line endings produce a single Enter.
+pub fn text_to_scancodes(text: &str) -> Vec<(u8, bool)> {
+
+    let shift_code = match_virtual_keycode(VirtualKeyCode::LShift).unwrap();
+    let mut events = Vec::new();
+
+    for c in text.chars() {
+        if c == '\r' {
+            continue
+        }
+        let (key, shift) = match match_char(c) {
+            Some(key) => key,
+            None => {
+                log::warn!("No key to type character: {:?}", c);
+                continue
+            }
+        };
+        let code = match match_virtual_keycode(key) {
+            Some(code) => code,
+            None => continue
+        };
+
+        if shift {
+            events.push((shift_code, true));
+        }
+        events.push((code, true));
+        events.push((code, false));
+        if shift {
+            events.push((shift_code, false));
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_text_to_scancodes() {
+        let events = text_to_scancodes("aB!\r\n");
+        assert_eq!(events, vec![
+            (0x1E, true), (0x1E, false),
+            (0x2A, true), (0x30, true), (0x30, false), (0x2A, false),
+            (0x2A, true), (0x02, true), (0x02, false), (0x2A, false),
+            (0x1C, true), (0x1C, false),
+        ]);
+    }
+
+    #[test]
+    pub fn test_keymap_parse() {
+        let keymap = KeyMap::parse("# comment\nmode = positional\nlayout = de\nNumpad5 = 0x4c\nOEM102 = none\n").unwrap();
+        assert_eq!(keymap.mode, KeyMapMode::Positional);
+        assert_eq!(keymap.layout, HostLayout::German);
+        assert_eq!(keymap.get_binding(VirtualKeyCode::Plus), Some(0x4E));
+        assert_eq!(keymap.get_binding(VirtualKeyCode::OEM102), None);
+
+        // Round trip through the file format
+        let reparsed = KeyMap::parse(&keymap.to_text()).unwrap();
+        assert_eq!(reparsed.bindings(), keymap.bindings());
+        assert_eq!(reparsed.mode, keymap.mode);
+
+        assert!(KeyMap::parse("Nonsense = 12").is_err());
+        assert!(KeyMap::parse("A = zz").is_err());
+    }
+
+    #[test]
+    pub fn test_keymap_translate() {
+        let mut keymap = KeyMap::default();
+
+        // Symbolic: the virtual keycode wins over position
+        assert_eq!(keymap.translate(Some(VirtualKeyCode::Z), 0x15), Some(MappedKey { code: 0x2C, navigation: false, shift: false }));
+        // Unbound keys fall back to position
+        assert_eq!(keymap.translate(None, 0x02), Some(MappedKey { code: 0x02, navigation: false, shift: false }));
+        assert_eq!(keymap.translate(Some(VirtualKeyCode::Up), 0xE048), Some(MappedKey { code: 0x48, navigation: true, shift: false }));
+
+        keymap.mode = KeyMapMode::Positional;
+        assert_eq!(keymap.translate(Some(VirtualKeyCode::Z), 0x15), Some(MappedKey { code: 0x15, navigation: false, shift: false }));
+
+        // '^' on a German keyboard is typed as shift+6
+        let mut keymap = KeyMap::from_layout(HostLayout::German);
+        assert_eq!(keymap.translate(Some(VirtualKeyCode::Caret), 0x29), Some(MappedKey { code: 0x07, navigation: false, shift: true }));
+        keymap.mode = KeyMapMode::Positional;
+        assert_eq!(keymap.translate(Some(VirtualKeyCode::Caret), 0x29), Some(MappedKey { code: 0x29, navigation: false, shift: false }));
+    }
+}
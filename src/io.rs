@@ -1,9 +1,8 @@
 #![allow(dead_code)]
 use std::collections::HashMap;
-use std::rc::Rc;
-use std::cell::RefCell;
+use std::sync::{Arc, Mutex};
 
-pub trait IoDevice {
+pub trait IoDevice: Send {
     fn read_u8(&mut self, port: u16) -> u8;
     fn write_u8(&mut self, port: u16, data: u8); 
 }
@@ -11,12 +10,12 @@ type IoDeviceReadU8Fn = fn (&mut (dyn IoDevice + 'static ), port: u16) -> u8;
 type IoDeviceWriteU8Fn = fn (&mut (dyn IoDevice + 'static ), port: u16, data: u8);
 
 pub struct IoHandler {
-    device: Rc<RefCell<dyn IoDevice>>,
+    device: Arc<Mutex<dyn IoDevice>>,
     read_u8: IoDeviceReadU8Fn,
     write_u8: IoDeviceWriteU8Fn
 }
 impl IoHandler {
-    pub fn new(device: Rc<RefCell<dyn IoDevice>>) -> Self {
+    pub fn new(device: Arc<Mutex<dyn IoDevice>>) -> Self {
         Self {
             device,
             read_u8: <dyn IoDevice>::read_u8,
@@ -60,7 +59,7 @@ impl IoBusInterface {
         let handler_opt = self.handlers.get_mut(&port);
         if let Some(handler) = handler_opt {
             // We found a IoHandler in hashmap
-            let mut writeable_thing = handler.device.borrow_mut();
+            let mut writeable_thing = handler.device.lock().unwrap();
             let func_ptr = handler.read_u8;
             func_ptr(&mut *writeable_thing, port)
         }
@@ -74,7 +73,7 @@ impl IoBusInterface {
         let handler_opt = self.handlers.get_mut(&port);
         if let Some(handler) = handler_opt {
             // We found a IoHandler in hashmap
-            let mut writeable_thing = handler.device.borrow_mut();
+            let mut writeable_thing = handler.device.lock().unwrap();
             let func_ptr = handler.write_u8;
             func_ptr(&mut *writeable_thing, port, data);
         }
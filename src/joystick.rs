@@ -0,0 +1,245 @@
+/*
+    joystick.rs
+    Bind host input to the emulated joysticks
+
+    The game port sticks can be driven from one host source:
+
+    Keyboard:   While joystick keys are toggled on, the arrow keys deflect stick A fully
+                and Z and X press its buttons. These keys don't reach the emulated keyboard
+                while the binding is active.
+    Mouse:      While the mouse is captured, its motion moves stick A and its buttons
+                press the stick buttons, in place of the serial mouse.
+    Gamepad:    The first host gamepad's left and right sticks drive sticks A and B. The
+                south and east buttons are A1 and A2, west and north are B1 and B2, and the
+                d-pad deflects stick A. Requires building with the 'gilrs' feature.
+*/
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+
+use winit::event::VirtualKeyCode;
+
+use crate::gameport::JoystickEvent;
+use crate::mouse::MouseButton;
+
+pub const AXIS_A_X: usize = 0;
+pub const AXIS_A_Y: usize = 1;
+pub const AXIS_B_X: usize = 2;
+pub const AXIS_B_Y: usize = 3;
+pub const BUTTON_A1: usize = 0;
+pub const BUTTON_A2: usize = 1;
+pub const BUTTON_B1: usize = 2;
+pub const BUTTON_B2: usize = 3;
+
+// Mouse motion, in host pixels, that moves a stick across its full range
+const MOUSE_TRAVEL: f32 = 400.0;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum JoystickSource {
+    Keyboard,
+    Mouse,
+    Gamepad,
+}
+
+impl JoystickSource {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "keyboard" => Some(JoystickSource::Keyboard),
+            "mouse" => Some(JoystickSource::Mouse),
+            "gamepad" => Some(JoystickSource::Gamepad),
+            _ => None
+        }
+    }
+
+    /// Sticks connected to the game port for this source.
+    pub fn sticks_connected(&self) -> [bool; 2] {
+        match self {
+            JoystickSource::Gamepad => [true, true],
+            _ => [true, false]
+        }
+    }
+}
+
+pub struct JoystickBinding {
+    source: JoystickSource,
+    keys_active: bool,
+    // Arrow keys held: left, right, up, down
+    arrows: [bool; 4],
+    mouse_position: [f32; 2],
+    events: VecDeque<JoystickEvent>,
+    #[cfg(feature = "gilrs")]
+    gilrs: Option<gilrs::Gilrs>,
+}
+
+impl JoystickBinding {
+    pub fn new(source: JoystickSource) -> Self {
+        #[cfg(feature = "gilrs")]
+        let gilrs = match source {
+            JoystickSource::Gamepad => match gilrs::Gilrs::new() {
+                Ok(gilrs) => Some(gilrs),
+                Err(e) => {
+                    log::warn!("Couldn't open host gamepads: {}", e);
+                    None
+                }
+            },
+            _ => None
+        };
+        #[cfg(not(feature = "gilrs"))]
+        if source == JoystickSource::Gamepad {
+            log::warn!("Gamepad support requires building with the 'gilrs' feature");
+        }
+
+        Self {
+            source,
+            keys_active: false,
+            arrows: [false; 4],
+            mouse_position: [0.0; 2],
+            events: VecDeque::new(),
+            #[cfg(feature = "gilrs")]
+            gilrs,
+        }
+    }
+
+    pub fn uses_mouse(&self) -> bool {
+        self.source == JoystickSource::Mouse
+    }
+
+    /// Toggle the keyboard binding on or off. Returns the new state.
+    pub fn toggle_keys(&mut self) -> bool {
+        if self.source != JoystickSource::Keyboard {
+            return false
+        }
+        self.keys_active = !self.keys_active;
+        if !self.keys_active {
+            // Center the stick and release the buttons
+            self.arrows = [false; 4];
+            self.events.extend([
+                JoystickEvent::Axis(AXIS_A_X, 0.0),
+                JoystickEvent::Axis(AXIS_A_Y, 0.0),
+                JoystickEvent::Button(BUTTON_A1, false),
+                JoystickEvent::Button(BUTTON_A2, false),
+            ]);
+        }
+        self.keys_active
+    }
+
+    /// Handle a host key. Returns true if the key was bound to the joystick and shouldn't
+    /// go to the emulated keyboard.
+    pub fn key(&mut self, keycode: VirtualKeyCode, pressed: bool) -> bool {
+        if !self.keys_active {
+            return false
+        }
+        let arrow = match keycode {
+            VirtualKeyCode::Left => 0,
+            VirtualKeyCode::Right => 1,
+            VirtualKeyCode::Up => 2,
+            VirtualKeyCode::Down => 3,
+            VirtualKeyCode::Z => {
+                self.events.push_back(JoystickEvent::Button(BUTTON_A1, pressed));
+                return true
+            }
+            VirtualKeyCode::X => {
+                self.events.push_back(JoystickEvent::Button(BUTTON_A2, pressed));
+                return true
+            }
+            _ => return false
+        };
+        self.arrows[arrow] = pressed;
+        let deflection = |negative: bool, positive: bool| (positive as i32 - negative as i32) as f32;
+        self.events.push_back(JoystickEvent::Axis(AXIS_A_X, deflection(self.arrows[0], self.arrows[1])));
+        self.events.push_back(JoystickEvent::Axis(AXIS_A_Y, deflection(self.arrows[2], self.arrows[3])));
+        true
+    }
+
+    pub fn mouse_motion(&mut self, dx: f64, dy: f64) {
+        for (i, delta) in [dx, dy].into_iter().enumerate() {
+            let position = &mut self.mouse_position[i];
+            *position = (*position + delta as f32 * 2.0 / MOUSE_TRAVEL).clamp(-1.0, 1.0);
+            self.events.push_back(JoystickEvent::Axis(AXIS_A_X + i, *position));
+        }
+    }
+
+    pub fn mouse_button(&mut self, button: MouseButton, pressed: bool) {
+        let button = match button {
+            MouseButton::Left => BUTTON_A1,
+            MouseButton::Right => BUTTON_A2,
+        };
+        self.events.push_back(JoystickEvent::Button(button, pressed));
+    }
+
+    /// Read input from host gamepads.
+    #[cfg(feature = "gilrs")]
+    fn poll_gamepad(&mut self) {
+        use gilrs::{Axis, Button, EventType};
+
+        let gilrs = match &mut self.gilrs {
+            Some(gilrs) => gilrs,
+            None => return
+        };
+        while let Some(gilrs::Event { event, .. }) = gilrs.next_event() {
+            let joystick_event = match event {
+                // Host sticks read positive upwards; joysticks read low values upwards
+                EventType::AxisChanged(Axis::LeftStickX, value, _) => Some(JoystickEvent::Axis(AXIS_A_X, value)),
+                EventType::AxisChanged(Axis::LeftStickY, value, _) => Some(JoystickEvent::Axis(AXIS_A_Y, -value)),
+                EventType::AxisChanged(Axis::RightStickX, value, _) => Some(JoystickEvent::Axis(AXIS_B_X, value)),
+                EventType::AxisChanged(Axis::RightStickY, value, _) => Some(JoystickEvent::Axis(AXIS_B_Y, -value)),
+                EventType::ButtonPressed(button, _) | EventType::ButtonReleased(button, _) => {
+                    let pressed = matches!(event, EventType::ButtonPressed(..));
+                    match button {
+                        Button::South => Some(JoystickEvent::Button(BUTTON_A1, pressed)),
+                        Button::East => Some(JoystickEvent::Button(BUTTON_A2, pressed)),
+                        Button::West => Some(JoystickEvent::Button(BUTTON_B1, pressed)),
+                        Button::North => Some(JoystickEvent::Button(BUTTON_B2, pressed)),
+                        Button::DPadLeft => Some(JoystickEvent::Axis(AXIS_A_X, if pressed { -1.0 } else { 0.0 })),
+                        Button::DPadRight => Some(JoystickEvent::Axis(AXIS_A_X, if pressed { 1.0 } else { 0.0 })),
+                        Button::DPadUp => Some(JoystickEvent::Axis(AXIS_A_Y, if pressed { -1.0 } else { 0.0 })),
+                        Button::DPadDown => Some(JoystickEvent::Axis(AXIS_A_Y, if pressed { 1.0 } else { 0.0 })),
+                        _ => None
+                    }
+                }
+                EventType::Connected => {
+                    log::info!("Gamepad connected");
+                    None
+                }
+                EventType::Disconnected => {
+                    log::info!("Gamepad disconnected");
+                    None
+                }
+                _ => None
+            };
+            if let Some(joystick_event) = joystick_event {
+                self.events.push_back(joystick_event);
+            }
+        }
+    }
+
+    /// Get the next joystick event, polling host gamepads first.
+    pub fn get_event(&mut self) -> Option<JoystickEvent> {
+        #[cfg(feature = "gilrs")]
+        if self.events.is_empty() {
+            self.poll_gamepad();
+        }
+        self.events.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_keyboard_binding() {
+        let mut binding = JoystickBinding::new(JoystickSource::Keyboard);
+        assert!(!binding.key(VirtualKeyCode::Left, true));
+
+        assert!(binding.toggle_keys());
+        assert!(binding.key(VirtualKeyCode::Left, true));
+        assert!(binding.key(VirtualKeyCode::Down, true));
+        assert!(!binding.key(VirtualKeyCode::A, true));
+        let events: Vec<JoystickEvent> = std::iter::from_fn(|| binding.get_event()).collect();
+        assert_eq!(events[events.len() - 2..], [
+            JoystickEvent::Axis(AXIS_A_X, -1.0),
+            JoystickEvent::Axis(AXIS_A_Y, 1.0),
+        ]);
+    }
+}
@@ -0,0 +1,257 @@
+/*
+    keyboard.rs
+    Implement the IBM 83-key PC/XT keyboard
+
+    The keyboard has its own 8048 microcontroller. It scans keys into a 16 byte buffer,
+    generates typematic repeats for a held key, and shifts bytes serially to the
+    motherboard. It may only send when the motherboard is ready: the clock line must not be
+    held low, and the previous byte must have been acknowledged by pulsing the clear bit
+    on PPI port B. Holding the clock line low long enough resets the keyboard, after which
+    it sends 0xAA.
+*/
+
+use std::collections::VecDeque;
+
+use crate::cpu::CPU_MHZ;
+
+pub const KB_RESET_CYCLES: u32 = 47700;
+pub const KB_RESET_CYCLE_DELAY: u32 = 50; // Cycles until reset byte is sent after reset
+
+pub const KB_BUFFER_SIZE: usize = 16;
+pub const KB_OVERRUN_CODE: u8 = 0xFF;
+pub const KB_RESET_CODE: u8 = 0xAA;
+pub const KB_KEY_COUNT: usize = 0x55; // Scancodes 0x01-0x53, plus 0x54 (SysRq on 84-key keyboards)
+pub const KB_BREAK_BIT: u8 = 0x80;
+
+// The keyboard clock runs at roughly 10kHz. Sending a byte takes nine clocks for the start
+// bit and eight data bits.
+const KB_BIT_MICROS: f64 = 100.0;
+const KB_BITS_PER_BYTE: f64 = 9.0;
+// XT keyboard typematic delay is half a second, repeating at about ten characters a second
+const KB_TYPEMATIC_DELAY_MICROS: f64 = 500_000.0;
+const KB_TYPEMATIC_RATE_MICROS: f64 = 92_000.0;
+
+const fn micros_to_cycles(us: f64) -> u32 {
+    (us * CPU_MHZ) as u32
+}
+
+const KB_BYTE_CYCLES: u32 = micros_to_cycles(KB_BIT_MICROS * KB_BITS_PER_BYTE);
+const KB_TYPEMATIC_DELAY_CYCLES: u32 = micros_to_cycles(KB_TYPEMATIC_DELAY_MICROS);
+const KB_TYPEMATIC_RATE_CYCLES: u32 = micros_to_cycles(KB_TYPEMATIC_RATE_MICROS);
+
+pub struct Keyboard {
+    keys_down: [bool; KB_KEY_COUNT],
+    buffer: VecDeque<u8>,
+    typematic_key: Option<u8>,
+    typematic_cycles: u32,
+    shift_cycles: Option<u32>,
+    clock_low_cycles: u32,
+    clock_was_low: bool,
+    self_test_cycles: Option<u32>,
+    reset_count: u32,
+}
+
+impl Keyboard {
+    pub fn new() -> Self {
+        Self {
+            keys_down: [false; KB_KEY_COUNT],
+            buffer: VecDeque::with_capacity(KB_BUFFER_SIZE),
+            typematic_key: None,
+            typematic_cycles: 0,
+            shift_cycles: None,
+            clock_low_cycles: 0,
+            clock_was_low: false,
+            self_test_cycles: None,
+            reset_count: 0,
+        }
+    }
+
+    pub fn get_reset_count(&self) -> u32 {
+        self.reset_count
+    }
+
+    pub fn get_buffer_len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Queue a byte for transmission. When the buffer is about to fill, the last slot
+    /// receives an overrun code and further bytes are lost until there is room.
+    fn enqueue(&mut self, byte: u8) {
+        match self.buffer.len() {
+            n if n < KB_BUFFER_SIZE - 1 => self.buffer.push_back(byte),
+            n if n == KB_BUFFER_SIZE - 1 => self.buffer.push_back(KB_OVERRUN_CODE),
+            _ => {}
+        }
+    }
+
+    /// A key was pressed on the host. Repeated presses of a key already down (host
+    /// auto-repeat) are ignored; the keyboard generates its own typematic repeats.
+    pub fn key_down(&mut self, code: u8) {
+        let key = code as usize;
+        if key >= KB_KEY_COUNT || self.keys_down[key] {
+            return
+        }
+        self.keys_down[key] = true;
+        self.enqueue(code);
+
+        // The most recently pressed key becomes the typematic key
+        self.typematic_key = Some(code);
+        self.typematic_cycles = KB_TYPEMATIC_DELAY_CYCLES;
+    }
+
+    pub fn key_up(&mut self, code: u8) {
+        let key = code as usize;
+        if key >= KB_KEY_COUNT || !self.keys_down[key] {
+            return
+        }
+        self.keys_down[key] = false;
+        self.enqueue(code | KB_BREAK_BIT);
+
+        if self.typematic_key == Some(code) {
+            self.typematic_key = None;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.keys_down = [false; KB_KEY_COUNT];
+        self.buffer.clear();
+        self.typematic_key = None;
+        self.shift_cycles = None;
+        self.self_test_cycles = Some(KB_RESET_CYCLE_DELAY);
+        self.reset_count += 1;
+    }
+
+    /// Run the keyboard for the specified number of cycles. `clock_low` is the state of the
+    /// clock line as driven by the motherboard, and `ready` indicates the motherboard's shift
+    /// register is clear to receive. Returns a byte if one finished shifting in.
+    pub fn run(&mut self, clock_low: bool, ready: bool, cycles: u32) -> Option<u8> {
+
+        // Holding the clock line low for long enough resets the keyboard once it is released
+        if clock_low {
+            if !self.clock_was_low {
+                self.clock_low_cycles = 0;
+            }
+            self.clock_low_cycles = self.clock_low_cycles.saturating_add(cycles);
+        }
+        else if self.clock_was_low && self.clock_low_cycles > KB_RESET_CYCLES {
+            log::trace!("Keyboard: reset");
+            self.reset();
+        }
+        self.clock_was_low = clock_low;
+
+        if let Some(test_cycles) = self.self_test_cycles {
+            if test_cycles > cycles {
+                self.self_test_cycles = Some(test_cycles - cycles);
+            }
+            else {
+                self.self_test_cycles = None;
+                self.buffer.push_back(KB_RESET_CODE);
+            }
+            return None
+        }
+
+        // Generate typematic repeats
+        if let Some(code) = self.typematic_key {
+            if self.typematic_cycles > cycles {
+                self.typematic_cycles -= cycles;
+            }
+            else {
+                self.typematic_cycles = KB_TYPEMATIC_RATE_CYCLES;
+                self.enqueue(code);
+            }
+        }
+
+        // A transmission is aborted if the motherboard pulls the clock line low. The byte
+        // stays in the buffer to be sent again.
+        if clock_low {
+            self.shift_cycles = None;
+            return None
+        }
+
+        match self.shift_cycles {
+            None => {
+                if ready && !self.buffer.is_empty() {
+                    self.shift_cycles = Some(KB_BYTE_CYCLES);
+                }
+                None
+            }
+            Some(shift_cycles) if shift_cycles > cycles => {
+                self.shift_cycles = Some(shift_cycles - cycles);
+                None
+            }
+            Some(_) => {
+                self.shift_cycles = None;
+                self.buffer.pop_front()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Run until a byte is received, acknowledging it immediately.
+    fn receive(kb: &mut Keyboard, max_cycles: u32) -> Option<u8> {
+        let mut elapsed = 0;
+        while elapsed < max_cycles {
+            if let Some(byte) = kb.run(false, true, 10) {
+                return Some(byte)
+            }
+            elapsed += 10;
+        }
+        None
+    }
+
+    #[test]
+    pub fn test_serial_timing() {
+        let mut kb = Keyboard::new();
+
+        kb.key_down(0x1E);
+        kb.key_up(0x1E);
+
+        // A byte should take about 1ms to shift in
+        assert_eq!(receive(&mut kb, KB_BYTE_CYCLES / 2), None);
+        assert_eq!(receive(&mut kb, KB_BYTE_CYCLES), Some(0x1E));
+
+        // Not ready: nothing is sent
+        for _ in 0..1000 {
+            assert_eq!(kb.run(false, false, 10), None);
+        }
+        assert_eq!(receive(&mut kb, KB_BYTE_CYCLES * 2), Some(0x9E));
+    }
+
+    #[test]
+    pub fn test_typematic_and_overrun() {
+        let mut kb = Keyboard::new();
+
+        kb.key_down(0x10);
+        // Host auto-repeat is ignored
+        kb.key_down(0x10);
+        assert_eq!(kb.get_buffer_len(), 1);
+
+        // Hold the key without acknowledging anything. The buffer fills with repeats and
+        // finally an overrun code.
+        let mut elapsed = 0;
+        while elapsed < KB_TYPEMATIC_DELAY_CYCLES + KB_TYPEMATIC_RATE_CYCLES * 20 {
+            kb.run(false, false, 100);
+            elapsed += 100;
+        }
+        assert_eq!(kb.get_buffer_len(), KB_BUFFER_SIZE);
+        assert_eq!(kb.buffer[KB_BUFFER_SIZE - 2], 0x10);
+        assert_eq!(kb.buffer[KB_BUFFER_SIZE - 1], KB_OVERRUN_CODE);
+    }
+
+    #[test]
+    pub fn test_reset() {
+        let mut kb = Keyboard::new();
+
+        kb.key_down(0x10);
+        for _ in 0..(KB_RESET_CYCLES / 100 + 1) {
+            kb.run(true, true, 100);
+        }
+        kb.run(false, true, 10);
+        assert_eq!(kb.get_reset_count(), 1);
+        assert_eq!(receive(&mut kb, KB_BYTE_CYCLES * 2), Some(KB_RESET_CODE));
+    }
+}
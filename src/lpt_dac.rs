@@ -0,0 +1,161 @@
+/*
+    lpt_dac.rs
+    Implement 8-bit DACs attached to the parallel port
+
+    The Covox Speech Thing is a resistor ladder on the data lines. Its output follows the
+    data port directly, so software plays samples by writing them at the sample rate.
+
+    The Disney Sound Source adds a 16 byte FIFO that the DAC drains at a fixed 7kHz.
+    Software writes a byte to the data port and clocks it into the FIFO by pulsing the
+    SELECT IN control line. The ACK status line reports when the FIFO is full, so
+    software polls it to keep the FIFO topped up.
+
+    Both output unsigned samples centered on 0x80, which are mixed with the PC speaker.
+*/
+
+use std::collections::VecDeque;
+
+use crate::cpu::CPU_MHZ;
+use crate::parallel::{
+    ParallelDevice, CONTROL_SELECT_IN, STATUS_NO_ACK, STATUS_NO_ERROR, STATUS_PAPER_OUT,
+    STATUS_SELECTED,
+};
+
+const SOUND_SOURCE_FIFO_LEN: usize = 16;
+const SOUND_SOURCE_RATE_HZ: f64 = 7000.0;
+const SOUND_SOURCE_SAMPLE_CYCLES: f64 = CPU_MHZ * 1_000_000.0 / SOUND_SOURCE_RATE_HZ;
+
+fn sample_level(sample: u8) -> f32 {
+    (sample as f32 - 128.0) / 128.0
+}
+
+pub struct Covox {
+    level: u8,
+}
+
+impl Covox {
+    pub fn new() -> Self {
+        Self {
+            level: 0x80,
+        }
+    }
+}
+
+impl ParallelDevice for Covox {
+    fn write_data(&mut self, data: u8) {
+        self.level = data;
+    }
+
+    fn write_control(&mut self, _old: u8, _control: u8, _data: u8) {}
+
+    /// Nothing drives the status lines, which read as an offline printer.
+    fn status(&self) -> u8 {
+        STATUS_NO_ACK | STATUS_PAPER_OUT | STATUS_SELECTED | STATUS_NO_ERROR
+    }
+
+    fn run(&mut self, _cycles: u32) {}
+
+    fn reset(&mut self) {
+        self.level = 0x80;
+    }
+
+    fn audio_level(&self) -> Option<f32> {
+        Some(sample_level(self.level))
+    }
+}
+
+pub struct SoundSource {
+    fifo: VecDeque<u8>,
+    level: u8,
+    sample_cycles: f64,
+}
+
+impl SoundSource {
+    pub fn new() -> Self {
+        Self {
+            fifo: VecDeque::with_capacity(SOUND_SOURCE_FIFO_LEN),
+            level: 0x80,
+            sample_cycles: 0.0,
+        }
+    }
+
+    fn fifo_full(&self) -> bool {
+        self.fifo.len() >= SOUND_SOURCE_FIFO_LEN
+    }
+}
+
+impl ParallelDevice for SoundSource {
+    fn write_control(&mut self, old: u8, control: u8, data: u8) {
+        // The byte on the data lines is clocked into the FIFO when SELECT IN is released.
+        // Bytes written while the FIFO is full are lost.
+        if old & CONTROL_SELECT_IN != 0 && control & CONTROL_SELECT_IN == 0 && !self.fifo_full() {
+            self.fifo.push_back(data);
+        }
+    }
+
+    fn status(&self) -> u8 {
+        let mut status = STATUS_SELECTED | STATUS_NO_ERROR;
+        if self.fifo_full() {
+            status |= STATUS_NO_ACK;
+        }
+        status
+    }
+
+    fn ack_interrupt(&self) -> bool {
+        false
+    }
+
+    fn run(&mut self, cycles: u32) {
+        self.sample_cycles += cycles as f64;
+        while self.sample_cycles >= SOUND_SOURCE_SAMPLE_CYCLES {
+            self.sample_cycles -= SOUND_SOURCE_SAMPLE_CYCLES;
+            // The DAC holds its last sample when the FIFO runs dry
+            if let Some(sample) = self.fifo.pop_front() {
+                self.level = sample;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.fifo.clear();
+        self.level = 0x80;
+        self.sample_cycles = 0.0;
+    }
+
+    fn audio_level(&self) -> Option<f32> {
+        Some(sample_level(self.level))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parallel::CONTROL_NOT_INIT;
+
+    #[test]
+    pub fn test_sound_source_fifo() {
+        let mut dss = SoundSource::new();
+        let idle = CONTROL_NOT_INIT;
+        let select = CONTROL_NOT_INIT | CONTROL_SELECT_IN;
+
+        // Overfill the FIFO. Only the first 16 bytes are kept.
+        for i in 0..20 {
+            dss.write_control(idle, select, 0);
+            dss.write_control(select, idle, 0xC0 + i);
+        }
+        assert!(dss.status() & STATUS_NO_ACK != 0);
+        assert_eq!(dss.audio_level(), Some(0.0));
+
+        // Samples play at 7kHz
+        dss.run(SOUND_SOURCE_SAMPLE_CYCLES as u32 + 1);
+        assert_eq!(dss.audio_level(), Some(sample_level(0xC0)));
+        assert!(dss.status() & STATUS_NO_ACK == 0);
+
+        dss.run((SOUND_SOURCE_SAMPLE_CYCLES * 20.0) as u32);
+        assert_eq!(dss.audio_level(), Some(sample_level(0xCF)));
+
+        let mut covox = Covox::new();
+        covox.write_data(0x00);
+        assert_eq!(covox.audio_level(), Some(-1.0));
+    }
+}
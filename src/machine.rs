@@ -9,24 +9,35 @@
 use log;
 
 use std::{
-    rc::Rc,
-    cell::{Cell, RefCell}, collections::VecDeque
+    cell::Cell,
+    collections::VecDeque,
+    sync::{Arc, Mutex}
 };
 
 use crate::{
     bus::BusInterface,
     cga::{self, CGACard},
-    cpu::{CpuType, Cpu, Flag, CpuError},
-    dma::{self, DMAControllerStringState},
-    fdc::{self, FloppyController},
+    cpu::{CpuType, Cpu, Flag, CpuError, CPU_MHZ},
+    dma::{self, DMAControllerStringState, DmaDevice, DMA_CHANNEL_COUNT},
+    fdc::{self, DriveType, FloppyController, FloppyStringState},
     hdc::{self, HardDiskController},
     floppy_manager::{FloppyManager},
     vhd_manager::{VHDManager},
     io::{IoHandler, IoBusInterface},
+    input,
     pit::{self, PitStringState},
     pic::{self, PicStringState},
     ppi::{self, PpiStringState},
+    cassette::CassetteStringState,
+    mouse::{Mouse, MouseButton},
+    adlib::{self, AdLib},
+    gameport::{self, GamePort, JoystickEvent},
+    parallel::{self, ParallelConfig, ParallelPort},
+    serial::{self, SerialPortController, SerialStringState},
+    rtc::{self, RtcCard, RtcConfig},
+    serial_host::{SerialAttachment, SerialHost},
     rom_manager::RomManager,
+    sound::{self, Speaker},
 };
 
 pub const NUM_FLOPPIES: u32 = 2;
@@ -34,6 +45,20 @@ pub const NUM_HDDS: u32 = 2;
 
 pub const MAX_MEMORY_ADDRESS: usize = 0xFFFFF;
 
+// BIOS data area keyboard buffer head and tail pointers. The buffer holds 16 words.
+const BDA_KB_BUFFER_HEAD: usize = 0x41A;
+const BDA_KB_BUFFER_TAIL: usize = 0x41C;
+const BDA_KB_BUFFER_BYTES: u16 = 32;
+
+// Pasted keys are held back while the BIOS keyboard buffer has this many keys in it
+const PASTE_BIOS_BUFFER_LIMIT: u16 = 8;
+// Minimum time between pasted key events (5ms)
+const PASTE_KEY_CYCLES: u32 = (CPU_MHZ * 5000.0) as u32;
+
+// BIOS keyboard flags. Bit 5 is set when NumLock is on.
+const BDA_KB_FLAGS: usize = 0x417;
+const BDA_KB_FLAG_NUMLOCK: u8 = 0b0010_0000;
+
 #[allow(non_camel_case_types)]
 #[derive(Copy, Clone, Debug)]
 pub enum MachineType {
@@ -57,6 +82,15 @@ pub enum ExecutionState {
     Running,
 }
 
+/// Commands sent by the GUI to change the execution state of the machine.
+#[derive(Copy, Clone, Debug)]
+pub enum ExecutionOperation {
+    Pause,
+    Step,
+    Run,
+    Reset
+}
+
 pub struct ExecutionControl {
     state: ExecutionState,
     do_step: Cell<bool>,
@@ -105,6 +139,15 @@ impl ExecutionControl {
     pub fn do_reset(&mut self) {
         self.do_reset.set(true)
     }
+
+    pub fn do_operation(&mut self, op: ExecutionOperation) {
+        match op {
+            ExecutionOperation::Pause => self.set_state(ExecutionState::Paused),
+            ExecutionOperation::Step => self.do_step(),
+            ExecutionOperation::Run => self.set_state(ExecutionState::Running),
+            ExecutionOperation::Reset => self.do_reset()
+        }
+    }
 }
 pub struct Machine {
     machine_type: MachineType,
@@ -114,27 +157,60 @@ pub struct Machine {
     bus: BusInterface,
     io_bus: IoBusInterface,
     cpu: Cpu,
-    dma_controller: Rc<RefCell<dma::DMAController>>,
-    pit: Rc<RefCell<pit::Pit>>,
-    pic: Rc<RefCell<pic::Pic>>,
-    ppi: Rc<RefCell<ppi::Ppi>>,
-    cga: Rc<RefCell<cga::CGACard>>,
-    fdc: Rc<RefCell<FloppyController>>,
-    hdc: Rc<RefCell<HardDiskController>>,
-    kb_buf: VecDeque<u8>,
+    dma_controller: Arc<Mutex<dma::DMAController>>,
+    pit: Arc<Mutex<pit::Pit>>,
+    pic: Arc<Mutex<pic::Pic>>,
+    ppi: Arc<Mutex<ppi::Ppi>>,
+    cga: Arc<Mutex<cga::CGACard>>,
+    fdc: Arc<Mutex<FloppyController>>,
+    hdc: Arc<Mutex<HardDiskController>>,
+    serial: Arc<Mutex<SerialPortController>>,
+    serial_hosts: [Option<SerialHost>; serial::SERIAL_PORT_COUNT],
+    serial_attachments: [String; serial::SERIAL_PORT_COUNT],
+    mouse: Mouse,
+    mouse_port: Option<usize>,
+    parallel: Option<Arc<Mutex<ParallelPort>>>,
+    adlib: Option<Arc<Mutex<AdLib>>>,
+    gameport: Option<Arc<Mutex<GamePort>>>,
+    rtc: Option<Arc<Mutex<RtcCard>>>,
+    speaker: Speaker,
+    paste_queue: VecDeque<(u8, bool)>,
+    paste_cycles: u32,
+    nav_shifted_keys: Vec<u8>,
     error: bool,
     error_str: String,
     cpu_cycles: u64,
 }
 
+/// Options for the devices installed in the machine.
+pub struct DeviceConfig {
+    /// What each serial port is attached to.
+    pub serial: [SerialAttachment; serial::SERIAL_PORT_COUNT],
+    /// The parallel port and the device attached to it.
+    pub parallel: ParallelConfig,
+    /// Whether an AdLib card is installed.
+    pub adlib: bool,
+    /// Whether a second PIC is cascaded from the first, as on some XT clones.
+    pub slave_pic: bool,
+    /// Which joysticks are connected to the game port, or None for no game port.
+    pub joysticks: Option<[bool; 2]>,
+    /// The clock card.
+    pub rtc: RtcConfig,
+    /// The type of each floppy drive.
+    pub floppy_types: [DriveType; 2],
+}
+
 impl Machine {
     pub fn new(
         machine_type: MachineType,
         video_type: VideoType,
         rom_manager: RomManager,
         floppy_manager: FloppyManager,
+        devices: DeviceConfig,
         ) -> Machine {
 
+        let DeviceConfig { serial: serial_config, parallel: parallel_config, adlib: adlib_enabled, slave_pic, joysticks, rtc: rtc_config, floppy_types } = devices;
+
         let mut bus = BusInterface::new();
         let mut io_bus = IoBusInterface::new();
         
@@ -144,14 +220,25 @@ impl Machine {
         // Attach IO Device handlers
 
         // Intel 8259 Programmable Interrupt Controller
-        let mut pic = Rc::new(RefCell::new(pic::Pic::new()));
+        // A second PIC may be cascaded through IRQ2, as on AT-class machines.
+        let pic = if slave_pic {
+            pic::Pic::new_cascaded()
+        }
+        else {
+            pic::Pic::new()
+        };
+        let mut pic = Arc::new(Mutex::new(pic));
         io_bus.register_port_handler(pic::PIC_COMMAND_PORT, IoHandler::new(pic.clone()));
         io_bus.register_port_handler(pic::PIC_DATA_PORT, IoHandler::new(pic.clone()));
+        if pic.lock().unwrap().has_slave() {
+            io_bus.register_port_handler(pic::PIC_SLAVE_COMMAND_PORT, IoHandler::new(pic.clone()));
+            io_bus.register_port_handler(pic::PIC_SLAVE_DATA_PORT, IoHandler::new(pic.clone()));
+        }
 
         // Intel 8255 Programmable Peripheral Interface
         // PPI Needs to know machine_type as DIP switches and thus PPI behavior are different 
         // for PC vs XT
-        let mut ppi = Rc::new(RefCell::new(ppi::Ppi::new(machine_type, video_type)));
+        let mut ppi = Arc::new(Mutex::new(ppi::Ppi::new(machine_type, video_type)));
         io_bus.register_port_handler(ppi::PPI_PORT_A, IoHandler::new(ppi.clone()));
         io_bus.register_port_handler(ppi::PPI_PORT_B, IoHandler::new(ppi.clone()));
         io_bus.register_port_handler(ppi::PPI_PORT_C, IoHandler::new(ppi.clone()));
@@ -159,7 +246,7 @@ impl Machine {
         
         // Intel 8253 Programmable Interval Timer
         // Ports 0x40,41,42 Data ports, 0x43 Control port
-        let mut pit = Rc::new(RefCell::new(pit::ProgrammableIntervalTimer::new()));
+        let mut pit = Arc::new(Mutex::new(pit::ProgrammableIntervalTimer::new()));
         io_bus.register_port_handler(pit::PIT_COMMAND_REGISTER, IoHandler::new(pit.clone()));
         io_bus.register_port_handler(pit::PIT_CHANNEL_0_DATA_PORT, IoHandler::new(pit.clone()));
         io_bus.register_port_handler(pit::PIT_CHANNEL_1_DATA_PORT, IoHandler::new(pit.clone()));
@@ -167,7 +254,7 @@ impl Machine {
 
         // DMA Controller: 
         // Intel 8237 DMA Controller
-        let mut dma = Rc::new(RefCell::new(dma::DMAController::new()));
+        let mut dma = Arc::new(Mutex::new(dma::DMAController::new()));
 
         io_bus.register_port_handler(dma::DMA_CHANNEL_0_ADDR_PORT, IoHandler::new(dma.clone()));
         io_bus.register_port_handler(dma::DMA_CHANNEL_0_WC_PORT, IoHandler::new(dma.clone()));
@@ -193,20 +280,91 @@ impl Machine {
         io_bus.register_port_handler(dma::DMA_CHANNEL_3_PAGE_REGISTER, IoHandler::new(dma.clone()));
 
         // Floppy Controller:
-        let mut fdc = Rc::new(RefCell::new(fdc::FloppyController::new()));
+        let mut fdc = Arc::new(Mutex::new(fdc::FloppyController::new()));
         io_bus.register_port_handler(fdc::FDC_DIGITAL_OUTPUT_REGISTER, IoHandler::new(fdc.clone()));
         io_bus.register_port_handler(fdc::FDC_STATUS_REGISTER, IoHandler::new(fdc.clone()));
         io_bus.register_port_handler(fdc::FDC_DATA_REGISTER, IoHandler::new(fdc.clone()));
+        io_bus.register_port_handler(fdc::FDC_CONFIG_CONTROL_REGISTER, IoHandler::new(fdc.clone()));
+        for (drive_select, drive_type) in floppy_types.into_iter().enumerate() {
+            fdc.lock().unwrap().set_drive_type(drive_select, drive_type);
+        }
 
         // Hard Disk Controller:  (Only functions if the required rom is loaded)
-        let mut hdc = Rc::new(RefCell::new(hdc::HardDiskController::new(dma.clone(), hdc::DRIVE_TYPE2_DIP)));
+        let mut hdc = Arc::new(Mutex::new(hdc::HardDiskController::new(dma.clone(), hdc::DRIVE_TYPE2_DIP)));
         io_bus.register_port_handler(hdc::HDC_DATA_REGISTER, IoHandler::new(hdc.clone()));
         io_bus.register_port_handler(hdc::HDC_STATUS_REGISTER, IoHandler::new(hdc.clone()));
         io_bus.register_port_handler(hdc::HDC_READ_DIP_REGISTER, IoHandler::new(hdc.clone()));
         io_bus.register_port_handler(hdc::HDC_WRITE_MASK_REGISTER, IoHandler::new(hdc.clone()));
 
+        // Serial ports: 8250 UARTs for COM1 and COM2
+        let serial = Arc::new(Mutex::new(SerialPortController::new()));
+        for base in [serial::SERIAL1_BASE_PORT, serial::SERIAL2_BASE_PORT] {
+            for port in base..base + serial::SERIAL_REGISTER_COUNT {
+                io_bus.register_port_handler(port, IoHandler::new(serial.clone()));
+            }
+        }
+
+        // Attach the mouse or host connections to the serial ports. A port whose host
+        // connection fails to open is left unattached.
+        let mut serial_hosts = [None, None];
+        let mut serial_attachments = [String::from("none"), String::from("none")];
+        let mut mouse_port = None;
+        for (i, attachment) in serial_config.iter().enumerate() {
+            match attachment {
+                SerialAttachment::Mouse if mouse_port.is_none() => {
+                    mouse_port = Some(i);
+                    serial_attachments[i] = String::from("mouse");
+                }
+                SerialAttachment::Mouse => {
+                    log::warn!("Serial: only one mouse can be attached, COM{} left unattached", i + 1);
+                }
+                _ => match SerialHost::open(attachment) {
+                    Ok(Some(host)) => {
+                        log::info!("Serial: COM{} attached to {}", i + 1, host.description());
+                        serial_attachments[i] = host.description();
+                        serial_hosts[i] = Some(host);
+                    }
+                    Ok(None) => {}
+                    Err(e) => log::error!("Serial: couldn't attach COM{}: {}", i + 1, e)
+                }
+            }
+        }
+
+        // Parallel printer adapter
+        let parallel = parallel_config.base.map(|base| {
+            let parallel = Arc::new(Mutex::new(ParallelPort::new(&parallel_config, base)));
+            for port in base..base + parallel::PARALLEL_REGISTER_COUNT {
+                io_bus.register_port_handler(port, IoHandler::new(parallel.clone()));
+            }
+            parallel
+        });
+
+        // AdLib Music Synthesizer Card
+        let adlib = adlib_enabled.then(|| {
+            let adlib = Arc::new(Mutex::new(AdLib::new()));
+            io_bus.register_port_handler(adlib::ADLIB_ADDRESS_PORT, IoHandler::new(adlib.clone()));
+            io_bus.register_port_handler(adlib::ADLIB_DATA_PORT, IoHandler::new(adlib.clone()));
+            adlib
+        });
+
+        // Game control adapter, with the given joysticks connected
+        let gameport = joysticks.map(|sticks_connected| {
+            let gameport = Arc::new(Mutex::new(GamePort::new(sticks_connected)));
+            io_bus.register_port_handler(gameport::GAMEPORT_PORT, IoHandler::new(gameport.clone()));
+            gameport
+        });
+
+        // Real-time clock card
+        let rtc = rtc_config.base.map(|base| {
+            let rtc = Arc::new(Mutex::new(RtcCard::new(base, rtc_config.start, rtc_config.frozen)));
+            for port in base..base + rtc::RTC_REGISTER_COUNT {
+                io_bus.register_port_handler(port, IoHandler::new(rtc.clone()));
+            }
+            rtc
+        });
+
         // CGA card:
-        let mut cga = Rc::new(RefCell::new(cga::CGACard::new()));
+        let mut cga = Arc::new(Mutex::new(cga::CGACard::new()));
         io_bus.register_port_handler(cga::CRTC_REGISTER_SELECT, IoHandler::new(cga.clone()));
         io_bus.register_port_handler(cga::CRTC_REGISTER, IoHandler::new(cga.clone()));
         io_bus.register_port_handler(cga::CGA_MODE_CONTROL_REGISTER, IoHandler::new(cga.clone()));
@@ -237,7 +395,19 @@ impl Machine {
             cga: cga,
             fdc: fdc,
             hdc: hdc,
-            kb_buf: VecDeque::new(),
+            serial,
+            serial_hosts,
+            serial_attachments,
+            mouse: Mouse::new(),
+            mouse_port,
+            parallel,
+            adlib,
+            gameport,
+            rtc,
+            speaker: Speaker::new(sound::SAMPLE_RATE_DEFAULT),
+            paste_queue: VecDeque::new(),
+            paste_cycles: 0,
+            nav_shifted_keys: Vec::new(),
             error: false,
             error_str: String::new(),
             cpu_cycles: 0
@@ -252,7 +422,7 @@ impl Machine {
         &mut self.bus
     }
 
-    pub fn cga(&self) -> Rc<RefCell<CGACard>> {
+    pub fn cga(&self) -> Arc<Mutex<CGACard>> {
         self.cga.clone()
     }
 
@@ -260,14 +430,18 @@ impl Machine {
         &self.cpu
     }
 
-    pub fn fdc(&self) -> Rc<RefCell<FloppyController>> {
+    pub fn fdc(&self) -> Arc<Mutex<FloppyController>> {
         self.fdc.clone()
     }
 
-    pub fn hdc(&self) -> Rc<RefCell<HardDiskController>> {
+    pub fn hdc(&self) -> Arc<Mutex<HardDiskController>> {
         self.hdc.clone()
     }
 
+    pub fn ppi(&self) -> Arc<Mutex<ppi::Ppi>> {
+        self.ppi.clone()
+    }
+
     pub fn floppy_manager(&self) -> &FloppyManager {
         &self.floppy_manager
     }
@@ -277,27 +451,53 @@ impl Machine {
     }
 
     pub fn pit_cycles(&self) -> u64 {
-        self.pit.borrow().get_cycles()
+        self.pit.lock().unwrap().get_cycles()
+    }
+
+    /// Set the sample rate of the speaker output to match the audio sink.
+    pub fn set_sound_sample_rate(&mut self, sample_rate: u32) {
+        self.speaker.set_sample_rate(sample_rate);
+    }
+
+    /// Move all audio samples produced since the last call into the provided buffer.
+    pub fn drain_sound_samples(&mut self, out: &mut Vec<f32>) {
+        self.speaker.drain_samples(out);
     }
 
     pub fn pit_state(&self) -> PitStringState {
-        let pit = self.pit.borrow();
+        let pit = self.pit.lock().unwrap();
         let pit_data = pit.get_string_repr();
         pit_data
     }
 
     pub fn pic_state(&self) -> PicStringState {
-        let pic = self.pic.borrow();
+        let pic = self.pic.lock().unwrap();
         pic.get_string_state()
     }
 
+    pub fn cassette_state(&self) -> CassetteStringState {
+        self.ppi.lock().unwrap().cassette_mut().get_string_state()
+    }
+
+    pub fn serial_state(&self) -> Vec<SerialStringState> {
+        let mut state = self.serial.lock().unwrap().get_string_state();
+        for (port, attachment) in state.iter_mut().zip(&self.serial_attachments) {
+            port.attachment = attachment.clone();
+        }
+        state
+    }
+
     pub fn ppi_state(&self) -> PpiStringState {
-        let pic = self.ppi.borrow();
+        let pic = self.ppi.lock().unwrap();
         pic.get_string_state()
     }
 
+    pub fn floppy_state(&self) -> Vec<FloppyStringState> {
+        self.fdc.lock().unwrap().get_string_state()
+    }
+
     pub fn dma_state(&self) -> DMAControllerStringState {
-        let dma = self.dma_controller.borrow();
+        let dma = self.dma_controller.lock().unwrap();
         dma.get_string_state()
     }
 
@@ -309,13 +509,96 @@ impl Machine {
     }
 
     pub fn key_press(&mut self, code: u8) {
-
-        self.kb_buf.push_back(code);
+        self.ppi.lock().unwrap().key_down(code);
     }
 
     pub fn key_release(&mut self, code: u8 ) {
-        // HO Bit set converts a scancode into its 'release' code
-        self.kb_buf.push_back(code | 0x80);
+        self.ppi.lock().unwrap().key_up(code);
+    }
+
+    /// Press a dedicated host cursor key. The XT's cursor keys are on the numeric keypad, so
+    /// if the guest has NumLock on, the key is wrapped in a shift press to make it move the
+    /// cursor instead of typing a digit. Shift stays held until all such keys are released.
+    pub fn nav_key_press(&mut self, code: u8) {
+        let numlock = self.bus.read_u8(BDA_KB_FLAGS).map_or(0, |(b, _)| b) & BDA_KB_FLAG_NUMLOCK != 0;
+        if numlock && !self.nav_shifted_keys.contains(&code) {
+            if self.nav_shifted_keys.is_empty() {
+                self.key_press(input::XT_LSHIFT);
+            }
+            self.nav_shifted_keys.push(code);
+        }
+        self.key_press(code);
+    }
+
+    pub fn nav_key_release(&mut self, code: u8) {
+        self.key_release(code);
+        if let Some(pos) = self.nav_shifted_keys.iter().position(|k| *k == code) {
+            self.nav_shifted_keys.remove(pos);
+            if self.nav_shifted_keys.is_empty() {
+                self.key_release(input::XT_LSHIFT);
+            }
+        }
+    }
+
+    /// Move the serial mouse by host mouse motion.
+    pub fn mouse_motion(&mut self, dx: f64, dy: f64) {
+        self.mouse.motion(dx, dy);
+    }
+
+    pub fn mouse_button(&mut self, button: MouseButton, pressed: bool) {
+        self.mouse.button(button, pressed);
+    }
+
+    /// Move a joystick axis or press a joystick button.
+    pub fn joystick_event(&mut self, event: JoystickEvent) {
+        if let Some(gameport) = &self.gameport {
+            gameport.lock().unwrap().handle_event(event);
+        }
+    }
+
+    /// Type host text into the emulated keyboard. Keys are injected gradually as the machine
+    /// runs, so that the BIOS keyboard buffer doesn't overflow.
+    pub fn paste_text(&mut self, text: &str) {
+        self.paste_queue.extend(input::text_to_scancodes(text));
+    }
+
+    /// Return whether pasted text is still being typed.
+    pub fn is_pasting(&self) -> bool {
+        !self.paste_queue.is_empty()
+    }
+
+    /// Return the number of keys waiting in the BIOS keyboard buffer.
+    fn bios_keyboard_buffer_len(&self) -> u16 {
+        let head = self.bus.read_u16(BDA_KB_BUFFER_HEAD).map_or(0, |(w, _)| w);
+        let tail = self.bus.read_u16(BDA_KB_BUFFER_TAIL).map_or(0, |(w, _)| w);
+        (tail.wrapping_sub(head) % BDA_KB_BUFFER_BYTES) / 2
+    }
+
+    /// Inject the next pasted key event once enough time has passed and the BIOS has
+    /// room for another key.
+    fn run_paste(&mut self, cycles: u32) {
+        if self.paste_queue.is_empty() {
+            return
+        }
+        if self.paste_cycles > cycles {
+            self.paste_cycles -= cycles;
+            return
+        }
+        self.paste_cycles = 0;
+
+        let (code, pressed) = self.paste_queue[0];
+        if pressed && self.bios_keyboard_buffer_len() >= PASTE_BIOS_BUFFER_LIMIT {
+            return
+        }
+        self.paste_queue.pop_front();
+
+        if pressed {
+            self.key_press(code);
+        }
+        else {
+            self.key_release(code);
+        }
+        self.paste_cycles = PASTE_KEY_CYCLES;
     }
 
     pub fn reset(&mut self) {
@@ -331,14 +614,27 @@ impl Machine {
         //self.rom_manager.install_patches(&mut self.bus);
 
         // Reset devices
-        self.pit.borrow_mut().reset();
-        self.pic.borrow_mut().reset();
+        self.pit.lock().unwrap().reset();
+        self.pic.lock().unwrap().reset();
+        self.serial.lock().unwrap().reset();
+        if let Some(parallel) = &self.parallel {
+            parallel.lock().unwrap().reset();
+        }
+        if let Some(adlib) = &self.adlib {
+            adlib.lock().unwrap().reset();
+        }
+        if let Some(gameport) = &self.gameport {
+            gameport.lock().unwrap().reset();
+        }
+
+        // Stop typing into the rebooting machine
+        self.paste_queue.clear();
+        self.paste_cycles = 0;
+        self.nav_shifted_keys.clear();
     }
     
     pub fn run(&mut self, cycle_target: u32, exec_control: &mut ExecutionControl, breakpoint: u32) {
 
-        let mut kb_event_processed = false;
-
         // Was reset requested?
         if exec_control.do_reset.get() {
             self.reset();
@@ -404,7 +700,7 @@ impl Machine {
                 // Check for hardware interrupts if Interrupt Flag is set and not in wait cycle
                 if self.cpu.interrupts_enabled() {
 
-                    let mut pic = self.pic.borrow_mut();
+                    let mut pic = self.pic.lock().unwrap();
                     if pic.query_interrupt_line() {
                         match pic.get_interrupt_vector() {
                             Some(irq) =>  self.cpu.do_hw_interrupt(&mut self.bus, irq),
@@ -413,49 +709,89 @@ impl Machine {
                     }
                 }
 
-                // Process a keyboard event once per frame.
-                // A reasonably fast typist can generate two events in a single 16ms frame, and to the virtual cpu
-                // they then appear to happen instantenously. The PPI has no buffer, so one scancode gets lost. 
-                // 
-                // If we limit keyboard events to once per frame, this avoids this problem. I'm a reasonably
-                // fast typist and this method seems to work fine.
-                if self.kb_buf.len() > 0 && !kb_event_processed {
-
-                    let kb_byte = self.kb_buf.pop_front().unwrap();
-
-                    self.ppi.borrow_mut().send_keyboard(kb_byte);
-                    self.pic.borrow_mut().request_interrupt(1);
-                    kb_event_processed = true;
-                }
+                // Type any pasted text
+                self.run_paste(fake_cycles);
 
                 // Run devices
-                
-                self.dma_controller.borrow_mut().run(&mut self.io_bus);
 
-                // PIT needs PIC to issue timer interrupts, DMA to do DRAM refresh
-                self.pit.borrow_mut().run(
+                // PIT needs PIC to issue timer interrupts, DMA to do DRAM refresh, PPI for the channel #2 
+                // gate and output, and clocks the speaker
+                self.pit.lock().unwrap().run(
                     &mut self.io_bus,
-                    &mut self.bus,
-                    &mut self.pic.borrow_mut(),
-                    &mut self.dma_controller.borrow_mut(),
+                    &mut self.pic.lock().unwrap(),
+                    &mut self.dma_controller.lock().unwrap(),
+                    &mut self.ppi.lock().unwrap(),
+                    &mut self.speaker,
                     fake_cycles);
 
-                self.cga.borrow_mut().run(&mut self.io_bus, 7);
-                self.ppi.borrow_mut().run(&mut self.pic.borrow_mut(), 7);
+                self.cga.lock().unwrap().run(&mut self.io_bus, 7);
+                // PPI runs the keyboard, which needs PIC to issue keyboard interrupts
+                self.ppi.lock().unwrap().run(&mut self.pic.lock().unwrap(), fake_cycles);
                 
-                // FDC needs PIC to issue controller interrupts, DMA to request DMA transfers, and Memory Bus to read/write to via DMA
-                self.fdc.borrow_mut().run(
-                    &mut self.pic.borrow_mut(),
-                    &mut self.dma_controller.borrow_mut(),
-                    &mut self.bus,
+                // Serial ports need PIC to issue interrupts. The mouse and host connections
+                // exchange data with their ports first.
+                {
+                    let mut serial = self.serial.lock().unwrap();
+                    if let Some(port) = self.mouse_port {
+                        self.mouse.run(serial.port_mut(port), fake_cycles);
+                    }
+                    for (i, host) in self.serial_hosts.iter_mut().enumerate() {
+                        if let Some(host) = host {
+                            host.run(serial.port_mut(i), fake_cycles);
+                        }
+                    }
+                    serial.run(&mut self.pic.lock().unwrap(), fake_cycles);
+                }
+
+                // Parallel port needs PIC to issue the printer acknowledge interrupt. A DAC on
+                // the port is mixed with the speaker.
+                if let Some(parallel) = &self.parallel {
+                    let mut parallel = parallel.lock().unwrap();
+                    parallel.run(&mut self.pic.lock().unwrap(), fake_cycles);
+                    if let Some(level) = parallel.audio_level() {
+                        self.speaker.set_dac_level(level);
+                    }
+                }
+
+                // AdLib generates samples as time passes, mixed with the speaker
+                if let Some(adlib) = &self.adlib {
+                    let mut adlib = adlib.lock().unwrap();
+                    adlib.run(fake_cycles);
+                    self.speaker.set_fm_level(adlib.output());
+                }
+
+                // Game port one-shots are timed in CPU cycles
+                if let Some(gameport) = &self.gameport {
+                    gameport.lock().unwrap().run(fake_cycles);
+                }
+
+                // The clock card is battery backed, so keeps running through resets
+                if let Some(rtc) = &self.rtc {
+                    rtc.lock().unwrap().run(fake_cycles);
+                }
+
+                // FDC needs PIC to issue controller interrupts and DMA to request DMA transfers
+                self.fdc.lock().unwrap().run(
+                    &mut self.pic.lock().unwrap(),
+                    &mut self.dma_controller.lock().unwrap(),
+                    fake_cycles);
+
+                // HDC needs PIC to issue controller interrupts and DMA to request DMA transfers
+                self.hdc.lock().unwrap().run(
+                    &mut self.pic.lock().unwrap(),
+                    &mut self.dma_controller.lock().unwrap(),
                     fake_cycles);
 
-                // HDC needs PIC to issue controller interrupts, DMA to request DMA stransfers, and Memory Bus to read/write to via DMA                    
-                self.hdc.borrow_mut().run(
-                    &mut self.pic.borrow_mut(),
-                    &mut self.dma_controller.borrow_mut(),
-                    &mut self.bus,
-                    fake_cycles);                
+                // DMA controller services any requests raised above, transferring data between 
+                // the Memory Bus and the devices attached to its channels
+                {
+                    let mut fdc = self.fdc.lock().unwrap();
+                    let mut hdc = self.hdc.lock().unwrap();
+                    let mut dma_devices: [Option<&mut dyn DmaDevice>; DMA_CHANNEL_COUNT] = 
+                        [None, None, Some(&mut *fdc), Some(&mut *hdc)];
+
+                    self.dma_controller.lock().unwrap().run(&mut self.bus, &mut dma_devices, fake_cycles);
+                }
             }
             // Eventually we want to return per-instruction cycle counts, emulate the effect of PIQ, DMA, wait states, all
             // that good stuff. For now during initial development we're going to assume an average instruction cost of 8** 7
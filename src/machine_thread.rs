@@ -0,0 +1,517 @@
+/*
+    machine_thread.rs
+
+    Run the emulated Machine on its own thread, decoupled from the winit event loop.
+
+    The GUI thread never touches the Machine directly. It sends MachineCommands over a
+    channel (GUI events, execution control, keyboard input) and receives a MachineStatus
+    once per emulated frame with the state needed by any open debug windows.
+    Video frames are published through a double buffer so that the GUI can copy out the
+    most recent complete frame without waiting on emulation.
+    Audio produced during each frame is handed to the sound sink, which is opened on the
+    machine thread since host audio streams can't be moved between threads.
+*/
+
+use std::{
+    ffi::OsString,
+    path::Path,
+    sync::{Arc, Mutex, mpsc::{self, Receiver, Sender, TryRecvError}},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant}
+};
+
+use crate::{
+    arch,
+    byteinterface::ByteInterface,
+    cassette::{self, CassetteStringState, Tape},
+    cpu::CpuStringState,
+    dma::DMAControllerStringState,
+    fdc::FloppyStringState,
+    gui::GuiEvent,
+    hdc::HardDiskFormat,
+    machine::{self, ExecutionControl, ExecutionOperation, ExecutionState, Machine},
+    gameport::JoystickEvent,
+    mouse::MouseButton,
+    pic::PicStringState,
+    pit::PitStringState,
+    ppi::PpiStringState,
+    serial::SerialStringState,
+    sound::{self, SoundConfig},
+    util,
+    vhd::{self, VirtualHardDisk},
+    vhd_manager::VHDManager,
+    video::Video,
+};
+
+/// Messages sent from the GUI thread to the machine thread.
+pub(crate) enum MachineCommand {
+    Gui(GuiEvent),
+    Control(ExecutionOperation),
+    KeyPress(u8),
+    KeyRelease(u8),
+    NavKeyPress(u8),
+    NavKeyRelease(u8),
+    MouseMotion(f64, f64),
+    MouseButton(MouseButton, bool),
+    Joystick(JoystickEvent),
+    SetBreakpoint(u32),
+    SetComposite(bool),
+    SetDebugRequest(DebugRequest),
+    Shutdown
+}
+
+/// Describes which debug displays the GUI currently has open, so that the machine thread
+/// only builds the state the GUI will actually show.
+#[derive(Clone, Default)]
+pub struct DebugRequest {
+    pub memory_address: Option<String>,
+    pub disassembly_address: Option<String>,
+    pub cpu_state: bool,
+    pub pit_state: bool,
+    pub pic_state: bool,
+    pub ppi_state: bool,
+    pub cassette_state: bool,
+    pub floppy_state: bool,
+    pub serial_state: bool,
+    pub dma_state: bool,
+    pub trace: bool,
+    pub call_stack: bool,
+    pub vhd_formats: bool,
+}
+
+/// State published by the machine thread once per emulated frame.
+pub struct MachineStatus {
+    pub exec_state: ExecutionState,
+    pub error: Option<String>,
+    pub cpu_cycles: u64,
+    pub pit_cycles: u64,
+    pub pasting: bool,
+    pub floppy_names: Vec<OsString>,
+    pub vhd_names: Vec<OsString>,
+    pub cassette_names: Vec<OsString>,
+    pub vhd_formats: Option<Vec<HardDiskFormat>>,
+    pub memory_dump: Option<String>,
+    pub disassembly: Option<String>,
+    pub cpu_state: Option<CpuStringState>,
+    pub pit_state: Option<PitStringState>,
+    pub pic_state: Option<PicStringState>,
+    pub ppi_state: Option<PpiStringState>,
+    pub cassette_state: Option<CassetteStringState>,
+    pub floppy_state: Option<Vec<FloppyStringState>>,
+    pub serial_state: Option<Vec<SerialStringState>>,
+    pub dma_state: Option<DMAControllerStringState>,
+    pub trace: Option<String>,
+    pub call_stack: Option<String>,
+}
+
+/// A double buffered video frame. The machine thread renders into its own back buffer and
+/// swaps it with the front buffer when a frame is complete.
+pub struct FrameBuffer {
+    front: Mutex<(Vec<u8>, bool)>,
+}
+
+impl FrameBuffer {
+    pub fn new(size: usize) -> Self {
+        Self {
+            front: Mutex::new((vec![0; size], false)),
+        }
+    }
+
+    /// Swap a completed back buffer with the front buffer. The previous front buffer is
+    /// returned in `back` to be drawn into next.
+    pub fn publish(&self, back: &mut Vec<u8>) {
+        let mut front = self.front.lock().unwrap();
+        std::mem::swap(&mut front.0, back);
+        front.1 = true;
+    }
+
+    /// Copy the front buffer into the provided slice if a new frame has been published
+    /// since the last call. Returns whether a frame was copied.
+    pub fn copy_to(&self, dst: &mut [u8]) -> bool {
+        let mut front = self.front.lock().unwrap();
+        if !front.1 {
+            return false
+        }
+        let len = dst.len().min(front.0.len());
+        dst[..len].copy_from_slice(&front.0[..len]);
+        front.1 = false;
+        true
+    }
+}
+
+/// The GUI thread's handle to a running machine thread.
+pub struct MachineThread {
+    command_tx: Sender<MachineCommand>,
+    status_rx: Receiver<MachineStatus>,
+    frame_buffer: Arc<FrameBuffer>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+impl MachineThread {
+    /// Move the Machine onto a new thread and start running it, targeting `cycles_per_frame`
+    /// emulated cycles every `frame_duration`.
+    pub fn spawn(
+        machine: Machine,
+        vhd_manager: VHDManager,
+        sound_config: SoundConfig,
+        frame_size: usize,
+        cycles_per_frame: u32,
+        frame_duration: Duration) -> Self {
+
+        let (command_tx, command_rx) = mpsc::channel();
+        let (status_tx, status_rx) = mpsc::channel();
+        let frame_buffer = Arc::new(FrameBuffer::new(frame_size));
+
+        let mut runner = MachineRunner {
+            machine,
+            vhd_manager,
+            cassette_names: cassette::scan_dir(cassette::CASSETTE_DIR),
+            video: Video::new(),
+            exec_control: ExecutionControl::new(),
+            breakpoint: 0,
+            composite: false,
+            debug_request: DebugRequest::default(),
+            back_buffer: vec![0; frame_size],
+            frame_buffer: frame_buffer.clone(),
+            sound_config,
+            sound_buffer: Vec::new(),
+            command_rx,
+            status_tx,
+        };
+
+        let join_handle = thread::Builder::new()
+            .name("machine".to_string())
+            .spawn(move || runner.run(cycles_per_frame, frame_duration))
+            .expect("Failed to spawn machine thread");
+
+        Self {
+            command_tx,
+            status_rx,
+            frame_buffer,
+            join_handle: Some(join_handle)
+        }
+    }
+
+    pub(crate) fn send(&self, command: MachineCommand) {
+        if self.command_tx.send(command).is_err() {
+            log::error!("Machine thread is no longer running");
+        }
+    }
+
+    /// Return the most recent status published by the machine thread, discarding any older ones.
+    pub fn latest_status(&self) -> Option<MachineStatus> {
+        let mut latest = None;
+        while let Ok(status) = self.status_rx.try_recv() {
+            latest = Some(status);
+        }
+        latest
+    }
+
+    pub fn frame_buffer(&self) -> &FrameBuffer {
+        &self.frame_buffer
+    }
+
+    /// Stop the machine thread and wait for it to exit.
+    pub fn shutdown(&mut self) {
+        self.send(MachineCommand::Shutdown);
+        if let Some(handle) = self.join_handle.take() {
+            if handle.join().is_err() {
+                log::error!("Machine thread panicked");
+            }
+        }
+    }
+}
+
+/// State owned by the machine thread.
+struct MachineRunner {
+    machine: Machine,
+    vhd_manager: VHDManager,
+    cassette_names: Vec<OsString>,
+    video: Video,
+    exec_control: ExecutionControl,
+    breakpoint: u32,
+    composite: bool,
+    debug_request: DebugRequest,
+    back_buffer: Vec<u8>,
+    frame_buffer: Arc<FrameBuffer>,
+    sound_config: SoundConfig,
+    sound_buffer: Vec<f32>,
+    command_rx: Receiver<MachineCommand>,
+    status_tx: Sender<MachineStatus>,
+}
+
+impl MachineRunner {
+
+    fn run(&mut self, cycles_per_frame: u32, frame_duration: Duration) {
+
+        let mut sound_sink = sound::open_sink(&self.sound_config);
+        if let Some(sink) = &sound_sink {
+            self.machine.set_sound_sample_rate(sink.sample_rate());
+        }
+
+        let mut next_frame = Instant::now();
+
+        loop {
+            // Drain any pending commands before running the next slice
+            loop {
+                match self.command_rx.try_recv() {
+                    Ok(MachineCommand::Shutdown) | Err(TryRecvError::Disconnected) => {
+                        // Don't lose changes to floppies still in the drives
+                        self.machine.fdc().lock().unwrap().flush_all();
+                        return
+                    }
+                    Ok(command) => self.handle_command(command),
+                    Err(TryRecvError::Empty) => break,
+                }
+            }
+
+            self.machine.run(cycles_per_frame, &mut self.exec_control, self.breakpoint);
+
+            // Draw video memory into the back buffer and publish it
+            self.video.draw(&mut self.back_buffer, self.machine.cga(), self.machine.bus(), self.composite);
+            self.frame_buffer.publish(&mut self.back_buffer);
+
+            // Send this frame's audio to the sink. Samples are discarded if we have no sink.
+            self.machine.drain_sound_samples(&mut self.sound_buffer);
+            if let Some(sink) = &mut sound_sink {
+                sink.queue_samples(&self.sound_buffer);
+            }
+            self.sound_buffer.clear();
+
+            // The GUI may have gone away; we'll find out from the command channel
+            let status = self.make_status();
+            let _ = self.status_tx.send(status);
+
+            // Sleep until the next frame is due. If we have fallen more than a frame behind,
+            // don't try to catch up.
+            next_frame += frame_duration;
+            let now = Instant::now();
+            if next_frame > now {
+                thread::sleep(next_frame - now);
+            }
+            else if now - next_frame > frame_duration {
+                next_frame = now;
+            }
+        }
+    }
+
+    fn handle_command(&mut self, command: MachineCommand) {
+        match command {
+            MachineCommand::Gui(event) => self.handle_gui_event(event),
+            MachineCommand::Control(op) => self.exec_control.do_operation(op),
+            MachineCommand::KeyPress(code) => self.machine.key_press(code),
+            MachineCommand::KeyRelease(code) => self.machine.key_release(code),
+            MachineCommand::NavKeyPress(code) => self.machine.nav_key_press(code),
+            MachineCommand::NavKeyRelease(code) => self.machine.nav_key_release(code),
+            MachineCommand::MouseMotion(dx, dy) => self.machine.mouse_motion(dx, dy),
+            MachineCommand::MouseButton(button, pressed) => self.machine.mouse_button(button, pressed),
+            MachineCommand::Joystick(event) => self.machine.joystick_event(event),
+            MachineCommand::SetBreakpoint(addr) => self.breakpoint = addr,
+            MachineCommand::SetComposite(state) => self.composite = state,
+            MachineCommand::SetDebugRequest(request) => self.debug_request = request,
+            MachineCommand::Shutdown => {}
+        }
+    }
+
+    fn handle_gui_event(&mut self, event: GuiEvent) {
+        match event {
+            GuiEvent::CreateVHD(filename, fmt) => {
+                log::info!("Got CreateVHD event: {:?}, {:?}", filename, fmt);
+
+                let vhd_path = Path::new("./hdd").join(filename);
+
+                match vhd::create_vhd(
+                    vhd_path.into_os_string(),
+                    fmt.max_cylinders,
+                    fmt.max_heads,
+                    fmt.max_sectors) {
+
+                    Ok(_) => {
+                        // We don't actually do anything with the newly created file
+
+                        // Rescan dir to show new file in list
+                        if let Err(e) = self.vhd_manager.scan_dir("./hdd") {
+                            log::error!("Error scanning hdd directory: {:?}", e);
+                        }
+                    }
+                    Err(err) => {
+                        log::error!("Error creating VHD: {}", err);
+                    }
+                }
+            }
+            GuiEvent::LoadVHD(drive, new_vhd_name) => {
+                log::debug!("Load new VHD image: {:?} in device: {}", new_vhd_name, drive);
+
+                match self.vhd_manager.get_vhd_file(&new_vhd_name) {
+                    Ok(vhd_file) => {
+
+                        match VirtualHardDisk::from_file(vhd_file) {
+                            Ok(vhd) => {
+                                match self.machine.hdc().lock().unwrap().set_vhd(drive as usize, vhd) {
+                                    Ok(_) => {
+                                        log::info!("VHD image {:?} successfully loaded into virtual drive: {}", new_vhd_name, drive);
+                                    }
+                                    Err(err) => {
+                                        log::error!("Error mounting VHD: {}", err);
+                                    }
+                                }
+                            },
+                            Err(err) => {
+                                log::error!("Error loading VHD: {}", err);
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        log::error!("Failed to load VHD image {:?}: {}", new_vhd_name, err);
+                    }
+                }
+            }
+            GuiEvent::LoadFloppy(drive_select, filename) => {
+                log::debug!("Load floppy image: {:?} into drive: {}", filename, drive_select);
+
+                match self.machine.floppy_manager().load_floppy_data(&filename) {
+                    Ok(vec) => {
+
+                        let path = self.machine.floppy_manager().get_floppy_path(&filename);
+                        match self.machine.fdc().lock().unwrap().load_image_from(drive_select, vec, path) {
+                            Ok(()) => {
+                                log::info!("Floppy image successfully loaded into virtual drive.");
+                            }
+                            Err(err) => {
+                                log::warn!("Floppy image failed to load: {}", err);
+                            }
+                        }
+                    }
+                    Err(_) => {
+                        log::error!("Failed to load floppy image! {:?}", filename);
+                        // TODO: Some sort of GUI indication of failure
+                        eprintln!("Failed to read floppy image file: {:?}", filename);
+                    }
+                }
+            }
+            GuiEvent::EjectFloppy(drive_select) => {
+                log::info!("Ejecting floppy in drive: {}", drive_select);
+                self.machine.fdc().lock().unwrap().unload_image(drive_select);
+            }
+            GuiEvent::SaveFloppy(drive_select) => {
+                if !self.machine.fdc().lock().unwrap().flush_image(drive_select) {
+                    log::info!("No changes to save in drive: {}", drive_select);
+                }
+            }
+            GuiEvent::SetFloppyWriteProtect(drive_select, protect) => {
+                log::info!("Write protect drive {}: {}", drive_select, protect);
+                self.machine.fdc().lock().unwrap().set_write_protect(drive_select, protect);
+            }
+            GuiEvent::LoadCassette(filename) => {
+                let path = Path::new(cassette::CASSETTE_DIR).join(&filename);
+                match Tape::load(&path) {
+                    Ok(tape) => {
+                        log::info!("Loaded cassette image: {:?}", filename);
+                        let ppi = self.machine.ppi();
+                        let mut ppi = ppi.lock().unwrap();
+                        ppi.cassette_mut().insert(tape, &filename.to_string_lossy());
+                    }
+                    Err(err) => {
+                        log::error!("Failed to load cassette image {:?}: {}", filename, err);
+                    }
+                }
+            }
+            GuiEvent::EjectCassette => {
+                log::info!("Ejecting cassette");
+                self.machine.ppi().lock().unwrap().cassette_mut().eject();
+            }
+            GuiEvent::RewindCassette => {
+                self.machine.ppi().lock().unwrap().cassette_mut().rewind();
+            }
+            GuiEvent::SetCassetteMode(mode) => {
+                self.machine.ppi().lock().unwrap().cassette_mut().set_mode(mode);
+            }
+            GuiEvent::SaveCassette(filename) => {
+                let path = Path::new(cassette::CASSETTE_DIR).join(&filename);
+                let ppi = self.machine.ppi();
+                let mut ppi = ppi.lock().unwrap();
+                match ppi.cassette_mut().tape().map(|tape| tape.save(&path)) {
+                    Some(Ok(())) => {
+                        log::info!("Saved cassette image: {:?}", filename);
+                        self.cassette_names = cassette::scan_dir(cassette::CASSETTE_DIR);
+                    }
+                    Some(Err(err)) => {
+                        log::error!("Failed to save cassette image {:?}: {}", filename, err);
+                    }
+                    None => {
+                        log::warn!("No cassette to save");
+                    }
+                }
+            }
+            GuiEvent::PasteText(text) => {
+                log::debug!("Pasting {} characters", text.chars().count());
+                self.machine.paste_text(&text);
+            }
+        }
+    }
+
+    /// Build the status update for the GUI, including state for any open debug windows.
+    fn make_status(&mut self) -> MachineStatus {
+
+        let request = &self.debug_request;
+        let machine = &mut self.machine;
+
+        let memory_dump = request.memory_address.as_ref().map(|addr_str| {
+            // Show address 0 if expression eval fails
+            let mem_dump_addr = machine.cpu().eval_address(addr_str).unwrap_or(0);
+            machine.bus().dump_flat(mem_dump_addr as usize, 256)
+        });
+
+        let disassembly = request.disassembly_address.as_ref().map(|addr_str| {
+            let disassembly_addr = machine.cpu().eval_address(addr_str).unwrap_or(0);
+
+            let bus = machine.mut_bus();
+            bus.set_cursor(disassembly_addr as usize);
+            let mut disassembly_string = String::new();
+            for _ in 0..24 {
+
+                let address = bus.tell();
+                if address < machine::MAX_MEMORY_ADDRESS {
+
+                    let decode_str: String = match arch::decode(bus) {
+                        Ok(i) => {
+
+                            let instr_slice = bus.get_slice_at(address, i.size as usize);
+                            let instr_bytes_str = util::fmt_byte_array(instr_slice);
+                            format!("{:05X} {:012} {}\n", address, instr_bytes_str, i)
+                        }
+                        Err(_) => {
+                            format!("{:05X} INVALID\n", address)
+                        }
+                    };
+                    disassembly_string.push_str(&decode_str)
+                }
+            }
+            disassembly_string
+        });
+
+        MachineStatus {
+            exec_state: self.exec_control.get_state(),
+            error: machine.get_error_str().map(|s| s.to_string()),
+            cpu_cycles: machine.cpu_cycles(),
+            pit_cycles: machine.pit_cycles(),
+            pasting: machine.is_pasting(),
+            floppy_names: machine.floppy_manager().get_floppy_names(),
+            vhd_names: self.vhd_manager.get_vhd_names(),
+            cassette_names: self.cassette_names.clone(),
+            vhd_formats: request.vhd_formats.then(|| machine.hdc().lock().unwrap().get_supported_formats()),
+            memory_dump,
+            disassembly,
+            cpu_state: request.cpu_state.then(|| machine.cpu().get_string_state()),
+            pit_state: request.pit_state.then(|| machine.pit_state()),
+            pic_state: request.pic_state.then(|| machine.pic_state()),
+            ppi_state: request.ppi_state.then(|| machine.ppi_state()),
+            cassette_state: request.cassette_state.then(|| machine.cassette_state()),
+            floppy_state: request.floppy_state.then(|| machine.floppy_state()),
+            serial_state: request.serial_state.then(|| machine.serial_state()),
+            dma_state: request.dma_state.then(|| machine.dma_state()),
+            trace: request.trace.then(|| machine.cpu().dump_instruction_history()),
+            call_stack: request.call_stack.then(|| machine.cpu().dump_call_stack()),
+        }
+    }
+}
@@ -5,51 +5,75 @@ use crate::gui::Framework;
 use log::error;
 use pixels::{Error, Pixels, SurfaceTexture};
 use winit::dpi::LogicalSize;
-use winit::event::{Event, WindowEvent, StartCause, VirtualKeyCode};
+use winit::event::{DeviceEvent, ElementState, Event, MouseButton, WindowEvent, StartCause, VirtualKeyCode};
 use winit::event_loop::{ControlFlow, EventLoop};
 use winit::window::WindowBuilder;
 use winit_input_helper::WinitInputHelper;
 
 use std::{
-    fs::{File, read},
+    path::PathBuf,
     time::{Duration, Instant},
-    cell::RefCell,
-    rc::Rc,
-    path::Path
 };
 
+mod adlib;
 mod arch;
 mod bus;
 mod bytebuf;
 mod byteinterface;
+mod cassette;
 mod cga;
 mod cpu;
+mod d86f;
 mod dma;
 mod fdc;
+mod floppy_image;
 mod floppy_manager;
+mod gameport;
 mod gui;
 mod gui_image;
 mod hdc;
+mod hxc;
+mod imd;
 mod io;
+mod joystick;
+mod keyboard;
+mod lpt_dac;
 mod machine;
+mod machine_thread;
+mod mfm;
 mod memerror;
+mod mouse;
+mod parallel;
 mod pic;
 mod pit;
 mod ppi;
+mod printer;
 mod rom_manager;
+mod rtc;
+mod serial;
+mod serial_host;
+mod sound;
+mod td0;
 mod util;
 mod vhd;
 mod vhd_manager;
 mod video;
 mod input;
 
-use machine::{Machine, MachineType, VideoType};
+use machine::{DeviceConfig, Machine, MachineType, VideoType};
 use rom_manager::{RomManager, RomError};
+use fdc::DriveType;
 use floppy_manager::{FloppyManager, FloppyError};
 use vhd_manager::{VHDManager, VHDManagerError};
 use vhd::{VirtualHardDisk};
-use byteinterface::ByteInterface;
 use gui::GuiEvent;
+use machine_thread::{DebugRequest, MachineCommand, MachineThread};
+use sound::SoundConfig;
+use joystick::{JoystickBinding, JoystickSource};
+use parallel::{LptDevice, ParallelConfig};
+use printer::PrintMode;
+use rtc::RtcConfig;
+use serial_host::SerialAttachment;
 
 const EGUI_MENU_BAR: u32 = 25;
 const WINDOW_WIDTH: u32 = 1280;
@@ -62,6 +86,11 @@ const MICROS_PER_FRAME: f64 = 1.0 / FPS_TARGET * 1000000.0;
 
 const CYCLES_PER_FRAME: u32 = (cpu::CPU_MHZ * 1000000.0 / FPS_TARGET) as u32;
 
+// Toggles capture of the host mouse for the emulated serial mouse
+const MOUSE_CAPTURE_KEY: VirtualKeyCode = VirtualKeyCode::F12;
+// Toggles binding of the arrow keys to the joystick
+const JOYSTICK_KEYS_KEY: VirtualKeyCode = VirtualKeyCode::F11;
+
 // Rendering Stats
 struct Counter {
     frame_count: u64,
@@ -74,12 +103,168 @@ struct Counter {
     current_pit_tps: u64,
 }
 
+/// Grab and hide the host cursor so that mouse motion goes to the emulated mouse, or
+/// release it.
+fn set_mouse_capture(window: &winit::window::Window, capture: bool) {
+    if let Err(e) = window.set_cursor_grab(capture) {
+        log::warn!("Couldn't grab mouse cursor: {}", e);
+    }
+    window.set_cursor_visible(!capture);
+}
+
 fn main() -> Result<(), Error> {
 
     env_logger::init();
 
-    // Choose machine type (move to cfg?)
-    let machine_type = MachineType::IBM_XT_5160;
+    // Choose machine type (move to cfg?). '--machine 5150' selects the IBM PC.
+    let mut machine_type = MachineType::IBM_XT_5160;
+
+    // The keyboard mapping is read from './keymap.cfg' if present, or from '--keymap <file>'.
+    // Otherwise the US layout preset is used.
+    let mut keymap_path = PathBuf::from(input::KEYMAP_DEFAULT_PATH);
+
+    // Audio goes to the host audio device if available, and is silent otherwise. '--wav <file>'
+    // records it to a WAV file instead.
+    let mut sound_config = SoundConfig::default();
+
+    // The mouse is attached to COM1 by default. '--com1 <spec>' and '--com2 <spec>' attach
+    // a port to 'none', 'mouse', 'pty', 'tcp:<port>' or 'file:<path>'.
+    let mut serial_config = [SerialAttachment::Mouse, SerialAttachment::None];
+
+    // A parallel printer adapter is installed at 0x378. '--lpt 378|278|3bc|none' moves or
+    // removes it. Print jobs are saved to './print' as text, or as raw data with
+    // '--printer raw'. '--lpt-device covox|dss' attaches a DAC instead of the printer.
+    let mut parallel_config = ParallelConfig::default();
+
+    // An AdLib card is installed at 0x388 unless '--no-adlib' is given.
+    let mut adlib_enabled = true;
+
+    // There is one PIC. '--slave-pic' cascades a second PIC at 0xA0 through IRQ2, as on some
+    // XT clones.
+    let mut slave_pic = false;
+
+    // A game port is installed when '--joystick keyboard|mouse|gamepad' selects the host
+    // input driving it.
+    let mut joystick_source = None;
+
+    // A clock card is installed at 0x2C0, set from host time. '--rtc 2c0|240|340|none' moves
+    // or removes it, and '--rtc-time "YYYY-MM-DD HH:MM:SS"' starts it at a fixed time instead.
+    // '--rtc-freeze' stops the clock at its start time.
+    let mut rtc_config = RtcConfig::default();
+
+    // Both floppy drives are 360K drives. '--floppy-a <type>' and '--floppy-b <type>' install
+    // a '360k', '1.2m', '720k' or '1.44m' drive instead.
+    let mut floppy_types = [DriveType::Drive360K; 2];
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--wav" {
+            sound_config.wav_path = args.next().map(PathBuf::from);
+        }
+        else if arg == "--machine" {
+            match args.next().as_deref() {
+                Some("5150") => machine_type = MachineType::IBM_PC_5150,
+                Some("5160") => machine_type = MachineType::IBM_XT_5160,
+                _ => {
+                    eprintln!("Machine type must be 5150 or 5160");
+                    std::process::exit(1);
+                }
+            }
+        }
+        else if arg == "--keymap" {
+            keymap_path = args.next().map(PathBuf::from).unwrap_or(keymap_path);
+        }
+        else if arg == "--lpt" {
+            parallel_config.base = match args.next().as_deref() {
+                Some("none") => None,
+                Some(base) => match u16::from_str_radix(base, 16) {
+                    Ok(base) if parallel::PARALLEL_BASE_PORTS.contains(&base) => Some(base),
+                    _ => {
+                        eprintln!("Parallel port must be 378, 278, 3bc or none");
+                        std::process::exit(1);
+                    }
+                },
+                None => parallel_config.base
+            };
+        }
+        else if arg == "--joystick" {
+            match args.next().as_deref().and_then(JoystickSource::from_name) {
+                Some(source) => joystick_source = Some(source),
+                None => {
+                    eprintln!("Joystick must be keyboard, mouse or gamepad");
+                    std::process::exit(1);
+                }
+            }
+        }
+        else if arg == "--rtc" {
+            rtc_config.base = match args.next().as_deref() {
+                Some("none") => None,
+                Some(base) => match u16::from_str_radix(base, 16) {
+                    Ok(base) if rtc::RTC_BASE_PORTS.contains(&base) => Some(base),
+                    _ => {
+                        eprintln!("Clock card port must be 2c0, 240, 340 or none");
+                        std::process::exit(1);
+                    }
+                },
+                None => rtc_config.base
+            };
+        }
+        else if arg == "--rtc-time" {
+            match args.next().as_deref().and_then(rtc::parse_timestamp) {
+                Some(start) => rtc_config.start = start,
+                None => {
+                    eprintln!("Clock time must be given as \"YYYY-MM-DD HH:MM:SS\"");
+                    std::process::exit(1);
+                }
+            }
+        }
+        else if arg == "--rtc-freeze" {
+            rtc_config.frozen = true;
+        }
+        else if arg == "--floppy-a" || arg == "--floppy-b" {
+            let drive = if arg == "--floppy-a" { 0 } else { 1 };
+            match args.next().as_deref().and_then(DriveType::from_name) {
+                Some(drive_type) => floppy_types[drive] = drive_type,
+                None => {
+                    eprintln!("Floppy drive type must be 360k, 1.2m, 720k or 1.44m");
+                    std::process::exit(1);
+                }
+            }
+        }
+        else if arg == "--no-adlib" {
+            adlib_enabled = false;
+        }
+        else if arg == "--slave-pic" {
+            slave_pic = true;
+        }
+        else if arg == "--lpt-device" {
+            match args.next().as_deref().and_then(LptDevice::from_name) {
+                Some(device) => parallel_config.device = device,
+                None => {
+                    eprintln!("Parallel port device must be printer, covox or dss");
+                    std::process::exit(1);
+                }
+            }
+        }
+        else if arg == "--printer" {
+            match args.next().as_deref().and_then(PrintMode::from_name) {
+                Some(mode) => parallel_config.mode = mode,
+                None => {
+                    eprintln!("Printer mode must be raw or text");
+                    std::process::exit(1);
+                }
+            }
+        }
+        else if arg == "--com1" || arg == "--com2" {
+            let port = if arg == "--com1" { 0 } else { 1 };
+            match SerialAttachment::parse(&args.next().unwrap_or_default()) {
+                Ok(attachment) => serial_config[port] = attachment,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
 
     // Instantiate the rom manager to load roms for the requested machine type    
     let mut rom_manager = RomManager::new(machine_type);
@@ -131,15 +316,41 @@ fn main() -> Result<(), Error> {
         std::process::exit(1);        
     } 
 
-    // ExecutionControl is shared via RefCell with GUI so that state can be updated by control widget
-    let exec_control = Rc::new(RefCell::new(machine::ExecutionControl::new()));
+    let mut keymap = input::KeyMap::default();
+    if keymap_path.exists() {
+        match input::KeyMap::load(&keymap_path) {
+            Ok(loaded) => keymap = loaded,
+            Err(e) => eprintln!("Error loading keymap: {}", e)
+        }
+    }
 
     // Instantiate the main Machine data struct
     // Machine coordinates all the parts of the emulated computer
-    let mut machine = Machine::new(machine_type, VideoType::CGA, rom_manager, floppy_manager );
-    
-    // Create the video renderer
-    let video = video::Video::new();
+    let machine = Machine::new(
+        machine_type,
+        VideoType::CGA,
+        rom_manager,
+        floppy_manager,
+        DeviceConfig {
+            serial: serial_config,
+            parallel: parallel_config,
+            adlib: adlib_enabled,
+            slave_pic,
+            joysticks: joystick_source.map(|source| source.sticks_connected()),
+            rtc: rtc_config,
+            floppy_types,
+        },
+    );
+
+    // Move the machine onto its own thread. The GUI communicates with it over a channel
+    // and receives video frames through a double buffer.
+    let mut machine_thread = MachineThread::spawn(
+        machine,
+        vhd_manager,
+        sound_config,
+        (WIDTH * HEIGHT * 4) as usize,
+        CYCLES_PER_FRAME,
+        Duration::from_micros(MICROS_PER_FRAME as u64));
 
     // Init graphics & GUI 
     let event_loop = EventLoop::new();
@@ -160,12 +371,22 @@ fn main() -> Result<(), Error> {
         let surface_texture = SurfaceTexture::new(window_size.width, window_size.height, &window);
         let pixels = Pixels::new(WIDTH, HEIGHT, surface_texture)?;
         let framework =
-            Framework::new(window_size.width, window_size.height, scale_factor, &pixels, exec_control.clone());
+            Framework::new(window_size.width, window_size.height, scale_factor, &pixels);
 
         (pixels, framework)
     };
     let mut stat_counter = Counter::new();
 
+    framework.gui.set_keymap(&keymap);
+
+    let mut last_breakpoint = 0;
+    let mut last_composite = false;
+    let mut mouse_captured = false;
+    let mut joystick = joystick_source.map(JoystickBinding::new);
+    // Host shift keys held down, and whether shift was pressed for a key typed with shift
+    let mut host_shift = [false; 2];
+    let mut injected_shift = false;
+
     // Run the winit event loop
     event_loop.run(move |event, _, control_flow| {
 
@@ -176,6 +397,7 @@ fn main() -> Result<(), Error> {
             // Close events
             
             if input.quit() {
+                machine_thread.shutdown();
                 *control_flow = ControlFlow::Exit;
                 return;
             }
@@ -205,26 +427,65 @@ fn main() -> Result<(), Error> {
                 match event {
                     WindowEvent::KeyboardInput{
                         input: winit::event::KeyboardInput {
-                            virtual_keycode:Some(keycode),
+                            virtual_keycode,
+                            scancode,
                             state,
                             ..
                         },
                         ..
                     } => {
-                        if !framework.has_focus() {
-                            match state {
-                                winit::event::ElementState::Pressed => {
-                                    
-                                    if let Some(keycode) = input::match_virtual_keycode(keycode) {
-                                        //log::debug!("Key pressed, keycode: {:?}: xt: {:02X}", keycode, keycode);
-                                        machine.key_press(keycode);
-                                    };
-                                },
-                                winit::event::ElementState::Released => {
-                                    if let Some(keycode) = input::match_virtual_keycode(keycode) {
-                                        //log::debug!("Key released, keycode: {:?}: xt: {:02X}", keycode, keycode);
-                                        machine.key_release(keycode);
-                                    };
+                        match virtual_keycode {
+                            Some(VirtualKeyCode::LShift) => host_shift[0] = state == ElementState::Pressed,
+                            Some(VirtualKeyCode::RShift) => host_shift[1] = state == ElementState::Pressed,
+                            _ => {}
+                        }
+
+                        if virtual_keycode == Some(MOUSE_CAPTURE_KEY) {
+                            if state == ElementState::Pressed {
+                                mouse_captured = !mouse_captured;
+                                set_mouse_capture(&window, mouse_captured);
+                            }
+                        }
+                        else if virtual_keycode == Some(JOYSTICK_KEYS_KEY) {
+                            if let (Some(joystick), ElementState::Pressed) = (&mut joystick, state) {
+                                let active = joystick.toggle_keys();
+                                log::info!("Joystick keys {}", if active { "on" } else { "off" });
+                            }
+                        }
+                        else if framework.gui.is_learning_key() {
+                            // The key binding editor is waiting for a host key
+                            if let (Some(keycode), ElementState::Pressed) = (virtual_keycode, state) {
+                                framework.gui.learn_key(keycode, input::match_positional_scancode(scancode).map(|(code, _)| code));
+                            }
+                        }
+                        else if !framework.has_focus() {
+                            // Keys bound to the joystick don't reach the emulated keyboard
+                            let joystick_key = match (&mut joystick, virtual_keycode) {
+                                (Some(joystick), Some(keycode)) => joystick.key(keycode, state == ElementState::Pressed),
+                                _ => false
+                            };
+                            let key = if joystick_key { None } else { keymap.translate(virtual_keycode, scancode) };
+                            if let Some(key) = key {
+                                //log::debug!("Key {:?}, keycode: {:?} scancode: {:X}: xt: {:02X}", state, virtual_keycode, scancode, key.code);
+                                let command = match (state, key.navigation) {
+                                    (ElementState::Pressed, false) => MachineCommand::KeyPress(key.code),
+                                    (ElementState::Released, false) => MachineCommand::KeyRelease(key.code),
+                                    (ElementState::Pressed, true) => MachineCommand::NavKeyPress(key.code),
+                                    (ElementState::Released, true) => MachineCommand::NavKeyRelease(key.code),
+                                };
+                                // Hold shift around keys typed as a shifted US key, unless the
+                                // user is already holding it
+                                if key.shift && state == ElementState::Pressed && !host_shift.contains(&true) {
+                                    machine_thread.send(MachineCommand::KeyPress(input::XT_LSHIFT));
+                                    injected_shift = true;
+                                }
+                                machine_thread.send(command);
+                                if key.shift && state == ElementState::Released && injected_shift {
+                                    // Left shift pressed on the host since then now belongs to the user
+                                    if !host_shift[0] {
+                                        machine_thread.send(MachineCommand::KeyRelease(input::XT_LSHIFT));
+                                    }
+                                    injected_shift = false;
                                 }
                             }
                         }
@@ -232,36 +493,50 @@ fn main() -> Result<(), Error> {
                             framework.handle_event(&event);
                         }
                     },
+                    // While the mouse is captured its buttons go to the emulated mouse, and
+                    // egui doesn't see the cursor.
+                    WindowEvent::MouseInput{ state, button, .. } if mouse_captured => {
+                        let button = match button {
+                            MouseButton::Left => Some(mouse::MouseButton::Left),
+                            MouseButton::Right => Some(mouse::MouseButton::Right),
+                            _ => None
+                        };
+                        if let Some(button) = button {
+                            let pressed = state == ElementState::Pressed;
+                            match &mut joystick {
+                                Some(joystick) if joystick.uses_mouse() => joystick.mouse_button(button, pressed),
+                                _ => machine_thread.send(MachineCommand::MouseButton(button, pressed))
+                            }
+                        }
+                    },
+                    WindowEvent::CursorMoved{ .. } | WindowEvent::MouseWheel{ .. } if mouse_captured => {},
+                    WindowEvent::Focused(false) if mouse_captured => {
+                        mouse_captured = false;
+                        set_mouse_capture(&window, false);
+                    },
                     _ => {
                         framework.handle_event(&event);
                     }
                 }
             },
 
+            // Raw mouse motion drives the emulated mouse while captured
+            Event::DeviceEvent{ event: DeviceEvent::MouseMotion{ delta: (dx, dy) }, .. } if mouse_captured => {
+                match &mut joystick {
+                    Some(joystick) if joystick.uses_mouse() => joystick.mouse_motion(dx, dy),
+                    _ => machine_thread.send(MachineCommand::MouseMotion(dx, dy))
+                }
+            },
+
             // Draw the current frame
             Event::MainEventsCleared => {
 
-                // Calculate FPS
-                let elapsed_ms = stat_counter.last_second.elapsed().as_millis();
-                if elapsed_ms > 1000 {
-                    // One second elapsed, calculate FPS/CPS
-                    let pit_ticks = machine.pit_cycles();
-                    let cpu_cycles = machine.cpu_cycles();
-
-                    stat_counter.current_cpu_cps = cpu_cycles - stat_counter.last_cpu_cycles;
-                    stat_counter.last_cpu_cycles = cpu_cycles;
-
-                    stat_counter.current_pit_tps = pit_ticks - stat_counter.last_pit_ticks;
-                    stat_counter.last_pit_ticks = pit_ticks;
-
-                    //println!("fps: {} | cps: {} | pit tps: {}", 
-                    //    stat_counter.current_fps,
-                    //    stat_counter.current_cpu_cps, 
-                    //    stat_counter.current_pit_tps);
-
-                    stat_counter.current_fps = 0;
-                    stat_counter.last_second = Instant::now();
-                } 
+                // Forward joystick input, including from host gamepads
+                if let Some(joystick) = &mut joystick {
+                    while let Some(event) = joystick.get_event() {
+                        machine_thread.send(MachineCommand::Joystick(event));
+                    }
+                }
 
                 // Decide whether to draw a frame
                 let elapsed_us = stat_counter.last_period.elapsed().as_micros();
@@ -273,234 +548,154 @@ fn main() -> Result<(), Error> {
                     stat_counter.current_fps += 1;
                     //println!("frame: {} elapsed: {}", world.current_fps, elapsed_us);
 
-                    // Get breakpoint from GUI
-                    let bp_str = framework.gui.get_breakpoint();
-                    let bp_addr = match u32::from_str_radix(bp_str, 16) {
-                        Ok(addr) => addr,
-                        Err(_) => 0
-                    };
-
-                    // Get single step flag from GUI and either step or run CPU
-                    // TODO: This logic is messy, figure out a better way to control CPU state 
-                    //       via gui
-
-                    //if framework.gui.get_cpu_single_step() {
-                    //    if framework.gui.get_cpu_step_flag() {
-                    //        machine.run(CYCLES_PER_FRAME, &exec_control.borrow(), 0);
-                    //    }
-                    //}
-                    //else {
-                    //    machine.run(CYCLES_PER_FRAME, &exec_control.borrow(), bp_addr);
-                    //    // Check for breakpoint
-                    //    if machine.cpu().get_flat_address() == bp_addr && bp_addr != 0 {
-                    //        log::debug!("Breakpoint hit at {:06X}", bp_addr);
-                    //        framework.gui.set_cpu_single_step();
-                    //    }
-                    //}
-
-                    machine.run(CYCLES_PER_FRAME, &mut exec_control.borrow_mut(), bp_addr);
-
-                    let composite_enabled = framework.gui.get_composite_enabled();
-                    // Draw video memory
-                    video.draw(pixels.get_frame(), machine.cga(), machine.bus(), composite_enabled);
-                    
-                    // Update egui data
-
-                    // Any errors?
-                    if let Some(err) = machine.get_error_str() {
-                        framework.gui.show_error(err);
-                        framework.gui.show_disassembly_view();
-                    }
-
-                    // -- Handle egui "Events"
-                    loop {
-                        match framework.gui.get_event() {
-                            Some(GuiEvent::CreateVHD(filename, fmt)) => {
-                                log::info!("Got CreateVHD event: {:?}, {:?}", filename, fmt);
-
-                                let vhd_path = Path::new("./hdd").join(filename);
-
-                                match vhd::create_vhd(
-                                    vhd_path.into_os_string(), 
-                                    fmt.max_cylinders, 
-                                    fmt.max_heads, 
-                                    fmt.max_sectors) {
-
-                                    Ok(_) => {
-                                        // We don't actually do anything with the newly created file
-
-                                        // Rescan dir to show new file in list
-                                        vhd_manager.scan_dir("./hdd");
-                                    }
-                                    Err(err) => {
-                                        log::error!("Error creating VHD: {}", err);
-                                    }
-                                }
-                            }
-                            Some(GuiEvent::LoadFloppy(drive_select, filename)) => {
-                                log::debug!("Load floppy image: {:?} into drive: {}", filename, drive_select);
-
-                                match machine.floppy_manager().load_floppy_data(&filename) {
-                                    Ok(vec) => {
-                                        
-                                        match machine.fdc().borrow_mut().load_image_from(drive_select, vec) {
-                                            Ok(()) => {
-                                                log::info!("Floppy image successfully loaded into virtual drive.");
-                                            }
-                                            Err(err) => {
-                                                log::warn!("Floppy image failed to load: {}", err);
-                                            }
-                                        }
-                                    } 
-                                    Err(e) => {
-                                        log::error!("Failed to load floppy image! {:?}", filename);
-                                        // TODO: Some sort of GUI indication of failure
-                                        eprintln!("Failed to read floppy image file: {:?}", filename);
-                                    }
-                                }                                
-                            }
-                            Some(GuiEvent::EjectFloppy(drive_select)) => {
-                                log::info!("Ejecting floppy in drive: {}", drive_select);
-                                machine.fdc().borrow_mut().unload_image(drive_select);
-                            }
-                            None => break,
-                            _ => {
-                                // Unhandled event?
-                            }
-                        }
+                    // -- Forward egui "Events" to the machine thread
+                    while let Some(event) = framework.gui.get_event() {
+                        machine_thread.send(MachineCommand::Gui(event));
                     }
 
-                    // -- Update list of floppies
-                    let name_vec = machine.floppy_manager().get_floppy_names();
-                    framework.gui.set_floppy_names(name_vec);
-
-                    // -- Update VHD Creator window
-                    if framework.gui.is_window_open(gui::GuiWindow::VHDCreator) {
-                        framework.gui.update_vhd_formats(machine.hdc().borrow_mut().get_supported_formats());
+                    // -- Forward execution control operations
+                    while let Some(op) = framework.gui.get_exec_op() {
+                        machine_thread.send(MachineCommand::Control(op));
                     }
 
-                    // -- Update list of VHD images
-                    let name_vec = vhd_manager.get_vhd_names();
-                    framework.gui.set_vhd_names(name_vec);
-
                     // -- Do we have a new VHD image to load?
                     for i in 0..machine::NUM_HDDS {
                         if let Some(new_vhd_name) = framework.gui.get_new_vhd_name(i) {
-                            log::debug!("Load new VHD image: {:?} in device: {}", new_vhd_name, i);
-
-                            match vhd_manager.get_vhd_file(&new_vhd_name) {
-                                Ok(vhd_file) => {
-
-                                    match VirtualHardDisk::from_file(vhd_file) {
-                                        Ok(vhd) => {
-                                            match machine.hdc().borrow_mut().set_vhd(i as usize, vhd) {
-                                                Ok(_) => {
-                                                    log::info!("VHD image {:?} successfully loaded into virtual drive: {}", new_vhd_name, i);
-                                                }
-                                                Err(err) => {
-                                                    log::error!("Error mounting VHD: {}", err);
-                                                }
-                                            }
-                                        },
-                                        Err(err) => {
-                                            log::error!("Error loading VHD: {}", err);
-                                        }
-                                    }
-                                }
-                                Err(err) => {
-                                    log::error!("Failed to load VHD image {:?}: {}", new_vhd_name, err);
-                                }                                
-                            }
+                            machine_thread.send(MachineCommand::Gui(GuiEvent::LoadVHD(i, new_vhd_name)));
                         }
                     }
 
-                    // -- Update memory viewer window if open
-                    if framework.gui.is_window_open(gui::GuiWindow::MemoryViewer) {
-                        let mem_dump_addr_str = framework.gui.get_memory_view_address();
-                        // Show address 0 if expression evail fails
-                        let mem_dump_addr = match machine.cpu().eval_address(mem_dump_addr_str) {
-                            Some(i) => i,
-                            None => 0
-                        };
-                        let mem_dump_str = machine.bus().dump_flat(mem_dump_addr as usize, 256);
-
-                        framework.gui.update_memory_view(mem_dump_str);
-                    }   
-
-                    // -- Update register viewer window
-                    if framework.gui.is_window_open(gui::GuiWindow::CpuStateViewer) {
-                        let cpu_state = machine.cpu().get_string_state();
-                        framework.gui.update_cpu_state(cpu_state);
+                    // -- Apply changes from the key binding editor
+                    if let Some((new_keymap, save)) = framework.gui.get_keymap_update() {
+                        if save {
+                            if let Err(e) = new_keymap.save(&keymap_path) {
+                                framework.gui.show_error(&format!("Error saving keymap: {}", e));
+                            }
+                        }
+                        keymap = new_keymap;
                     }
 
-                    // -- Update PIT viewer window
-                    if framework.gui.is_window_open(gui::GuiWindow::PitViewer) {
-                        let pit_state = machine.pit_state();
-                        framework.gui.update_pit_state(pit_state);
+                    // Get breakpoint from GUI
+                    let bp_str = framework.gui.get_breakpoint();
+                    let bp_addr = match u32::from_str_radix(bp_str, 16) {
+                        Ok(addr) => addr,
+                        Err(_) => 0
+                    };
+                    if bp_addr != last_breakpoint {
+                        machine_thread.send(MachineCommand::SetBreakpoint(bp_addr));
+                        last_breakpoint = bp_addr;
                     }
 
-                    // -- Update PIC viewer window
-                    if framework.gui.is_window_open(gui::GuiWindow::PicViewer) {
-                        let pic_state = machine.pic_state();
-                        framework.gui.update_pic_state(pic_state);
+                    let composite_enabled = framework.gui.get_composite_enabled();
+                    if composite_enabled != last_composite {
+                        machine_thread.send(MachineCommand::SetComposite(composite_enabled));
+                        last_composite = composite_enabled;
                     }
 
-                    // -- Update PPI viewer window
-                    if framework.gui.is_window_open(gui::GuiWindow::PpiViewer) {
-                        let ppi_state = machine.ppi_state();
-                        framework.gui.update_ppi_state(ppi_state);  
-                    }
+                    // -- Tell the machine thread which debug windows need updating
+                    let gui = &mut framework.gui;
+                    let debug_request = DebugRequest {
+                        memory_address: gui.is_window_open(gui::GuiWindow::MemoryViewer)
+                            .then(|| gui.get_memory_view_address().to_string()),
+                        disassembly_address: gui.is_window_open(gui::GuiWindow::DiassemblyViewer)
+                            .then(|| gui.get_disassembly_view_address().to_string()),
+                        cpu_state: gui.is_window_open(gui::GuiWindow::CpuStateViewer),
+                        pit_state: gui.is_window_open(gui::GuiWindow::PitViewer),
+                        pic_state: gui.is_window_open(gui::GuiWindow::PicViewer),
+                        ppi_state: gui.is_window_open(gui::GuiWindow::PpiViewer),
+                        cassette_state: gui.is_window_open(gui::GuiWindow::Cassette),
+                        floppy_state: gui.is_window_open(gui::GuiWindow::FloppyInfo),
+                        serial_state: gui.is_window_open(gui::GuiWindow::SerialViewer),
+                        dma_state: gui.is_window_open(gui::GuiWindow::DmaViewer),
+                        trace: gui.is_window_open(gui::GuiWindow::TraceViewer),
+                        call_stack: gui.is_window_open(gui::GuiWindow::CallStack),
+                        vhd_formats: gui.is_window_open(gui::GuiWindow::VHDCreator),
+                    };
+                    machine_thread.send(MachineCommand::SetDebugRequest(debug_request));
 
-                    // -- Update DMA viewer window
-                    if framework.gui.is_window_open(gui::GuiWindow::DmaViewer) {
-                        let dma_state = machine.dma_state();
-                        framework.gui.update_dma_state(dma_state);
-                    }
+                    // Copy the most recently completed video frame
+                    machine_thread.frame_buffer().copy_to(pixels.get_frame());
 
-                    // -- Update Instruction Trace window
-                    if framework.gui.is_window_open(gui::GuiWindow::TraceViewer) {
-                        let trace = machine.cpu().dump_instruction_history();
-                        framework.gui.update_trace_state(trace);
-                    }
+                    // Update egui data from the latest machine status
+                    if let Some(status) = machine_thread.latest_status() {
 
-                    // -- Update Call Stack window
-                    if framework.gui.is_window_open(gui::GuiWindow::CallStack) {
-                        let stack = machine.cpu().dump_call_stack();
-                        framework.gui.update_call_stack_state(stack);
-                    }
+                        // Calculate FPS
+                        let elapsed_ms = stat_counter.last_second.elapsed().as_millis();
+                        if elapsed_ms > 1000 {
+                            // One second elapsed, calculate FPS/CPS
+                            stat_counter.current_cpu_cps = status.cpu_cycles - stat_counter.last_cpu_cycles;
+                            stat_counter.last_cpu_cycles = status.cpu_cycles;
 
-                    // -- Update disassembly viewer window
-                    if framework.gui.is_window_open(gui::GuiWindow::DiassemblyViewer) {
-                        let disassembly_addr_str = framework.gui.get_disassembly_view_address();
-                        let disassembly_addr = match machine.cpu().eval_address(disassembly_addr_str) {
-                            Some(i) => i,
-                            None => 0
-                        };
+                            stat_counter.current_pit_tps = status.pit_cycles - stat_counter.last_pit_ticks;
+                            stat_counter.last_pit_ticks = status.pit_cycles;
 
-                        let bus = machine.mut_bus();
-                        bus.set_cursor(disassembly_addr as usize);
-                        let mut disassembly_string = String::new();
-                        for _ in 0..24 {
+                            //println!("fps: {} | cps: {} | pit tps: {}", 
+                            //    stat_counter.current_fps,
+                            //    stat_counter.current_cpu_cps, 
+                            //    stat_counter.current_pit_tps);
 
-                            let address = bus.tell();
-                            if address < machine::MAX_MEMORY_ADDRESS {
+                            stat_counter.current_fps = 0;
+                            stat_counter.last_second = Instant::now();
+                        }
 
-                                let decode_str: String = match arch::decode(bus) {
-                                    Ok(i) => {
-                                    
-                                        let instr_slice = bus.get_slice_at(address, i.size as usize);
-                                        let instr_bytes_str = util::fmt_byte_array(instr_slice);                                    
-                                        format!("{:05X} {:012} {}\n", address, instr_bytes_str, i)
-                                    }
-                                    Err(_) => {
-                                        format!("{:05X} INVALID\n", address)
-                                    }
-                                };
-                                disassembly_string.push_str(&decode_str)
-                            }
+                        framework.gui.update_exec_state(status.exec_state);
+                        framework.gui.update_pasting(status.pasting);
 
+                        // Any errors?
+                        if let Some(err) = status.error {
+                            framework.gui.show_error(&err);
+                            framework.gui.show_disassembly_view();
+                        }
+
+                        // -- Update list of floppies
+                        framework.gui.set_floppy_names(status.floppy_names);
+
+                        // -- Update list of VHD images
+                        framework.gui.set_vhd_names(status.vhd_names);
+                        framework.gui.set_cassette_names(status.cassette_names);
+
+                        // -- Update VHD Creator window
+                        if let Some(formats) = status.vhd_formats {
+                            framework.gui.update_vhd_formats(formats);
+                        }
+
+                        // -- Update debug windows
+                        if let Some(mem_dump_str) = status.memory_dump {
+                            framework.gui.update_memory_view(mem_dump_str);
+                        }
+                        if let Some(cpu_state) = status.cpu_state {
+                            framework.gui.update_cpu_state(cpu_state);
+                        }
+                        if let Some(pit_state) = status.pit_state {
+                            framework.gui.update_pit_state(pit_state);
+                        }
+                        if let Some(pic_state) = status.pic_state {
+                            framework.gui.update_pic_state(pic_state);
+                        }
+                        if let Some(ppi_state) = status.ppi_state {
+                            framework.gui.update_ppi_state(ppi_state);
+                        }
+                        if let Some(serial_state) = status.serial_state {
+                            framework.gui.update_serial_state(serial_state);
+                        }
+                        if let Some(cassette_state) = status.cassette_state {
+                            framework.gui.update_cassette_state(cassette_state);
+                        }
+                        if let Some(floppy_state) = status.floppy_state {
+                            framework.gui.update_floppy_state(floppy_state);
+                        }
+                        if let Some(dma_state) = status.dma_state {
+                            framework.gui.update_dma_state(dma_state);
+                        }
+                        if let Some(trace) = status.trace {
+                            framework.gui.update_trace_state(trace);
+                        }
+                        if let Some(stack) = status.call_stack {
+                            framework.gui.update_call_stack_state(stack);
+                        }
+                        if let Some(disassembly_string) = status.disassembly {
+                            framework.gui.update_dissassembly_view(disassembly_string);
                         }
-                        framework.gui.update_dissassembly_view(disassembly_string);
                     }
 
                     // Prepare egui
@@ -523,6 +718,7 @@ fn main() -> Result<(), Error> {
                         .map_err(|e| error!("pixels.render() failed: {}", e))
                         .is_err()
                     {
+                        machine_thread.shutdown();
                         *control_flow = ControlFlow::Exit;
                     }   
                 }
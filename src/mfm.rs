@@ -0,0 +1,261 @@
+/*
+    mfm.rs
+    Decode MFM tracks from bitstream floppy images
+
+    Bitstream images record each track as the flux cells read from the disk, so they keep
+    what sector images can't: weak bits, CRC errors, deleted data marks, sectors overlapping
+    the index, and any layout a copy protection scheme used. Tracks are decoded the way the
+    FDC reads them, into the sector model used for all images.
+
+    In MFM each data bit is written as a clock cell followed by a data cell. Address marks
+    are preceded by three 0xA1 sync bytes written with a missing clock (cells 0x4489), and
+    are followed by:
+
+        0xFE            ID field: cylinder, head, sector number, size code, CRC
+        0xFB            Data field: data, CRC
+        0xF8            Deleted data field: data, CRC
+
+    CRCs are CRC-CCITT over the sync bytes, mark and field, so a field followed by its CRC
+    checks to zero.
+*/
+
+use crate::floppy_image::{Sector, SectorId, Track, TrackMode};
+
+const SYNC_CELLS: u16 = 0x4489;
+const SYNC_BYTE: u8 = 0xA1;
+const MARK_ID: u8 = 0xFE;
+const MARK_DATA: u8 = 0xFB;
+const MARK_DELETED_DATA: u8 = 0xF8;
+
+// How far past an ID field the FDC looks for its data field
+const DATA_MARK_WINDOW: usize = 64 * 16;
+
+/// The flux cells of one revolution of a track. Weak cells, if any, read differently each
+/// time.
+pub struct TrackCells {
+    pub cells: Vec<bool>,
+    pub weak: Vec<bool>,
+}
+
+impl TrackCells {
+    /// Cells are read around the track, so fields can run over the index.
+    fn cell(&self, pos: usize) -> bool {
+        self.cells[pos % self.cells.len()]
+    }
+
+    fn is_weak(&self, pos: usize) -> bool {
+        !self.weak.is_empty() && self.weak[pos % self.weak.len()]
+    }
+
+    /// Decode the byte whose cells start at `pos`, with a mask of its weak bits.
+    fn byte(&self, pos: usize) -> (u8, u8) {
+        let mut byte = 0;
+        let mut weak = 0;
+        for bit in 0..8 {
+            let cell = pos + bit * 2;
+            byte = (byte << 1) | self.cell(cell + 1) as u8;
+            weak = (weak << 1) | (self.is_weak(cell) || self.is_weak(cell + 1)) as u8;
+        }
+        (byte, weak)
+    }
+
+    fn bytes(&self, pos: usize, len: usize) -> (Vec<u8>, Vec<u8>) {
+        (0..len).map(|i| self.byte(pos + i * 16)).unzip()
+    }
+}
+
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn field_crc_ok(mark: u8, field: &[u8]) -> bool {
+    let mut data = vec![SYNC_BYTE, SYNC_BYTE, SYNC_BYTE, mark];
+    data.extend_from_slice(field);
+    crc16(&data) == 0
+}
+
+/// Decode the sectors of a track, in the order they pass the head from the index.
+pub fn decode_track(cells: &TrackCells, cylinder: u8, head: u8, rate_kbps: u16) -> Track {
+    let len = cells.cells.len();
+    let mut sectors: Vec<Sector> = Vec::new();
+    // The last ID field read and where it ends, until its data field is found
+    let mut pending: Option<(Sector, usize)> = None;
+
+    let mut shift: u16 = 0;
+    let mut sync_count = 0;
+    let mut last_sync = 0;
+    // Scan one revolution for ID fields, and past the index for the last data field
+    for pos in 0..len + DATA_MARK_WINDOW {
+        if pos >= len && pending.is_none() {
+            break
+        }
+        shift = (shift << 1) | cells.cell(pos) as u16;
+        if shift != SYNC_CELLS {
+            continue
+        }
+        sync_count = if sync_count > 0 && pos == last_sync + 16 { sync_count + 1 } else { 1 };
+        last_sync = pos;
+        if sync_count < 3 {
+            continue
+        }
+
+        let field = pos + 1;
+        let (mark, _) = cells.byte(field);
+        match mark {
+            MARK_ID if pos < len => {
+                if let Some((sector, _)) = pending.take() {
+                    sectors.push(sector);
+                }
+                let (id, _) = cells.bytes(field + 16, 6);
+                let sector = Sector {
+                    id: SectorId { c: id[0], h: id[1], r: id[2], n: id[3] },
+                    data: None,
+                    deleted: false,
+                    data_error: false,
+                    id_error: !field_crc_ok(MARK_ID, &id),
+                    weak: None,
+                };
+                pending = Some((sector, field + 7 * 16));
+            }
+            MARK_DATA | MARK_DELETED_DATA => {
+                match pending.take() {
+                    Some((mut sector, id_end)) if pos.saturating_sub(id_end) < DATA_MARK_WINDOW => {
+                        let size = sector.id.size();
+                        let (data, weak) = cells.bytes(field + 16, size + 2);
+                        let has_weak_bits = weak.iter().any(|&w| w != 0);
+
+                        sector.deleted = mark == MARK_DELETED_DATA;
+                        sector.data_error = !field_crc_ok(mark, &data) || has_weak_bits;
+                        sector.data = Some(data[..size].to_vec());
+                        if has_weak_bits {
+                            sector.weak = Some(weak[..size].to_vec());
+                        }
+                        sectors.push(sector);
+                    }
+                    Some((sector, _)) => sectors.push(sector),
+                    None => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    if let Some((sector, _)) = pending {
+        sectors.push(sector);
+    }
+
+    Track {
+        cylinder,
+        head,
+        mode: TrackMode { rate_kbps, mfm: true },
+        sectors,
+    }
+}
+
+/// Unpack a bitstream into cells, reading bytes most significant bit first or last.
+pub fn unpack_cells(data: &[u8], count: usize, msb_first: bool) -> Vec<bool> {
+    (0..count.min(data.len() * 8))
+        .map(|i| {
+            let bit = if msb_first { 7 - i % 8 } else { i % 8 };
+            data[i / 8] & (1 << bit) != 0
+        })
+        .collect()
+}
+
+/// Encode bytes into MFM cells, most significant bit first. Bytes flagged as sync are
+/// written as 0xA1 with a missing clock.
+#[cfg(test)]
+pub fn encode(bytes: &[(u8, bool)]) -> Vec<bool> {
+    let mut cells = Vec::new();
+    let mut last = false;
+    for &(byte, sync) in bytes {
+        if sync {
+            cells.extend((0..16).rev().map(|b| SYNC_CELLS & (1 << b) != 0));
+            last = true;
+            continue
+        }
+        for b in (0..8).rev() {
+            let bit = byte & (1 << b) != 0;
+            cells.push(!bit && !last);
+            cells.push(bit);
+            last = bit;
+        }
+    }
+    cells
+}
+
+/// Encode a formatted track. Each sector is given as its ID, data mark and data, which is
+/// written whole whatever the ID size code says.
+#[cfg(test)]
+pub fn encode_track(sectors: &[(SectorId, u8, Vec<u8>)]) -> Vec<bool> {
+    let mut bytes = vec![(0x4E, false); 80];
+    let field = |bytes: &mut Vec<(u8, bool)>, mark: u8, data: &[u8]| {
+        bytes.extend([(0x00, false); 12]);
+        bytes.extend([(SYNC_BYTE, true); 3]);
+        let mut crc_data = vec![SYNC_BYTE, SYNC_BYTE, SYNC_BYTE, mark];
+        crc_data.extend_from_slice(data);
+        let crc = crc16(&crc_data);
+        bytes.push((mark, false));
+        bytes.extend(data.iter().map(|&b| (b, false)));
+        bytes.extend(crc.to_be_bytes().map(|b| (b, false)));
+        bytes.extend([(0x4E, false); 22]);
+    };
+    for (id, mark, data) in sectors {
+        field(&mut bytes, MARK_ID, &[id.c, id.h, id.r, id.n]);
+        field(&mut bytes, *mark, data);
+    }
+    bytes.extend([(0x4E, false); 200]);
+    encode(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_decode_track() {
+        let id = |r| SectorId { c: 0, h: 0, r, n: 2 };
+        let mut cells = encode_track(&[
+            (id(1), MARK_DATA, vec![0x11; 512]),
+            (id(2), MARK_DELETED_DATA, vec![0x22; 512]),
+            (id(3), MARK_DATA, vec![0x33; 512]),
+        ]);
+
+        // Damage a data bit of sector 3 so its CRC fails
+        let sector_3 = cells.len() - 200 * 16 - 22 * 16 - 2 * 16 - 100 * 16;
+        cells[sector_3 + 1] = !cells[sector_3 + 1];
+
+        let mut weak = vec![false; cells.len()];
+        let track = decode_track(&TrackCells { cells: cells.clone(), weak: weak.clone() }, 0, 0, 250);
+        assert_eq!(track.sectors.len(), 3);
+        assert_eq!(track.sectors[0].id, id(1));
+        assert_eq!(track.sectors[0].data, Some(vec![0x11; 512]));
+        assert!(!track.sectors[0].data_error && !track.sectors[0].deleted);
+        assert!(track.sectors[1].deleted);
+        assert!(track.sectors[2].data_error);
+
+        // Weak cells in sector 1's data give a CRC error and a mask of the bits affected
+        let sector_1 = (80 + 12 + 4 + 6 + 22 + 12 + 4) * 16;
+        weak[sector_1 + 16] = true;
+        let track = decode_track(&TrackCells { cells, weak }, 0, 0, 250);
+        assert!(track.sectors[0].data_error);
+        assert_eq!(track.sectors[0].weak.as_ref().unwrap()[1], 0x80);
+
+        // An ID field with no data field following it
+        let mut bytes = vec![(0x4E, false); 80];
+        bytes.extend([(0x00, false); 12]);
+        bytes.extend([(SYNC_BYTE, true); 3]);
+        bytes.extend([MARK_ID, 5, 1, 9, 2, 0, 0].map(|b| (b, false)));
+        bytes.extend([(0x4E, false); 1000]);
+        let track = decode_track(&TrackCells { cells: encode(&bytes), weak: Vec::new() }, 5, 1, 250);
+        assert_eq!(track.sectors.len(), 1);
+        assert!(track.sectors[0].id_error);
+        assert_eq!(track.sectors[0].data, None);
+    }
+}
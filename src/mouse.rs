@@ -0,0 +1,205 @@
+/*
+    mouse.rs
+    Implement a Microsoft serial mouse
+
+    The mouse is powered from the serial port's RTS and DTR lines. Drivers reset it by
+    dropping RTS and raising it again, after which the mouse identifies itself by sending
+    'M'. It then reports motion and button changes in three byte packets at 1200 baud,
+    7 data bits, no parity and one stop bit:
+
+        Byte 1: 0 1 L R Y7 Y6 X7 X6
+        Byte 2: 0 0 X5 X4 X3 X2 X1 X0
+        Byte 3: 0 0 Y5 Y4 Y3 Y2 Y1 Y0
+
+    Motion is accumulated from the host and reported as fast as the serial line allows.
+*/
+
+use crate::cpu::CPU_MHZ;
+use crate::serial::SerialPort;
+
+const MOUSE_ID: u8 = b'M';
+const MOUSE_SYNC_BIT: u8 = 0b0100_0000;
+const MOUSE_LEFT_BIT: u8 = 0b0010_0000;
+const MOUSE_RIGHT_BIT: u8 = 0b0001_0000;
+
+// Time from RTS rising until the mouse sends its ID byte
+const MOUSE_RESET_CYCLES: u32 = (CPU_MHZ * 14_000.0) as u32;
+
+#[derive(Copy, Clone, Debug)]
+pub enum MouseButton {
+    Left,
+    Right,
+}
+
+pub struct Mouse {
+    powered: bool,
+    reset_cycles: Option<u32>,
+    dx: f64,
+    dy: f64,
+    left: bool,
+    right: bool,
+    buttons_changed: bool,
+}
+
+impl Mouse {
+    pub fn new() -> Self {
+        Self {
+            powered: false,
+            reset_cycles: None,
+            dx: 0.0,
+            dy: 0.0,
+            left: false,
+            right: false,
+            buttons_changed: false,
+        }
+    }
+
+    /// Accumulate host mouse motion.
+    pub fn motion(&mut self, dx: f64, dy: f64) {
+        if self.powered {
+            self.dx += dx;
+            self.dy += dy;
+        }
+    }
+
+    pub fn button(&mut self, button: MouseButton, pressed: bool) {
+        let state = match button {
+            MouseButton::Left => &mut self.left,
+            MouseButton::Right => &mut self.right,
+        };
+        if *state != pressed {
+            *state = pressed;
+            self.buttons_changed = true;
+        }
+    }
+
+    /// Run the mouse for the specified number of cycles, sending reports to the serial port.
+    pub fn run(&mut self, port: &mut SerialPort, cycles: u32) {
+
+        // The mouse is reset when its power from RTS and DTR is cycled
+        let powered = port.get_rts() && port.get_dtr();
+        if powered != self.powered {
+            self.powered = powered;
+            self.dx = 0.0;
+            self.dy = 0.0;
+            self.reset_cycles = powered.then_some(MOUSE_RESET_CYCLES);
+        }
+        if !self.powered {
+            return
+        }
+
+        if let Some(reset_cycles) = self.reset_cycles {
+            if reset_cycles > cycles {
+                self.reset_cycles = Some(reset_cycles - cycles);
+            }
+            else {
+                log::trace!("Mouse: sending ID");
+                self.reset_cycles = None;
+                port.send_rx(MOUSE_ID);
+            }
+            return
+        }
+
+        // Wait for the previous packet to go out before sending another
+        if port.rx_pending() > 0 {
+            return
+        }
+
+        let dx = self.dx.trunc().clamp(-128.0, 127.0);
+        let dy = self.dy.trunc().clamp(-128.0, 127.0);
+        if dx == 0.0 && dy == 0.0 && !self.buttons_changed {
+            return
+        }
+        self.dx -= dx;
+        self.dy -= dy;
+        self.buttons_changed = false;
+
+        let x = dx as i8 as u8;
+        let y = dy as i8 as u8;
+        let mut byte1 = MOUSE_SYNC_BIT | ((y >> 4) & 0x0C) | (x >> 6);
+        if self.left {
+            byte1 |= MOUSE_LEFT_BIT;
+        }
+        if self.right {
+            byte1 |= MOUSE_RIGHT_BIT;
+        }
+        port.send_rx(byte1);
+        port.send_rx(x & 0x3F);
+        port.send_rx(y & 0x3F);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::IoDevice;
+    use crate::pic::Pic;
+    use crate::serial::*;
+
+    /// Power the mouse from COM1, set to the mouse's 7N1 line format.
+    fn power_up(serial: &mut SerialPortController) {
+        serial.write_u8(SERIAL1_BASE_PORT + REG_LINE_CONTROL, 0x02);
+        serial.write_u8(SERIAL1_BASE_PORT + REG_MODEM_CONTROL, MCR_DTR | MCR_RTS);
+    }
+
+    /// Run the UART until the bytes sent by the mouse have been received.
+    fn receive(serial: &mut SerialPortController, pic: &mut Pic) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for _ in 0..1000 {
+            serial.run(pic, 100);
+            if serial.read_u8(SERIAL1_BASE_PORT + REG_LINE_STATUS) & LSR_DATA_READY != 0 {
+                bytes.push(serial.read_u8(SERIAL1_BASE_PORT + REG_DATA));
+            }
+            else if serial.port_mut(0).rx_pending() == 0 {
+                break
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    pub fn test_mouse_id() {
+        let mut pic = Pic::new();
+        let mut serial = SerialPortController::new();
+        let mut mouse = Mouse::new();
+
+        mouse.run(serial.port_mut(0), MOUSE_RESET_CYCLES);
+        assert_eq!(serial.port_mut(0).rx_pending(), 0);
+
+        // The ID is sent once the mouse has been powered long enough to reset
+        power_up(&mut serial);
+        mouse.run(serial.port_mut(0), MOUSE_RESET_CYCLES - 1);
+        assert_eq!(serial.port_mut(0).rx_pending(), 0);
+        mouse.run(serial.port_mut(0), 1);
+        assert_eq!(receive(&mut serial, &mut pic), vec![b'M']);
+    }
+
+    #[test]
+    pub fn test_mouse_packet() {
+        let mut pic = Pic::new();
+        let mut serial = SerialPortController::new();
+        let mut mouse = Mouse::new();
+
+        power_up(&mut serial);
+        mouse.run(serial.port_mut(0), MOUSE_RESET_CYCLES);
+        assert_eq!(receive(&mut serial, &mut pic), vec![b'M']);
+
+        // Left 3 and down 70 with the left button held. The top two bits of each count go
+        // in the first byte.
+        mouse.motion(-3.5, 70.0);
+        mouse.button(MouseButton::Left, true);
+        mouse.run(serial.port_mut(0), 7);
+        assert_eq!(receive(&mut serial, &mut pic), vec![
+            MOUSE_SYNC_BIT | MOUSE_LEFT_BIT | 0b0100 | 0b11,
+            0x3D,
+            0x06,
+        ]);
+
+        // The fraction left over is kept, adding up to one more count left
+        mouse.motion(-0.5, 0.0);
+        mouse.button(MouseButton::Left, false);
+        mouse.button(MouseButton::Right, true);
+        mouse.run(serial.port_mut(0), 7);
+        assert_eq!(receive(&mut serial, &mut pic), vec![MOUSE_SYNC_BIT | MOUSE_RIGHT_BIT | 0b11, 0x3F, 0x00]);
+    }
+}
@@ -0,0 +1,256 @@
+/*
+    parallel.rs
+    Implement the IBM Parallel Printer Adapter
+
+    The adapter has three registers: an output data latch, a status port reading the
+    printer's handshake lines, and a control port driving STROBE, AUTO FEED, INIT and
+    SELECT IN. The data latch reads back what was written, which the BIOS uses to detect
+    the adapter at 0x3BC, 0x378 and 0x278. Bit 4 of the control port enables IRQ7, raised
+    while the printer pulses ACK after accepting a byte. The Disney Sound Source holds ACK
+    as a FIFO status flag instead, which doesn't raise the interrupt.
+
+    Several lines are inverted between the register bits and the connector. The status and
+    control constants below give the meaning of a bit when it is set.
+
+    One device is attached to the port: a printer (printer.rs), or a Covox Speech Thing or
+    Disney Sound Source DAC (lpt_dac.rs).
+*/
+#![allow(dead_code)]
+
+use std::path::PathBuf;
+
+use crate::io::IoDevice;
+use crate::lpt_dac::{Covox, SoundSource};
+use crate::pic;
+use crate::printer::{PrintMode, Printer};
+
+pub const PARALLEL_IRQ: u8 = 7;
+pub const PARALLEL_REGISTER_COUNT: u16 = 3;
+pub const PARALLEL_BASE_PORTS: [u16; 3] = [0x378, 0x278, 0x3BC];
+
+const REG_DATA: u16 = 0;
+const REG_STATUS: u16 = 1;
+const REG_CONTROL: u16 = 2;
+
+pub const STATUS_UNUSED: u8      = 0b0000_0111; // Unused bits read as 1
+pub const STATUS_NO_ERROR: u8    = 0b0000_1000;
+pub const STATUS_SELECTED: u8    = 0b0001_0000;
+pub const STATUS_PAPER_OUT: u8   = 0b0010_0000;
+pub const STATUS_NO_ACK: u8      = 0b0100_0000;
+pub const STATUS_NOT_BUSY: u8    = 0b1000_0000;
+
+pub const CONTROL_STROBE: u8     = 0b0000_0001;
+pub const CONTROL_AUTO_FEED: u8  = 0b0000_0010;
+pub const CONTROL_NOT_INIT: u8   = 0b0000_0100;
+pub const CONTROL_SELECT_IN: u8  = 0b0000_1000;
+pub const CONTROL_IRQ_ENABLE: u8 = 0b0001_0000;
+const CONTROL_UNUSED: u8         = 0b1110_0000;
+
+/// The device attached to the parallel port.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LptDevice {
+    Printer,
+    Covox,
+    SoundSource,
+}
+
+impl LptDevice {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "printer" => Some(LptDevice::Printer),
+            "covox" => Some(LptDevice::Covox),
+            "dss" => Some(LptDevice::SoundSource),
+            _ => None
+        }
+    }
+}
+
+/// Options for the parallel port: its base address, or None for no adapter, the device
+/// attached to it, and how printed output is captured.
+#[derive(Clone, Debug)]
+pub struct ParallelConfig {
+    pub base: Option<u16>,
+    pub device: LptDevice,
+    pub mode: PrintMode,
+    pub print_dir: PathBuf,
+}
+
+impl Default for ParallelConfig {
+    fn default() -> Self {
+        Self {
+            base: Some(PARALLEL_BASE_PORTS[0]),
+            device: LptDevice::Printer,
+            mode: PrintMode::Text,
+            print_dir: PathBuf::from(crate::printer::PRINT_DIR),
+        }
+    }
+}
+
+/// A device attached to the parallel port. It sees the data and control outputs of the
+/// adapter and drives the status inputs.
+pub trait ParallelDevice: Send {
+    /// The adapter wrote the data lines. Most devices only sample them on a control line
+    /// edge.
+    fn write_data(&mut self, _data: u8) {}
+    /// The adapter's control outputs changed from `old` to `control`, with `data` on the
+    /// data lines.
+    fn write_control(&mut self, old: u8, control: u8, data: u8);
+    /// The status inputs, as status register bits.
+    fn status(&self) -> u8;
+    /// Whether ACK is a handshake pulse that raises IRQ7, rather than a status flag.
+    fn ack_interrupt(&self) -> bool {
+        true
+    }
+    fn run(&mut self, cycles: u32);
+    fn reset(&mut self);
+    /// The audio output level from -1.0 to 1.0, for devices that make sound.
+    fn audio_level(&self) -> Option<f32> {
+        None
+    }
+}
+
+pub struct ParallelPort {
+    base: u16,
+    data: u8,
+    control: u8,
+    device: Box<dyn ParallelDevice>,
+    irq_active: bool,
+}
+
+impl ParallelPort {
+    pub fn new(config: &ParallelConfig, base: u16) -> Self {
+        let device: Box<dyn ParallelDevice> = match config.device {
+            LptDevice::Printer => Box::new(Printer::new(config.mode, config.print_dir.clone())),
+            LptDevice::Covox => Box::new(Covox::new()),
+            LptDevice::SoundSource => Box::new(SoundSource::new()),
+        };
+        Self {
+            base,
+            data: 0,
+            control: CONTROL_NOT_INIT,
+            device,
+            irq_active: false,
+        }
+    }
+
+    /// Reset the adapter and the attached device.
+    pub fn reset(&mut self) {
+        self.data = 0;
+        self.control = CONTROL_NOT_INIT;
+        self.device.reset();
+    }
+
+    pub fn audio_level(&self) -> Option<f32> {
+        self.device.audio_level()
+    }
+
+    fn write_control(&mut self, byte: u8) {
+        let old = self.control;
+        self.control = byte & !CONTROL_UNUSED;
+        self.device.write_control(old, self.control, self.data);
+    }
+
+    pub fn run(&mut self, pic: &mut pic::Pic, cycles: u32) {
+        self.device.run(cycles);
+
+        // IRQ7 follows the ACK line while enabled
+        let ack = self.device.ack_interrupt() && self.device.status() & STATUS_NO_ACK == 0;
+        let irq = ack && self.control & CONTROL_IRQ_ENABLE != 0;
+        if irq != self.irq_active {
+            self.irq_active = irq;
+            if irq {
+                pic.request_interrupt(PARALLEL_IRQ);
+            }
+            else {
+                pic.clear_interrupt(PARALLEL_IRQ);
+            }
+        }
+    }
+}
+
+impl IoDevice for ParallelPort {
+    fn read_u8(&mut self, port: u16) -> u8 {
+        match port - self.base {
+            REG_DATA => self.data,
+            REG_STATUS => self.device.status() | STATUS_UNUSED,
+            REG_CONTROL => self.control | CONTROL_UNUSED,
+            _ => 0xFF
+        }
+    }
+
+    fn write_u8(&mut self, port: u16, data: u8) {
+        match port - self.base {
+            REG_DATA => {
+                self.data = data;
+                self.device.write_data(data);
+            }
+            REG_CONTROL => self.write_control(data),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_print_handshake() {
+        let mut pic = pic::Pic::new();
+        let dir = std::env::temp_dir().join(format!("marty_parallel_test_{}", std::process::id()));
+        let config = ParallelConfig {
+            base: Some(0x378),
+            device: LptDevice::Printer,
+            mode: PrintMode::Raw,
+            print_dir: dir.clone(),
+        };
+        let mut port = ParallelPort::new(&config, 0x378);
+
+        // Data latch reads back, used by the BIOS to detect the adapter
+        port.write_u8(0x378, 0xAA);
+        assert_eq!(port.read_u8(0x378), 0xAA);
+        assert_eq!(port.read_u8(0x379) & (STATUS_NOT_BUSY | STATUS_NO_ACK), STATUS_NOT_BUSY | STATUS_NO_ACK);
+
+        port.write_u8(0x37A, CONTROL_NOT_INIT | CONTROL_SELECT_IN | CONTROL_IRQ_ENABLE);
+        for byte in b"OK\r\n" {
+            port.write_u8(0x378, *byte);
+            port.write_u8(0x37A, CONTROL_NOT_INIT | CONTROL_SELECT_IN | CONTROL_IRQ_ENABLE | CONTROL_STROBE);
+            port.write_u8(0x37A, CONTROL_NOT_INIT | CONTROL_SELECT_IN | CONTROL_IRQ_ENABLE);
+            assert_eq!(port.read_u8(0x379) & STATUS_NOT_BUSY, 0);
+
+            // Wait for the printer to acknowledge the byte
+            let mut acked = false;
+            for _ in 0..1000 {
+                port.run(&mut pic, 7);
+                acked |= port.irq_active;
+                if port.read_u8(0x379) & STATUS_NOT_BUSY != 0 && !port.irq_active {
+                    break
+                }
+            }
+            assert!(acked);
+            assert!(!port.irq_active);
+        }
+
+        // Initializing the printer ends the job
+        port.write_u8(0x37A, CONTROL_SELECT_IN);
+        let job = std::fs::read(dir.join("job_0001.prn")).unwrap();
+        assert_eq!(job, b"OK\r\n");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    pub fn test_sound_source_no_irq() {
+        let mut pic = pic::Pic::new();
+        let config = ParallelConfig {
+            device: LptDevice::SoundSource,
+            ..Default::default()
+        };
+        let mut port = ParallelPort::new(&config, 0x378);
+
+        // ACK reads low while the FIFO has room, but doesn't interrupt
+        port.write_u8(0x37A, CONTROL_NOT_INIT | CONTROL_IRQ_ENABLE);
+        port.run(&mut pic, 7);
+        assert_eq!(port.read_u8(0x379) & STATUS_NO_ACK, 0);
+        assert!(!port.irq_active);
+    }
+}
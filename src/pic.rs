@@ -2,38 +2,75 @@
     pic.rc
     Implement the 8259 PIC (Programmable Interrupt Controller)
 
+    Supports fully nested and special fully nested modes, specific and non-specific EOI,
+    automatic EOI, priority rotation, special mask mode, the poll command and edge or level
+    triggered inputs.
+
+    Two PICs may be cascaded as on AT-class machines. The master owns the slave, and devices
+    request IRQs 0-15 through the master. The slave's INT output drives the master's IR2, and
+    IRQ2 requests are redirected to IRQ9 on the slave. On an acknowledge of a cascade input the
+    slave with the matching ICW3 ID supplies the vector.
+
+    Device IR lines are driven by request_interrupt() (line high) and clear_interrupt()
+    (line low). In edge triggered mode a request is latched on a rising edge and withdrawn if
+    the line falls before it is acknowledged. If the INT line was raised but the request is
+    gone by the time the CPU acknowledges it, a spurious IRQ7 is returned.
 */
 
-use std::io::Read;
-
 use crate::io::{IoDevice};
 
-
 pub const PIC_INTERRUPT_OFFSET: u8 = 8;
 
 pub const PIC_COMMAND_PORT: u16 = 0x20;
 pub const PIC_DATA_PORT: u16    = 0x21;
 
-const ICW1_ICW4_NEEDED: u8      = 0b0000_0001; // Bit set if a 4th control world is required (not supported)
-const ICW1_SINGLE_MODE: u8      = 0b0000_0010; // Bit is set if PIC is operating in signle mode (only supported configuration)
+pub const PIC_SLAVE_INTERRUPT_OFFSET: u8 = 0x70;
+pub const PIC_SLAVE_COMMAND_PORT: u16 = 0xA0;
+pub const PIC_SLAVE_DATA_PORT: u16    = 0xA1;
+
+pub const PIC_CASCADE_IRQ: u8   = 2; // Master IR the slave INT output is connected to
+pub const PIC_REDIRECT_IRQ: u8  = 9; // IRQ2 from the bus is redirected here when cascaded
+
+const ICW1_ICW4_NEEDED: u8      = 0b0000_0001; // Bit set if a 4th control world is required
+const ICW1_SINGLE_MODE: u8      = 0b0000_0010; // Bit is set if PIC is operating in signle mode, otherwise cascaded
 const ICW1_ADI: u8              = 0b0000_0100; // Bit is set if PIC is using a call address interval of 4, otherwise 8
 const ICW1_LTIML: u8            = 0b0000_1000; // Bit is set if PIC is in Level Triggered Mode
 const ICW1_IS_ICW1: u8          = 0b0001_0000; // Bit determines if input is ICW1
 
+const ICW2_VECTOR_MASK: u8      = 0b1111_1000; // Low 3 bits of the vector are the IR level in 8086 mode
+
+const ICW3_SLAVE_ID_MASK: u8    = 0b0000_0111; // Slave ICW3 holds its ID (master IR it is connected to)
+
 const ICW4_8088_MODE: u8        = 0b0000_0001; // Bit on if 8086/8088 mode (required)
 const ICW4_AEOI_MODE: u8        = 0b0000_0010; // Bit on if Auto EOI is enabled
 const ICW4_BUFFERED:  u8        = 0b0000_1000; // Bit on if Buffered mode
-const ICW4_NESTED: u8           = 0b0001_0000; // Bit on if Fully Nested mode
+const ICW4_SFNM: u8             = 0b0001_0000; // Bit on if Special Fully Nested mode
 
 const OCW_IS_OCW3: u8           = 0b0000_1000; // Bit on if OCW is OCW3
 
-const OCW2_NONSPECIFIC_EOI: u8  = 0b0010_0000;
+const OCW2_LEVEL_MASK: u8       = 0b0000_0111;
+const OCW2_COMMAND_MASK: u8     = 0b1110_0000;
+const OCW2_ROTATE_AEOI_CLEAR: u8        = 0b0000_0000;
+const OCW2_NONSPECIFIC_EOI: u8          = 0b0010_0000;
+const OCW2_NOP: u8                      = 0b0100_0000;
+const OCW2_SPECIFIC_EOI: u8             = 0b0110_0000;
+const OCW2_ROTATE_AEOI_SET: u8          = 0b1000_0000;
+const OCW2_ROTATE_NONSPECIFIC_EOI: u8   = 0b1010_0000;
+const OCW2_SET_PRIORITY: u8             = 0b1100_0000;
+const OCW2_ROTATE_SPECIFIC_EOI: u8      = 0b1110_0000;
+
+const OCW3_ESMM: u8             = 0b0100_0000; // Enable a change to special mask mode
+const OCW3_SMM: u8              = 0b0010_0000; // Special mask mode value
 const OCW3_POLL_COMMAND: u8     = 0b0000_0100;
 const OCW3_RR_COMMAND: u8       = 0b0000_0011;
 
+const POLL_INTERRUPT_BIT: u8    = 0b1000_0000;
+const SPURIOUS_IRQ: u8          = 7;
+
 pub enum InitializationState {
     Normal,             // Normal operation, can receive an ICW1 at any point
     ExpectingICW2,      // In initialization sequence, expecting ICW2
+    ExpectingICW3,      // In initialization sequence, expecting ICW3 (cascade mode only)
     ExpectingICW4       // In initialization sequence, expecting ICW4
 }
 
@@ -69,18 +106,25 @@ pub struct Pic {
     imr: u8,                 // Interrupt Mask Register
     isr: u8,                 // In-Service Register
     irr: u8,                 // Interrupt Request Register
+    ir_lines: u8,            // Current state of the IR input lines
     read_select: ReadSelect, // Select register to read.  True=ISR, False=IRR
-    irq: u8,                 // IRQ Number
+    lowest_priority: u8,     // IR level with the lowest priority. Rotation changes this.
     int_request: bool,       // INT request line of PIC
+    level_triggered: bool,   // Level triggered mode
     buffered: bool,          // Buffered mode
-    nested: bool,            // Nested mode
     special_nested: bool,    // Special fully nested mode
-    polled: bool,            // Polled mode
+    special_mask: bool,      // Special mask mode
+    poll_pending: bool,      // Poll command received, next read of the command port is a poll
     auto_eoi: bool,          // Auto-EOI mode
     rotate_on_aeoi: bool,    // Should rotate in Auto-EOI mode
-    expecting_icw2: bool,
-    expecting_icw4: bool,    // ICW3 not supported in Single mode operation
+    expecting_icw4: bool,
+    single: bool,            // Single mode. If false, ICW3 is expected during initialization
+    is_slave: bool,          // Whether this PIC is wired as a slave
+    cascade_mask: u8,        // Master ICW3: IR inputs that have a slave attached
+    slave_id: u8,            // Slave ICW3: ID of this slave
+    slave: Option<Box<Pic>>, // Slave PIC, if cascaded
     error: bool,             // We encountered an invalid condition or request
+    spurious_count: u64,
 
     interrupt_stats: Vec<InterruptStats>
 }
@@ -91,6 +135,10 @@ pub struct PicStringState {
     pub imr: String,
     pub isr: String,
     pub irr: String,
+    pub ir_lines: String,
+    pub lowest_priority: String,
+    pub trigger_mode: String,
+    pub spurious_count: String,
 
     pub interrupt_stats: Vec<(String, String, String)>
 }
@@ -105,8 +153,14 @@ impl IoDevice for Pic {
             PIC_DATA_PORT => {
                 self.handle_data_register_read()
             },
+            PIC_SLAVE_COMMAND_PORT | PIC_SLAVE_DATA_PORT if self.slave.is_some() => {
+                let byte = self.slave.as_mut().unwrap().read_u8(port & !0x80);
+                // A poll read may have acknowledged a slave request
+                self.sync_cascade();
+                byte
+            },
             _ => unreachable!("PIC: Bad port #")
-        }        
+        }
     }
     fn write_u8(&mut self, port: u16, data: u8) {
         match port {
@@ -116,9 +170,13 @@ impl IoDevice for Pic {
             PIC_DATA_PORT => {
                 self.handle_data_register_write(data);
             },
+            PIC_SLAVE_COMMAND_PORT | PIC_SLAVE_DATA_PORT if self.slave.is_some() => {
+                self.slave.as_mut().unwrap().write_u8(port & !0x80, data);
+                self.sync_cascade();
+            },
             _ => unreachable!("PIC: Bad port #")
-        }    
-    }    
+        }
+    }
 }
 
 impl Pic {
@@ -129,39 +187,71 @@ impl Pic {
             imr: 0xFF,                           // All IRQs initially masked
             isr: 0x00,
             irr: 0,
+            ir_lines: 0,
             read_select: ReadSelect::IRR,
-            irq: 0,
+            lowest_priority: 7,
             int_request: false,
+            level_triggered: false,
             buffered: false,
-            nested: true,
             special_nested: false,
-            polled: false,
+            special_mask: false,
+            poll_pending: false,
             auto_eoi: false,
             rotate_on_aeoi: false,
-            expecting_icw2: false,
             expecting_icw4: false,
+            single: true,
+            is_slave: false,
+            cascade_mask: 0,
+            slave_id: 0,
+            slave: None,
             error: false,
+            spurious_count: 0,
             interrupt_stats: vec![InterruptStats::new(); 8]
         }
     }
 
+    /// Create a master PIC with a slave PIC attached to its IR2 input.
+    pub fn new_cascaded() -> Self {
+        let mut slave = Pic::new();
+        slave.is_slave = true;
+        slave.int_offset = PIC_SLAVE_INTERRUPT_OFFSET;
+
+        let mut master = Pic::new();
+        master.slave = Some(Box::new(slave));
+        master
+    }
+
+    pub fn has_slave(&self) -> bool {
+        self.slave.is_some()
+    }
+
     pub fn reset(&mut self) {
         self.init_state = InitializationState::Normal;
+        self.int_offset = if self.is_slave { PIC_SLAVE_INTERRUPT_OFFSET } else { PIC_INTERRUPT_OFFSET };
         self.imr = 0xFF;
         self.isr = 0x00;
         self.irr = 0x00;
+        self.ir_lines = 0x00;
         self.read_select = ReadSelect::IRR;
-        self.irq = 0;
+        self.lowest_priority = 7;
         self.int_request = false;
+        self.level_triggered = false;
         self.buffered = false;
-        self.nested = true;
         self.special_nested = false;
-        self.polled = false;
+        self.special_mask = false;
+        self.poll_pending = false;
         self.auto_eoi = false;
         self.rotate_on_aeoi = false;
-        self.expecting_icw2 = false;
         self.expecting_icw4 = false;
+        self.single = true;
+        self.cascade_mask = 0;
+        self.slave_id = 0;
         self.error = false;
+        self.spurious_count = 0;
+
+        if let Some(slave) = self.slave.as_mut() {
+            slave.reset();
+        }
 
         for stat_entry in &mut self.interrupt_stats {
             stat_entry.imr_masked_count = 0;
@@ -182,94 +272,234 @@ impl Pic {
                 log::warn!("PIC: Warning: Received unexpected ICW1: {:02X}", byte);
             }
 
-            if byte & ICW1_SINGLE_MODE == 0 { 
-                log::error!("PIC: Error: Chained mode not supported");
-                self.error = true;
+            self.single = byte & ICW1_SINGLE_MODE != 0;
+            if !self.single && !self.is_slave && self.slave.is_none() {
+                log::warn!("PIC: Cascade mode selected but no slave PIC is attached");
             }
             if byte & ICW1_ADI != 0 {
-                log::error!("PIC: Error: 4 byte ADI unsupported");
-                self.error = true;
+                // Call address interval only matters in MCS-80/85 mode
+                log::debug!("PIC: 4 byte ADI set (ignored in 8086 mode)");
             }
 
-            self.init_state = InitializationState::ExpectingICW2;
-            if byte & ICW1_ICW4_NEEDED != 0 {
-                self.expecting_icw4 = true;
+            // The initialization sequence clears the IMR and ISR, resets the edge sense
+            // latches, assigns IR7 the lowest priority, clears special mask mode and selects
+            // the IRR for reading. ICW4 bits are cleared if no ICW4 is to follow.
+            self.level_triggered = byte & ICW1_LTIML != 0;
+            self.imr = 0x00;
+            self.isr = 0x00;
+            self.irr = if self.level_triggered { self.ir_lines } else { 0x00 };
+            self.lowest_priority = 7;
+            self.special_mask = false;
+            self.poll_pending = false;
+            self.read_select = ReadSelect::IRR;
+            self.int_request = false;
+
+            self.expecting_icw4 = byte & ICW1_ICW4_NEEDED != 0;
+            if !self.expecting_icw4 {
+                self.auto_eoi = false;
+                self.buffered = false;
+                self.special_nested = false;
             }
+            self.init_state = InitializationState::ExpectingICW2;
         }
-        else if byte & OCW2_NONSPECIFIC_EOI != 0 {
-            //log::trace!("PIC: Received nonspecific EOI");
-
-            self.isr = Pic::clear_lsb(self.isr);
-        }
-        else if byte & OCW_IS_OCW3 != 0  { 
-            
-            let rr = match byte & OCW3_RR_COMMAND {
-                0b10 => {
-                    log::debug!("PIC: OCW3 Read Selected IRR register");
-                    ReadSelect::IRR
-                },
-                0b11 => {
-                    log::debug!("PIC: OCW3 Read Selected ISR register");
-                    ReadSelect::ISR
+        else if byte & OCW_IS_OCW3 != 0  {
+            self.handle_ocw3(byte);
+        }
+        else {
+            self.handle_ocw2(byte);
+        }
+
+        self.update_int_request();
+    }
+
+    fn handle_ocw2(&mut self, byte: u8) {
+        let level = byte & OCW2_LEVEL_MASK;
+
+        match byte & OCW2_COMMAND_MASK {
+            OCW2_NONSPECIFIC_EOI => {
+                //log::trace!("PIC: Received nonspecific EOI");
+                self.nonspecific_eoi();
+            }
+            OCW2_SPECIFIC_EOI => {
+                log::trace!("PIC: Received specific EOI for IR {}", level);
+                self.isr &= !(0x01 << level);
+            }
+            OCW2_ROTATE_NONSPECIFIC_EOI => {
+                if let Some(ir) = self.nonspecific_eoi() {
+                    self.lowest_priority = ir;
                 }
-                _ => self.read_select
-            };
-            self.read_select = rr;
+            }
+            OCW2_ROTATE_SPECIFIC_EOI => {
+                self.isr &= !(0x01 << level);
+                self.lowest_priority = level;
+            }
+            OCW2_SET_PRIORITY => {
+                log::debug!("PIC: Set lowest priority to IR {}", level);
+                self.lowest_priority = level;
+            }
+            OCW2_ROTATE_AEOI_SET => {
+                self.rotate_on_aeoi = true;
+            }
+            OCW2_ROTATE_AEOI_CLEAR => {
+                self.rotate_on_aeoi = false;
+            }
+            OCW2_NOP => {}
+            _ => unreachable!()
+        }
+    }
 
+    fn handle_ocw3(&mut self, byte: u8) {
+        if byte & OCW3_ESMM != 0 {
+            self.special_mask = byte & OCW3_SMM != 0;
+            log::debug!("PIC: Special mask mode: {}", self.special_mask);
         }
-        else {
-            log::trace!("PIC: Unhandled command: {:02X}", byte)
+
+        if byte & OCW3_POLL_COMMAND != 0 {
+            self.poll_pending = true;
         }
+
+        match byte & OCW3_RR_COMMAND {
+            0b10 => {
+                log::debug!("PIC: OCW3 Read Selected IRR register");
+                self.read_select = ReadSelect::IRR;
+            },
+            0b11 => {
+                log::debug!("PIC: OCW3 Read Selected ISR register");
+                self.read_select = ReadSelect::ISR;
+            }
+            _ => {}
+        }
+    }
+
+    /// Clear the highest priority ISR bit and return its IR level.
+    fn nonspecific_eoi(&mut self) -> Option<u8> {
+        let ir = self.highest_priority_in(self.isr)?;
+        self.isr &= !(0x01 << ir);
+        Some(ir)
     }
 
+    /// Iterate over IR levels from highest to lowest priority.
+    fn priority_order(&self) -> impl Iterator<Item = u8> {
+        let highest = (self.lowest_priority + 1) & 0x07;
+        (0..8).map(move |i| (highest + i) & 0x07)
+    }
+
+    fn highest_priority_in(&self, bits: u8) -> Option<u8> {
+        self.priority_order().find(|ir| bits & (0x01 << ir) != 0)
+    }
+
+    /// Resolve the highest priority request that may be serviced, taking into account
+    /// the IMR and the requests currently in service.
+    fn resolve_request(&self) -> Option<u8> {
+
+        let requests = self.irr & !self.imr;
 
-    pub fn clear_msb(byte: u8) -> u8 {
+        for ir in self.priority_order() {
+            let ir_bit = 0x01 << ir;
 
-        let mut mask: u8 = 0x80;
-        let mut byte = byte;
-        for _ in 0..8 {
-            if byte & mask != 0 {
-                byte &= !mask;
-                break;
+            // In special mask mode the ISR doesn't inhibit other levels. The in-service
+            // level is expected to be masked by the IMR.
+            if self.isr & ir_bit != 0 && !self.special_mask {
+                if self.special_nested && requests & ir_bit != 0 {
+                    // In special fully nested mode a request from a level in service is
+                    // allowed through (for a slave's higher priority inputs)
+                    return Some(ir)
+                }
+                else {
+                    // A higher priority level is in service, block everything below it
+                    return None
+                }
             }
-            mask >>= 1;
-        }
-        byte
-    }    
-    pub fn clear_lsb(byte: u8) -> u8 {
-
-        let mut mask: u8 = 0x01;
-        let mut byte = byte;
-        for _ in 0..8 {
-            if byte & mask != 0 {
-                byte &= !mask;
-                break;
+
+            if requests & ir_bit != 0 {
+                return Some(ir)
             }
-            mask <<= 1;
         }
-        byte
+        None
+    }
+
+    /// Raise the INT line if there is a request to be serviced. Once raised, INT stays high
+    /// until acknowledged.
+    fn update_int_request(&mut self) {
+        if self.resolve_request().is_some() {
+            self.int_request = true;
+        }
+    }
+
+    /// Acknowledge the highest priority request, as if by INTA or a poll command. Returns
+    /// the IR level, or None if there is nothing to service.
+    fn acknowledge(&mut self) -> Option<u8> {
+        self.int_request = false;
+
+        let ir = self.resolve_request()?;
+        let ir_bit = 0x01 << ir;
+
+        self.isr |= ir_bit;
+        self.interrupt_stats[ir as usize].serviced_count += 1;
+
+        // An edge triggered request must see another rising edge before it is latched again.
+        // A level triggered request is reasserted for as long as the line is high.
+        self.irr &= !ir_bit;
+        if self.level_triggered {
+            self.irr |= self.ir_lines & ir_bit;
+        }
+
+        if self.auto_eoi {
+            self.isr &= !ir_bit;
+            if self.rotate_on_aeoi {
+                self.lowest_priority = ir;
+            }
+        }
+
+        self.update_int_request();
+        Some(ir)
     }
 
     pub fn handle_data_register_write(&mut self, byte: u8) {
-        // Handle ICW2 & ICW4 (ICW3 skipped in Single mode)
+        // Handle ICW2, ICW3 & ICW4 (ICW3 skipped in Single mode)
         match self.init_state {
             InitializationState::Normal => {
                 // We aren't expecting any ICWs, so treat this write as a set of the IMR
                 log::trace!("PIC: Set IMR to: {:02X}", byte);
                 self.set_imr(byte);
-                return;
             }
             InitializationState::ExpectingICW2 => {
                 // This value should be an ICW2 based on just receiving an ICW1 on control port
 
                 log::debug!("PIC: Read ICW2: {:02X}", byte);
-                self.init_state = InitializationState::ExpectingICW4;
-                return;
+                self.int_offset = byte & ICW2_VECTOR_MASK;
+                if !self.single {
+                    self.init_state = InitializationState::ExpectingICW3;
+                }
+                else if self.expecting_icw4 {
+                    self.init_state = InitializationState::ExpectingICW4;
+                }
+                else {
+                    self.init_state = InitializationState::Normal;
+                }
+            }
+            InitializationState::ExpectingICW3 => {
+                // A master's ICW3 has a bit set for each IR with a slave attached. A slave's ICW3
+                // holds its ID, which is the master IR it is connected to.
+                log::debug!("PIC: Read ICW3: {:02X}", byte);
+                if self.is_slave {
+                    self.slave_id = byte & ICW3_SLAVE_ID_MASK;
+                }
+                else {
+                    self.cascade_mask = byte;
+                }
+                if self.expecting_icw4 {
+                    self.init_state = InitializationState::ExpectingICW4;
+                }
+                else {
+                    self.init_state = InitializationState::Normal;
+                }
             }
             InitializationState::ExpectingICW4 => {
-                // This value should be an ICW4 based on receiving an ICW2 (ICW3 skipped in Single mode)
+                // This value should be an ICW4 based on receiving an ICW2 or ICW3
                 log::debug!("PIC: Read ICW4: {:02X}", byte);
                 self.init_state = InitializationState::Normal;
+                self.expecting_icw4 = false;
 
                 if byte & ICW4_8088_MODE == 0 {
                     log::error!("PIC: Error: MCS-80/85 mode unsupported");
@@ -277,17 +507,24 @@ impl Pic {
                 }
                 self.auto_eoi = byte & ICW4_AEOI_MODE != 0;
                 self.buffered = byte & ICW4_BUFFERED != 0;
-                if byte & ICW4_NESTED != 0 {
-                    log::error!("PIC: Error: MCS-80/85 mode unsupported");
-                    self.error = true;
-                }
-                return;
+                self.special_nested = byte & ICW4_SFNM != 0;
             }
         }
 
     }
 
     pub fn handle_command_register_read(&mut self) -> u8 {
+
+        if self.poll_pending {
+            // A poll read acknowledges the highest priority request like an INTA would,
+            // and returns its level with bit 7 set.
+            self.poll_pending = false;
+            return match self.acknowledge() {
+                Some(ir) => POLL_INTERRUPT_BIT | ir,
+                None => 0
+            }
+        }
+
         match self.read_select {
             ReadSelect::ISR => {
                 self.isr
@@ -306,39 +543,65 @@ impl Pic {
 
         // Changing the IMR will allow devices with current high IR lines to generate interrupts
         self.imr = byte;
+        self.update_int_request();
+    }
 
-        let mut ir_bit = 0x01;
-        for interrupt in 0..8 {
-
-            let have_request = ir_bit & self.irr != 0;
-            let is_masked = ir_bit & self.imr != 0;
-            let is_in_service = ir_bit & self.isr != 0;
-
-            if have_request && !is_masked && !is_in_service {
-                // (Set INT request line high)
-                self.int_request = true;
-                self.interrupt_stats[interrupt as usize].serviced_count += 1;
-            }
+    /// Map a device IRQ to the slave's IR input if it is routed there.
+    fn slave_ir(&self, interrupt: u8) -> Option<u8> {
+        self.slave.as_ref()?;
+        match interrupt {
+            PIC_CASCADE_IRQ => Some(PIC_REDIRECT_IRQ - 8),
+            8..=15 => Some(interrupt - 8),
+            _ => None
+        }
+    }
 
-            ir_bit <<= 1;
+    /// Propagate the slave's INT output to the master's cascade IR input.
+    fn sync_cascade(&mut self) {
+        let slave_int = match &self.slave {
+            Some(slave) => slave.int_request,
+            None => return
+        };
+        if slave_int {
+            self.raise_ir_line(PIC_CASCADE_IRQ);
+        }
+        else {
+            self.lower_ir_line(PIC_CASCADE_IRQ);
         }
-        
     }
 
     pub fn request_interrupt(&mut self, interrupt: u8) {
         // Called by a device to request interrupt service
         // Simulates IR line going high
 
+        if let Some(ir) = self.slave_ir(interrupt) {
+            self.slave.as_mut().unwrap().raise_ir_line(ir);
+            self.sync_cascade();
+            return
+        }
+
         if interrupt > 7 {
             panic!("PIC: Received interrupt out of range: {}", interrupt);
         }
 
         //log::trace!("PIC: Interrupt {} requested by device", interrupt);
+        self.raise_ir_line(interrupt);
+    }
+
+    fn raise_ir_line(&mut self, interrupt: u8) {
 
         // Interrupts 0-7 map to bits 0-7 in IMR register
         let intr_bit: u8 = 0x01 << interrupt;
+
+        if self.ir_lines & intr_bit != 0 {
+            // Line is already high. In edge triggered mode there is no new edge to latch,
+            // and in level triggered mode the request is already asserted.
+            return
+        }
+        self.ir_lines |= intr_bit;
+
         // Set the request bit in the IR register
-        self.irr |= intr_bit; 
+        self.irr |= intr_bit;
 
         if self.imr & intr_bit != 0 {
             // If the corresponding bit is set in the IMR, it is masked: do not process right now
@@ -348,84 +611,249 @@ impl Pic {
             // If the corresponding bit is set in the ISR, do not process right now
             self.interrupt_stats[interrupt as usize].isr_masked_count += 1;
         }
-        else {
-            // Interrupt is not masked or already in service, process it...
-            // (Set INT request line high)
-            self.int_request = true;
-            self.interrupt_stats[interrupt as usize].serviced_count += 1;
-        }
+
+        self.update_int_request();
     }
 
     pub fn clear_interrupt(&mut self, interrupt: u8) {
         // Called by device to withdraw interrupt service request
         // Simulates IR line going low
+
+        if let Some(ir) = self.slave_ir(interrupt) {
+            self.slave.as_mut().unwrap().lower_ir_line(ir);
+            self.sync_cascade();
+            return
+        }
+
         if interrupt > 7 {
             panic!("PIC: Received interrupt out of range: {}", interrupt);
         }
+        self.lower_ir_line(interrupt);
+    }
+
+    fn lower_ir_line(&mut self, interrupt: u8) {
 
-        // Clear the corresponding bit in the IRR register
+        // Clear the corresponding bit in the IRR register. In either trigger mode a request
+        // must be held until it is acknowledged. We don't lower INT here; if nothing else is
+        // requesting by the time the CPU acknowledges, it will get a spurious IRQ7.
         let intr_bit: u8 = 0x01 << interrupt;
+        self.ir_lines &= !intr_bit;
         self.irr &= !intr_bit;
     }
 
     pub fn query_interrupt_line(&self) -> bool {
-         
+
         self.int_request
     }
 
+    /// Perform an interrupt acknowledge cycle and return the vector to execute.
     pub fn get_interrupt_vector(&mut self) -> Option<u8> {
 
-        // Only handling timer interrupts for now
-        // Return the highest priority vector not currently masked from the IRR
-        let mut ir_bit: u8 = 0x01;
-        for irq in 0..8 {
-
-            let have_request = ir_bit & self.irr != 0;
-            let is_masked = ir_bit & self.imr != 0;
-            let is_in_service = ir_bit & self.isr != 0;
-
-            if have_request && !is_masked {
-                
-                // found highest priority IRR not masked
-
-                // Clear its bit in the IR...
-                self.irr &= !ir_bit;
-                // ...and set it in ISR being serviced
-                self.isr |= ir_bit;
-                self.irq = irq;
-                // INT line low
-                self.int_request = false;
+        if !self.int_request {
+            return None
+        }
 
-                return Some(irq + PIC_INTERRUPT_OFFSET)
+        match self.acknowledge() {
+            Some(ir) if !self.single && self.cascade_mask & (0x01 << ir) != 0 && self.slave.is_some() => {
+                // A slave is attached to this input. It supplies the vector.
+                let slave = self.slave.as_mut().unwrap();
+                if slave.slave_id != ir {
+                    log::warn!("PIC: Slave ID {} doesn't match cascade IR {}", slave.slave_id, ir);
+                }
+                let vector = slave.get_interrupt_vector();
+                // The slave's INT drops during the acknowledge. If it has another request
+                // pending, INT rises again, giving us a new edge on the cascade input.
+                self.lower_ir_line(PIC_CASCADE_IRQ);
+                self.sync_cascade();
+                vector
+            }
+            Some(ir) => Some(ir + self.int_offset),
+            None => {
+                // The request went away between raising INT and the acknowledge. The 8259
+                // supplies the IR7 vector without setting the ISR bit.
+                log::trace!("PIC: Spurious interrupt");
+                self.spurious_count += 1;
+                Some(SPURIOUS_IRQ + self.int_offset)
             }
-            ir_bit <<= 1;
         }
-        None
-    }
-
-    pub fn end_of_interrupt(&mut self) {
-        // Clear ISR bit
-        let intr_bit: u8 = 0x01 << self.irq;
-        self.isr &= !intr_bit;
     }
 
     pub fn get_string_state(&self) -> PicStringState {
-    
+
         let mut state = PicStringState {
             imr: format!("{:08b}", self.imr),
             irr: format!("{:08b}", self.irr),
             isr: format!("{:08b}", self.isr),
+            ir_lines: format!("{:08b}", self.ir_lines),
+            lowest_priority: format!("{}", self.lowest_priority),
+            trigger_mode: if self.level_triggered { "Level".to_string() } else { "Edge".to_string() },
+            spurious_count: format!("{}", self.spurious_count),
             interrupt_stats: Vec::new()
         };
 
         for i in 0..8 {
             state.interrupt_stats.push(
-                ( 
-                    format!("{}", self.interrupt_stats[i].imr_masked_count), 
-                    format!("{}", self.interrupt_stats[i].isr_masked_count), 
+                (
+                    format!("{}", self.interrupt_stats[i].imr_masked_count),
+                    format!("{}", self.interrupt_stats[i].isr_masked_count),
                     format!("{}", self.interrupt_stats[i].serviced_count )
                 ));
         }
         state
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn init_pic(icw1: u8, icw4: u8) -> Pic {
+        let mut pic = Pic::new();
+        pic.handle_command_register_write(ICW1_IS_ICW1 | ICW1_SINGLE_MODE | ICW1_ICW4_NEEDED | icw1);
+        pic.handle_data_register_write(PIC_INTERRUPT_OFFSET);
+        pic.handle_data_register_write(ICW4_8088_MODE | icw4);
+        pic
+    }
+
+    #[test]
+    pub fn test_priority_nesting() {
+        let mut pic = init_pic(0, 0);
+
+        pic.request_interrupt(3);
+        assert_eq!(pic.get_interrupt_vector(), Some(PIC_INTERRUPT_OFFSET + 3));
+
+        // Lower priority is blocked while IR3 is in service, higher is not
+        pic.request_interrupt(5);
+        assert!(!pic.query_interrupt_line());
+        pic.request_interrupt(1);
+        assert!(pic.query_interrupt_line());
+        assert_eq!(pic.get_interrupt_vector(), Some(PIC_INTERRUPT_OFFSET + 1));
+
+        // Non-specific EOI clears IR1, IR3 still blocks IR5
+        pic.handle_command_register_write(OCW2_NONSPECIFIC_EOI);
+        assert_eq!(pic.isr, 0b0000_1000);
+        assert!(!pic.query_interrupt_line());
+
+        // Specific EOI for IR3 lets IR5 through
+        pic.handle_command_register_write(OCW2_SPECIFIC_EOI | 3);
+        assert!(pic.query_interrupt_line());
+        assert_eq!(pic.get_interrupt_vector(), Some(PIC_INTERRUPT_OFFSET + 5));
+    }
+
+    #[test]
+    pub fn test_rotation() {
+        let mut pic = init_pic(0, 0);
+
+        // Make IR4 the lowest priority, so IR5 is the highest
+        pic.handle_command_register_write(OCW2_SET_PRIORITY | 4);
+        pic.request_interrupt(0);
+        pic.request_interrupt(6);
+        assert_eq!(pic.get_interrupt_vector(), Some(PIC_INTERRUPT_OFFSET + 6));
+
+        // Rotate on non-specific EOI: IR6 becomes lowest, IR7 the highest
+        pic.handle_command_register_write(OCW2_ROTATE_NONSPECIFIC_EOI);
+        assert_eq!(pic.lowest_priority, 6);
+        assert_eq!(pic.get_interrupt_vector(), Some(PIC_INTERRUPT_OFFSET));
+
+        // Automatic rotation in AEOI mode
+        let mut pic = init_pic(0, ICW4_AEOI_MODE);
+        pic.handle_command_register_write(OCW2_ROTATE_AEOI_SET);
+        pic.request_interrupt(2);
+        assert_eq!(pic.get_interrupt_vector(), Some(PIC_INTERRUPT_OFFSET + 2));
+        assert_eq!(pic.isr, 0);
+        assert_eq!(pic.lowest_priority, 2);
+    }
+
+    #[test]
+    pub fn test_special_mask() {
+        let mut pic = init_pic(0, 0);
+
+        pic.request_interrupt(2);
+        assert_eq!(pic.get_interrupt_vector(), Some(PIC_INTERRUPT_OFFSET + 2));
+
+        // Mask IR2 and enable special mask mode. Lower priority IR6 can now interrupt.
+        pic.handle_data_register_write(0b0000_0100);
+        pic.handle_command_register_write(OCW_IS_OCW3 | OCW3_ESMM | OCW3_SMM);
+        pic.request_interrupt(6);
+        assert_eq!(pic.get_interrupt_vector(), Some(PIC_INTERRUPT_OFFSET + 6));
+    }
+
+    #[test]
+    pub fn test_poll_and_spurious() {
+        let mut pic = init_pic(0, 0);
+
+        pic.handle_command_register_write(OCW_IS_OCW3 | OCW3_POLL_COMMAND);
+        assert_eq!(pic.handle_command_register_read(), 0);
+
+        pic.request_interrupt(4);
+        pic.handle_command_register_write(OCW_IS_OCW3 | OCW3_POLL_COMMAND);
+        assert_eq!(pic.handle_command_register_read(), POLL_INTERRUPT_BIT | 4);
+        assert_eq!(pic.isr, 0b0001_0000);
+        pic.handle_command_register_write(OCW2_NONSPECIFIC_EOI);
+
+        // Request withdrawn before the acknowledge: spurious IRQ7 without setting ISR
+        pic.request_interrupt(0);
+        pic.clear_interrupt(0);
+        assert!(pic.query_interrupt_line());
+        assert_eq!(pic.get_interrupt_vector(), Some(PIC_INTERRUPT_OFFSET + 7));
+        assert_eq!(pic.isr, 0);
+    }
+
+    #[test]
+    pub fn test_level_triggered() {
+        let mut pic = init_pic(ICW1_LTIML, 0);
+
+        pic.request_interrupt(3);
+        assert_eq!(pic.get_interrupt_vector(), Some(PIC_INTERRUPT_OFFSET + 3));
+
+        // Line still high: request is reasserted after EOI without a new edge
+        pic.handle_command_register_write(OCW2_NONSPECIFIC_EOI);
+        assert!(pic.query_interrupt_line());
+        assert_eq!(pic.get_interrupt_vector(), Some(PIC_INTERRUPT_OFFSET + 3));
+
+        // Edge triggered mode requires a new edge
+        let mut pic = init_pic(0, 0);
+        pic.request_interrupt(3);
+        assert_eq!(pic.get_interrupt_vector(), Some(PIC_INTERRUPT_OFFSET + 3));
+        pic.handle_command_register_write(OCW2_NONSPECIFIC_EOI);
+        assert!(!pic.query_interrupt_line());
+    }
+
+    #[test]
+    pub fn test_cascade() {
+        let mut pic = Pic::new_cascaded();
+
+        // Initialize the master with a slave on IR2, then the slave with ID 2
+        pic.write_u8(PIC_COMMAND_PORT, ICW1_IS_ICW1 | ICW1_ICW4_NEEDED);
+        pic.write_u8(PIC_DATA_PORT, PIC_INTERRUPT_OFFSET);
+        pic.write_u8(PIC_DATA_PORT, 0b0000_0100);
+        pic.write_u8(PIC_DATA_PORT, ICW4_8088_MODE);
+        pic.write_u8(PIC_SLAVE_COMMAND_PORT, ICW1_IS_ICW1 | ICW1_ICW4_NEEDED);
+        pic.write_u8(PIC_SLAVE_DATA_PORT, PIC_SLAVE_INTERRUPT_OFFSET);
+        pic.write_u8(PIC_SLAVE_DATA_PORT, PIC_CASCADE_IRQ);
+        pic.write_u8(PIC_SLAVE_DATA_PORT, ICW4_8088_MODE);
+
+        // IRQ 14 comes in through the slave
+        pic.request_interrupt(14);
+        assert!(pic.query_interrupt_line());
+        assert_eq!(pic.get_interrupt_vector(), Some(PIC_SLAVE_INTERRUPT_OFFSET + 6));
+        assert_eq!(pic.isr, 0b0000_0100);
+
+        // IRQ 2 is redirected to IRQ 9. It is blocked by IR2 in service on the master.
+        pic.request_interrupt(2);
+        assert!(!pic.query_interrupt_line());
+
+        // EOI the slave, then the master. IRQ 9 is then delivered.
+        pic.write_u8(PIC_SLAVE_COMMAND_PORT, OCW2_NONSPECIFIC_EOI);
+        assert!(!pic.query_interrupt_line());
+        pic.write_u8(PIC_COMMAND_PORT, OCW2_NONSPECIFIC_EOI);
+        assert!(pic.query_interrupt_line());
+        assert_eq!(pic.get_interrupt_vector(), Some(PIC_SLAVE_INTERRUPT_OFFSET + 1));
+
+        // Master IRQs are unaffected
+        pic.write_u8(PIC_SLAVE_COMMAND_PORT, OCW2_NONSPECIFIC_EOI);
+        pic.write_u8(PIC_COMMAND_PORT, OCW2_NONSPECIFIC_EOI);
+        pic.request_interrupt(0);
+        assert_eq!(pic.get_interrupt_vector(), Some(PIC_INTERRUPT_OFFSET));
+    }
+}
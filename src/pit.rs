@@ -6,10 +6,11 @@
 use log;
 
 use crate::io::{IoBusInterface, IoDevice};
-use crate::bus::{BusInterface};
 use crate::cpu::CPU_MHZ;
 use crate::pic;
 use crate::dma;
+use crate::ppi;
+use crate::sound;
 
 const PIT_CHANNEL_PORT_BASE: u16 = 0x40;
 pub const PIT_CHANNEL_0_DATA_PORT: u16 = 0x40;
@@ -48,11 +49,14 @@ pub struct PitChannel {
     access_mode: AccessMode,
     reload_value: u16,
     waiting_for_reload: bool,
-    waiting_for_lobyte: bool,
     waiting_for_hibyte: bool,
+    load_pending: bool,
+    counting: bool,
+    armed: bool,
+    null_count: bool,
+    half_cycle_extend: bool,
     current_count: u16,
     read_in_progress: bool,
-    normal_lobyte_read: bool,    
     count_is_latched: bool,
     output_is_high: bool,
     latched_lobyte_read: bool,
@@ -74,14 +78,20 @@ pub struct PitStringState {
     pub c0_reload_value: String,
     pub c0_access_mode: String,
     pub c0_channel_mode: String,
+    pub c0_output: String,
+    pub c0_null_count: String,
     pub c1_value: String,
     pub c1_reload_value: String,
     pub c1_access_mode: String,
     pub c1_channel_mode: String,
+    pub c1_output: String,
+    pub c1_null_count: String,
     pub c2_value: String,
     pub c2_reload_value: String,
     pub c2_access_mode: String,
     pub c2_channel_mode: String,
+    pub c2_output: String,
+    pub c2_null_count: String,
     pub c2_gate_status: String,
 }
 
@@ -107,6 +117,275 @@ impl IoDevice for ProgrammableIntervalTimer {
 
 }
 
+/// Decrement a count in BCD, wrapping from 0000 to 9999.
+fn bcd_decrement(count: u16) -> u16 {
+    if count == 0 {
+        return 0x9999
+    }
+    let mut result = count;
+    let mut digit_mask: u16 = 0x000F;
+    let mut shift = 0;
+    // Borrow from higher digits while the current digit is 0
+    while (result & digit_mask) >> shift == 0 {
+        result |= 0x9 << shift;
+        digit_mask <<= 4;
+        shift += 4;
+    }
+    result - (1 << shift)
+}
+
+impl PitChannel {
+    fn new() -> Self {
+        Self {
+            channel_mode: ChannelMode::InterruptOnTerminalCount,
+            access_mode: AccessMode::HiByteOnly,
+            reload_value: 0,
+            waiting_for_reload: true,
+            waiting_for_hibyte: false,
+            load_pending: false,
+            counting: false,
+            armed: false,
+            null_count: true,
+            half_cycle_extend: false,
+            current_count: 0,
+            read_in_progress: false,
+            count_is_latched: false,
+            output_is_high: false,
+            latched_lobyte_read: false,
+            latch_count: 0,
+            bcd_mode: false,
+            input_gate: true,
+        }
+    }
+
+    /// Set the channel mode and access mode from a control word. This resets the channel
+    /// until a new count is written.
+    fn set_mode(&mut self, channel_mode: ChannelMode, access_mode: AccessMode, bcd_mode: bool) {
+
+        // Intel: The output will be initially low after the mode set operation in Mode 0. In all 
+        // other modes the output goes high.
+        self.output_is_high = !matches!(channel_mode, ChannelMode::InterruptOnTerminalCount);
+
+        self.channel_mode = channel_mode;
+        self.access_mode = access_mode;
+        self.bcd_mode = bcd_mode;
+
+        self.reload_value = 0;
+        self.waiting_for_reload = true;
+        self.waiting_for_hibyte = false;
+        self.read_in_progress = false;
+        self.count_is_latched = false;
+        self.latched_lobyte_read = false;
+        self.load_pending = false;
+        self.counting = false;
+        self.armed = false;
+        self.null_count = true;
+        self.half_cycle_extend = false;
+    }
+
+    /// Handle a complete count being written to the Count Register.
+    fn count_written(&mut self) {
+        self.waiting_for_reload = false;
+        self.null_count = true;
+
+        match self.channel_mode {
+            ChannelMode::InterruptOnTerminalCount | ChannelMode::SoftwareTriggeredStrobe => {
+                // A new count is loaded on the next clock, restarting the count.
+                self.load_pending = true;
+            }
+            ChannelMode::RateGenerator | ChannelMode::SquareWaveGenerator => {
+                // A new count only takes effect at the end of the current period, unless the 
+                // counter has not been started yet.
+                if !self.counting {
+                    self.load_pending = true;
+                }
+            }
+            ChannelMode::HardwareRetriggerableOneShot | ChannelMode::HardwareTriggeredStrobe => {
+                // Count is loaded on the next gate trigger.
+            }
+        }
+    }
+
+    /// Handle the first byte of a two-byte count being written.
+    fn count_partially_written(&mut self) {
+        // Intel: In Mode 0, writing the first byte disables counting. OUT is set low immediately.
+        if let ChannelMode::InterruptOnTerminalCount = self.channel_mode {
+            self.counting = false;
+            self.load_pending = false;
+            self.output_is_high = false;
+        }
+    }
+
+    /// Transfer the Count Register to the Counting Element.
+    fn load_counter(&mut self) {
+        self.current_count = match self.channel_mode {
+            // Mode 3 counts down by two, so odd counts are loaded as the count minus one.
+            // This also works for BCD, since an odd BCD count has an odd low digit.
+            ChannelMode::SquareWaveGenerator => self.reload_value & !0x01,
+            _ => self.reload_value
+        };
+        self.load_pending = false;
+        self.null_count = false;
+        self.counting = true;
+        self.armed = true;
+    }
+
+    fn decrement(&mut self) {
+        self.current_count = match self.bcd_mode {
+            true => bcd_decrement(self.current_count),
+            false => self.current_count.wrapping_sub(1)
+        };
+    }
+
+    fn set_gate(&mut self, state: bool) {
+        let rising_edge = !self.input_gate && state;
+        self.input_gate = state;
+
+        match self.channel_mode {
+            ChannelMode::RateGenerator | ChannelMode::SquareWaveGenerator => {
+                if !state {
+                    // Intel: If GATE goes low during an output pulse, OUT is set high immediately
+                    // and counting is suspended.
+                    self.output_is_high = true;
+                    self.half_cycle_extend = false;
+                }
+                else if rising_edge && !self.waiting_for_reload {
+                    // A rising edge on GATE reloads the counter on the next clock.
+                    self.load_pending = true;
+                }
+            }
+            ChannelMode::HardwareRetriggerableOneShot | ChannelMode::HardwareTriggeredStrobe 
+                if rising_edge && !self.waiting_for_reload => {
+                // A rising edge on GATE triggers (or retriggers) the count on the next clock.
+                self.load_pending = true;
+            }
+            _ => {
+                // In modes 0 and 4 GATE only enables or disables counting.
+            }
+        }
+    }
+
+    /// Clock the channel once.
+    fn tick(&mut self) {
+        match self.channel_mode {
+            ChannelMode::InterruptOnTerminalCount => {
+                if self.load_pending {
+                    self.load_counter();
+                    return
+                }
+                if !self.counting || !self.input_gate {
+                    return
+                }
+                self.decrement();
+
+                // Terminal count reached. The output goes high and stays high until a new count 
+                // or mode is written. The counter keeps wrapping around in this mode, it is NOT reloaded.
+                if self.current_count == 0 && self.armed {
+                    self.output_is_high = true;
+                    self.armed = false;
+                }
+            }
+            ChannelMode::HardwareRetriggerableOneShot => {
+                if self.load_pending {
+                    // Intel: OUT will go low on the CLK pulse following a trigger to begin the one-shot pulse.
+                    self.load_counter();
+                    self.output_is_high = false;
+                    return
+                }
+                // Gate level does not affect counting in this mode.
+                if !self.counting {
+                    return
+                }
+                self.decrement();
+                if self.current_count == 0 && self.armed {
+                    self.output_is_high = true;
+                    self.armed = false;
+                }
+            }
+            ChannelMode::RateGenerator => {
+                if self.load_pending {
+                    self.load_counter();
+                    self.output_is_high = true;
+                    return
+                }
+                if !self.counting || !self.input_gate {
+                    return
+                }
+                if self.current_count == 1 {
+                    // End of the one-clock low pulse. Reload the counter, picking up any new count.
+                    self.load_counter();
+                    self.output_is_high = true;
+                }
+                else {
+                    self.decrement();
+                    if self.current_count == 1 {
+                        // Output goes low for one clock when the count reaches 1.
+                        self.output_is_high = false;
+                    }
+                }
+            }
+            ChannelMode::SquareWaveGenerator => {
+                if self.load_pending {
+                    self.load_counter();
+                    self.output_is_high = true;
+                    return
+                }
+                if !self.counting || !self.input_gate {
+                    return
+                }
+                if self.half_cycle_extend {
+                    // Odd counts hold the output high for one extra clock.
+                    self.half_cycle_extend = false;
+                    self.output_is_high = false;
+                    self.load_counter();
+                    return
+                }
+
+                // Intel: "If the count is odd and the output is high, the first clock pulse
+                // (after the count is loaded) decrements the count by 1. Subsequent pulses
+                // decrement the clock by 2..."
+                self.decrement();
+                self.decrement();
+
+                if self.current_count == 0 {
+                    if self.output_is_high && (self.reload_value & 0x01 != 0) {
+                        self.half_cycle_extend = true;
+                    }
+                    else {
+                        // Change flipflop state and reload counter
+                        self.output_is_high = !self.output_is_high;
+                        self.load_counter();
+                    }
+                }
+            }
+            ChannelMode::SoftwareTriggeredStrobe | ChannelMode::HardwareTriggeredStrobe => {
+                // The strobe lasts for one clock.
+                if !self.output_is_high {
+                    self.output_is_high = true;
+                }
+                if self.load_pending {
+                    self.load_counter();
+                    return
+                }
+                if !self.counting {
+                    return
+                }
+                // Gate disables counting in mode 4 only.
+                if let ChannelMode::SoftwareTriggeredStrobe = self.channel_mode {
+                    if !self.input_gate {
+                        return
+                    }
+                }
+                self.decrement();
+                if self.current_count == 0 && self.armed {
+                    self.output_is_high = false;
+                    self.armed = false;
+                }
+            }
+        }
+    }
+}
+
 impl ProgrammableIntervalTimer {
     pub fn new() -> Self {
         /*
@@ -118,24 +397,7 @@ impl ProgrammableIntervalTimer {
         */
         let mut vec = Vec::<PitChannel>::new();
         for _ in 0..3 {
-            let pit = PitChannel {
-                channel_mode: ChannelMode::InterruptOnTerminalCount,
-                access_mode: AccessMode::HiByteOnly,
-                reload_value: 0,
-                waiting_for_reload: true,
-                waiting_for_lobyte: false,
-                waiting_for_hibyte: false,
-                current_count: 0,
-                read_in_progress: false,
-                normal_lobyte_read: false,
-                count_is_latched: false,
-                output_is_high: false,
-                latched_lobyte_read: false,
-                latch_count: 0,
-                bcd_mode: false,
-                input_gate: true,
-            };
-            vec.push(pit);
+            vec.push(PitChannel::new());
         }
         Self {
             pit_cycles: 0,
@@ -149,28 +411,10 @@ impl ProgrammableIntervalTimer {
         self.cycle_accumulator = 0.0;
         
         for channel in &mut self.channels {
-            channel.channel_mode = ChannelMode::InterruptOnTerminalCount;
-            channel.access_mode = AccessMode::HiByteOnly;
-            channel.reload_value = 0;
-            channel.waiting_for_reload = true;
-            channel.waiting_for_lobyte = false;
-            channel.waiting_for_hibyte = false;
-            channel.current_count = 0;
-            channel.read_in_progress = false;
-            channel.normal_lobyte_read = false;
-            channel.count_is_latched = false;
-            channel.output_is_high = false;
-            channel.latched_lobyte_read = false;
-            channel.latch_count = 0;
-            channel.bcd_mode = false;
-            channel.input_gate = true;
+            *channel = PitChannel::new();
         }
     }
 
-    fn is_latch_command(command_byte: u8) -> bool {
-        command_byte & PIT_ACCESS_MODE_MASK == 0
-    }
-
     fn get_pit_cycles(cpu_cycles: u32) -> f64 {
         cpu_cycles as f64 * PIT_DIVISOR
     }
@@ -200,9 +444,6 @@ impl ProgrammableIntervalTimer {
         };
 
         let bcd_enable = command_byte & PIT_BCD_MODE_MASK == 0x01;
-        if bcd_enable {
-            log::error!("PIT: BCD mode unimplemented");
-        }
         (channel_select, access_mode, channel_mode, bcd_enable)
     }
 
@@ -210,81 +451,57 @@ impl ProgrammableIntervalTimer {
 
         let (channel_select, access_mode, channel_mode, bcd_enable) = self.parse_command_register(command_byte);
 
+        if channel_select > 2 {
+            // Channel select of 0b11 is illegal on the 8253 (Read-Back command on the 8254)
+            log::warn!("PIT: Illegal channel select in control word: {:02X}", command_byte);
+            return
+        }
+
+        let channel = &mut self.channels[channel_select as usize];
+
         if let AccessMode::LatchCountValue = access_mode {
             // All 0's access mode indicates a Latch Count Value command
             // Not an access mode itself, we now latch the current value of the channel until it is read
-            // or a command byte is received
-            self.channels[channel_select as usize].latch_count = self.channels[channel_select as usize].current_count;
-            self.channels[channel_select as usize].count_is_latched = true;
-            self.channels[channel_select as usize].latched_lobyte_read = false;
+            // or a command byte is received.
+            // Intel: If a Counter is latched again before the count is read, the second latch is ignored.
+            if !channel.count_is_latched {
+                channel.latch_count = channel.current_count;
+                channel.count_is_latched = true;
+                channel.latched_lobyte_read = false;
+            }
         }
         else {
-            log::debug!("PIT: Channel {} selected, access mode {:?}, channel_mode {:?}", channel_select, access_mode, channel_mode );
-
-            let channel = &mut self.channels[channel_select as usize];
-            channel.channel_mode = channel_mode;
-
-            match channel.channel_mode {
-                ChannelMode::InterruptOnTerminalCount => {
-                    channel.waiting_for_reload = true;
-                    // Intel: The output will be intiially low after the mode set operation.
-                    channel.output_is_high = false;
-                }
-                ChannelMode::HardwareRetriggerableOneShot => {
-                    channel.waiting_for_reload = true;
-                }
-                ChannelMode::RateGenerator => {
-                    channel.waiting_for_reload = true;
-                },
-                ChannelMode::SquareWaveGenerator => {
-                    channel.waiting_for_reload = true;
-                },
-                ChannelMode::SoftwareTriggeredStrobe => {},
-                ChannelMode::HardwareTriggeredStrobe => {}
-            }
-            
-            channel.reload_value = 0;
-            channel.access_mode = access_mode;
-            channel.bcd_mode = bcd_enable;
-        }        
+            log::debug!("PIT: Channel {} selected, access mode {:?}, channel_mode {:?}, bcd: {}", 
+                channel_select, access_mode, channel_mode, bcd_enable );
 
+            channel.set_mode(channel_mode, access_mode, bcd_enable);
+        }        
     }
 
     pub fn data_write(&mut self, port_num: usize, data: u8) {
         
-        let mut port = &mut self.channels[port_num];
+        let port = &mut self.channels[port_num];
 
         match port.access_mode {
             AccessMode::LoByteOnly => {
                 port.reload_value = data as u16;
-                port.waiting_for_reload = false;
-                //log::trace!("Channel {} reloaded with value {} in LSB mode.", port_num, port.reload_value);
-                port.current_count =  port.reload_value;
+                port.count_written();
             }
             AccessMode::HiByteOnly => {
                 port.reload_value = (data as u16) << 8;
-                port.waiting_for_reload = false;
-                //log::trace!("Channel {} reloaded with value {} in HSB mode.", port_num, port.reload_value);
-                port.current_count =  port.reload_value;
+                port.count_written();
             }
             AccessMode::LoByteHiByte => {
                 // Expect lo byte first, hi byte second
-                if port.waiting_for_lobyte {
-                    port.reload_value = data as u16;
-                    port.waiting_for_lobyte = false;
-                    port.waiting_for_hibyte = true;
-                }
-                else if port.waiting_for_hibyte {
-                    port.reload_value |= (data as u16) << 8;
+                if port.waiting_for_hibyte {
+                    port.reload_value = (port.reload_value & 0x00FF) | (data as u16) << 8;
                     port.waiting_for_hibyte = false;
-                    port.waiting_for_reload = false;
-                    //log::trace!("Channel {} reloaded with value {} in WORD mode.", port_num, port.reload_value);
-                    port.current_count =  port.reload_value;
+                    port.count_written();
                 }
                 else {
-                    port.reload_value = data as u16;
-                    port.waiting_for_lobyte = false;
+                    port.reload_value = (port.reload_value & 0xFF00) | data as u16;
                     port.waiting_for_hibyte = true;
+                    port.count_partially_written();
                 }
             }
             AccessMode::LatchCountValue => {
@@ -294,25 +511,28 @@ impl ProgrammableIntervalTimer {
     }
 
     pub fn data_read(&mut self, port: usize) -> u8 {
-        let mut port = &mut self.channels[port];
+        let port = &mut self.channels[port];
         if port.count_is_latched {
             match port.access_mode {
                 AccessMode::LoByteOnly => {
-                    return (port.latch_count & 0xFF) as u8;
+                    port.count_is_latched = false;
+                    (port.latch_count & 0xFF) as u8
                 }
                 AccessMode::HiByteOnly => {
-                    return (port.latch_count >> 8) as u8;
+                    port.count_is_latched = false;
+                    (port.latch_count >> 8) as u8
                 }
                 AccessMode::LoByteHiByte => {
                     if port.latched_lobyte_read {
                         // Return hi byte and unlatch output
                         port.count_is_latched = false;
                         port.latched_lobyte_read = false;
-                        return (port.latch_count >> 8) as u8;
+                        (port.latch_count >> 8) as u8
                     }
                     else {
                         // Return lo byte
-                        return (port.latch_count & 0xFF) as u8;
+                        port.latched_lobyte_read = true;
+                        (port.latch_count & 0xFF) as u8
                     }
                 }
                 _ => unreachable!()
@@ -321,22 +541,22 @@ impl ProgrammableIntervalTimer {
         else {
             match port.access_mode {
                 AccessMode::LoByteOnly => {
-                    return (port.current_count & 0xFF) as u8;
+                    (port.current_count & 0xFF) as u8
                 }
                 AccessMode::HiByteOnly => {
-                    return (port.current_count >> 8) as u8;
+                    (port.current_count >> 8) as u8
                 }
                 AccessMode::LoByteHiByte => {
                     // Output lo byte of counter, then on next read output hi byte
                     if port.read_in_progress {
-                        // Return hi byte and unlatch output
+                        // Return hi byte
                         port.read_in_progress = false;
-                        return (port.latch_count >> 8) as u8;
+                        (port.current_count >> 8) as u8
                     }
                     else {
                         // Return lo byte and set read in progress flag
                         port.read_in_progress = true;
-                        return (port.latch_count & 0xFF) as u8;
+                        (port.current_count & 0xFF) as u8
                     }
                 }
                 _ => unreachable!()
@@ -349,17 +569,34 @@ impl ProgrammableIntervalTimer {
             return
         }
         // Note: Only the gate to PIT channel #2 is connected to anything (PPI port)
-        self.channels[channel].input_gate = state;
+        self.channels[channel].set_gate(state);
+    }
+
+    /// Return the state of the OUT pin of the specified channel.
+    pub fn get_output_state(&self, channel: usize) -> bool {
+        self.channels[channel].output_is_high
+    }
+
+    /// Return whether the specified channel has a count written that has not yet been loaded
+    /// into the counting element.
+    pub fn get_null_count(&self, channel: usize) -> bool {
+        self.channels[channel].null_count
     }
 
     pub fn run(
         &mut self, 
         io_bus: &mut IoBusInterface, 
-        bus: &mut BusInterface, 
         pic: &mut pic::Pic, 
         dma: &mut dma::DMAController,
+        ppi: &mut ppi::Ppi,
+        speaker: &mut sound::Speaker,
         cpu_cycles: u32 ) {
 
+        // The gate of channel #2 is driven by PPI port B. Port B may have been written since 
+        // we last ran, so propagate the gate before clocking the timer.
+        self.set_channel_gate(2, ppi.get_timer2_gate());
+        ppi.set_timer2_output(self.channels[2].output_is_high);
+
         let mut pit_cycles = Pit::get_pit_cycles(cpu_cycles);
         let pit_cycles_remainder = pit_cycles.fract();
 
@@ -376,145 +613,50 @@ impl ProgrammableIntervalTimer {
         let pit_cycles_int = pit_cycles as u32;
         
         for _ in 0..pit_cycles_int {
-            self.tick(bus, pic, dma);
+            self.tick(pic, dma);
+
+            // Sample the speaker every PIT tick so that PWM tricks driven by either the 
+            // timer or the port B speaker data bit are captured.
+            ppi.set_timer2_output(self.channels[2].output_is_high);
+            speaker.tick(ppi.get_speaker_output());
         }
+
+        // Channel #2 output is readable on PPI port C and drives the speaker
+        ppi.set_timer2_output(self.channels[2].output_is_high);
     }
 
     pub fn get_cycles(&self) -> u64 {
         self.pit_cycles
     }
 
-    pub fn tick(&mut self, bus: &mut BusInterface, pic: &mut pic::Pic, dma: &mut dma::DMAController ) {
+    pub fn tick(&mut self, pic: &mut pic::Pic, dma: &mut dma::DMAController ) {
 
         self.pit_cycles += 1;
 
         for (i,t) in &mut self.channels.iter_mut().enumerate() {
-            match t.channel_mode {
-                ChannelMode::InterruptOnTerminalCount => {
-                    // Don't count while waiting for reload value
-                    if !t.waiting_for_reload {
-
-                        // Reload value of 0 equates to to reload value of 2^16
-                        if t.current_count == 0 {
-                            if t.reload_value != 0 {
-                                panic!("unexpected timer state");
-                            }
-                            t.current_count = u16::MAX - 1;
-                        }
-                        else {
-                            t.current_count -= 1;
-                        }
-                        
-                        // Terminal Count reached.
-                        if t.current_count == 0 {
-                            
-                            // Only trigger an interrupt on Channel #0, and only if output is going from low to high
-                            if !t.output_is_high && i == 0 {
-                                pic.request_interrupt(0);
-                            }
-
-                            t.output_is_high = true;
-                            // Counter just wraps around in this mode, it is NOT reloaded.
-                            t.current_count = u16::MAX - 1;
-                        }
-                    }
-                },
-                ChannelMode::HardwareRetriggerableOneShot => {},
-                ChannelMode::RateGenerator => {
-                    // Don't count while waiting for reload value
-                    if !t.waiting_for_reload {
-
-
-                        if t.current_count == 0 {
-                            if t.reload_value == 0 {
-                                // 0 functions as a reload value of 65536
-                                t.current_count = u16::MAX;
-                            }
-                            else {
-                                t.current_count = t.reload_value;                            
-                            }
-                        }
-                        else {
-                            t.current_count -= 1;
-                            if t.current_count == 1 {
-
-                                // Only trigger interrupt on Channel #0
-                                if i == 0 {                                
-                                    pic.request_interrupt(0);
-                                }
-                                if i == 1 {
-                                    // Channel 1 wants to do DMA refresh.
-                                    dma.do_dma_read_u8(bus, 0);
-                                }
-                                // Output would go low here
-                            }
-                        }
+
+            let output_was_high = t.output_is_high;
+            t.tick();
+
+            if output_was_high == t.output_is_high {
+                continue
+            }
+
+            match i {
+                0 => {
+                    // Channel #0 drives IRQ0, which is edge triggered
+                    if t.output_is_high {
+                        pic.request_interrupt(0);
                     }
-                },
-                ChannelMode::SquareWaveGenerator => {
-                    // Don't count while waiting for reload value
-                    if !t.waiting_for_reload {
-                        
-                        // Reload value of 0 equates to to reload value of 2^16
-                        if t.current_count == 0 {
-                            if t.reload_value != 0 {
-                                panic!("unexpected timer state");
-                            }
-                            t.current_count = u16::MAX - 1;
-                        }
-
-                        // Intel: "If the count is odd and the output is high, the first clock pulse
-                        // (after the count is loaded) decrements the count by 1. Subsequent pulses
-                        // decrement the clock by 2..."
-                        if t.current_count & 0x01 != 0 {
-                            // Count is odd - can only occur immediately after reload of odd reload value
-
-                            if t.output_is_high { 
-                                t.current_count = t.current_count.wrapping_sub(1);
-                                // count is even from now on
-                            }
-                            else {
-                                // Intel: "... After timeout, the output goes low and the full count is reloaded.
-                                // The first clock pulse decrements the counter by 3."
-                                t.current_count = t.current_count.wrapping_sub(3);
-                                // count is even from now on
-
-                                // TODO: What happens on a reload value of 1? OSdev says you should avoid it - would 
-                                // it wrap the counter?
-                            }
-                        }
-                        else {
-                            t.current_count = t.current_count.wrapping_sub(2);
-                        }
-
-                        // Terminal count reached
-                        if t.current_count == 0 {
-
-                            // Change flipflop state
-                            t.output_is_high = !t.output_is_high;
-
-                            // Only Channel #0 generates interrupts
-                            if i == 0 {
-                                if t.output_is_high {
-                                    pic.request_interrupt(0);
-                                }
-                                else {
-                                    pic.clear_interrupt(0);
-                                }
-                            }  
-
-                            // Reload counter
-                            if t.reload_value == 0 {
-                                t.current_count = u16::MAX - 1; 
-                            }
-                            else {
-                                t.current_count = t.reload_value;                            
-                            }
-                        }
+                    else {
+                        pic.clear_interrupt(0);
                     }
-                },
-                ChannelMode::SoftwareTriggeredStrobe => {},
-                ChannelMode::HardwareTriggeredStrobe => {},
+                }
+                1 if t.output_is_high => {
+                    // Channel #1 triggers a DRAM refresh cycle on DMA channel #0
+                    dma.pulse_dma_request(0);
+                }
+                _ => {}
             }
         }
     }
@@ -525,15 +667,202 @@ impl ProgrammableIntervalTimer {
             c0_reload_value: format!("{:06}", self.channels[0].reload_value),
             c0_access_mode: format!("{:?}", self.channels[0].access_mode),
             c0_channel_mode: format!("{:?}", self.channels[0].channel_mode),
+            c0_output: format!("{:?}", self.get_output_state(0)),
+            c0_null_count: format!("{:?}", self.get_null_count(0)),
             c1_value: format!("{:06}", self.channels[1].current_count),
             c1_reload_value: format!("{:06}", self.channels[1].reload_value),
             c1_access_mode: format!("{:?}", self.channels[1].access_mode),
             c1_channel_mode: format!("{:?}", self.channels[1].channel_mode),
+            c1_output: format!("{:?}", self.get_output_state(1)),
+            c1_null_count: format!("{:?}", self.get_null_count(1)),
             c2_value: format!("{:06}", self.channels[2].current_count),
             c2_reload_value: format!("{:06}", self.channels[2].reload_value),
             c2_access_mode: format!("{:?}", self.channels[2].access_mode),
             c2_channel_mode: format!("{:?}", self.channels[2].channel_mode),
+            c2_output: format!("{:?}", self.get_output_state(2)),
+            c2_null_count: format!("{:?}", self.get_null_count(2)),
             c2_gate_status: format!("{:?}", self.channels[2].input_gate)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(pit: &mut Pit, channel: usize, mode: u8, count: u16, bcd: bool) {
+        let control = ((channel as u8) << 6) | 0b0011_0000 | (mode << 1) | bcd as u8;
+        pit.command_register_write(control);
+        pit.data_write(channel, (count & 0xFF) as u8);
+        pit.data_write(channel, (count >> 8) as u8);
+    }
+
+    fn tick_n(pit: &mut Pit, n: usize) {
+        let mut pic = pic::Pic::new();
+        let mut dma = dma::DMAController::new();
+        for _ in 0..n {
+            pit.tick(&mut pic, &mut dma);
+        }
+    }
+
+    /// Clock a channel and record the output state after each clock.
+    fn trace_output(pit: &mut Pit, channel: usize, n: usize) -> Vec<bool> {
+        let mut trace = Vec::new();
+        for _ in 0..n {
+            tick_n(pit, 1);
+            trace.push(pit.get_output_state(channel));
+        }
+        trace
+    }
+
+    #[test]
+    pub fn test_bcd_decrement() {
+        assert_eq!(bcd_decrement(0x0010), 0x0009);
+        assert_eq!(bcd_decrement(0x1000), 0x0999);
+        assert_eq!(bcd_decrement(0x0001), 0x0000);
+        assert_eq!(bcd_decrement(0x0000), 0x9999);
+    }
+
+    #[test]
+    pub fn test_mode0() {
+        let mut pit = Pit::new();
+        program(&mut pit, 2, 0, 3, false);
+        assert!(!pit.get_output_state(2));
+        assert!(pit.get_null_count(2));
+
+        // One clock to load, then three to count down
+        tick_n(&mut pit, 1);
+        assert!(!pit.get_null_count(2));
+        assert_eq!(trace_output(&mut pit, 2, 4), vec![false, false, true, true]);
+
+        // Gate low disables counting
+        program(&mut pit, 2, 0, 2, false);
+        pit.set_channel_gate(2, false);
+        assert_eq!(trace_output(&mut pit, 2, 4), vec![false, false, false, false]);
+        pit.set_channel_gate(2, true);
+        assert_eq!(trace_output(&mut pit, 2, 2), vec![false, true]);
+
+        // Writing the first byte of a new count sets the output low
+        pit.data_write(2, 5);
+        assert!(!pit.get_output_state(2));
+    }
+
+    #[test]
+    pub fn test_mode0_bcd() {
+        let mut pit = Pit::new();
+        program(&mut pit, 2, 0, 0x0010, true);
+        tick_n(&mut pit, 2);
+        assert_eq!(pit.channels[2].current_count, 0x0009);
+        tick_n(&mut pit, 9);
+        assert!(pit.get_output_state(2));
+        tick_n(&mut pit, 1);
+        assert_eq!(pit.channels[2].current_count, 0x9999);
+    }
+
+    #[test]
+    pub fn test_mode1() {
+        let mut pit = Pit::new();
+        pit.set_channel_gate(2, false);
+        program(&mut pit, 2, 1, 3, false);
+        assert!(pit.get_output_state(2));
+
+        // Nothing happens until the gate is triggered
+        assert_eq!(trace_output(&mut pit, 2, 3), vec![true, true, true]);
+        pit.set_channel_gate(2, true);
+        assert_eq!(trace_output(&mut pit, 2, 5), vec![false, false, false, true, true]);
+
+        // Retriggering during the pulse restarts the count
+        pit.set_channel_gate(2, false);
+        pit.set_channel_gate(2, true);
+        assert_eq!(trace_output(&mut pit, 2, 2), vec![false, false]);
+        pit.set_channel_gate(2, false);
+        pit.set_channel_gate(2, true);
+        assert_eq!(trace_output(&mut pit, 2, 5), vec![false, false, false, true, true]);
+    }
+
+    #[test]
+    pub fn test_mode2() {
+        let mut pit = Pit::new();
+        program(&mut pit, 2, 2, 3, false);
+        assert!(pit.get_output_state(2));
+
+        tick_n(&mut pit, 1);
+        assert_eq!(trace_output(&mut pit, 2, 6), vec![true, false, true, true, false, true]);
+
+        // Gate low forces output high and suspends counting
+        pit.set_channel_gate(2, false);
+        assert_eq!(trace_output(&mut pit, 2, 4), vec![true, true, true, true]);
+
+        // A new count takes effect at the end of the current period
+        pit.set_channel_gate(2, true);
+        pit.data_write(2, 4);
+        pit.data_write(2, 0);
+        assert!(pit.get_null_count(2));
+        tick_n(&mut pit, 1);
+        assert_eq!(trace_output(&mut pit, 2, 8), vec![true, true, false, true, true, true, false, true]);
+        assert!(!pit.get_null_count(2));
+    }
+
+    #[test]
+    pub fn test_mode3() {
+        let mut pit = Pit::new();
+
+        // Even count: 2 clocks high, 2 clocks low
+        program(&mut pit, 2, 3, 4, false);
+        tick_n(&mut pit, 1);
+        assert_eq!(trace_output(&mut pit, 2, 8), vec![true, false, false, true, true, false, false, true]);
+
+        // Odd count: 3 clocks high, 2 clocks low
+        program(&mut pit, 2, 3, 5, false);
+        tick_n(&mut pit, 1);
+        assert_eq!(trace_output(&mut pit, 2, 10), 
+            vec![true, true, false, false, true, true, true, false, false, true]);
+
+        // Gate low forces output high
+        pit.set_channel_gate(2, false);
+        assert!(pit.get_output_state(2));
+    }
+
+    #[test]
+    pub fn test_mode4() {
+        let mut pit = Pit::new();
+        program(&mut pit, 2, 4, 3, false);
+        assert!(pit.get_output_state(2));
+
+        tick_n(&mut pit, 1);
+        assert_eq!(trace_output(&mut pit, 2, 6), vec![true, true, false, true, true, true]);
+
+        // The strobe only happens once per count written
+        tick_n(&mut pit, 0x10000);
+        assert!(pit.get_output_state(2));
+    }
+
+    #[test]
+    pub fn test_mode5() {
+        let mut pit = Pit::new();
+        program(&mut pit, 2, 5, 2, false);
+
+        // Nothing happens until the gate is triggered
+        assert_eq!(trace_output(&mut pit, 2, 4), vec![true, true, true, true]);
+        pit.set_channel_gate(2, false);
+        pit.set_channel_gate(2, true);
+        assert_eq!(trace_output(&mut pit, 2, 5), vec![true, true, false, true, true]);
+    }
+
+    #[test]
+    pub fn test_latch() {
+        let mut pit = Pit::new();
+        program(&mut pit, 0, 2, 0x1234, false);
+        tick_n(&mut pit, 1);
+
+        pit.command_register_write(0x00);
+        tick_n(&mut pit, 4);
+        // A second latch command before the count is read is ignored
+        pit.command_register_write(0x00);
+
+        assert_eq!(pit.data_read(0), 0x34);
+        assert_eq!(pit.data_read(0), 0x12);
+        assert_eq!(pit.data_read(0), 0x30);
+        assert_eq!(pit.data_read(0), 0x12);
+    }
+}
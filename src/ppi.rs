@@ -3,23 +3,26 @@
     Implement the 8255 PPI (Programmable Peripheral Interface)
 
     Other than reporting DIP switch status and other system information the PPI
-    acts as the interface for the PC/XT keyboard. We emulate the keyboard through 
-    the PPI.
+    acts as the interface for the PC/XT keyboard. The keyboard itself is emulated in
+    keyboard.rs and attached to the PPI, which receives its bytes into port A and raises
+    IRQ1. A byte is held until acknowledged by setting the clear bit on port B.
+
+    On the 5150 the PPI also drives the cassette motor relay and reads cassette data in.
+    The cassette deck is emulated in cassette.rs.
 */
 #![allow(dead_code)]
 
 use crate::io::{IoDevice};
 use crate::machine::{MachineType, VideoType};
 use crate::pic;
+use crate::keyboard::Keyboard;
+use crate::cassette::Cassette;
 
 pub const PPI_PORT_A: u16 = 0x60;
 pub const PPI_PORT_B: u16 = 0x61;
 pub const PPI_PORT_C: u16 = 0x62;
 pub const PPI_COMMAND_PORT: u16 = 0x63;
 
-pub const KB_RESET_CYCLES: u32 = 47700;
-pub const KB_RESET_CYCLE_DELAY: u32 = 50; // Cycles until reset byte is sent after reset
-
 // Dipswitch information from
 // http://www.minuszerodegrees.net/5150/misc/5150_motherboard_switch_settings.htm
 
@@ -87,7 +90,7 @@ pub const PORTB_TIMER2_GATE: u8  = 0b0000_0001;
 pub const PORTB_SPEAKER_DATA: u8 = 0b0000_0010;
 pub const PORTB_SW2_SELECT: u8   = 0b0000_0100;
 
-// This bit is cassette motor control on 5150, SW1 select on 5160
+// This bit is cassette motor control on 5150 (low = motor on), SW1 select on 5160
 pub const PORTB_CASSETTE: u8     = 0b0000_1000;
 pub const PORTB_SW1_SELECT: u8   = 0b0000_1000;
 
@@ -114,14 +117,12 @@ pub struct Ppi {
     machine_type: MachineType,
     port_a_mode: PortAMode,
     port_c_mode: PortCMode,
+    keyboard: Keyboard,
+    cassette: Cassette,
     kb_clock_low: bool,
-    kb_counting_low: bool,
-    kb_low_count: u32,
-    kb_been_reset: bool,
-    kb_count_until_reset_byte: u32,
-    kb_resets_counter: u32,
     pb_byte: u8,
     kb_byte: u8,
+    kb_byte_pending: bool,
     clear_keyboard: bool,
     dip_sw1: u8,
     dip_sw2: u8,
@@ -136,6 +137,7 @@ pub struct PpiStringState {
     pub port_a_value_hex: String,
     pub kb_byte_value_hex: String,
     pub kb_resets_counter: String,
+    pub kb_buffer_len: String,
     pub port_c_mode: String,
     pub port_c_value: String,
 }
@@ -154,14 +156,12 @@ impl Ppi {
                 MachineType::IBM_PC_5150 => PortCMode::Switch2OneToFour,
                 MachineType::IBM_XT_5160 => PortCMode::Switch1FiveToEight
             },
+            keyboard: Keyboard::new(),
+            cassette: Cassette::new(),
             kb_clock_low: false,
-            kb_counting_low: false,
-            kb_low_count: 0,
-            kb_been_reset: false,
-            kb_count_until_reset_byte: 0,
-            kb_resets_counter: 0,
             pb_byte: 0,
             kb_byte: 0,
+            kb_byte_pending: false,
             clear_keyboard: false,
             dip_sw1: match machine_type {
                 MachineType::IBM_PC_5150 => SW1_HAVE_CGA_HIRES | SW1_HAS_FLOPPIES | SW1_TWO_FLOPPIES | SW1_RAM_BANKS,
@@ -226,6 +226,9 @@ impl Ppi {
     pub fn handle_portb_write(&mut self, byte: u8) {
                 
         self.pb_byte = byte;
+
+        // Speaker data bit gates OUT2 to the speaker
+        self.speaker_in = self.timer_in && (byte & PORTB_SPEAKER_DATA != 0);
         
         match self.machine_type {
             MachineType::IBM_PC_5150 => {
@@ -268,44 +271,68 @@ impl Ppi {
             }
         }
 
-        // Handle keyboard clock line bit for either 5150 or 5160
-        if self.pb_byte & PORTB_PULL_KB_LOW == 0 {
-            //log::trace!("PPI: Pulling keyboard clock LOW");
-            self.kb_clock_low = true;
-            self.kb_counting_low = true;
-        }
-        else if self.kb_clock_low {
-            //log::trace!("PPI: Keyboard clock resume HIGH");
-            self.kb_clock_low = false;
-
-            if self.kb_low_count > KB_RESET_CYCLES {
-                // Clock line was low long enough to trigger reset
-                // Start timer until reset byte is sent
-                self.kb_low_count;
-                self.kb_been_reset = true;
-                self.kb_count_until_reset_byte = 0;
-            }
-        }
+        // Handle keyboard clock line bit for either 5150 or 5160. The keyboard watches
+        // this line to detect a reset.
+        self.kb_clock_low = self.pb_byte & PORTB_PULL_KB_LOW == 0;
+    }
+
+    pub fn key_down(&mut self, code: u8) {
+        self.keyboard.key_down(code);
+    }
 
+    pub fn key_up(&mut self, code: u8) {
+        self.keyboard.key_up(code);
     }
 
-    pub fn send_keyboard(&mut self, byte: u8 ) {
-        self.kb_byte = byte;
+    /// Return whether the keyboard shift register can accept a byte. It is held clear
+    /// while the clear bit is set, and holds a received byte until it is cleared.
+    fn keyboard_ready(&self) -> bool {
+        !self.kb_byte_pending && self.pb_byte & PORTB_KB_CLEAR == 0
+    }
+
+    pub fn cassette_mut(&mut self) -> &mut Cassette {
+        &mut self.cassette
+    }
+
+    /// Return whether the cassette motor relay is closed. Only the 5150 has a cassette port.
+    fn cassette_motor_on(&self) -> bool {
+        matches!(self.machine_type, MachineType::IBM_PC_5150) && self.pb_byte & PORTB_CASSETTE == 0
+    }
+
+    /// Return the state of the PIT channel #2 gate, driven by port B bit 0.
+    pub fn get_timer2_gate(&self) -> bool {
+        self.pb_byte & PORTB_TIMER2_GATE != 0
+    }
+
+    /// Set the state of the PIT channel #2 output (OUT2) as seen by the PPI.
+    /// The speaker is driven by OUT2 ANDed with port B bit 1.
+    pub fn set_timer2_output(&mut self, state: bool) {
+        self.timer_in = state;
+        self.speaker_in = state && (self.pb_byte & PORTB_SPEAKER_DATA != 0);
+    }
+
+    /// Return the current level of the signal driving the PC speaker.
+    pub fn get_speaker_output(&self) -> bool {
+        self.speaker_in
     }
 
     pub fn calc_port_c_value(&self) -> u8 {
-        let timer_bit = (self.timer_in as u8) << 4;
-        let speaker_bit = (self.speaker_in as u8) << 5;
+        // PC5 reads the output of PIT channel #2 on both the 5150 and 5160
+        let timer_bit = (self.timer_in as u8) << 5;
+        // PC4 monitors the speaker signal on the 5160
+        let speaker_bit = (self.speaker_in as u8) << 4;
+        // PC4 reads cassette data in on the 5150
+        let cassette_bit = (self.cassette.get_input() as u8) << 4;
 
         match (&self.machine_type, &self.port_c_mode) {
             (MachineType::IBM_PC_5150, PortCMode::Switch2OneToFour) => {
-                // We aren't implementing the cassette on 5150, and we'll never have parity errors
-                (self.dip_sw2 & 0x0F) | timer_bit
+                // We'll never have parity errors
+                (self.dip_sw2 & 0x0F) | cassette_bit | timer_bit
             }
             (MachineType::IBM_PC_5150, PortCMode::Switch2Five) => {
                 // On 5150, only Switch Block 2, Switch #5 is actually passed through
                 // If Port C is in Switch Block 2 mode, switches 6, 7, 8 and will read high (off)
-                (self.dip_sw2 >> 4 & 0x01) | timer_bit
+                (self.dip_sw2 >> 4 & 0x01) | cassette_bit | timer_bit
             }
             (MachineType::IBM_XT_5160, PortCMode::Switch1OneToFour) => {
                 // Cassette data line has been replaced with a speaker monitor line.
@@ -339,7 +366,8 @@ impl Ppi {
             port_a_value_bin: format!("{:08b}", port_a_value),
             port_a_value_hex: format!("{:02X}", port_a_value),
             kb_byte_value_hex: format!("{:02X}", self.kb_byte),
-            kb_resets_counter: format!("{}", self.kb_resets_counter),
+            kb_resets_counter: format!("{}", self.keyboard.get_reset_count()),
+            kb_buffer_len: format!("{}", self.keyboard.get_buffer_len()),
             port_c_mode: format!("{:?}", self.port_c_mode),
             port_c_value: format!("{:08b}", port_c_value )
         }
@@ -352,32 +380,22 @@ impl Ppi {
         if self.clear_keyboard {
             self.clear_keyboard = false;
             self.kb_byte = 0;
+            self.kb_byte_pending = false;
             pic.clear_interrupt(1);
             //log::trace!("PPI: Clearing keyboard");
         }
 
-        // Keyboard should send a 'aa' byte when clock line is held low (for how long?)
-        // BIOS waits 20ms. 
-        // Clock line must go high again
-        if self.kb_counting_low && self.kb_low_count < KB_RESET_CYCLES {
-            self.kb_low_count += cycles;
+        // Run the keyboard. When a byte has been shifted in, latch it and request an interrupt.
+        let ready = self.keyboard_ready();
+        if let Some(byte) = self.keyboard.run(self.kb_clock_low, ready, cycles) {
+            //log::trace!("PPI: Received keyboard byte: {:02X}", byte);
+            self.kb_byte = byte;
+            self.kb_byte_pending = true;
+            pic.request_interrupt(1);
         }
 
-        // Send reset byte after delay elapsed. The delay gives the BIOS POST routines
-        // time to check for interrupts as they do not do it immediately 
-        if self.kb_been_reset {
-            self.kb_count_until_reset_byte += cycles;
-
-            if self.kb_count_until_reset_byte > KB_RESET_CYCLE_DELAY {
-                self.kb_been_reset = false;
-                self.kb_count_until_reset_byte = 0;
-                self.kb_resets_counter += 1;
-
-                //log::trace!("PPI: Sending keyboard reset byte");
-                self.kb_byte = 0xAA;
-                // Bios KB check expects a reset byte to generate a KB interrupt
-                pic.request_interrupt(1);
-            }
-        }
+        // Run the cassette deck. Data out is the output of PIT channel #2.
+        let motor_on = self.cassette_motor_on();
+        self.cassette.run(motor_on, self.timer_in, cycles);
     }
 }
\ No newline at end of file
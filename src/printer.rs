@@ -0,0 +1,397 @@
+/*
+    printer.rs
+    Emulate a printer attached to the parallel port
+
+    Bytes are accepted with the Centronics handshake: the printer raises BUSY when data is
+    strobed in, then pulses ACK when it is ready for the next byte.
+
+    Output is captured to a file per print job. A job starts with the first byte received
+    and ends when the printer is initialized through the INIT line, or when no data has
+    arrived for a few seconds. In raw mode the bytes are saved as received (.prn). In text
+    mode they are interpreted as for an Epson / IBM Graphics Printer and saved as UTF-8
+    text (.txt): escape sequences and graphics are skipped, and the upper half of the
+    character set is printed as code page 437.
+*/
+
+use std::{
+    fs,
+    path::PathBuf,
+};
+
+use crate::cpu::CPU_MHZ;
+use crate::parallel::{
+    ParallelDevice, CONTROL_AUTO_FEED, CONTROL_NOT_INIT, CONTROL_STROBE, STATUS_NO_ACK,
+    STATUS_NO_ERROR, STATUS_NOT_BUSY, STATUS_SELECTED,
+};
+
+pub const PRINT_DIR: &str = "./print";
+
+// Time the printer is busy after each byte, and the length of the ACK pulse
+const PRINTER_BUSY_CYCLES: u32 = (CPU_MHZ * 10.0) as u32;
+const PRINTER_ACK_CYCLES: u32 = (CPU_MHZ * 5.0) as u32;
+// A print job ends after this long without data
+const PRINT_JOB_TIMEOUT_CYCLES: u32 = (CPU_MHZ * 5_000_000.0) as u32;
+
+const TAB_WIDTH: usize = 8;
+
+const NUL: u8 = 0x00;
+const BS: u8 = 0x08;
+const HT: u8 = 0x09;
+const LF: u8 = 0x0A;
+const VT: u8 = 0x0B;
+const FF: u8 = 0x0C;
+const CR: u8 = 0x0D;
+const CAN: u8 = 0x18;
+const ESC: u8 = 0x1B;
+
+/// Code page 437 characters 0x80 to 0xFF.
+const CP437_HIGH: &str = "ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒáíóúñÑªº¿⌐¬½¼¡«»\
+    ░▒▓│┤╡╢╖╕╣║╗╝╜╛┐└┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀\
+    αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{A0}";
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PrintMode {
+    Raw,
+    Text,
+}
+
+impl PrintMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "raw" => Some(PrintMode::Raw),
+            "text" => Some(PrintMode::Text),
+            _ => None
+        }
+    }
+}
+
+pub struct Printer {
+    mode: PrintMode,
+    print_dir: PathBuf,
+    job: Vec<u8>,
+    idle_cycles: u32,
+    busy_cycles: u32,
+    ack_cycles: u32,
+    auto_feed: bool,
+}
+
+impl Printer {
+    pub fn new(mode: PrintMode, print_dir: PathBuf) -> Self {
+        Self {
+            mode,
+            print_dir,
+            job: Vec::new(),
+            idle_cycles: 0,
+            busy_cycles: 0,
+            ack_cycles: 0,
+            auto_feed: false,
+        }
+    }
+
+    fn busy(&self) -> bool {
+        self.busy_cycles > 0
+    }
+
+    fn ack(&self) -> bool {
+        self.ack_cycles > 0
+    }
+
+    /// Set whether a carriage return also feeds a line, from the AUTO FEED line. The
+    /// setting applies to the whole job if it is raised while the job is printing.
+    fn set_auto_feed(&mut self, auto_feed: bool) {
+        self.auto_feed |= auto_feed;
+    }
+
+    /// Accept a byte strobed in by the adapter. Bytes sent while busy are lost.
+    fn strobe(&mut self, byte: u8) {
+        if self.busy() {
+            log::warn!("Printer: byte {:02X} sent while busy", byte);
+            return
+        }
+        self.job.push(byte);
+        self.idle_cycles = 0;
+        self.busy_cycles = PRINTER_BUSY_CYCLES;
+        self.ack_cycles = 0;
+    }
+
+    /// Initialize the printer, ending the current print job.
+    fn init(&mut self) {
+        self.finish_job();
+        self.busy_cycles = 0;
+        self.ack_cycles = 0;
+    }
+
+    /// Write the current print job to a file in the print directory.
+    fn finish_job(&mut self) {
+        if self.job.is_empty() {
+            return
+        }
+        let job = std::mem::take(&mut self.job);
+        let auto_feed = std::mem::take(&mut self.auto_feed);
+        self.idle_cycles = 0;
+
+        let (extension, contents) = match self.mode {
+            PrintMode::Raw => ("prn", job),
+            PrintMode::Text => ("txt", interpret_text(&job, auto_feed).into_bytes()),
+        };
+
+        if let Err(e) = fs::create_dir_all(&self.print_dir) {
+            log::error!("Printer: couldn't create {}: {}", self.print_dir.display(), e);
+            return
+        }
+        let path = (1..)
+            .map(|n| self.print_dir.join(format!("job_{:04}.{}", n, extension)))
+            .find(|path| !path.exists())
+            .unwrap();
+        match fs::write(&path, contents) {
+            Ok(_) => log::info!("Printer: saved print job to {}", path.display()),
+            Err(e) => log::error!("Printer: couldn't write {}: {}", path.display(), e)
+        }
+    }
+}
+
+impl ParallelDevice for Printer {
+    fn write_control(&mut self, old: u8, control: u8, data: u8) {
+        // INIT is active low. Initializing the printer ends the current print job.
+        if old & CONTROL_NOT_INIT != 0 && control & CONTROL_NOT_INIT == 0 {
+            log::trace!("Printer: init");
+            self.init();
+        }
+        // The printer latches the data on the leading edge of STROBE
+        if old & CONTROL_STROBE == 0 && control & CONTROL_STROBE != 0 {
+            self.set_auto_feed(control & CONTROL_AUTO_FEED != 0);
+            self.strobe(data);
+        }
+    }
+
+    fn status(&self) -> u8 {
+        let mut status = STATUS_NO_ERROR | STATUS_SELECTED;
+        if !self.busy() {
+            status |= STATUS_NOT_BUSY;
+        }
+        if !self.ack() {
+            status |= STATUS_NO_ACK;
+        }
+        status
+    }
+
+    fn run(&mut self, cycles: u32) {
+        if self.busy_cycles > 0 {
+            self.busy_cycles = self.busy_cycles.saturating_sub(cycles);
+            if self.busy_cycles == 0 {
+                self.ack_cycles = PRINTER_ACK_CYCLES;
+            }
+        }
+        else if self.ack_cycles > 0 {
+            self.ack_cycles = self.ack_cycles.saturating_sub(cycles);
+        }
+
+        if !self.job.is_empty() {
+            self.idle_cycles += cycles;
+            if self.idle_cycles >= PRINT_JOB_TIMEOUT_CYCLES {
+                self.finish_job();
+            }
+        }
+    }
+
+    /// A print job in progress is finished.
+    fn reset(&mut self) {
+        self.init();
+    }
+}
+
+impl Drop for Printer {
+    fn drop(&mut self) {
+        self.finish_job();
+    }
+}
+
+/// How the parameters of an escape sequence are laid out.
+enum EscParams {
+    Fixed(usize),
+    // ESC C n, or ESC C 0 n to set the page length in inches
+    PageLength,
+    // n1 n2 followed by n1 + n2 * 256 columns of graphics data
+    Graphics,
+    // m n1 n2 followed by graphics data whose size depends on the density m
+    GraphicsMode,
+    // Tab stops terminated by NUL
+    NulTerminated,
+}
+
+fn esc_params(command: u8) -> EscParams {
+    match command {
+        b'K' | b'L' | b'Y' | b'Z' => EscParams::Graphics,
+        b'*' => EscParams::GraphicsMode,
+        b'B' | b'D' => EscParams::NulTerminated,
+        b'C' => EscParams::PageLength,
+        b'-' | b'W' | b'3' | b'A' | b'J' | b'N' | b'Q' | b'l' | b'S' | b'U' | b'x' | b'k'
+            | b'p' | b'R' | b'j' | b'!' | b'w' | b'r' | b't' | b'/' | b'a' | b's' | b'i' => EscParams::Fixed(1),
+        b'?' | b'f' | b'$' | b'\\' => EscParams::Fixed(2),
+        _ => EscParams::Fixed(0)
+    }
+}
+
+enum TextState {
+    Normal,
+    Escape,
+    Params(usize),
+    PageLength,
+    GraphicsMode,
+    GraphicsCount { wide: bool, count: Vec<u8> },
+    NulTerminated,
+}
+
+/// Text output of the Epson / IBM Graphics Printer interpreter. Characters are placed on a
+/// line buffer so carriage returns without line feeds overprint rather than duplicate.
+struct TextPage {
+    out: String,
+    line: Vec<char>,
+    column: usize,
+}
+
+impl TextPage {
+    fn put(&mut self, c: char) {
+        if self.column < self.line.len() {
+            // Overprinting keeps the first character, except over a space
+            if self.line[self.column] == ' ' {
+                self.line[self.column] = c;
+            }
+        }
+        else {
+            self.line.resize(self.column, ' ');
+            self.line.push(c);
+        }
+        self.column += 1;
+    }
+
+    fn new_line(&mut self) {
+        let line: String = self.line.iter().collect();
+        self.out.push_str(line.trim_end());
+        self.out.push('\n');
+        self.line.clear();
+        self.column = 0;
+    }
+}
+
+/// Interpret a print job as an Epson / IBM Graphics Printer would, returning the text printed.
+/// With `auto_feed` set, a carriage return also feeds a line.
+pub fn interpret_text(job: &[u8], auto_feed: bool) -> String {
+    let mut page = TextPage {
+        out: String::new(),
+        line: Vec::new(),
+        column: 0,
+    };
+    let mut state = TextState::Normal;
+
+    for &byte in job {
+        state = match state {
+            TextState::Normal => match byte {
+                ESC => TextState::Escape,
+                CR => {
+                    page.column = 0;
+                    if auto_feed {
+                        page.new_line();
+                    }
+                    TextState::Normal
+                }
+                LF | VT => {
+                    page.new_line();
+                    TextState::Normal
+                }
+                FF => {
+                    if !page.line.is_empty() {
+                        page.new_line();
+                    }
+                    page.out.push('\x0C');
+                    TextState::Normal
+                }
+                HT => {
+                    page.column = (page.column / TAB_WIDTH + 1) * TAB_WIDTH;
+                    TextState::Normal
+                }
+                BS => {
+                    page.column = page.column.saturating_sub(1);
+                    TextState::Normal
+                }
+                CAN => {
+                    page.line.clear();
+                    page.column = 0;
+                    TextState::Normal
+                }
+                0x20..=0x7E => {
+                    page.put(byte as char);
+                    TextState::Normal
+                }
+                0x80..=0xFF => {
+                    page.put(CP437_HIGH.chars().nth(byte as usize - 0x80).unwrap());
+                    TextState::Normal
+                }
+                // Other control codes select print modes and don't affect the text
+                _ => TextState::Normal
+            },
+            TextState::Escape => match esc_params(byte) {
+                EscParams::Fixed(0) => TextState::Normal,
+                EscParams::Fixed(n) => TextState::Params(n),
+                EscParams::PageLength => TextState::PageLength,
+                EscParams::Graphics => TextState::GraphicsCount { wide: false, count: Vec::new() },
+                EscParams::GraphicsMode => TextState::GraphicsMode,
+                EscParams::NulTerminated => TextState::NulTerminated,
+            },
+            TextState::PageLength if byte == NUL => TextState::Params(1),
+            TextState::PageLength => TextState::Normal,
+            TextState::Params(n) if n > 1 => TextState::Params(n - 1),
+            TextState::Params(_) => TextState::Normal,
+            TextState::NulTerminated if byte == NUL => TextState::Normal,
+            TextState::NulTerminated => TextState::NulTerminated,
+            // 24-pin densities, 32 and up, send three bytes per column
+            TextState::GraphicsMode => TextState::GraphicsCount { wide: byte >= 32, count: Vec::new() },
+            TextState::GraphicsCount { wide, mut count } => {
+                count.push(byte);
+                if count.len() < 2 {
+                    TextState::GraphicsCount { wide, count }
+                }
+                else {
+                    let columns = count[0] as usize | (count[1] as usize) << 8;
+                    let bytes = if wide { columns * 3 } else { columns };
+                    match bytes {
+                        0 => TextState::Normal,
+                        _ => TextState::Params(bytes)
+                    }
+                }
+            }
+        };
+    }
+
+    if !page.line.is_empty() {
+        page.new_line();
+    }
+    page.out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_interpret_text() {
+        assert_eq!(CP437_HIGH.chars().count(), 128);
+
+        let mut job = Vec::new();
+        // Reset, bold on, text, bold off
+        job.extend_from_slice(b"\x1b@\x1bEREPORT\x1bF\r\n");
+        // 8-pin graphics with two columns, which may contain any byte
+        job.extend_from_slice(b"\x1bK\x02\x00\x0d\x1b");
+        // 24-pin graphics with one column
+        job.extend_from_slice(b"\x1b*\x27\x01\x00\x41\x42\x43");
+        // Underline on, tab, overprint via carriage return
+        job.extend_from_slice(b"\x1b-\x01A\tB\r___\r\n");
+        // Box drawing from code page 437, then a form feed
+        job.extend_from_slice(&[0xC9, 0xCD, 0xBB, CR, LF, FF]);
+
+        assert_eq!(interpret_text(&job, false), "REPORT\nA__     B\n╔═╗\n\x0C");
+
+        // AUTO FEED makes a carriage return feed a line
+        assert_eq!(interpret_text(b"ONE\rTWO\r", true), "ONE\nTWO\n");
+    }
+}
@@ -0,0 +1,303 @@
+/*
+    rtc.rs
+    Implement a real-time clock card based on the National MM58167
+
+    Cards such as the AST SixPakPlus add a battery-backed MM58167 so that DOS can be given
+    the date and time at boot by a utility like ASTCLOCK. The chip decodes 32 registers:
+
+        0x00 - 0x07     BCD counters: milliseconds, hundredths and tenths, seconds,
+                        minutes, hours, day of week, day of month, month
+        0x08 - 0x0F     Alarm latches, used as general purpose RAM
+        0x10 - 0x11     Interrupt status and control
+        0x12            Counter reset, one bit per counter
+        0x13            RAM reset
+        0x14            Status: set if the counters rolled over during a read
+        0x15            GO: restart the seconds
+
+    The chip has no year counter. Clock utilities keep the year in an unused alarm latch,
+    as BCD years since 1980.
+
+    The clock is set from host local time at startup, or from a fixed timestamp, and then
+    counts emulated CPU time so that runs from a fixed timestamp are deterministic. A
+    frozen clock doesn't count at all, and always reads the time it was last set to.
+*/
+#![allow(dead_code)]
+
+use time::{Date, Month, OffsetDateTime, PrimitiveDateTime, Time};
+
+use crate::cpu::CPU_MHZ;
+use crate::io::IoDevice;
+
+pub const RTC_REGISTER_COUNT: u16 = 32;
+pub const RTC_BASE_PORTS: [u16; 3] = [0x2C0, 0x240, 0x340];
+
+const REG_MILLIS: u16 = 0x00;
+const REG_HUNDREDTHS: u16 = 0x01;
+const REG_SECONDS: u16 = 0x02;
+const REG_MINUTES: u16 = 0x03;
+const REG_HOURS: u16 = 0x04;
+const REG_DAY_OF_WEEK: u16 = 0x05;
+const REG_DAY_OF_MONTH: u16 = 0x06;
+const REG_MONTH: u16 = 0x07;
+const REG_RAM: u16 = 0x08;
+const REG_INT_STATUS: u16 = 0x10;
+const REG_INT_CONTROL: u16 = 0x11;
+const REG_COUNTER_RESET: u16 = 0x12;
+const REG_RAM_RESET: u16 = 0x13;
+const REG_STATUS: u16 = 0x14;
+const REG_GO: u16 = 0x15;
+
+// Alarm day of month latch, where the year is kept
+const RAM_YEAR: usize = 6;
+const YEAR_BASE: i32 = 1980;
+
+const MILLISECOND_CYCLES: f64 = CPU_MHZ * 1000.0;
+
+/// Options for the clock card: its base address, or None for no card, the date and time
+/// it starts from, and whether it stays stopped at that time.
+#[derive(Clone, Debug)]
+pub struct RtcConfig {
+    pub base: Option<u16>,
+    pub start: PrimitiveDateTime,
+    pub frozen: bool,
+}
+
+impl Default for RtcConfig {
+    fn default() -> Self {
+        Self {
+            base: Some(RTC_BASE_PORTS[0]),
+            start: host_time(),
+            frozen: false,
+        }
+    }
+}
+
+/// The host's local date and time. The local offset can only be read safely before other
+/// threads start, so call this early; UTC is used if it can't be read.
+pub fn host_time() -> PrimitiveDateTime {
+    let now = OffsetDateTime::now_local().unwrap_or_else(|_| {
+        log::warn!("Couldn't read the host time zone, setting the clock to UTC");
+        OffsetDateTime::now_utc()
+    });
+    PrimitiveDateTime::new(now.date(), now.time())
+}
+
+/// Parse a timestamp of the form 'YYYY-MM-DD HH:MM:SS', with a space or 'T' between the
+/// date and time.
+pub fn parse_timestamp(timestamp: &str) -> Option<PrimitiveDateTime> {
+    let fields: Vec<&str> = timestamp.split(['-', ':', ' ', 'T']).collect();
+    if fields.len() != 6 {
+        return None
+    }
+    let year = fields[0].parse().ok()?;
+    let numbers = fields[1..].iter().map(|f| f.parse::<u8>().ok()).collect::<Option<Vec<u8>>>()?;
+    let month = Month::try_from(numbers[0]).ok()?;
+    let date = Date::from_calendar_date(year, month, numbers[1]).ok()?;
+    let time = Time::from_hms(numbers[2], numbers[3], numbers[4]).ok()?;
+    Some(PrimitiveDateTime::new(date, time))
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+fn from_bcd(byte: u8) -> u8 {
+    (byte >> 4) * 10 + (byte & 0x0F)
+}
+
+fn days_in_month(month: u8, year: i32) -> u8 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31
+    }
+}
+
+pub struct RtcCard {
+    base: u16,
+    millis: u16,
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    day_of_week: u8,
+    day_of_month: u8,
+    month: u8,
+    ram: [u8; 8],
+    int_control: u8,
+    cycles: f64,
+    frozen: bool,
+}
+
+impl RtcCard {
+    pub fn new(base: u16, start: PrimitiveDateTime, frozen: bool) -> Self {
+        let mut ram = [0; 8];
+        ram[RAM_YEAR] = to_bcd(((start.year() - YEAR_BASE).rem_euclid(100)) as u8);
+        Self {
+            base,
+            millis: start.millisecond(),
+            seconds: start.second(),
+            minutes: start.minute(),
+            hours: start.hour(),
+            day_of_week: start.weekday().number_from_sunday(),
+            day_of_month: start.day(),
+            month: start.month() as u8,
+            ram,
+            int_control: 0,
+            cycles: 0.0,
+            frozen,
+        }
+    }
+
+    fn year(&self) -> i32 {
+        YEAR_BASE + from_bcd(self.ram[RAM_YEAR]) as i32
+    }
+
+    /// Advance the counters by one millisecond, carrying into the larger units.
+    fn tick(&mut self) {
+        self.millis += 1;
+        if self.millis < 1000 {
+            return
+        }
+        self.millis = 0;
+        self.seconds += 1;
+        if self.seconds < 60 {
+            return
+        }
+        self.seconds = 0;
+        self.minutes += 1;
+        if self.minutes < 60 {
+            return
+        }
+        self.minutes = 0;
+        self.hours += 1;
+        if self.hours < 24 {
+            return
+        }
+        self.hours = 0;
+        self.day_of_week = self.day_of_week % 7 + 1;
+        self.day_of_month += 1;
+        if self.day_of_month <= days_in_month(self.month, self.year()) {
+            return
+        }
+        self.day_of_month = 1;
+        self.month = self.month % 12 + 1;
+    }
+
+    fn reset_counters(&mut self, mask: u8) {
+        if mask & 0x01 != 0 {
+            self.millis -= self.millis % 10;
+        }
+        if mask & 0x02 != 0 {
+            self.millis %= 10;
+        }
+        let counters = [
+            (0x04, &mut self.seconds, 0),
+            (0x08, &mut self.minutes, 0),
+            (0x10, &mut self.hours, 0),
+            (0x20, &mut self.day_of_week, 1),
+            (0x40, &mut self.day_of_month, 1),
+            (0x80, &mut self.month, 1),
+        ];
+        for (bit, counter, value) in counters {
+            if mask & bit != 0 {
+                *counter = value;
+            }
+        }
+    }
+
+    pub fn run(&mut self, cycles: u32) {
+        if self.frozen {
+            return
+        }
+        self.cycles += cycles as f64;
+        while self.cycles >= MILLISECOND_CYCLES {
+            self.cycles -= MILLISECOND_CYCLES;
+            self.tick();
+        }
+    }
+}
+
+impl IoDevice for RtcCard {
+    fn read_u8(&mut self, port: u16) -> u8 {
+        match port - self.base {
+            // The millisecond counter reads in the upper digit only
+            REG_MILLIS => to_bcd((self.millis % 10) as u8) << 4,
+            REG_HUNDREDTHS => to_bcd((self.millis / 10) as u8),
+            REG_SECONDS => to_bcd(self.seconds),
+            REG_MINUTES => to_bcd(self.minutes),
+            REG_HOURS => to_bcd(self.hours),
+            REG_DAY_OF_WEEK => self.day_of_week,
+            REG_DAY_OF_MONTH => to_bcd(self.day_of_month),
+            REG_MONTH => to_bcd(self.month),
+            reg @ REG_RAM..=0x0F => self.ram[(reg - REG_RAM) as usize],
+            REG_INT_CONTROL => self.int_control,
+            // Counters are updated between CPU accesses, so never roll over during a read
+            REG_INT_STATUS | REG_STATUS => 0,
+            _ => 0xFF
+        }
+    }
+
+    fn write_u8(&mut self, port: u16, data: u8) {
+        match port - self.base {
+            REG_MILLIS => self.millis = self.millis - self.millis % 10 + (from_bcd(data >> 4) % 10) as u16,
+            REG_HUNDREDTHS => self.millis = self.millis % 10 + from_bcd(data) as u16 % 100 * 10,
+            REG_SECONDS => self.seconds = from_bcd(data) % 60,
+            REG_MINUTES => self.minutes = from_bcd(data) % 60,
+            REG_HOURS => self.hours = from_bcd(data) % 24,
+            REG_DAY_OF_WEEK => self.day_of_week = (data & 0x07).max(1),
+            REG_DAY_OF_MONTH => self.day_of_month = from_bcd(data).clamp(1, 31),
+            REG_MONTH => self.month = from_bcd(data).clamp(1, 12),
+            reg @ REG_RAM..=0x0F => self.ram[(reg - REG_RAM) as usize] = data,
+            REG_INT_CONTROL => self.int_control = data,
+            REG_COUNTER_RESET => self.reset_counters(data),
+            REG_RAM_RESET if data == 0xFF => self.ram = [0; 8],
+            REG_GO => {
+                self.millis = 0;
+                self.seconds = 0;
+                self.cycles = 0.0;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_clock() {
+        let start = parse_timestamp("1989-02-28T23:59:59").unwrap();
+        let mut rtc = RtcCard::new(0x2C0, start, false);
+        assert_eq!(rtc.read_u8(0x2C2), 0x59);
+        assert_eq!(rtc.read_u8(0x2C4), 0x23);
+        assert_eq!(rtc.read_u8(0x2C5), 3); // Tuesday
+        assert_eq!(rtc.read_u8(0x2C0 + REG_RAM + RAM_YEAR as u16), 0x09);
+
+        // 1989 isn't a leap year, so February rolls over into March
+        rtc.run((MILLISECOND_CYCLES * 1000.0) as u32 + 1);
+        assert_eq!(rtc.read_u8(0x2C2), 0x00);
+        assert_eq!(rtc.read_u8(0x2C4), 0x00);
+        assert_eq!(rtc.read_u8(0x2C5), 4);
+        assert_eq!(rtc.read_u8(0x2C6), 0x01);
+        assert_eq!(rtc.read_u8(0x2C7), 0x03);
+
+        rtc.write_u8(0x2C3, 0x42);
+        assert_eq!(rtc.read_u8(0x2C3), 0x42);
+        rtc.write_u8(0x2C0 + REG_COUNTER_RESET, 0xFF);
+        assert_eq!(rtc.read_u8(0x2C3), 0x00);
+        assert_eq!(rtc.read_u8(0x2C7), 0x01);
+
+        assert!(parse_timestamp("1989-02-30 12:00:00").is_none());
+
+        // A frozen clock keeps the time it was set to
+        let mut rtc = RtcCard::new(0x2C0, start, true);
+        rtc.run((MILLISECOND_CYCLES * 1000.0) as u32 + 1);
+        assert_eq!(rtc.read_u8(0x2C2), 0x59);
+        assert_eq!(rtc.read_u8(0x2C7), 0x02);
+        rtc.write_u8(0x2C3, 0x42);
+        rtc.run((MILLISECOND_CYCLES * 60_000.0) as u32);
+        assert_eq!(rtc.read_u8(0x2C3), 0x42);
+    }
+}
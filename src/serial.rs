@@ -0,0 +1,496 @@
+/*
+    serial.rs
+    Implement the 8250 UART and the IBM Asynchronous Communications Adapter
+
+    The PC/XT supports two serial ports: COM1 at 0x3F8 on IRQ4 and COM2 at 0x2F8 on IRQ3.
+    Each port is an 8250 UART. Bytes are transferred at the rate set by the baud rate
+    divisor and the line format, so software that polls the line status or counts on
+    interrupt pacing sees realistic timing. The interrupt output is gated by the OUT2 bit
+    of the modem control register, as on the IBM adapter.
+
+    Devices attached to a port exchange bytes with the UART through the port's line
+    queues and read the modem control outputs. The Microsoft serial mouse is emulated
+    in mouse.rs.
+*/
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+
+use crate::cpu::CPU_MHZ;
+use crate::io::IoDevice;
+use crate::pic;
+
+pub const SERIAL1_BASE_PORT: u16 = 0x3F8;
+pub const SERIAL2_BASE_PORT: u16 = 0x2F8;
+pub const SERIAL1_IRQ: u8 = 4;
+pub const SERIAL2_IRQ: u8 = 3;
+pub const SERIAL_PORT_COUNT: usize = 2;
+pub const SERIAL_REGISTER_COUNT: u16 = 8;
+
+// Register offsets from the base port
+pub const REG_DATA: u16 = 0;              // RBR read / THR write, DLL when DLAB is set
+pub const REG_INTERRUPT_ENABLE: u16 = 1;  // IER, DLM when DLAB is set
+pub const REG_INTERRUPT_ID: u16 = 2;      // IIR, read only
+pub const REG_LINE_CONTROL: u16 = 3;
+pub const REG_MODEM_CONTROL: u16 = 4;
+pub const REG_LINE_STATUS: u16 = 5;
+pub const REG_MODEM_STATUS: u16 = 6;
+pub const REG_SCRATCH: u16 = 7;
+
+pub const IER_RX_DATA: u8        = 0b0000_0001;
+pub const IER_THR_EMPTY: u8      = 0b0000_0010;
+pub const IER_LINE_STATUS: u8    = 0b0000_0100;
+pub const IER_MODEM_STATUS: u8   = 0b0000_1000;
+
+pub const IIR_NO_INTERRUPT: u8   = 0b0000_0001;
+pub const IIR_MODEM_STATUS: u8   = 0b0000_0000;
+pub const IIR_THR_EMPTY: u8      = 0b0000_0010;
+pub const IIR_RX_DATA: u8        = 0b0000_0100;
+pub const IIR_LINE_STATUS: u8    = 0b0000_0110;
+
+pub const LCR_WORD_LENGTH: u8    = 0b0000_0011;
+pub const LCR_STOP_BITS: u8      = 0b0000_0100;
+pub const LCR_PARITY_ENABLE: u8  = 0b0000_1000;
+pub const LCR_BREAK: u8          = 0b0100_0000;
+pub const LCR_DLAB: u8           = 0b1000_0000;
+
+pub const MCR_DTR: u8            = 0b0000_0001;
+pub const MCR_RTS: u8            = 0b0000_0010;
+pub const MCR_OUT1: u8           = 0b0000_0100;
+pub const MCR_OUT2: u8           = 0b0000_1000;
+pub const MCR_LOOP: u8           = 0b0001_0000;
+
+pub const LSR_DATA_READY: u8     = 0b0000_0001;
+pub const LSR_OVERRUN: u8        = 0b0000_0010;
+pub const LSR_PARITY_ERROR: u8   = 0b0000_0100;
+pub const LSR_FRAMING_ERROR: u8  = 0b0000_1000;
+pub const LSR_BREAK: u8          = 0b0001_0000;
+pub const LSR_THR_EMPTY: u8      = 0b0010_0000;
+pub const LSR_TSR_EMPTY: u8      = 0b0100_0000;
+const LSR_ERRORS: u8 = LSR_OVERRUN | LSR_PARITY_ERROR | LSR_FRAMING_ERROR | LSR_BREAK;
+
+pub const MSR_DELTA_CTS: u8      = 0b0000_0001;
+pub const MSR_DELTA_DSR: u8      = 0b0000_0010;
+pub const MSR_TRAILING_RI: u8    = 0b0000_0100;
+pub const MSR_DELTA_DCD: u8      = 0b0000_1000;
+pub const MSR_CTS: u8            = 0b0001_0000;
+pub const MSR_DSR: u8            = 0b0010_0000;
+pub const MSR_RI: u8             = 0b0100_0000;
+pub const MSR_DCD: u8            = 0b1000_0000;
+
+// The UART is clocked by a 1.8432MHz crystal, divided by 16 and then by the divisor
+const UART_CLOCK_HZ: f64 = 1_843_200.0 / 16.0;
+
+/// An 8250 UART. `rx_line` holds bytes sent to the UART by the attached device, and
+/// `tx_line` bytes the UART has finished transmitting.
+pub struct SerialPort {
+    irq: u8,
+    rbr: u8,
+    thr: u8,
+    tsr: Option<u8>,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    lsr: u8,
+    msr: u8,
+    scratch: u8,
+    divisor: u16,
+    // Set when a THR empty interrupt is pending. Cleared by reading the IIR or writing THR.
+    thre_interrupt: bool,
+    rx_cycles: u32,
+    tx_cycles: u32,
+    rx_line: VecDeque<u8>,
+    tx_line: VecDeque<u8>,
+    irq_active: bool,
+}
+
+#[derive(Default)]
+pub struct SerialStringState {
+    pub port: String,
+    pub attachment: String,
+    pub baud: String,
+    pub line_control: String,
+    pub modem_control: String,
+    pub line_status: String,
+    pub modem_status: String,
+    pub interrupt_enable: String,
+}
+
+impl SerialPort {
+    pub fn new(irq: u8) -> Self {
+        Self {
+            irq,
+            rbr: 0,
+            thr: 0,
+            tsr: None,
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            lsr: LSR_THR_EMPTY | LSR_TSR_EMPTY,
+            msr: 0,
+            scratch: 0,
+            divisor: 12,
+            thre_interrupt: false,
+            rx_cycles: 0,
+            tx_cycles: 0,
+            rx_line: VecDeque::new(),
+            tx_line: VecDeque::new(),
+            irq_active: false,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = SerialPort::new(self.irq);
+    }
+
+    /// Return the number of CPU cycles needed to transfer one character in the current
+    /// line format: a start bit, 5-8 data bits, an optional parity bit and 1-2 stop bits.
+    fn char_cycles(&self) -> u32 {
+        let data_bits = 5 + (self.lcr & LCR_WORD_LENGTH) as u32;
+        let parity_bits = (self.lcr & LCR_PARITY_ENABLE != 0) as u32;
+        let stop_bits = 1 + (self.lcr & LCR_STOP_BITS != 0) as u32;
+        let bits = 1 + data_bits + parity_bits + stop_bits;
+        let baud = UART_CLOCK_HZ / self.divisor.max(1) as f64;
+        (bits as f64 / baud * CPU_MHZ * 1_000_000.0) as u32
+    }
+
+    fn data_mask(&self) -> u8 {
+        (0xFF_u16 >> (3 - (self.lcr & LCR_WORD_LENGTH))) as u8
+    }
+
+    pub fn get_baud_rate(&self) -> u32 {
+        (UART_CLOCK_HZ / self.divisor.max(1) as f64) as u32
+    }
+
+    /// Send a byte to the UART from the attached device.
+    pub fn send_rx(&mut self, byte: u8) {
+        self.rx_line.push_back(byte);
+    }
+
+    /// Return the number of bytes waiting to be received by the UART.
+    pub fn rx_pending(&self) -> usize {
+        self.rx_line.len()
+    }
+
+    /// Take the next byte transmitted by the UART.
+    pub fn take_tx(&mut self) -> Option<u8> {
+        self.tx_line.pop_front()
+    }
+
+    pub fn get_dtr(&self) -> bool {
+        self.mcr & MCR_DTR != 0
+    }
+
+    pub fn get_rts(&self) -> bool {
+        self.mcr & MCR_RTS != 0
+    }
+
+    /// Set the modem status inputs from the attached device, latching the delta bits.
+    pub fn set_modem_status(&mut self, cts: bool, dsr: bool, ri: bool, dcd: bool) {
+        if self.mcr & MCR_LOOP != 0 {
+            return
+        }
+        self.update_modem_status(cts, dsr, ri, dcd);
+    }
+
+    fn update_modem_status(&mut self, cts: bool, dsr: bool, ri: bool, dcd: bool) {
+        let new = (cts as u8 * MSR_CTS) | (dsr as u8 * MSR_DSR) | (ri as u8 * MSR_RI) | (dcd as u8 * MSR_DCD);
+        let old = self.msr;
+        let changed = new ^ (old & 0xF0);
+        let mut delta = old & 0x0F;
+        if changed & MSR_CTS != 0 {
+            delta |= MSR_DELTA_CTS;
+        }
+        if changed & MSR_DSR != 0 {
+            delta |= MSR_DELTA_DSR;
+        }
+        if old & MSR_RI != 0 && !ri {
+            delta |= MSR_TRAILING_RI;
+        }
+        if changed & MSR_DCD != 0 {
+            delta |= MSR_DELTA_DCD;
+        }
+        self.msr = new | delta;
+    }
+
+    /// In loopback mode the modem control outputs are wired to the modem status inputs.
+    fn update_loopback(&mut self) {
+        if self.mcr & MCR_LOOP != 0 {
+            let mcr = self.mcr;
+            self.update_modem_status(mcr & MCR_RTS != 0, mcr & MCR_DTR != 0, mcr & MCR_OUT1 != 0, mcr & MCR_OUT2 != 0);
+        }
+    }
+
+    /// Return the highest priority pending interrupt, as reported in the IIR.
+    fn pending_interrupt(&self) -> u8 {
+        if self.ier & IER_LINE_STATUS != 0 && self.lsr & LSR_ERRORS != 0 {
+            IIR_LINE_STATUS
+        }
+        else if self.ier & IER_RX_DATA != 0 && self.lsr & LSR_DATA_READY != 0 {
+            IIR_RX_DATA
+        }
+        else if self.ier & IER_THR_EMPTY != 0 && self.thre_interrupt {
+            IIR_THR_EMPTY
+        }
+        else if self.ier & IER_MODEM_STATUS != 0 && self.msr & 0x0F != 0 {
+            IIR_MODEM_STATUS
+        }
+        else {
+            IIR_NO_INTERRUPT
+        }
+    }
+
+    fn read_register(&mut self, reg: u16) -> u8 {
+        match reg {
+            REG_DATA if self.lcr & LCR_DLAB != 0 => self.divisor as u8,
+            REG_DATA => {
+                self.lsr &= !LSR_DATA_READY;
+                self.rbr
+            }
+            REG_INTERRUPT_ENABLE if self.lcr & LCR_DLAB != 0 => (self.divisor >> 8) as u8,
+            REG_INTERRUPT_ENABLE => self.ier,
+            REG_INTERRUPT_ID => {
+                let iir = self.pending_interrupt();
+                if iir == IIR_THR_EMPTY {
+                    self.thre_interrupt = false;
+                }
+                iir
+            }
+            REG_LINE_CONTROL => self.lcr,
+            REG_MODEM_CONTROL => self.mcr,
+            REG_LINE_STATUS => {
+                let lsr = self.lsr;
+                self.lsr &= !LSR_ERRORS;
+                lsr
+            }
+            REG_MODEM_STATUS => {
+                let msr = self.msr;
+                self.msr &= 0xF0;
+                msr
+            }
+            _ => self.scratch
+        }
+    }
+
+    fn write_register(&mut self, reg: u16, byte: u8) {
+        match reg {
+            REG_DATA if self.lcr & LCR_DLAB != 0 => {
+                self.divisor = (self.divisor & 0xFF00) | byte as u16;
+            }
+            REG_DATA => {
+                self.thr = byte;
+                self.lsr &= !LSR_THR_EMPTY;
+                self.thre_interrupt = false;
+            }
+            REG_INTERRUPT_ENABLE if self.lcr & LCR_DLAB != 0 => {
+                self.divisor = (self.divisor & 0x00FF) | (byte as u16) << 8;
+            }
+            REG_INTERRUPT_ENABLE => {
+                // Enabling the THR empty interrupt while THR is empty raises it immediately
+                if byte & IER_THR_EMPTY != 0 && self.ier & IER_THR_EMPTY == 0 && self.lsr & LSR_THR_EMPTY != 0 {
+                    self.thre_interrupt = true;
+                }
+                self.ier = byte & 0x0F;
+            }
+            REG_LINE_CONTROL => {
+                self.lcr = byte;
+            }
+            REG_MODEM_CONTROL => {
+                self.mcr = byte & 0x1F;
+                self.update_loopback();
+            }
+            REG_SCRATCH => {
+                self.scratch = byte;
+            }
+            _ => {}
+        }
+    }
+
+    /// Run the UART for the specified number of cycles, shifting bytes in and out at the
+    /// current baud rate and updating the interrupt request line.
+    pub fn run(&mut self, pic: &mut pic::Pic, cycles: u32) {
+
+        let char_cycles = self.char_cycles();
+        let loopback = self.mcr & MCR_LOOP != 0;
+
+        // Transmit: move THR into the shift register, and complete the byte after a
+        // character time.
+        if self.tsr.is_none() && self.lsr & LSR_THR_EMPTY == 0 {
+            self.tsr = Some(self.thr & self.data_mask());
+            self.lsr |= LSR_THR_EMPTY;
+            self.lsr &= !LSR_TSR_EMPTY;
+            self.thre_interrupt = true;
+            self.tx_cycles = char_cycles;
+        }
+        if let Some(byte) = self.tsr {
+            if self.tx_cycles > cycles {
+                self.tx_cycles -= cycles;
+            }
+            else {
+                self.tsr = None;
+                self.lsr |= LSR_TSR_EMPTY;
+                if loopback {
+                    self.rx_line.push_back(byte);
+                }
+                else {
+                    self.tx_line.push_back(byte);
+                }
+            }
+        }
+
+        // Receive: one byte arrives per character time. A byte arriving before the last
+        // one was read is an overrun.
+        if !self.rx_line.is_empty() {
+            if self.rx_cycles == 0 {
+                self.rx_cycles = char_cycles;
+            }
+            if self.rx_cycles > cycles {
+                self.rx_cycles -= cycles;
+            }
+            else {
+                self.rx_cycles = 0;
+                let byte = self.rx_line.pop_front().unwrap();
+                if self.lsr & LSR_DATA_READY != 0 {
+                    self.lsr |= LSR_OVERRUN;
+                }
+                self.rbr = byte & self.data_mask();
+                self.lsr |= LSR_DATA_READY;
+            }
+        }
+
+        // The IBM adapter gates the interrupt output with OUT2
+        let irq_active = self.mcr & MCR_OUT2 != 0 && !loopback && self.pending_interrupt() != IIR_NO_INTERRUPT;
+        if irq_active != self.irq_active {
+            self.irq_active = irq_active;
+            if irq_active {
+                pic.request_interrupt(self.irq);
+            }
+            else {
+                pic.clear_interrupt(self.irq);
+            }
+        }
+    }
+
+    pub fn get_string_state(&self, name: &str) -> SerialStringState {
+        SerialStringState {
+            port: name.to_string(),
+            attachment: String::new(),
+            baud: format!("{}", self.get_baud_rate()),
+            line_control: format!("{:08b}", self.lcr),
+            modem_control: format!("{:08b}", self.mcr),
+            line_status: format!("{:08b}", self.lsr),
+            modem_status: format!("{:08b}", self.msr),
+            interrupt_enable: format!("{:08b}", self.ier),
+        }
+    }
+}
+
+/// The two standard serial ports, COM1 and COM2.
+pub struct SerialPortController {
+    ports: [SerialPort; SERIAL_PORT_COUNT],
+}
+
+impl SerialPortController {
+    pub fn new() -> Self {
+        Self {
+            ports: [SerialPort::new(SERIAL1_IRQ), SerialPort::new(SERIAL2_IRQ)],
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for port in &mut self.ports {
+            port.reset();
+        }
+    }
+
+    pub fn port_mut(&mut self, port: usize) -> &mut SerialPort {
+        &mut self.ports[port]
+    }
+
+    fn decode_port(port: u16) -> (usize, u16) {
+        if (SERIAL2_BASE_PORT..SERIAL2_BASE_PORT + SERIAL_REGISTER_COUNT).contains(&port) {
+            (1, port - SERIAL2_BASE_PORT)
+        }
+        else {
+            (0, port - SERIAL1_BASE_PORT)
+        }
+    }
+
+    pub fn run(&mut self, pic: &mut pic::Pic, cycles: u32) {
+        for port in &mut self.ports {
+            port.run(pic, cycles);
+        }
+    }
+
+    pub fn get_string_state(&self) -> Vec<SerialStringState> {
+        vec![
+            self.ports[0].get_string_state("COM1"),
+            self.ports[1].get_string_state("COM2"),
+        ]
+    }
+}
+
+impl IoDevice for SerialPortController {
+    fn read_u8(&mut self, port: u16) -> u8 {
+        let (idx, reg) = SerialPortController::decode_port(port);
+        self.ports[idx].read_register(reg)
+    }
+
+    fn write_u8(&mut self, port: u16, data: u8) {
+        let (idx, reg) = SerialPortController::decode_port(port);
+        self.ports[idx].write_register(reg, data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_loopback_and_interrupts() {
+        let mut pic = pic::Pic::new();
+        let mut serial = SerialPortController::new();
+
+        // 9600 baud, 8N1, loopback, interrupts on receive
+        serial.write_u8(SERIAL1_BASE_PORT + REG_LINE_CONTROL, LCR_DLAB | 0x03);
+        serial.write_u8(SERIAL1_BASE_PORT + REG_DATA, 12);
+        serial.write_u8(SERIAL1_BASE_PORT + REG_INTERRUPT_ENABLE, 0);
+        serial.write_u8(SERIAL1_BASE_PORT + REG_LINE_CONTROL, 0x03);
+        assert_eq!(serial.port_mut(0).get_baud_rate(), 9600);
+
+        serial.write_u8(SERIAL1_BASE_PORT + REG_MODEM_CONTROL, MCR_LOOP | MCR_RTS);
+        assert_eq!(serial.read_u8(SERIAL1_BASE_PORT + REG_MODEM_STATUS) & (MSR_CTS | MSR_DELTA_CTS), MSR_CTS | MSR_DELTA_CTS);
+
+        serial.write_u8(SERIAL1_BASE_PORT + REG_DATA, 0x5A);
+        let char_cycles = serial.port_mut(0).char_cycles();
+        let mut elapsed = 0;
+        while serial.read_u8(SERIAL1_BASE_PORT + REG_LINE_STATUS) & LSR_DATA_READY == 0 {
+            serial.run(&mut pic, 10);
+            elapsed += 10;
+            assert!(elapsed < char_cycles * 3);
+        }
+        // Transmit and receive each take a character time
+        assert!(elapsed >= char_cycles * 2);
+        assert_eq!(serial.read_u8(SERIAL1_BASE_PORT + REG_DATA), 0x5A);
+
+        // Receive interrupt from an attached device, gated by OUT2
+        serial.write_u8(SERIAL1_BASE_PORT + REG_MODEM_CONTROL, MCR_OUT2);
+        serial.write_u8(SERIAL1_BASE_PORT + REG_INTERRUPT_ENABLE, IER_RX_DATA);
+        serial.port_mut(0).send_rx(b'M');
+        serial.port_mut(0).send_rx(b'X');
+        for _ in 0..(char_cycles / 10 + 1) {
+            serial.run(&mut pic, 10);
+        }
+        assert_eq!(serial.read_u8(SERIAL1_BASE_PORT + REG_INTERRUPT_ID), IIR_RX_DATA);
+        assert!(serial.ports[0].irq_active);
+
+        // A second byte arriving before the first is read is an overrun
+        for _ in 0..(char_cycles / 10 + 1) {
+            serial.run(&mut pic, 10);
+        }
+        assert_ne!(serial.read_u8(SERIAL1_BASE_PORT + REG_LINE_STATUS) & LSR_OVERRUN, 0);
+        assert_eq!(serial.read_u8(SERIAL1_BASE_PORT + REG_DATA), b'X');
+        serial.run(&mut pic, 10);
+        assert!(!serial.ports[0].irq_active);
+    }
+}
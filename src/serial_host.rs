@@ -0,0 +1,449 @@
+/*
+    serial_host.rs
+    Connect emulated serial ports to the host
+
+    A serial port can be attached to a Linux pseudo-terminal, a TCP listener on the local
+    host (a null modem over TCP), or a capture file. Host connections are polled at a
+    fixed interval rather than on every emulated cycle, and buffered in between.
+
+    Modem control is modelled as a null modem cable: while a peer is connected, the
+    port sees CTS, DSR and DCD asserted. Incoming data is held while the guest drops RTS,
+    and dropping DTR hangs up a TCP connection. CTS is dropped while the host isn't taking
+    the data sent to it, and data sent with the host buffer full is lost.
+*/
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+};
+
+use crate::cpu::CPU_MHZ;
+use crate::serial::SerialPort;
+
+// Poll host connections every millisecond
+const HOST_POLL_CYCLES: u32 = (CPU_MHZ * 1000.0) as u32;
+const HOST_READ_SIZE: usize = 256;
+// Stop reading from the host while this much received data is buffered
+const HOST_RX_BUFFER_LIMIT: usize = 4096;
+// Drop CTS, and discard transmitted data, while this much is waiting to be sent to the host
+const HOST_TX_BUFFER_LIMIT: usize = 4096;
+
+/// What is attached to a serial port, as chosen on the command line.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SerialAttachment {
+    None,
+    Mouse,
+    Pty,
+    Tcp(u16),
+    File(PathBuf),
+}
+
+impl SerialAttachment {
+    /// Parse an attachment: 'none', 'mouse', 'pty', 'tcp:<port>' or 'file:<path>'.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        match spec.split_once(':') {
+            Some(("tcp", port)) => port.parse::<u16>()
+                .map(SerialAttachment::Tcp)
+                .map_err(|_| format!("Invalid TCP port: {}", port)),
+            Some(("file", path)) if !path.is_empty() => Ok(SerialAttachment::File(PathBuf::from(path))),
+            None if spec == "none" => Ok(SerialAttachment::None),
+            None if spec == "mouse" => Ok(SerialAttachment::Mouse),
+            None if spec == "pty" => Ok(SerialAttachment::Pty),
+            _ => Err(format!("Invalid serial port attachment: {}", spec))
+        }
+    }
+}
+
+/// A connection to the host. Reads and writes must not block.
+trait HostConnection: Send {
+    /// Read available bytes into `buf`, returning the number read.
+    fn read(&mut self, buf: &mut [u8]) -> usize;
+    /// Write as many bytes as possible, returning the number written.
+    fn write(&mut self, data: &[u8]) -> usize;
+    /// Return whether a peer is connected.
+    fn connected(&mut self) -> bool;
+    /// The guest changed DTR.
+    fn set_dtr(&mut self, _dtr: bool) {}
+    fn description(&self) -> String;
+}
+
+/// Map the result of a non-blocking read or write to a byte count. Returns None if the
+/// connection failed.
+fn nonblocking(result: io::Result<usize>) -> Option<usize> {
+    match result {
+        Ok(n) => Some(n),
+        Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => Some(0),
+        Err(_) => None
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod pty {
+    use nix::{fcntl::OFlag, pty::{self, PtyMaster}};
+    use super::*;
+
+    /// The master side of a pseudo-terminal. Host programs open the slave device.
+    pub struct PtyConnection {
+        master: PtyMaster,
+        slave_name: String,
+        connected: bool,
+    }
+
+    impl PtyConnection {
+        pub fn open() -> Result<Self, String> {
+            let master = pty::posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_NONBLOCK)
+                .map_err(|e| format!("Couldn't open pseudo-terminal: {}", e))?;
+            pty::grantpt(&master).map_err(|e| format!("grantpt failed: {}", e))?;
+            pty::unlockpt(&master).map_err(|e| format!("unlockpt failed: {}", e))?;
+            let slave_name = pty::ptsname_r(&master).map_err(|e| format!("ptsname failed: {}", e))?;
+            Ok(Self {
+                master,
+                slave_name,
+                connected: false,
+            })
+        }
+    }
+
+    impl HostConnection for PtyConnection {
+        fn read(&mut self, buf: &mut [u8]) -> usize {
+            // Reading the master fails with EIO while nothing has the slave open
+            match nonblocking(self.master.read(buf)) {
+                Some(n) => {
+                    self.connected = true;
+                    n
+                }
+                None => {
+                    self.connected = false;
+                    0
+                }
+            }
+        }
+
+        fn write(&mut self, data: &[u8]) -> usize {
+            if !self.connected {
+                // Discard data sent with nothing listening
+                return data.len()
+            }
+            nonblocking(self.master.write(data)).unwrap_or(data.len())
+        }
+
+        fn connected(&mut self) -> bool {
+            self.connected
+        }
+
+        fn description(&self) -> String {
+            format!("pty {}", self.slave_name)
+        }
+    }
+}
+
+/// A TCP listener on the local host that accepts one connection at a time.
+struct TcpConnection {
+    listener: TcpListener,
+    stream: Option<TcpStream>,
+    port: u16,
+}
+
+impl TcpConnection {
+    fn open(port: u16) -> Result<Self, String> {
+        let listener = TcpListener::bind(("127.0.0.1", port))
+            .map_err(|e| format!("Couldn't listen on port {}: {}", port, e))?;
+        listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+        // Port 0 picks a free port
+        let port = listener.local_addr().map_err(|e| e.to_string())?.port();
+        Ok(Self {
+            listener,
+            stream: None,
+            port,
+        })
+    }
+
+    fn accept(&mut self) {
+        if self.stream.is_some() {
+            return
+        }
+        if let Ok((stream, addr)) = self.listener.accept() {
+            if stream.set_nonblocking(true).is_ok() {
+                let _ = stream.set_nodelay(true);
+                log::info!("Serial: connection from {} on port {}", addr, self.port);
+                self.stream = Some(stream);
+            }
+        }
+    }
+
+    fn hang_up(&mut self) {
+        if self.stream.take().is_some() {
+            log::info!("Serial: connection on port {} closed", self.port);
+        }
+    }
+}
+
+impl HostConnection for TcpConnection {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        self.accept();
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => return 0
+        };
+        match stream.read(buf) {
+            // A read of zero bytes means the peer closed the connection
+            Ok(0) => {
+                self.hang_up();
+                0
+            }
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => 0,
+            Err(_) => {
+                self.hang_up();
+                0
+            }
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> usize {
+        let result = match &mut self.stream {
+            Some(stream) => nonblocking(stream.write(data)),
+            None => return data.len()
+        };
+        match result {
+            Some(n) => n,
+            None => {
+                self.hang_up();
+                data.len()
+            }
+        }
+    }
+
+    fn connected(&mut self) -> bool {
+        self.accept();
+        self.stream.is_some()
+    }
+
+    fn set_dtr(&mut self, dtr: bool) {
+        if !dtr {
+            self.hang_up();
+        }
+    }
+
+    fn description(&self) -> String {
+        format!("tcp 127.0.0.1:{}", self.port)
+    }
+}
+
+/// Capture transmitted bytes to a file. Nothing is received.
+struct FileConnection {
+    file: File,
+    path: PathBuf,
+}
+
+impl HostConnection for FileConnection {
+    fn read(&mut self, _buf: &mut [u8]) -> usize {
+        0
+    }
+
+    fn write(&mut self, data: &[u8]) -> usize {
+        if let Err(e) = self.file.write_all(data) {
+            log::error!("Serial: error writing {}: {}", self.path.display(), e);
+        }
+        data.len()
+    }
+
+    fn connected(&mut self) -> bool {
+        true
+    }
+
+    fn description(&self) -> String {
+        format!("file {}", self.path.display())
+    }
+}
+
+/// A host connection attached to a serial port.
+pub struct SerialHost {
+    connection: Box<dyn HostConnection>,
+    rx_buf: VecDeque<u8>,
+    tx_buf: Vec<u8>,
+    poll_cycles: u32,
+    dtr: bool,
+    tx_overflow: bool,
+}
+
+impl SerialHost {
+    /// Open a host connection for an attachment. Returns None for attachments that aren't
+    /// host connections.
+    pub fn open(attachment: &SerialAttachment) -> Result<Option<Self>, String> {
+        let connection: Box<dyn HostConnection> = match attachment {
+            SerialAttachment::None | SerialAttachment::Mouse => return Ok(None),
+            #[cfg(target_os = "linux")]
+            SerialAttachment::Pty => Box::new(pty::PtyConnection::open()?),
+            #[cfg(not(target_os = "linux"))]
+            SerialAttachment::Pty => return Err("Pseudo-terminals are only supported on Linux".to_string()),
+            SerialAttachment::Tcp(port) => Box::new(TcpConnection::open(*port)?),
+            SerialAttachment::File(path) => Box::new(FileConnection {
+                file: File::create(path).map_err(|e| format!("Couldn't create {}: {}", path.display(), e))?,
+                path: path.clone(),
+            }),
+        };
+        Ok(Some(Self::new(connection)))
+    }
+
+    fn new(connection: Box<dyn HostConnection>) -> Self {
+        Self {
+            connection,
+            rx_buf: VecDeque::new(),
+            tx_buf: Vec::new(),
+            poll_cycles: 0,
+            dtr: false,
+            tx_overflow: false,
+        }
+    }
+
+    pub fn description(&self) -> String {
+        self.connection.description()
+    }
+
+    fn poll(&mut self, port: &mut SerialPort) {
+        if !self.tx_buf.is_empty() {
+            let written = self.connection.write(&self.tx_buf);
+            self.tx_buf.drain(..written.min(self.tx_buf.len()));
+        }
+
+        if self.rx_buf.len() < HOST_RX_BUFFER_LIMIT {
+            let mut buf = [0; HOST_READ_SIZE];
+            let n = self.connection.read(&mut buf);
+            self.rx_buf.extend(&buf[..n]);
+        }
+
+        let connected = self.connection.connected();
+        let cts = connected && self.tx_buf.len() < HOST_TX_BUFFER_LIMIT;
+        port.set_modem_status(cts, connected, false, connected);
+    }
+
+    /// Run the connection for the specified number of cycles, passing bytes between the
+    /// host and the serial port.
+    pub fn run(&mut self, port: &mut SerialPort, cycles: u32) {
+
+        while let Some(byte) = port.take_tx() {
+            if self.tx_buf.len() < HOST_TX_BUFFER_LIMIT {
+                self.tx_buf.push(byte);
+                self.tx_overflow = false;
+            }
+            else if !self.tx_overflow {
+                log::warn!("Serial: {} isn't taking data, discarding output", self.connection.description());
+                self.tx_overflow = true;
+            }
+        }
+
+        let dtr = port.get_dtr();
+        if dtr != self.dtr {
+            self.dtr = dtr;
+            self.connection.set_dtr(dtr);
+        }
+
+        self.poll_cycles += cycles;
+        if self.poll_cycles >= HOST_POLL_CYCLES {
+            self.poll_cycles = 0;
+            self.poll(port);
+        }
+
+        // Pass received bytes to the UART one at a time, unless the guest is holding RTS
+        // low to stop the flow.
+        if port.get_rts() && port.rx_pending() == 0 {
+            if let Some(byte) = self.rx_buf.pop_front() {
+                port.send_rx(byte);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{thread, time::Duration};
+    use crate::io::IoDevice;
+    use crate::pic::Pic;
+    use crate::serial::*;
+
+    const MSR_LINES: u8 = MSR_CTS | MSR_DSR | MSR_DCD;
+
+    /// Run the UART and its host connection, polling the host each step.
+    fn step(host: &mut SerialHost, serial: &mut SerialPortController, pic: &mut Pic) {
+        serial.run(pic, HOST_POLL_CYCLES);
+        host.run(serial.port_mut(0), HOST_POLL_CYCLES);
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    #[test]
+    pub fn test_parse_attachment() {
+        assert_eq!(SerialAttachment::parse("mouse"), Ok(SerialAttachment::Mouse));
+        assert_eq!(SerialAttachment::parse("tcp:2323"), Ok(SerialAttachment::Tcp(2323)));
+        assert_eq!(SerialAttachment::parse("file:com2.log"), Ok(SerialAttachment::File(PathBuf::from("com2.log"))));
+        assert!(SerialAttachment::parse("tcp:port").is_err());
+        assert!(SerialAttachment::parse("modem").is_err());
+    }
+
+    #[test]
+    pub fn test_tcp_null_modem() {
+        let mut pic = Pic::new();
+        let mut serial = SerialPortController::new();
+        let connection = TcpConnection::open(0).unwrap();
+        let tcp_port = connection.port;
+        let mut host = SerialHost::new(Box::new(connection));
+
+        serial.write_u8(SERIAL1_BASE_PORT + REG_LINE_CONTROL, 0x03);
+        serial.write_u8(SERIAL1_BASE_PORT + REG_MODEM_CONTROL, MCR_DTR | MCR_RTS);
+        step(&mut host, &mut serial, &mut pic);
+        assert_eq!(serial.read_u8(SERIAL1_BASE_PORT + REG_MODEM_STATUS) & MSR_LINES, 0);
+
+        // CTS, DSR and DCD are raised when a peer connects
+        let mut peer = TcpStream::connect(("127.0.0.1", tcp_port)).unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        for _ in 0..1000 {
+            step(&mut host, &mut serial, &mut pic);
+            if serial.read_u8(SERIAL1_BASE_PORT + REG_MODEM_STATUS) & MSR_LINES == MSR_LINES {
+                break
+            }
+        }
+        assert_eq!(serial.read_u8(SERIAL1_BASE_PORT + REG_MODEM_STATUS) & MSR_LINES, MSR_LINES);
+
+        // A byte written to THR arrives at the peer
+        serial.write_u8(SERIAL1_BASE_PORT + REG_DATA, b'A');
+        for _ in 0..4 {
+            step(&mut host, &mut serial, &mut pic);
+        }
+        let mut buf = [0; 1];
+        peer.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[0], b'A');
+
+        // A byte from the peer is held while RTS is low
+        serial.write_u8(SERIAL1_BASE_PORT + REG_MODEM_CONTROL, MCR_DTR);
+        peer.write_all(b"Z").unwrap();
+        for _ in 0..1000 {
+            step(&mut host, &mut serial, &mut pic);
+            if !host.rx_buf.is_empty() {
+                break
+            }
+        }
+        assert_eq!(host.rx_buf.len(), 1);
+        for _ in 0..4 {
+            step(&mut host, &mut serial, &mut pic);
+        }
+        assert_eq!(serial.read_u8(SERIAL1_BASE_PORT + REG_LINE_STATUS) & LSR_DATA_READY, 0);
+
+        // and reaches RBR once RTS is raised
+        serial.write_u8(SERIAL1_BASE_PORT + REG_MODEM_CONTROL, MCR_DTR | MCR_RTS);
+        for _ in 0..4 {
+            step(&mut host, &mut serial, &mut pic);
+        }
+        assert_ne!(serial.read_u8(SERIAL1_BASE_PORT + REG_LINE_STATUS) & LSR_DATA_READY, 0);
+        assert_eq!(serial.read_u8(SERIAL1_BASE_PORT + REG_DATA), b'Z');
+
+        // Dropping DTR hangs up the peer and drops the modem status lines
+        serial.write_u8(SERIAL1_BASE_PORT + REG_MODEM_CONTROL, MCR_RTS);
+        step(&mut host, &mut serial, &mut pic);
+        assert_eq!(peer.read(&mut buf).unwrap(), 0);
+        assert_eq!(serial.read_u8(SERIAL1_BASE_PORT + REG_MODEM_STATUS) & MSR_LINES, 0);
+    }
+}
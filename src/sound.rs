@@ -0,0 +1,387 @@
+/*
+    sound.rs
+    Implement PC speaker sampling and audio output
+
+    The speaker is a 1-bit output. It is clocked at the PIT rate, which lets us capture
+    both tones from PIT channel #2 and PWM-style tricks that toggle the speaker data bit
+    directly. The signal is low-pass filtered at the PIT rate, integrated over each output
+    sample period (a box filter) and DC-blocked to produce PCM.
+
+    The outputs of a DAC on the parallel port (Covox or Disney Sound Source) and of the
+    AdLib are sampled at the same rate, integrated the same way and mixed in before DC
+    blocking.
+
+    Samples are played through the host audio device when built with the 'cpal' feature
+    (the default), or written to a WAV file if one is requested.
+*/
+
+use std::{
+    f64::consts::PI,
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+pub const PIT_HZ: f64 = 1_193_182.0;
+pub const SAMPLE_RATE_DEFAULT: u32 = 44100;
+
+// Speaker output level. Keep some headroom for other sources.
+const SPEAKER_VOLUME: f32 = 0.5;
+// Parallel port DAC and AdLib output levels
+const DAC_VOLUME: f64 = 0.5;
+const FM_VOLUME: f64 = 0.5;
+// Cutoff of the two-pole low-pass filter applied before decimation, as a fraction of
+// the output sample rate
+const LOWPASS_CUTOFF_RATIO: f64 = 0.3;
+// Pole of the DC blocking filter. The speaker cone can't hold a DC offset.
+const DC_BLOCK_POLE: f32 = 0.995;
+
+/// Options for opening an audio output.
+#[derive(Clone, Debug, Default)]
+pub struct SoundConfig {
+    pub wav_path: Option<PathBuf>,
+}
+
+/// Converts the speaker signal, clocked at the PIT rate, into PCM samples.
+pub struct Speaker {
+    sample_rate: u32,
+    ticks_per_sample: f64,
+    tick_accumulator: f64,
+    level_accumulator: f64,
+    dac_level: f64,
+    fm_level: f64,
+    mix_accumulator: f64,
+    lowpass_alpha: f64,
+    lowpass_state: [f64; 2],
+    dc_prev_in: f32,
+    dc_prev_out: f32,
+    samples: Vec<f32>,
+}
+
+impl Speaker {
+    pub fn new(sample_rate: u32) -> Self {
+        let mut speaker = Self {
+            sample_rate,
+            ticks_per_sample: 0.0,
+            tick_accumulator: 0.0,
+            level_accumulator: 0.0,
+            dac_level: 0.0,
+            fm_level: 0.0,
+            mix_accumulator: 0.0,
+            lowpass_alpha: 0.0,
+            lowpass_state: [0.0; 2],
+            dc_prev_in: 0.0,
+            dc_prev_out: 0.0,
+            samples: Vec::new(),
+        };
+        speaker.set_sample_rate(sample_rate);
+        speaker
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.ticks_per_sample = PIT_HZ / sample_rate as f64;
+        let cutoff = sample_rate as f64 * LOWPASS_CUTOFF_RATIO;
+        self.lowpass_alpha = 1.0 - (-2.0 * PI * cutoff / PIT_HZ).exp();
+        self.tick_accumulator = 0.0;
+        self.level_accumulator = 0.0;
+        self.mix_accumulator = 0.0;
+    }
+
+    /// Set the output of the parallel port DAC, from -1.0 to 1.0. It is held until changed.
+    pub fn set_dac_level(&mut self, level: f32) {
+        self.dac_level = level as f64;
+    }
+
+    /// Set the output of the AdLib, from -1.0 to 1.0. It is held until changed.
+    pub fn set_fm_level(&mut self, level: f32) {
+        self.fm_level = level as f64;
+    }
+
+    /// Clock the speaker for one PIT tick with the current speaker signal level.
+    pub fn tick(&mut self, level: bool) {
+        let input = if level { 1.0 } else { 0.0 };
+        self.lowpass_state[0] += self.lowpass_alpha * (input - self.lowpass_state[0]);
+        self.lowpass_state[1] += self.lowpass_alpha * (self.lowpass_state[0] - self.lowpass_state[1]);
+        let level = self.lowpass_state[1];
+
+        let mix = self.dac_level * DAC_VOLUME + self.fm_level * FM_VOLUME;
+        let remaining = self.ticks_per_sample - self.tick_accumulator;
+
+        if remaining > 1.0 {
+            self.level_accumulator += level;
+            self.mix_accumulator += mix;
+            self.tick_accumulator += 1.0;
+        }
+        else {
+            // This tick straddles a sample boundary. Split it between the two samples.
+            self.level_accumulator += level * remaining;
+            self.mix_accumulator += mix * remaining;
+            let average = self.level_accumulator / self.ticks_per_sample;
+            let mix_average = self.mix_accumulator / self.ticks_per_sample;
+            self.emit_sample(average as f32, mix_average as f32);
+
+            self.level_accumulator = level * (1.0 - remaining);
+            self.mix_accumulator = mix * (1.0 - remaining);
+            self.tick_accumulator = 1.0 - remaining;
+        }
+    }
+
+    fn emit_sample(&mut self, average: f32, mix_average: f32) {
+        // Map the average level (0..1) to -1..1
+        let input = (average * 2.0 - 1.0) * SPEAKER_VOLUME + mix_average;
+
+        let output = input - self.dc_prev_in + DC_BLOCK_POLE * self.dc_prev_out;
+        self.dc_prev_in = input;
+        self.dc_prev_out = output;
+
+        self.samples.push(output);
+    }
+
+    /// Move all samples produced so far into the provided buffer.
+    pub fn drain_samples(&mut self, out: &mut Vec<f32>) {
+        out.append(&mut self.samples);
+    }
+}
+
+/// A destination for PCM samples.
+pub trait SoundSink {
+    fn sample_rate(&self) -> u32;
+    fn queue_samples(&mut self, samples: &[f32]);
+}
+
+/// Writes mono 16-bit PCM samples to a WAV file. The header is completed when the sink
+/// is dropped.
+pub struct WavFileSink {
+    writer: BufWriter<File>,
+    sample_rate: u32,
+    samples_written: u32,
+}
+
+const WAV_HEADER_SIZE: u32 = 44;
+
+impl WavFileSink {
+    pub fn new(path: &Path, sample_rate: u32) -> io::Result<Self> {
+        let mut sink = Self {
+            writer: BufWriter::new(File::create(path)?),
+            sample_rate,
+            samples_written: 0,
+        };
+        sink.write_header()?;
+        Ok(sink)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let data_size = self.samples_written * 2;
+        let w = &mut self.writer;
+
+        w.write_all(b"RIFF")?;
+        w.write_all(&(WAV_HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+        w.write_all(b"WAVE")?;
+        w.write_all(b"fmt ")?;
+        w.write_all(&16u32.to_le_bytes())?;                     // fmt chunk size
+        w.write_all(&1u16.to_le_bytes())?;                      // PCM
+        w.write_all(&1u16.to_le_bytes())?;                      // Mono
+        w.write_all(&self.sample_rate.to_le_bytes())?;
+        w.write_all(&(self.sample_rate * 2).to_le_bytes())?;    // Byte rate
+        w.write_all(&2u16.to_le_bytes())?;                      // Block align
+        w.write_all(&16u16.to_le_bytes())?;                     // Bits per sample
+        w.write_all(b"data")?;
+        w.write_all(&data_size.to_le_bytes())?;
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.writer.flush()
+    }
+}
+
+impl SoundSink for WavFileSink {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn queue_samples(&mut self, samples: &[f32]) {
+        for sample in samples {
+            let pcm = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            if let Err(e) = self.writer.write_all(&pcm.to_le_bytes()) {
+                log::error!("Error writing WAV file: {}", e);
+                return
+            }
+            self.samples_written += 1;
+        }
+    }
+}
+
+impl Drop for WavFileSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.finalize() {
+            log::error!("Error finalizing WAV file: {}", e);
+        }
+    }
+}
+
+#[cfg(feature = "cpal")]
+mod host {
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+    };
+
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+    use super::SoundSink;
+
+    /// Plays samples through the default output device of the host.
+    /// The stream is not Send on all platforms, so the sink must be created on the thread
+    /// that produces samples.
+    pub struct HostAudioSink {
+        _stream: cpal::Stream,
+        queue: Arc<Mutex<VecDeque<f32>>>,
+        sample_rate: u32,
+        max_queue_len: usize,
+    }
+
+    impl HostAudioSink {
+        pub fn new() -> Result<Self, String> {
+            let host = cpal::default_host();
+            let device = host.default_output_device().ok_or_else(|| "No audio output device".to_string())?;
+            let supported = device.default_output_config().map_err(|e| e.to_string())?;
+            let sample_format = supported.sample_format();
+            let config: cpal::StreamConfig = supported.into();
+
+            let sample_rate = config.sample_rate.0;
+            let channels = config.channels as usize;
+            let queue = Arc::new(Mutex::new(VecDeque::new()));
+            let err_fn = |err| log::error!("Audio stream error: {}", err);
+
+            let stream_queue = queue.clone();
+            let stream = match sample_format {
+                cpal::SampleFormat::F32 => device.build_output_stream(
+                    &config,
+                    move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                        fill_buffer(data, channels, &stream_queue, |s| s)
+                    },
+                    err_fn),
+                cpal::SampleFormat::I16 => device.build_output_stream(
+                    &config,
+                    move |data: &mut [i16], _: &cpal::OutputCallbackInfo| {
+                        fill_buffer(data, channels, &stream_queue, |s| (s * i16::MAX as f32) as i16)
+                    },
+                    err_fn),
+                cpal::SampleFormat::U16 => device.build_output_stream(
+                    &config,
+                    move |data: &mut [u16], _: &cpal::OutputCallbackInfo| {
+                        fill_buffer(data, channels, &stream_queue, |s| ((s * 0.5 + 0.5) * u16::MAX as f32) as u16)
+                    },
+                    err_fn),
+            }.map_err(|e| e.to_string())?;
+
+            stream.play().map_err(|e| e.to_string())?;
+
+            Ok(Self {
+                _stream: stream,
+                queue,
+                sample_rate,
+                // Don't let latency build up beyond a quarter second
+                max_queue_len: sample_rate as usize / 4,
+            })
+        }
+    }
+
+    /// Fill an output buffer from the sample queue, duplicating mono samples across channels.
+    /// Outputs silence if the queue runs dry.
+    fn fill_buffer<T: Copy>(data: &mut [T], channels: usize, queue: &Mutex<VecDeque<f32>>, convert: impl Fn(f32) -> T) {
+        let mut queue = queue.lock().unwrap();
+        for frame in data.chunks_mut(channels) {
+            let sample = convert(queue.pop_front().unwrap_or(0.0).clamp(-1.0, 1.0));
+            for out in frame.iter_mut() {
+                *out = sample;
+            }
+        }
+    }
+
+    impl SoundSink for HostAudioSink {
+        fn sample_rate(&self) -> u32 {
+            self.sample_rate
+        }
+
+        fn queue_samples(&mut self, samples: &[f32]) {
+            let mut queue = self.queue.lock().unwrap();
+            queue.extend(samples);
+            while queue.len() > self.max_queue_len {
+                queue.pop_front();
+            }
+        }
+    }
+}
+
+/// Open an audio output. A WAV file is used if one was requested, otherwise the host audio
+/// device. With no device available the emulator runs silent.
+pub fn open_sink(config: &SoundConfig) -> Option<Box<dyn SoundSink>> {
+
+    if let Some(path) = &config.wav_path {
+        return match WavFileSink::new(path, SAMPLE_RATE_DEFAULT) {
+            Ok(sink) => {
+                log::debug!("Writing audio output to {}", path.display());
+                Some(Box::new(sink))
+            }
+            Err(e) => {
+                log::error!("Couldn't create WAV file {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    #[cfg(feature = "cpal")]
+    match host::HostAudioSink::new() {
+        Ok(sink) => {
+            log::debug!("Opened host audio output at {}Hz", sink.sample_rate());
+            return Some(Box::new(sink))
+        }
+        Err(e) => {
+            log::warn!("Couldn't open host audio output, running without sound: {}", e);
+        }
+    }
+
+    #[cfg(not(feature = "cpal"))]
+    log::warn!("Built without host audio support, running without sound");
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_speaker_pwm() {
+        let mut speaker = Speaker::new(SAMPLE_RATE_DEFAULT);
+        let mut samples = Vec::new();
+
+        // A PWM carrier well above the audio band should average out to a level set by its
+        // duty cycle. Modulate the duty cycle with a slow square wave and expect to hear it.
+        for period in 0..2000 {
+            let duty = if (period / 500) % 2 == 0 { 4 } else { 12 };
+            for tick in 0..16 {
+                speaker.tick(tick < duty);
+            }
+        }
+        speaker.drain_samples(&mut samples);
+
+        let expected = (2000.0 * 16.0 / speaker.ticks_per_sample) as usize;
+        assert!((samples.len() as i64 - expected as i64).abs() <= 1);
+
+        // The carrier itself should be filtered out, leaving swings between the low and high 
+        // duty cycle sections.
+        let section = samples.len() / 4;
+        let low = samples[section - 1];
+        let high = samples[section + 10];
+        assert!(high - low > 0.4);
+        for window in samples[section + 10..2 * section - 1].windows(2) {
+            assert!((window[1] - window[0]).abs() < 0.01);
+        }
+    }
+}
@@ -0,0 +1,535 @@
+/*
+    td0.rs
+    Import Teledisk (.TD0) floppy images
+
+    Teledisk images begin with a 12 byte header:
+
+        2 bytes     Signature: "TD" for normal images, "td" for advanced compression
+        u8          Volume sequence
+        u8          Check signature
+        u8          Teledisk version, eg 21 for 2.1
+        u8          Data rate: 0-2 for 250, 300, 500Kbps. Bit 7 set for FM
+        u8          Drive type
+        u8          Stepping. Bit 7 set if a comment block follows
+        u8          DOS allocation flag
+        u8          Sides
+        u16         CRC
+
+    With advanced compression, everything after the header is compressed with LZSS and
+    adaptive Huffman coding (LZHUF). The rest of the image is:
+
+        Optional comment block: u16 CRC, u16 length, then the year since 1900, month from 0,
+            day, hour, minute and second, followed by `length` bytes of NUL separated lines
+        Tracks of:
+            u8 sector count (0xFF ends the image), u8 cylinder, u8 head (bit 7 set for FM), u8 CRC
+            Sectors of:
+                u8 cylinder, u8 head, u8 sector number, u8 size code, u8 flags, u8 CRC
+                Unless the flags say no data was recorded, u16 length, u8 encoding and data:
+                    0   Raw data
+                    1   u16 count, then a 2 byte pattern repeated count times
+                    2   Runs of either 0, u8 length and literal bytes, or n, u8 count and a
+                        pattern of 1 << n bytes repeated count times
+
+    All values are little endian. Teledisk images can't be written back, so they are loaded
+    write protected.
+*/
+
+use time::{Date, Month, PrimitiveDateTime, Time};
+
+use crate::floppy_image::{DiskImage, ImageError, ImageFormat, Sector, SectorId, Track, TrackMode};
+
+pub const TD0_SIGNATURE: &[u8] = b"TD";
+pub const TD0_ADVANCED_SIGNATURE: &[u8] = b"td";
+const HEADER_LEN: usize = 12;
+// The first version to use LZHUF for advanced compression. Earlier versions used LZW.
+const LZHUF_VERSION: u8 = 20;
+const END_OF_IMAGE: u8 = 0xFF;
+
+const RATE_MASK: u8 = 0b0000_0011;
+const RATE_FM: u8 = 0b1000_0000;
+const STEPPING_COMMENT: u8 = 0b1000_0000;
+const HEAD_FM: u8 = 0b1000_0000;
+const HEAD_MASK: u8 = 0b0000_0001;
+
+const SECTOR_CRC_ERROR: u8 = 0b0000_0010;
+const SECTOR_DELETED: u8 = 0b0000_0100;
+const SECTOR_SKIPPED: u8 = 0b0001_0000;
+const SECTOR_NO_DATA: u8 = 0b0010_0000;
+
+const ENCODING_RAW: u8 = 0;
+const ENCODING_REPEAT: u8 = 1;
+const ENCODING_RLE: u8 = 2;
+
+const RATES: [u16; 3] = [250, 300, 500];
+
+const BAD_SECTOR_DATA: ImageError = ImageError::BadFormat("Invalid TD0 sector data");
+
+// LZHUF parameters: ring buffer size, longest match, shortest match, and the sizes of the
+// adaptive Huffman tree
+const N: usize = 4096;
+const F: usize = 60;
+const THRESHOLD: usize = 2;
+const N_CHAR: usize = 256 - THRESHOLD + F;
+const T: usize = N_CHAR * 2 - 1;
+const R: usize = T - 1;
+const MAX_FREQ: u16 = 0x8000;
+
+/// An LZHUF decompressor, after Haruyasu Yoshizaki's LZHUF.C as used by Teledisk.
+struct Lzhuf<'a> {
+    input: &'a [u8],
+    pos: usize,
+    bits: u16,
+    bit_count: u32,
+    bits_read: usize,
+    freq: [u16; T + 1],
+    // Parents of nodes, then of leaves at T + character
+    parent: [usize; T + N_CHAR],
+    son: [usize; T],
+    text: [u8; N],
+    r: usize,
+    copy_pos: usize,
+    copy_len: usize,
+}
+
+impl<'a> Lzhuf<'a> {
+    fn new(input: &'a [u8]) -> Self {
+        let mut lzhuf = Self {
+            input,
+            pos: 0,
+            bits: 0,
+            bit_count: 0,
+            bits_read: 0,
+            freq: [0; T + 1],
+            parent: [0; T + N_CHAR],
+            son: [0; T],
+            text: [b' '; N],
+            r: N - F,
+            copy_pos: 0,
+            copy_len: 0,
+        };
+
+        for i in 0..N_CHAR {
+            lzhuf.freq[i] = 1;
+            lzhuf.son[i] = i + T;
+            lzhuf.parent[i + T] = i;
+        }
+        let mut i = 0;
+        for j in N_CHAR..=R {
+            lzhuf.freq[j] = lzhuf.freq[i] + lzhuf.freq[i + 1];
+            lzhuf.son[j] = i;
+            lzhuf.parent[i] = j;
+            lzhuf.parent[i + 1] = j;
+            i += 2;
+        }
+        lzhuf.freq[T] = 0xFFFF;
+        lzhuf.parent[R] = 0;
+        lzhuf
+    }
+
+    fn fill(&mut self) {
+        while self.bit_count <= 8 {
+            let byte = self.input.get(self.pos).copied().unwrap_or(0);
+            self.pos += 1;
+            self.bits |= (byte as u16) << (8 - self.bit_count);
+            self.bit_count += 8;
+        }
+    }
+
+    fn bit(&mut self) -> usize {
+        self.fill();
+        let bit = self.bits >> 15;
+        self.bits <<= 1;
+        self.bit_count -= 1;
+        self.bits_read += 1;
+        bit as usize
+    }
+
+    fn byte(&mut self) -> usize {
+        self.fill();
+        let byte = self.bits >> 8;
+        self.bits <<= 8;
+        self.bit_count -= 8;
+        self.bits_read += 8;
+        byte as usize
+    }
+
+    /// Rebuild the tree with halved frequencies once the root reaches MAX_FREQ.
+    fn reconstruct(&mut self) {
+        // Collect the leaves into the first half of the table
+        let mut j = 0;
+        for i in 0..T {
+            if self.son[i] >= T {
+                self.freq[j] = self.freq[i].div_ceil(2);
+                self.son[j] = self.son[i];
+                j += 1;
+            }
+        }
+        // Join pairs of nodes, keeping the table sorted by frequency
+        let mut i = 0;
+        for j in N_CHAR..T {
+            let f = self.freq[i] + self.freq[i + 1];
+            let mut k = j;
+            while f < self.freq[k - 1] {
+                k -= 1;
+            }
+            self.freq.copy_within(k..j, k + 1);
+            self.freq[k] = f;
+            self.son.copy_within(k..j, k + 1);
+            self.son[k] = i;
+            i += 2;
+        }
+        for i in 0..T {
+            let k = self.son[i];
+            self.parent[k] = i;
+            if k < T {
+                self.parent[k + 1] = i;
+            }
+        }
+    }
+
+    /// Count an occurrence of character `c`, swapping nodes to keep the tree ordered.
+    fn update(&mut self, c: usize) {
+        if self.freq[R] == MAX_FREQ {
+            self.reconstruct();
+        }
+        let mut c = self.parent[c + T];
+        loop {
+            self.freq[c] += 1;
+            let k = self.freq[c];
+
+            let mut l = c + 1;
+            if k > self.freq[l] {
+                while k > self.freq[l + 1] {
+                    l += 1;
+                }
+                self.freq[c] = self.freq[l];
+                self.freq[l] = k;
+
+                let i = self.son[c];
+                self.parent[i] = l;
+                if i < T {
+                    self.parent[i + 1] = l;
+                }
+                let j = self.son[l];
+                self.son[l] = i;
+                self.parent[j] = c;
+                if j < T {
+                    self.parent[j + 1] = c;
+                }
+                self.son[c] = j;
+                c = l;
+            }
+
+            c = self.parent[c];
+            if c == 0 {
+                break
+            }
+        }
+    }
+
+    fn decode_char(&mut self) -> usize {
+        let mut c = self.son[R];
+        while c < T {
+            c += self.bit();
+            c = self.son[c];
+        }
+        c -= T;
+        self.update(c);
+        c
+    }
+
+    /// Decode a match position. The upper 6 bits are coded by the first byte, with shorter
+    /// codes for nearer positions, and the lower 6 bits follow.
+    fn decode_position(&mut self) -> usize {
+        let mut i = self.byte();
+        let (code, len) = match i {
+            0..=31 => (0, 3),
+            32..=79 => (1 + (i - 32) / 16, 4),
+            80..=143 => (4 + (i - 80) / 8, 5),
+            144..=191 => (12 + (i - 144) / 4, 6),
+            192..=239 => (24 + (i - 192) / 2, 7),
+            _ => (48 + (i - 240), 8)
+        };
+        for _ in 0..len - 2 {
+            i = (i << 1) + self.bit();
+        }
+        (code << 6) | (i & 0x3F)
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.text[self.r] = byte;
+        self.r = (self.r + 1) & (N - 1);
+    }
+
+    /// The next decompressed byte, or None once the input is used up.
+    fn next_byte(&mut self) -> Option<u8> {
+        if self.copy_len == 0 {
+            let c = self.decode_char();
+            if c < 256 {
+                if self.bits_read > self.input.len() * 8 {
+                    return None
+                }
+                self.push(c as u8);
+                return Some(c as u8)
+            }
+            let position = self.decode_position();
+            if self.bits_read > self.input.len() * 8 {
+                return None
+            }
+            self.copy_pos = (self.r + N - position - 1) & (N - 1);
+            self.copy_len = c - 255 + THRESHOLD;
+        }
+        let byte = self.text[self.copy_pos];
+        self.copy_pos = (self.copy_pos + 1) & (N - 1);
+        self.copy_len -= 1;
+        self.push(byte);
+        Some(byte)
+    }
+}
+
+/// The image after its header, read directly or through the decompressor.
+enum Source<'a> {
+    Raw(&'a [u8], usize),
+    Lzhuf(Box<Lzhuf<'a>>),
+}
+
+impl<'a> Source<'a> {
+    fn u8(&mut self) -> Result<u8, ImageError> {
+        let byte = match self {
+            Source::Raw(data, pos) => {
+                let byte = data.get(*pos).copied();
+                *pos += 1;
+                byte
+            }
+            Source::Lzhuf(lzhuf) => lzhuf.next_byte()
+        };
+        byte.ok_or(ImageError::BadFormat("Truncated TD0 image"))
+    }
+
+    fn u16(&mut self) -> Result<u16, ImageError> {
+        Ok(u16::from_le_bytes([self.u8()?, self.u8()?]))
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<Vec<u8>, ImageError> {
+        (0..len).map(|_| self.u8()).collect()
+    }
+}
+
+/// Expand a sector's data record into `size` bytes.
+fn decode_sector(record: &[u8], size: usize) -> Result<Vec<u8>, ImageError> {
+    let (&encoding, record) = record.split_first().ok_or(BAD_SECTOR_DATA)?;
+    let mut data = Vec::with_capacity(size);
+    match encoding {
+        ENCODING_RAW => data.extend_from_slice(record),
+        ENCODING_REPEAT => {
+            if record.len() < 4 {
+                return Err(BAD_SECTOR_DATA)
+            }
+            let count = u16::from_le_bytes([record[0], record[1]]) as usize;
+            for _ in 0..count {
+                data.extend_from_slice(&record[2..4]);
+            }
+        }
+        ENCODING_RLE => {
+            let mut pos = 0;
+            while pos + 2 <= record.len() {
+                let (kind, count) = (record[pos], record[pos + 1] as usize);
+                pos += 2;
+                let (run, repeat) = match kind {
+                    0 => (count, 1),
+                    _ => (1usize.checked_shl(kind as u32).ok_or(BAD_SECTOR_DATA)?, count)
+                };
+                let pattern = record.get(pos..pos + run).ok_or(BAD_SECTOR_DATA)?;
+                pos += run;
+                for _ in 0..repeat {
+                    data.extend_from_slice(pattern);
+                }
+            }
+        }
+        _ => return Err(BAD_SECTOR_DATA)
+    }
+    data.resize(size, 0);
+    Ok(data)
+}
+
+fn read_comment(source: &mut Source) -> Result<(String, Option<PrimitiveDateTime>), ImageError> {
+    let _crc = source.u16()?;
+    let len = source.u16()? as usize;
+    let stamp = source.bytes(6)?;
+    let text = source.bytes(len)?;
+
+    let created = stamp[1].checked_add(1).and_then(|month| Month::try_from(month).ok())
+        .and_then(|month| Date::from_calendar_date(1900 + stamp[0] as i32, month, stamp[2]).ok())
+        .zip(Time::from_hms(stamp[3], stamp[4], stamp[5]).ok())
+        .map(|(date, time)| PrimitiveDateTime::new(date, time));
+
+    let comment = text.split(|&b| b == 0)
+        .map(|line| String::from_utf8_lossy(line).trim_end().to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+    Ok((comment.trim_end().to_string(), created))
+}
+
+pub fn load(data: &[u8]) -> Result<DiskImage, ImageError> {
+    if data.len() < HEADER_LEN {
+        return Err(ImageError::BadFormat("Truncated TD0 image"))
+    }
+    let advanced = data.starts_with(TD0_ADVANCED_SIGNATURE);
+    let version = data[4];
+    let rate = data[5];
+    let stepping = data[7];
+
+    let mut source = if advanced {
+        if version < LZHUF_VERSION {
+            return Err(ImageError::BadFormat("Teledisk 1.x advanced compression isn't supported"))
+        }
+        Source::Lzhuf(Box::new(Lzhuf::new(&data[HEADER_LEN..])))
+    }
+    else {
+        Source::Raw(data, HEADER_LEN)
+    };
+
+    let (comment, created) = match stepping & STEPPING_COMMENT {
+        0 => (String::new(), None),
+        _ => read_comment(&mut source)?
+    };
+
+    let rate_kbps = *RATES.get((rate & RATE_MASK) as usize).ok_or(ImageError::BadFormat("Invalid TD0 data rate"))?;
+    let mut tracks = Vec::new();
+    loop {
+        // Some images end without the end of image marker
+        let sector_count = match source.u8() {
+            Ok(END_OF_IMAGE) | Err(_) => break,
+            Ok(count) => count
+        };
+        let cylinder = source.u8()?;
+        let head_flags = source.u8()?;
+        let _crc = source.u8()?;
+
+        let mut sectors = Vec::with_capacity(sector_count as usize);
+        for _ in 0..sector_count {
+            let header = source.bytes(6)?;
+            let id = SectorId { c: header[0], h: header[1], r: header[2], n: header[3] };
+            let flags = header[4];
+
+            let data = if flags & SECTOR_NO_DATA != 0 {
+                None
+            }
+            else if flags & SECTOR_SKIPPED != 0 {
+                // Teledisk didn't store sectors DOS hadn't allocated
+                Some(vec![0; id.size()])
+            }
+            else {
+                let len = source.u16()? as usize;
+                let record = source.bytes(len)?;
+                Some(decode_sector(&record, id.size())?)
+            };
+            sectors.push(Sector {
+                id,
+                data,
+                deleted: flags & SECTOR_DELETED != 0,
+                data_error: flags & SECTOR_CRC_ERROR != 0,
+                id_error: false,
+                weak: None,
+            });
+        }
+
+        let mode = TrackMode {
+            rate_kbps,
+            mfm: rate & RATE_FM == 0 && head_flags & HEAD_FM == 0,
+        };
+        tracks.push(Track { cylinder, head: head_flags & HEAD_MASK, mode, sectors });
+    }
+
+    let mut image = DiskImage::new(ImageFormat::Td0, tracks, comment);
+    image.created = created;
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compress a list of LZHUF codes: characters, or match lengths with positions under 64.
+    fn compress(codes: &[(usize, usize)]) -> Vec<u8> {
+        let mut model = Lzhuf::new(&[]);
+        let mut bits = Vec::new();
+        for &(c, position) in codes {
+            let mut path = Vec::new();
+            let mut k = model.parent[c + T];
+            while k != R {
+                let parent = model.parent[k];
+                path.push(k - model.son[parent]);
+                k = parent;
+            }
+            bits.extend(path.iter().rev());
+            model.update(c);
+            if c >= 256 {
+                bits.extend([0, 0, 0]);
+                bits.extend((0..6).rev().map(|b| (position >> b) & 1));
+            }
+        }
+        bits.chunks(8)
+            .map(|byte| byte.iter().enumerate().fold(0, |acc, (i, &b)| acc | ((b as u8) << (7 - i))))
+            .collect()
+    }
+
+    #[test]
+    pub fn test_lzhuf() {
+        // "ABC" then a copy of the 3 bytes starting 3 back
+        let input = compress(&[(b'A' as usize, 0), (b'B' as usize, 0), (b'C' as usize, 0), (256, 2)]);
+        let mut lzhuf = Lzhuf::new(&input);
+        let output: Vec<u8> = (0..6).map(|_| lzhuf.next_byte().unwrap()).collect();
+        assert_eq!(output, b"ABCABC");
+
+        // Enough input to make the tree be rebuilt
+        let data: Vec<usize> = (0..40_000).map(|i| (i * 7) % 13).collect();
+        let input = compress(&data.iter().map(|&c| (c, 0)).collect::<Vec<_>>());
+        let mut lzhuf = Lzhuf::new(&input);
+        assert!(data.iter().all(|&c| lzhuf.next_byte() == Some(c as u8)));
+    }
+
+    #[test]
+    pub fn test_td0_image() {
+        let mut body = vec![0, 0, 16, 0, 85, 1, 3, 4, 5, 6];
+        body.extend_from_slice(b"Disk one\0Side A\0");
+        body.extend_from_slice(&[4, 0, 0, 0]);
+        body.extend_from_slice(&[0, 0, 1, 2, 0, 0]);
+        body.extend_from_slice(&513u16.to_le_bytes());
+        body.push(ENCODING_RAW);
+        body.extend((0..512).map(|i| i as u8));
+        body.extend_from_slice(&[0, 0, 2, 2, SECTOR_CRC_ERROR, 0, 5, 0, ENCODING_REPEAT, 0, 1, 0xAB, 0xCD]);
+        body.extend_from_slice(&[0, 0, 0x41, 1, SECTOR_DELETED, 0, 9, 0, ENCODING_RLE, 0, 2, 0x11, 0x22, 1, 127, 0x33, 0x44]);
+        body.extend_from_slice(&[0, 0, 3, 2, SECTOR_NO_DATA, 0]);
+        body.push(END_OF_IMAGE);
+
+        let mut normal = b"TD\0\0\x15\x00\x01\x80\0\x01\0\0".to_vec();
+        normal.extend_from_slice(&body);
+        let mut advanced = b"td\0\0\x15\x00\x01\x80\0\x01\0\0".to_vec();
+        advanced.extend(compress(&body.iter().map(|&b| (b as usize, 0)).collect::<Vec<_>>()));
+
+        for data in [normal, advanced] {
+            let image = DiskImage::load(&data).unwrap();
+            assert_eq!(image.format, ImageFormat::Td0);
+            assert_eq!(image.comment, "Disk one\nSide A");
+            assert_eq!(image.created, Some(PrimitiveDateTime::new(
+                Date::from_calendar_date(1985, Month::February, 3).unwrap(),
+                Time::from_hms(4, 5, 6).unwrap())));
+
+            let sectors = &image.tracks[0].sectors;
+            assert_eq!(sectors[0].data.as_ref().unwrap()[255], 255);
+            assert!(sectors[1].data_error);
+            assert_eq!(sectors[1].data.as_ref().unwrap()[..4], [0xAB, 0xCD, 0xAB, 0xCD]);
+            assert!(sectors[2].deleted);
+            assert_eq!(sectors[2].data.as_ref().unwrap().len(), 256);
+            assert_eq!(sectors[2].data.as_ref().unwrap()[..4], [0x11, 0x22, 0x33, 0x44]);
+            assert_eq!(sectors[3].data, None);
+        }
+
+        // An invalid timestamp is ignored
+        let mut bad_stamp = b"TD\0\0\x15\x00\x01\x80\0\x01\0\0".to_vec();
+        bad_stamp.extend_from_slice(&body);
+        bad_stamp[HEADER_LEN + 5] = 0xFF;
+        assert_eq!(DiskImage::load(&bad_stamp).unwrap().created, None);
+    }
+}
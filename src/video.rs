@@ -2,8 +2,7 @@
 // Video module
 // This module takes an internal representation from the cga module and actually draws the screen
 // It also defines representational details such as colors
-use std::rc::Rc;
-use std::cell::RefCell;
+use std::sync::{Arc, Mutex};
 
 use crate::cga::{self, CGACard, CGAColor, CGAPalette, DisplayMode, CursorInfo};
 use crate::bus::BusInterface;
@@ -186,11 +185,11 @@ impl Video {
         }
     }
 
-    pub fn draw(&self, frame: &mut [u8], cga: Rc<RefCell<CGACard>>, bus: &BusInterface, composite: bool) {
+    pub fn draw(&self, frame: &mut [u8], cga: Arc<Mutex<CGACard>>, bus: &BusInterface, composite: bool) {
 
         let video_mem = bus.get_slice_at(cga::CGA_MEM_ADDRESS, cga::CGA_MEM_SIZE);
 
-        let cga_card = cga.borrow();
+        let cga_card = cga.lock().unwrap();
         let mode_40_cols = cga_card.is_40_columns();
 
 